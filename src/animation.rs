@@ -0,0 +1,189 @@
+use std::{fmt, path::{Path, PathBuf}, str::FromStr};
+
+use crate::{camera::Camera, geometry::Point};
+
+/// Where the camera is, what it looks at and how wide it sees at a time, in seconds
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CameraKey {
+    pub time: f32,
+    pub position: Point,
+    pub look_at: Point,
+    /// Vertical field of view in degrees
+    pub fov: f32,
+}
+
+impl CameraKey {
+    pub fn new(time: f32, position: Point, look_at: Point, fov: f32) -> Self {
+        Self { time, position, look_at, fov }
+    }
+}
+
+/// A camera flying through keys along Catmull-Rom splines, which pass through every key and
+/// keep the speed continuous from one key to the next. Before the first key and after the last
+/// one it stands still.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CameraPath {
+    keys: Vec<CameraKey>,
+}
+
+impl CameraPath {
+    /// Flies through the keys in the order of their times. Panics without keys.
+    pub fn new(mut keys: Vec<CameraKey>) -> Self {
+        assert!(!keys.is_empty(), "a camera path needs at least one key");
+        keys.sort_by(|a, b| a.time.total_cmp(&b.time));
+        Self { keys }
+    }
+
+    pub fn keys(&self) -> &[CameraKey] {
+        &self.keys
+    }
+
+    pub fn at(&self, time: f32) -> CameraKey {
+        let next = self.keys.partition_point(|key| key.time <= time);
+        if next == 0 {
+            return self.keys[0];
+        }
+        if next == self.keys.len() {
+            return self.keys[next - 1];
+        }
+        let (i, j) = (next - 1, next);
+        let (a, b) = (&self.keys[i], &self.keys[j]);
+        let duration = b.time - a.time;
+        let t = (time - a.time) / duration;
+        // Hermite basis, with tangents scaled to the length of the segment
+        let h00 = 2.0 * t * t * t - 3.0 * t * t + 1.0;
+        let h10 = t * t * t - 2.0 * t * t + t;
+        let h01 = -2.0 * t * t * t + 3.0 * t * t;
+        let h11 = t * t * t - t * t;
+        let spline = |value: &dyn Fn(&CameraKey) -> [f32; 3]| -> [f32; 3] {
+            let (ma, mb) = (self.tangent(i, value), self.tangent(j, value));
+            let (va, vb) = (value(a), value(b));
+            std::array::from_fn(|c| h00 * va[c] + h10 * duration * ma[c] + h01 * vb[c] + h11 * duration * mb[c])
+        };
+        let position = spline(&|key| key.position.coords.into());
+        let look_at = spline(&|key| key.look_at.coords.into());
+        let fov = spline(&|key| [key.fov, 0.0, 0.0])[0];
+        CameraKey::new(time, Point::from(position), Point::from(look_at), fov.clamp(1.0, 179.0))
+    }
+
+    /// Rate of change of a value at key `i`, from its neighbors, or towards the only neighbor
+    /// of the first and last keys
+    fn tangent(&self, i: usize, value: &dyn Fn(&CameraKey) -> [f32; 3]) -> [f32; 3] {
+        let (before, after) = (&self.keys[i.saturating_sub(1)], &self.keys[(i + 1).min(self.keys.len() - 1)]);
+        let duration = after.time - before.time;
+        if duration <= 0.0 {
+            return [0.0; 3];
+        }
+        let (vb, va) = (value(before), value(after));
+        std::array::from_fn(|c| (va[c] - vb[c]) / duration)
+    }
+
+    /// Puts `camera` where the path takes it at `time`
+    pub fn apply(&self, camera: &mut Camera, time: f32) {
+        let key = self.at(time);
+        camera.set_origin(key.position);
+        camera.set_direction(key.look_at - key.position);
+        camera.set_fov(key.fov);
+    }
+}
+
+/// Frames of an animation, both ends included
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FrameRange {
+    pub first: u32,
+    pub last: u32,
+}
+
+impl FrameRange {
+    pub fn frames(&self) -> std::ops::RangeInclusive<u32> {
+        self.first..=self.last
+    }
+
+    pub fn count(&self) -> u32 {
+        self.last - self.first + 1
+    }
+}
+
+impl fmt::Display for FrameRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}..{}", self.first, self.last)
+    }
+}
+
+impl FromStr for FrameRange {
+    type Err = anyhow::Error;
+
+    /// Parses `first..last` or a single frame
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (first, last) = s.split_once("..").unwrap_or((s, s));
+        let frame = |value: &str| value.parse::<u32>().map_err(|e| anyhow::anyhow!("invalid frame range '{}': {}", s, e));
+        let (first, last) = (frame(first)?, frame(last)?);
+        if first > last {
+            anyhow::bail!("the frame range '{}' ends before it starts", s);
+        }
+        Ok(Self { first, last })
+    }
+}
+
+/// Whether `pattern` has a `%d` or `%0<width>d` placeholder for the frame number
+pub fn is_frame_pattern(pattern: &Path) -> bool {
+    frame_placeholder(&pattern.to_string_lossy()).is_some()
+}
+
+/// The path of `frame` in `pattern`, e.g. `frame_0012.png` for `frame_%04d.png`
+pub fn frame_path(pattern: &Path, frame: u32) -> PathBuf {
+    let pattern = pattern.to_string_lossy();
+    match frame_placeholder(&pattern) {
+        Some((start, end, width)) => PathBuf::from(format!("{}{:0width$}{}", &pattern[..start], frame, &pattern[end..], width = width)),
+        None => PathBuf::from(pattern.as_ref()),
+    }
+}
+
+/// Start, end and zero padded width of the first frame placeholder
+fn frame_placeholder(pattern: &str) -> Option<(usize, usize, usize)> {
+    pattern.match_indices('%').find_map(|(start, _)| {
+        let rest = &pattern[start + 1..];
+        let digits = rest.len() - rest.trim_start_matches(|c: char| c.is_ascii_digit()).len();
+        if !rest[digits..].starts_with('d') {
+            return None;
+        }
+        let width = if digits == 0 { 0 } else { rest[..digits].parse().ok()? };
+        Some((start, start + 1 + digits + 1, width))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::{Path, PathBuf};
+
+    use super::{frame_path, CameraKey, CameraPath, FrameRange};
+    use crate::geometry::Point;
+
+    #[test]
+    fn camera_path_passes_through_its_keys_smoothly() {
+        let path = CameraPath::new(vec![
+            CameraKey::new(2.0, Point::new(0.0, 0.0, -4.0), Point::origin(), 40.0),
+            CameraKey::new(0.0, Point::new(4.0, 0.0, 0.0), Point::origin(), 60.0),
+            CameraKey::new(1.0, Point::new(0.0, 0.0, 4.0), Point::new(0.0, 1.0, 0.0), 50.0),
+        ]);
+        for key in path.keys() {
+            let at = path.at(key.time);
+            assert!((at.position - key.position).norm() < 1e-5 && (at.look_at - key.look_at).norm() < 1e-5);
+            assert!((at.fov - key.fov).abs() < 1e-4);
+        }
+        // Still outside of the keys
+        assert_eq!(path.at(-1.0).position, Point::new(4.0, 0.0, 0.0));
+        assert_eq!(path.at(3.0).position, Point::new(0.0, 0.0, -4.0));
+        // No jump in speed through the middle key
+        let speed = |time: f32| (path.at(time + 1e-3).position - path.at(time).position) / 1e-3;
+        assert!((speed(1.0 - 2e-3) - speed(1.0 + 1e-3)).norm() < 0.1);
+        // The spline curves around the middle key, unlike straight lines
+        assert!(path.at(0.5).position.coords.norm() > 3.0);
+
+        assert_eq!("3..10".parse::<FrameRange>().unwrap(), FrameRange { first: 3, last: 10 });
+        assert_eq!("7".parse::<FrameRange>().unwrap().count(), 1);
+        assert!("10..3".parse::<FrameRange>().is_err());
+        assert_eq!(frame_path(Path::new("out/frame_%04d.exr"), 12), PathBuf::from("out/frame_0012.exr"));
+        assert_eq!(frame_path(Path::new("shot%d.png"), 7), PathBuf::from("shot7.png"));
+    }
+}
@@ -0,0 +1,324 @@
+use std::{fmt, path::Path, str::FromStr};
+
+use image::RgbaImage;
+
+use crate::{
+    checkpoint::{CheckpointReader, CheckpointWriter},
+    film::{Features, PixelStats},
+    geometry::Ray,
+    integrator::{LightContribution, LightSource},
+    tonemap,
+    world::{Intersection, World},
+};
+
+/// Arbitrary output variables, images accumulated next to the beauty pass for compositing
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Aov {
+    /// Distance to the nearest first hit in the pixel, infinite for the background
+    Depth,
+    /// World position of the first hit
+    Position,
+    /// Shading normal of the first hit
+    Normal,
+    /// Color of the material first hit
+    Albedo,
+    /// Coverage of the pixel by each object. Every object owns its material, so these are also
+    /// the material masks.
+    ObjectMask,
+    /// Light reaching the camera without bouncing, from the emitters and the background
+    Emission,
+    /// Light that bounced once
+    Direct,
+    /// Light that bounced twice or more
+    Indirect,
+    /// Light coming from the background and from each emitter, one layer per light
+    LightGroups,
+}
+
+impl Aov {
+    pub const ALL: [Aov; 9] = [
+        Aov::Depth,
+        Aov::Position,
+        Aov::Normal,
+        Aov::Albedo,
+        Aov::ObjectMask,
+        Aov::Emission,
+        Aov::Direct,
+        Aov::Indirect,
+        Aov::LightGroups,
+    ];
+
+    fn name(&self) -> &'static str {
+        match self {
+            Self::Depth => "depth",
+            Self::Position => "position",
+            Self::Normal => "normal",
+            Self::Albedo => "albedo",
+            Self::ObjectMask => "mask",
+            Self::Emission => "emission",
+            Self::Direct => "direct",
+            Self::Indirect => "indirect",
+            Self::LightGroups => "lightgroups",
+        }
+    }
+
+    /// Whether the AOV comes from the first hit of the camera rays, instead of being a split
+    /// of the radiance computed by the integrator
+    fn is_geometric(&self) -> bool {
+        matches!(self, Self::Depth | Self::Position | Self::Normal | Self::Albedo | Self::ObjectMask)
+    }
+
+    /// Parses a comma separated list of AOV names, or `all`
+    pub fn parse_list(s: &str) -> anyhow::Result<Vec<Aov>> {
+        if s == "all" {
+            return Ok(Self::ALL.to_vec());
+        }
+        let mut aovs = Vec::new();
+        for name in s.split(',').filter(|name| !name.is_empty()) {
+            let aov = name.parse()?;
+            if !aovs.contains(&aov) {
+                aovs.push(aov);
+            }
+        }
+        Ok(aovs)
+    }
+}
+
+impl fmt::Display for Aov {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for Aov {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::ALL.iter().find(|aov| aov.name() == s).copied().ok_or_else(|| {
+            let names = Self::ALL.iter().map(|aov| aov.name()).collect::<Vec<_>>();
+            anyhow::anyhow!("unknown AOV '{}', expected all or a list of {}", s, names.join(", "))
+        })
+    }
+}
+
+/// The channels of the AOVs of every pixel. The geometric AOVs are summed over the feature
+/// samples of the first passes, and the lighting AOVs over the samples of each pixel.
+pub struct AovFilm {
+    /// Each AOV and the index of its first channel, in the geometry or the lighting channels
+    offsets: Vec<(Aov, usize)>,
+    /// Emitters with a light group, after the one of the background
+    emitters: Vec<usize>,
+    /// Channel names, `layer.channel` as compositors expect in a multi-layer EXR
+    geometry_channels: Vec<String>,
+    lighting_channels: Vec<String>,
+    geometry: Vec<f32>,
+    lighting: Vec<f32>,
+}
+
+impl AovFilm {
+    /// Lays out the channels of `aovs` for the objects and lights of `world`
+    pub fn new(aovs: &[Aov], world: &World, pixels: usize) -> Self {
+        let emitters = world.emitters().to_vec();
+        let (mut geometry_channels, mut lighting_channels) = (Vec::new(), Vec::new());
+        let mut offsets = Vec::new();
+        for &aov in aovs {
+            let channels: &mut Vec<String> = if aov.is_geometric() { &mut geometry_channels } else { &mut lighting_channels };
+            offsets.push((aov, channels.len()));
+            let mut add_layer = |layer: &str, names: &[&str]| {
+                channels.extend(names.iter().map(|name| format!("{}.{}", layer, name)));
+            };
+            match aov {
+                Aov::Depth => add_layer("depth", &["Z"]),
+                Aov::Position | Aov::Normal => add_layer(aov.name(), &["X", "Y", "Z"]),
+                Aov::Albedo | Aov::Emission | Aov::Direct | Aov::Indirect => add_layer(aov.name(), &["R", "G", "B"]),
+                Aov::ObjectMask => {
+                    for id in 0..world.objects.len() {
+                        add_layer("mask", &[&format!("object_{}", id)]);
+                    }
+                }
+                Aov::LightGroups => {
+                    add_layer("light_background", &["R", "G", "B"]);
+                    for emitter in &emitters {
+                        add_layer(&format!("light_object_{}", emitter), &["R", "G", "B"]);
+                    }
+                }
+            }
+        }
+        let mut film = Self {
+            offsets,
+            emitters,
+            geometry: Vec::new(),
+            lighting: vec![0.0; pixels * lighting_channels.len()],
+            geometry_channels,
+            lighting_channels,
+        };
+        film.geometry = (0..pixels).flat_map(|_| film.empty_geometry()).collect();
+        film
+    }
+
+    fn offset(&self, aov: Aov) -> Option<usize> {
+        self.offsets.iter().find(|(other, _)| *other == aov).map(|(_, offset)| *offset)
+    }
+
+    /// Whether the integrator has to record the lighting of the samples
+    pub fn needs_lighting(&self) -> bool {
+        !self.lighting_channels.is_empty()
+    }
+
+    /// Geometry channels of a pixel before any sample
+    pub fn empty_geometry(&self) -> Vec<f32> {
+        let mut channels = vec![0.0; self.geometry_channels.len()];
+        if let Some(depth) = self.offset(Aov::Depth) {
+            channels[depth] = f32::INFINITY;
+        }
+        channels
+    }
+
+    /// Adds a camera ray that first hit `hit` to the geometry `channels` of its pixel
+    pub fn add_first_hit(&self, ray: &Ray, hit: Option<&Intersection>, features: &Features, channels: &mut [f32]) {
+        let mut add = |offset: usize, values: [f32; 3]| {
+            for (channel, value) in channels[offset..offset + 3].iter_mut().zip(values) {
+                *channel += value;
+            }
+        };
+        for &(aov, offset) in &self.offsets {
+            match (aov, hit) {
+                (Aov::Position, Some(hit)) => add(offset, [hit.point.x, hit.point.y, hit.point.z]),
+                (Aov::Normal, _) => add(offset, [features.normal.x, features.normal.y, features.normal.z]),
+                (Aov::Albedo, _) => add(offset, [features.albedo.r, features.albedo.g, features.albedo.b]),
+                _ => {}
+            }
+        }
+        if let (Some(offset), Some(hit)) = (self.offset(Aov::Depth), hit) {
+            channels[offset] = channels[offset].min(hit.distance * ray.direction.magnitude());
+        }
+        if let (Some(offset), Some(hit)) = (self.offset(Aov::ObjectMask), hit) {
+            channels[offset + hit.object_id] += 1.0;
+        }
+    }
+
+    /// Merges the geometry channels of a pass into the pixel
+    pub fn merge_geometry(&mut self, pixel: usize, channels: &[f32]) {
+        let count = self.geometry_channels.len();
+        let depth = self.offset(Aov::Depth);
+        for (i, (sum, value)) in self.geometry[pixel * count..(pixel + 1) * count].iter_mut().zip(channels).enumerate() {
+            if Some(i) == depth {
+                *sum = sum.min(*value);
+            } else {
+                *sum += value;
+            }
+        }
+    }
+
+    /// Adds the light recorded by the integrator for a sample to the lighting `channels` of
+    /// its pixel
+    pub fn add_lighting(&self, contributions: &[LightContribution], channels: &mut [f32]) {
+        let mut add = |offset: usize, contribution: &LightContribution| {
+            let color = contribution.color;
+            for (channel, value) in channels[offset..offset + 3].iter_mut().zip([color.r, color.g, color.b]) {
+                *channel += value;
+            }
+        };
+        for contribution in contributions {
+            let kind = match contribution.bounce {
+                0 => Aov::Emission,
+                1 => Aov::Direct,
+                _ => Aov::Indirect,
+            };
+            if let Some(offset) = self.offset(kind) {
+                add(offset, contribution);
+            }
+            let group = match contribution.source {
+                LightSource::Background => Some(0),
+                LightSource::Emitter(id) => self.emitters.binary_search(&id).ok().map(|index| index + 1),
+            };
+            if let (Some(offset), Some(group)) = (self.offset(Aov::LightGroups), group) {
+                add(offset + 3 * group, contribution);
+            }
+        }
+    }
+
+    pub fn lighting_channel_count(&self) -> usize {
+        self.lighting_channels.len()
+    }
+
+    /// Merges the lighting channels of a pass into the pixel
+    pub fn merge_lighting(&mut self, pixel: usize, channels: &[f32]) {
+        let count = self.lighting_channels.len();
+        for (sum, value) in self.lighting[pixel * count..(pixel + 1) * count].iter_mut().zip(channels) {
+            *sum += value;
+        }
+    }
+
+    /// Writes the sums of every channel to a checkpoint
+    pub fn write(&self, checkpoint: &mut CheckpointWriter) {
+        checkpoint.f32s(&self.geometry);
+        checkpoint.f32s(&self.lighting);
+    }
+
+    /// Reads back the sums of a film laid out like this one
+    pub fn read(&mut self, checkpoint: &mut CheckpointReader) -> anyhow::Result<()> {
+        self.geometry = checkpoint.f32s(self.geometry.len())?;
+        self.lighting = checkpoint.f32s(self.lighting.len())?;
+        Ok(())
+    }
+
+    /// Every channel with its name and the average of each pixel, row by row
+    pub fn resolve(&self, feature_samples: u32, stats: &[PixelStats]) -> Vec<(String, Vec<f32>)> {
+        let depth = self.offset(Aov::Depth);
+        let geometry = self.geometry_channels.iter().enumerate().map(|(channel, name)| {
+            let count = self.geometry_channels.len();
+            let values = (0..stats.len())
+                .map(|pixel| {
+                    let sum = self.geometry[pixel * count + channel];
+                    if Some(channel) == depth {
+                        sum
+                    } else {
+                        sum / feature_samples.max(1) as f32
+                    }
+                })
+                .collect();
+            (name.clone(), values)
+        });
+        let lighting = self.lighting_channels.iter().enumerate().map(|(channel, name)| {
+            let count = self.lighting_channels.len();
+            let values = stats
+                .iter()
+                .enumerate()
+                .map(|(pixel, stats)| self.lighting[pixel * count + channel] / stats.samples().max(1) as f32)
+                .collect();
+            (name.clone(), values)
+        });
+        geometry.chain(lighting).collect()
+    }
+}
+
+/// Writes the layers that fit in 8 bits, the albedo, normals and masks, as PNGs named after
+/// `png` with the layer as a suffix
+pub fn write_pngs(png: &Path, width: u32, height: u32, layers: &[(String, Vec<f32>)]) -> anyhow::Result<()> {
+    let channel = |name: &str| layers.iter().find(|(other, _)| other == name).map(|(_, values)| values);
+    let stem = png.file_stem().and_then(|stem| stem.to_str()).unwrap_or("render");
+    let save = |suffix: &str, encode: &dyn Fn(usize) -> [u8; 3]| -> anyhow::Result<()> {
+        let image = RgbaImage::from_fn(width, height, |x, y| {
+            let [r, g, b] = encode((y * width + x) as usize);
+            image::Rgba([r, g, b, 255])
+        });
+        image.save(png.with_file_name(format!("{}_{}.png", stem, suffix)))?;
+        Ok(())
+    };
+    let to_u8 = |value: f32| (value.clamp(0.0, 1.0) * 255.0).round() as u8;
+
+    if let (Some(r), Some(g), Some(b)) = (channel("albedo.R"), channel("albedo.G"), channel("albedo.B")) {
+        let srgb = |value: f32| to_u8(tonemap::srgb_oetf(value.clamp(0.0, 1.0)));
+        save("albedo", &|i| [srgb(r[i]), srgb(g[i]), srgb(b[i])])?;
+    }
+    if let (Some(x), Some(y), Some(z)) = (channel("normal.X"), channel("normal.Y"), channel("normal.Z")) {
+        let remap = |value: f32| to_u8(value * 0.5 + 0.5);
+        save("normal", &|i| [remap(x[i]), remap(y[i]), remap(z[i])])?;
+    }
+    for (name, values) in layers.iter().filter(|(name, _)| name.starts_with("mask.")) {
+        let suffix = name.replace('.', "_");
+        save(&suffix, &|i| [to_u8(values[i]); 3])?;
+    }
+    Ok(())
+}
@@ -1,7 +1,14 @@
 use nalgebra::Vector3;
 
-use crate::geometry::Point;
+use crate::{
+    animation::CameraPath,
+    geometry::{Point, Ray},
+    motion::AnimatedTransform,
+};
 
+/// A pinhole camera at `origin` looking along `direction`, with a vertical field of view of
+/// `fov` degrees over a `width` x `height` film. Its rays are spread over the times the shutter
+/// is open, blurring what moves.
 pub struct Camera {
     origin: Point,
     direction: Vector3<f32>,
@@ -10,6 +17,13 @@ pub struct Camera {
     width: u32,
     height: u32,
 
+    shutter_open: f32,
+    shutter_close: f32,
+    /// Replaces `origin`, `direction` and `fov` by where the path is at the time of each ray
+    path: Option<CameraPath>,
+    /// Moves the camera from where `origin` and `direction`, or the path, put it
+    motion: Option<AnimatedTransform>,
+
     move_forward: bool,
     move_backward: bool,
     move_left: bool,
@@ -25,6 +39,11 @@ impl Camera {
             width,
             height,
 
+            shutter_open: 0.0,
+            shutter_close: 1.0,
+            path: None,
+            motion: None,
+
             move_forward: false,
             move_backward: false,
             move_left: false,
@@ -53,19 +72,81 @@ impl Camera {
         self.height
     }
 
-    pub(crate) fn move_forward(&mut self, arg: bool)  {
+    pub fn set_origin(&mut self, origin: Point) {
+        self.origin = origin;
+    }
+
+    pub fn set_direction(&mut self, direction: Vector3<f32>) {
+        self.direction = direction.normalize();
+    }
+
+    pub fn set_fov(&mut self, fov: f32) {
+        self.fov = fov;
+    }
+
+    /// Times the shutter opens and closes at
+    pub fn shutter(&self) -> (f32, f32) {
+        (self.shutter_open, self.shutter_close)
+    }
+
+    /// Opens the shutter from `open` to `close`, the same time for both takes sharp pictures
+    pub fn set_shutter(&mut self, open: f32, close: f32) {
+        self.shutter_open = open;
+        self.shutter_close = close;
+    }
+
+    pub fn path(&self) -> Option<&CameraPath> {
+        self.path.as_ref()
+    }
+
+    pub fn set_path(&mut self, path: Option<CameraPath>) {
+        self.path = path;
+    }
+
+    pub fn motion(&self) -> Option<&AnimatedTransform> {
+        self.motion.as_ref()
+    }
+
+    pub fn set_motion(&mut self, motion: Option<AnimatedTransform>) {
+        self.motion = motion;
+    }
+
+    /// The time a number in [0, 1) picks while the shutter is open
+    pub fn sample_time(&self, u: f32) -> f32 {
+        self.shutter_open + (self.shutter_close - self.shutter_open) * u
+    }
+
+    /// The camera where its path and its motion take it at `time`, standing still
+    pub fn at_time(&self, time: f32) -> Self {
+        let mut posed = Self {
+            path: None,
+            motion: None,
+            ..*self
+        };
+        if let Some(path) = &self.path {
+            path.apply(&mut posed, time);
+        }
+        if let Some(motion) = &self.motion {
+            let matrix = motion.matrix_at(time);
+            posed.origin = matrix.transform_point(&posed.origin);
+            posed.direction = matrix.transform_vector(&posed.direction).normalize();
+        }
+        posed
+    }
+
+    pub fn move_forward(&mut self, arg: bool)  {
         self.move_forward = arg;
     }
 
-    pub(crate) fn move_backward(&mut self, arg: bool)  {
+    pub fn move_backward(&mut self, arg: bool)  {
         self.move_backward = arg;
     }
 
-    pub(crate) fn move_left(&mut self, arg: bool)  {
+    pub fn move_left(&mut self, arg: bool)  {
         self.move_left = arg;
     }
 
-    pub(crate) fn move_right(&mut self, arg: bool)  {
+    pub fn move_right(&mut self, arg: bool)  {
         self.move_right = arg;
     }
 
@@ -90,7 +171,7 @@ impl Camera {
         self.direction = direction;
     }
 
-    pub(crate) fn rotate(&mut self, delta_x: f32, delta_y: f32) {
+    pub fn rotate(&mut self, delta_x: f32, delta_y: f32) {
         let mut direction = self.direction.to_owned();
         let origin = self.origin.to_owned();
 
@@ -102,11 +183,86 @@ impl Camera {
         self.direction = direction;
     }
 
-    pub(crate) fn resize(&mut self, width: u32, height: u32) {
+    pub fn resize(&mut self, width: u32, height: u32) {
         self.width = width;
         self.height = height;
     }
 
+    /// Right, up and forward unit vectors of the camera
+    pub fn basis(&self) -> (Vector3<f32>, Vector3<f32>, Vector3<f32>) {
+        let forward = self.direction.normalize();
+        // Looking straight up or down, fall back to another reference axis
+        let reference = if forward.y.abs() > 0.999 { Vector3::z() } else { Vector3::y() };
+        let right = forward.cross(&reference).normalize();
+        let up = right.cross(&forward);
+        (right, up, forward)
+    }
+
+    /// Half extents of the image plane at distance 1 from the camera
+    fn sensor_size(&self) -> (f32, f32) {
+        let tan = (self.fov.to_radians() / 2.0).tan();
+        let aspect_ratio = self.width as f32 / self.height as f32;
+        (aspect_ratio * tan, tan)
+    }
+
+    /// Ray at `time` through a point of the film given in (continuous) pixel coordinates
+    pub fn generate_ray(&self, film_x: f32, film_y: f32, time: f32) -> Ray {
+        if self.path.is_some() || self.motion.is_some() {
+            return self.at_time(time).generate_ray(film_x, film_y, time);
+        }
+        let (right, up, forward) = self.basis();
+        let (half_width, half_height) = self.sensor_size();
+        let sensor_x = ((film_x / self.width as f32) * 2.0 - 1.0) * half_width;
+        let sensor_y = (1.0 - (film_y / self.height as f32) * 2.0) * half_height;
+        let direction = (right * sensor_x + up * sensor_y + forward).normalize();
+        Ray::new(self.origin, direction).with_time(time)
+    }
+
+    /// Pixel coordinates where a point in the world shows up on the film, if it is in view
+    pub fn project(&self, point: &Point) -> Option<(f32, f32)> {
+        let (right, up, forward) = self.basis();
+        let (half_width, half_height) = self.sensor_size();
+        let offset = point - self.origin;
+        let depth = offset.dot(&forward);
+        if depth <= 0.0 {
+            return None;
+        }
+        let sensor_x = offset.dot(&right) / depth / half_width;
+        let sensor_y = offset.dot(&up) / depth / half_height;
+        let film_x = (sensor_x + 1.0) / 2.0 * self.width as f32;
+        let film_y = (1.0 - sensor_y) / 2.0 * self.height as f32;
+        if film_x < 0.0 || film_x >= self.width as f32 || film_y < 0.0 || film_y >= self.height as f32 {
+            return None;
+        }
+        Some((film_x, film_y))
+    }
+
+    /// Importance emitted by the pinhole camera along `direction`, normalized so that it
+    /// integrates to one over the whole film. Zero outside of the field of view.
+    pub fn importance(&self, direction: &Vector3<f32>) -> f32 {
+        let cos = direction.normalize().dot(&self.basis().2);
+        if cos <= 0.0 || self.project(&(self.origin + direction)).is_none() {
+            return 0.0;
+        }
+        1.0 / (self.film_area() * cos.powi(4))
+    }
+
+    /// Solid angle density of the camera generating a ray along `direction`
+    /// when the film is sampled uniformly
+    pub fn pdf_direction(&self, direction: &Vector3<f32>) -> f32 {
+        let cos = direction.normalize().dot(&self.basis().2);
+        if cos <= 0.0 || self.project(&(self.origin + direction)).is_none() {
+            return 0.0;
+        }
+        1.0 / (self.film_area() * cos.powi(3))
+    }
+
+    /// Area of the film when placed at distance 1 from the camera
+    fn film_area(&self) -> f32 {
+        let (half_width, half_height) = self.sensor_size();
+        4.0 * half_width * half_height
+    }
+
 
 
 }
\ No newline at end of file
@@ -0,0 +1,150 @@
+use std::{
+    fmt::{self, Write as _},
+    fs,
+    path::Path,
+};
+
+use anyhow::Context;
+
+use crate::sampler;
+
+/// Start of every checkpoint file, followed by the version of the layout
+const MAGIC: &[u8; 8] = b"PTCHKPT\n";
+const VERSION: u32 = 2;
+
+/// Little endian binary snapshot of an accumulation, built in memory and written in one go
+pub struct CheckpointWriter {
+    bytes: Vec<u8>,
+}
+
+impl Default for CheckpointWriter {
+    fn default() -> Self {
+        let mut bytes = MAGIC.to_vec();
+        bytes.extend_from_slice(&VERSION.to_le_bytes());
+        Self { bytes }
+    }
+}
+
+impl CheckpointWriter {
+    pub fn u32(&mut self, value: u32) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn u64(&mut self, value: u64) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn f64(&mut self, value: f64) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    /// A slice of floats, preceded by its length
+    pub fn f32s(&mut self, values: &[f32]) {
+        self.u64(values.len() as u64);
+        for value in values {
+            self.bytes.extend_from_slice(&value.to_le_bytes());
+        }
+    }
+
+    /// Writes the checkpoint next to `path` and renames it over the previous one, so a crash
+    /// while writing keeps the previous checkpoint intact
+    pub fn finish(self, path: &Path) -> anyhow::Result<()> {
+        let mut partial = path.as_os_str().to_owned();
+        partial.push(".partial");
+        let result = fs::write(&partial, &self.bytes).and_then(|_| fs::rename(&partial, path));
+        result.with_context(|| format!("failed to write the checkpoint '{}'", path.display()))
+    }
+}
+
+/// Reads back what a `CheckpointWriter` wrote, in the same order
+pub struct CheckpointReader {
+    bytes: Vec<u8>,
+    position: usize,
+}
+
+impl CheckpointReader {
+    pub fn open(path: &Path) -> anyhow::Result<Self> {
+        let bytes = fs::read(path).with_context(|| format!("failed to read the checkpoint '{}'", path.display()))?;
+        let mut reader = Self { bytes, position: 0 };
+        if reader.take(MAGIC.len()).ok() != Some(&MAGIC[..]) {
+            anyhow::bail!("'{}' isn't a checkpoint", path.display());
+        }
+        let version = reader.u32()?;
+        if version != VERSION {
+            anyhow::bail!("'{}' is a version {} checkpoint, expected version {}", path.display(), version, VERSION);
+        }
+        Ok(reader)
+    }
+
+    fn take(&mut self, count: usize) -> anyhow::Result<&[u8]> {
+        let bytes = self
+            .bytes
+            .get(self.position..self.position + count)
+            .ok_or_else(|| anyhow::anyhow!("the checkpoint is truncated"))?;
+        self.position += count;
+        Ok(bytes)
+    }
+
+    pub fn u32(&mut self) -> anyhow::Result<u32> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into()?))
+    }
+
+    pub fn u64(&mut self) -> anyhow::Result<u64> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into()?))
+    }
+
+    pub fn f64(&mut self) -> anyhow::Result<f64> {
+        Ok(f64::from_le_bytes(self.take(8)?.try_into()?))
+    }
+
+    /// A slice of floats, which must have `len` values
+    pub fn f32s(&mut self, len: usize) -> anyhow::Result<Vec<f32>> {
+        let stored = self.u64()? as usize;
+        if stored != len {
+            anyhow::bail!("the checkpoint has {} values where {} were expected", stored, len);
+        }
+        let bytes = self.take(4 * len)?;
+        Ok(bytes.chunks_exact(4).map(|value| f32::from_le_bytes([value[0], value[1], value[2], value[3]])).collect())
+    }
+}
+
+/// Hash of everything an accumulation depends on, to refuse resuming it with another scene or
+/// other settings. It only has to tell scenes apart, not resist collisions made on purpose.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Fingerprint(u64);
+
+impl Fingerprint {
+    pub fn add(&mut self, value: u64) {
+        self.0 = sampler::hash(self.0, value);
+    }
+
+    pub fn add_f32(&mut self, value: f32) {
+        self.add(value.to_bits() as u64);
+    }
+
+    pub fn add_str(&mut self, value: &str) {
+        self.add(value.len() as u64);
+        for byte in value.bytes() {
+            self.add(byte as u64);
+        }
+    }
+
+    /// Adds the `Debug` form of a value, which writes the floats exactly, for the parameters of
+    /// shapes and materials
+    pub fn add_debug(&mut self, value: &impl fmt::Debug) {
+        write!(self, "{:?};", value).expect("hashing can't fail");
+    }
+
+    pub fn value(&self) -> u64 {
+        self.0
+    }
+}
+
+impl fmt::Write for Fingerprint {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for byte in s.bytes() {
+            self.add(byte as u64);
+        }
+        Ok(())
+    }
+}
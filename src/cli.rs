@@ -0,0 +1,595 @@
+use std::{fmt::Write, path::PathBuf, str::FromStr, time::Duration};
+
+use pathtracer::{
+    animation::{is_frame_pattern, FrameRange},
+    aov::Aov,
+    film::AdaptiveSettings,
+    integrator::IntegratorKind,
+    output::{CropOutput, OutputFormat},
+    raytracer::Pathtracer,
+    sampler::SamplerKind,
+    scene::{SceneKind, SceneOverride},
+    subdivision::Subdivision,
+    tiles::{Crop, TileSettings},
+    tonemap::ToneMapping,
+};
+
+const PROGRAM: &str = env!("CARGO_PKG_NAME");
+
+/// What the binary was asked to do
+pub enum Command {
+    /// Renders the scene without a window and saves the image
+    Render(Box<RenderOptions>),
+    /// Opens the interactive viewer
+    View(ViewOptions),
+    /// Prints statistics of the scene
+    Info(InfoOptions),
+    /// Converts a mesh to another format
+    Convert(ConvertOptions),
+    /// Prints the usage of the binary or of a command
+    Help(String),
+}
+
+/// Settings shared by the viewer and the headless renders
+#[derive(Default)]
+pub struct PathtracerOptions {
+    pub scene: SceneKind,
+    pub integrator: Option<IntegratorKind>,
+    pub sampler: Option<SamplerKind>,
+    pub seed: Option<u64>,
+    pub tone_mapping: Option<ToneMapping>,
+    pub tiles: Option<TileSettings>,
+    /// Threads tracing the tiles, all the cores when `None`
+    pub threads: Option<usize>,
+    pub overrides: Vec<SceneOverride>,
+}
+
+impl PathtracerOptions {
+    /// Applies the settings to a pathtracer whose scene is built
+    pub fn apply(&self, pathtracer: &mut Pathtracer) {
+        if let Some(kind) = self.integrator {
+            pathtracer.set_integrator(kind);
+        }
+        if let Some(kind) = self.sampler {
+            pathtracer.set_sampler(kind);
+        }
+        if let Some(seed) = self.seed {
+            pathtracer.set_seed(seed);
+        }
+        if let Some(tone_mapping) = self.tone_mapping {
+            pathtracer.set_tone_mapping(tone_mapping);
+        }
+        if let Some(tiles) = self.tiles {
+            pathtracer.set_tiles(tiles);
+        }
+        for scene_override in &self.overrides {
+            scene_override.apply(pathtracer.camera_mut());
+        }
+        pathtracer.reset();
+    }
+
+    /// Sizes the global thread pool, before anything runs on it
+    pub fn init_threads(&self) -> anyhow::Result<()> {
+        if let Some(threads) = self.threads {
+            rayon::ThreadPoolBuilder::new().num_threads(threads).build_global()?;
+        }
+        Ok(())
+    }
+}
+
+pub struct RenderOptions {
+    pub width: u32,
+    pub height: u32,
+    pub settings: PathtracerOptions,
+    /// Samples per pixel, the most any pixel takes with adaptive sampling
+    pub samples: u64,
+    pub adaptive: Option<AdaptiveSettings>,
+    /// Stops the render after the time, whatever the samples or the noise
+    pub time_budget: Option<Duration>,
+    pub output: PathBuf,
+    pub format: OutputFormat,
+    pub denoise: bool,
+    pub aovs: Vec<Aov>,
+    pub crop: Option<Crop>,
+    pub crop_output: CropOutput,
+    pub checkpoint: Option<PathBuf>,
+    pub checkpoint_every: Duration,
+    pub resume: Option<PathBuf>,
+    /// File the JSON stats are written to, the standard output with `-`
+    pub stats: Option<String>,
+    /// Frames of the animation to render, each to `output` with its number in the placeholder
+    pub frames: Option<FrameRange>,
+    pub fps: f32,
+    /// Leaves the frames whose image is already there
+    pub skip_existing: bool,
+}
+
+pub struct ViewOptions {
+    pub width: u32,
+    pub height: u32,
+    pub settings: PathtracerOptions,
+}
+
+pub struct InfoOptions {
+    pub scene: SceneKind,
+    pub overrides: Vec<SceneOverride>,
+}
+
+pub struct ConvertOptions {
+    pub input: PathBuf,
+    pub output: PathBuf,
+    pub subdivision: Option<Subdivision>,
+}
+
+/// An option of a command, used to parse the arguments and to print the help
+struct Opt {
+    name: &'static str,
+    short: Option<char>,
+    /// Placeholder of the value in the help, `None` for switches
+    value: Option<&'static str>,
+    help: &'static str,
+}
+
+const fn opt(name: &'static str, short: Option<char>, value: Option<&'static str>, help: &'static str) -> Opt {
+    Opt { name, short, value, help }
+}
+
+const HELP: Opt = opt("help", Some('h'), None, "Prints the usage of the command");
+const SCENE: Opt = opt("scene", None, Some("<name>"), "default, motion-blur, turntable, shapes, csg, sdf or subdivision [default: default]");
+const SET: Opt = opt("set", None, Some("<key=value>"), "Overrides a setting of the scene: camera.position=x,y,z, camera.direction=x,y,z, camera.fov=degrees or camera.shutter=open,close. Can be repeated");
+const RESOLUTION: Opt = opt("resolution", Some('r'), Some("<width>x<height>"), "Size of the film in pixels [default: 800x600]");
+const INTEGRATOR: Opt = opt("integrator", Some('i'), Some("<name>"), "path, path:rr=3,diffuse=8,specular=32,transmission=32, bdpt, sppm, mlt, direct, ao[:<radius>], normals, depth, albedo, uv, object-id or bounces [default: path]");
+const SAMPLER: Opt = opt("sampler", None, Some("<name>"), "sobol, halton, stratified or independent [default: sobol]");
+const SEED: Opt = opt("seed", None, Some("<seed>"), "Seed of the random numbers, the same seed renders the same image [default: 0]");
+const TONEMAP: Opt = opt("tonemap", None, Some("<name>"), "aces, agx, reinhard, reinhard-extended[:white=<luminance>] or clamp, followed by an exposure like aces:ev=-1 [default: aces]");
+const TILES: Opt = opt("tiles", None, Some("<order>[:<size>]"), "Order of the tiles, scanline, spiral or hilbert, and their size [default: hilbert:32]");
+const THREADS: Opt = opt("threads", Some('j'), Some("<count>"), "Threads tracing the tiles [default: all the cores]");
+
+/// A subcommand and the options it accepts
+struct Spec {
+    name: &'static str,
+    about: &'static str,
+    /// Arguments after the options in the usage line
+    usage: &'static str,
+    options: &'static [Opt],
+}
+
+const RENDER: Spec = Spec {
+    name: "render",
+    about: "Renders the scene without a window and saves the image",
+    usage: "--output <file>",
+    options: &[
+        opt("output", Some('o'), Some("<file>"), "Image to write, its extension picks the format: png, jpg, hdr, pfm, tif or exr"),
+        RESOLUTION,
+        opt("spp", Some('s'), Some("<samples>"), "Samples per pixel [default: 128]"),
+        opt("noise", None, Some("<level>[,min=<samples>][,max=<samples>]"), "Stops sampling each pixel once its relative error is below the level, instead of --spp"),
+        opt("time", Some('t'), Some("<seconds>"), "Stops the render after the time, whatever the samples or the noise"),
+        SEED,
+        THREADS,
+        INTEGRATOR,
+        SAMPLER,
+        TONEMAP,
+        opt("format", None, Some("<format>"), "Options of the output format, e.g. png16 or exr:half,zip"),
+        opt("denoise", None, None, "Filters the saved image, keeping the noisy one with a _noisy suffix"),
+        opt("aovs", None, Some("<list>"), "Layers added to the EXR, e.g. depth,normal,albedo or all"),
+        TILES,
+        opt("crop", None, Some("<x>,<y>,<width>,<height>"), "Only renders the pixels of the window"),
+        opt("crop-output", None, Some("full|cropped"), "Saves the whole film or only the crop window [default: full]"),
+        opt("checkpoint", None, Some("<file>"), "Saves the accumulation periodically and when the render is done"),
+        opt("checkpoint-every", None, Some("<seconds>"), "Time between checkpoints [default: 300]"),
+        opt("resume", None, Some("<file>"), "Continues a checkpoint of the same scene and settings"),
+        opt("stats", None, Some("<file>"), "Writes the stats of the render as JSON, to the standard output with -"),
+        opt("frames", None, Some("<first>..<last>"), "Renders the frames of the animation to an output like frame_%04d.png, the shutter of the camera is then in frames"),
+        opt("fps", None, Some("<rate>"), "Frames per second of the animation [default: 24]"),
+        opt("skip-existing", None, None, "Skips the frames whose image already exists"),
+        SCENE,
+        SET,
+        HELP,
+    ],
+};
+
+const VIEW: Spec = Spec {
+    name: "view",
+    about: "Opens the interactive viewer",
+    usage: "",
+    options: &[RESOLUTION, SEED, THREADS, INTEGRATOR, SAMPLER, TONEMAP, TILES, SCENE, SET, HELP],
+};
+
+const INFO: Spec = Spec {
+    name: "info",
+    about: "Prints statistics of the scene: objects, emitters, triangles and camera",
+    usage: "",
+    options: &[SCENE, SET, HELP],
+};
+
+const CONVERT: Spec = Spec {
+    name: "convert",
+    about: "Converts an OBJ mesh to a triangulated OBJ or a binary PLY",
+    usage: "<input.obj> <output.obj|output.ply>",
+    options: &[
+        opt("subdivide", None, Some("<levels>[,crease=<degrees>]"), "Smooths the mesh by subdivision, Loop for triangles and Catmull-Clark for quads, keeping the edges sharper than the crease angle"),
+        HELP,
+    ],
+};
+
+const COMMANDS: [&Spec; 4] = [&RENDER, &VIEW, &INFO, &CONVERT];
+
+/// Usage of the binary, listing its commands
+pub fn usage() -> String {
+    let mut text = format!("A path tracer with an interactive viewer and a headless renderer\n\nUsage: {} [command] [options]\n\nCommands:\n", PROGRAM);
+    for spec in COMMANDS {
+        writeln!(text, "  {:<10}{}", spec.name, spec.about).unwrap();
+    }
+    writeln!(text, "  {:<10}Prints the usage of a command", "help").unwrap();
+    writeln!(text, "\nWithout a command the viewer opens. Run `{} help <command>` for the options of a command.", PROGRAM).unwrap();
+    text
+}
+
+impl Spec {
+    fn usage(&self) -> String {
+        let mut text = format!("{}\n\nUsage: {} {} [options] {}\n\nOptions:\n", self.about, PROGRAM, self.name, self.usage);
+        let flags: Vec<String> = self
+            .options
+            .iter()
+            .map(|option| {
+                let short = option.short.map_or("    ".to_string(), |short| format!("-{}, ", short));
+                format!("{}--{} {}", short, option.name, option.value.unwrap_or_default())
+            })
+            .collect();
+        let width = flags.iter().map(String::len).max().unwrap_or_default();
+        for (flag, option) in flags.iter().zip(self.options) {
+            writeln!(text, "  {:<width$}  {}", flag, option.help, width = width).unwrap();
+        }
+        text
+    }
+
+    fn find(&self, arg: &str) -> Option<&'static Opt> {
+        match arg.strip_prefix("--") {
+            Some(name) => self.options.iter().find(|option| option.name == name),
+            None => self.options.iter().find(|option| option.short.is_some() && arg == format!("-{}", option.short.unwrap())),
+        }
+    }
+
+    /// Splits the arguments of the command into its options and the positional arguments
+    fn parse(&'static self, args: &[String]) -> anyhow::Result<Parsed> {
+        let mut parsed = Parsed { spec: self, values: Vec::new(), positionals: Vec::new() };
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            if !arg.starts_with('-') || arg == "-" {
+                parsed.positionals.push(arg.clone());
+                continue;
+            }
+            let (flag, inline) = match arg.split_once('=') {
+                Some((flag, value)) => (flag, Some(value.to_string())),
+                None => (arg.as_str(), None),
+            };
+            let option = self
+                .find(flag)
+                .ok_or_else(|| anyhow::anyhow!("unknown option '{}' for {}, see `{} help {}`", flag, self.name, PROGRAM, self.name))?;
+            let value = match (option.value, inline) {
+                (None, Some(_)) => anyhow::bail!("--{} doesn't take a value", option.name),
+                (None, None) => String::new(),
+                (Some(_), Some(value)) => value,
+                (Some(placeholder), None) => args
+                    .next()
+                    .cloned()
+                    .ok_or_else(|| anyhow::anyhow!("--{} needs a value: {}", option.name, placeholder))?,
+            };
+            if option.name != SET.name && parsed.values.iter().any(|(name, _)| *name == option.name) {
+                anyhow::bail!("--{} is given more than once", option.name);
+            }
+            parsed.values.push((option.name, value));
+        }
+        Ok(parsed)
+    }
+}
+
+/// Arguments of a command, split by `Spec::parse`
+struct Parsed {
+    spec: &'static Spec,
+    values: Vec<(&'static str, String)>,
+    positionals: Vec<String>,
+}
+
+impl Parsed {
+    fn has(&self, name: &str) -> bool {
+        self.values.iter().any(|(option, _)| *option == name)
+    }
+
+    fn get(&self, name: &str) -> Option<&str> {
+        self.values.iter().find(|(option, _)| *option == name).map(|(_, value)| value.as_str())
+    }
+
+    /// The value of an option parsed with `FromStr`, whose errors already say what's wrong
+    fn parse<T>(&self, name: &str) -> anyhow::Result<Option<T>>
+    where
+        T: FromStr<Err = anyhow::Error>,
+    {
+        self.get(name).map(|value| value.parse::<T>()).transpose()
+    }
+
+    /// The value of a numeric option, which must be positive
+    fn number<T>(&self, name: &str) -> anyhow::Result<Option<T>>
+    where
+        T: FromStr + PartialOrd + Default,
+        T::Err: std::fmt::Display,
+    {
+        let Some(value) = self.get(name) else {
+            return Ok(None);
+        };
+        let number = value.parse::<T>().map_err(|e| anyhow::anyhow!("invalid value '{}' for --{}: {}", value, name, e))?;
+        if number <= T::default() {
+            anyhow::bail!("--{} must be positive, got {}", name, value);
+        }
+        Ok(Some(number))
+    }
+
+    fn overrides(&self) -> anyhow::Result<Vec<SceneOverride>> {
+        self.values
+            .iter()
+            .filter(|(option, _)| *option == SET.name)
+            .map(|(_, value)| value.parse::<SceneOverride>().map_err(|e| anyhow::anyhow!("invalid --set '{}': {}", value, e)))
+            .collect()
+    }
+
+    fn resolution(&self) -> anyhow::Result<(u32, u32)> {
+        let Some(value) = self.get(RESOLUTION.name) else {
+            return Ok((800, 600));
+        };
+        let invalid = || anyhow::anyhow!("invalid resolution '{}', expected <width>x<height> like 800x600", value);
+        let (width, height) = value.split_once('x').ok_or_else(invalid)?;
+        match (width.parse::<u32>(), height.parse::<u32>()) {
+            (Ok(width), Ok(height)) if width > 0 && height > 0 => Ok((width, height)),
+            _ => Err(invalid()),
+        }
+    }
+
+    fn settings(&self) -> anyhow::Result<PathtracerOptions> {
+        Ok(PathtracerOptions {
+            scene: self.parse(SCENE.name)?.unwrap_or_default(),
+            integrator: self.parse(INTEGRATOR.name)?,
+            sampler: self.parse(SAMPLER.name)?,
+            seed: match self.get(SEED.name) {
+                Some(seed) => Some(seed.parse::<u64>().map_err(|e| anyhow::anyhow!("invalid seed '{}': {}", seed, e))?),
+                None => None,
+            },
+            tone_mapping: self.parse(TONEMAP.name)?,
+            tiles: self.parse(TILES.name)?,
+            threads: self.number(THREADS.name)?,
+            overrides: self.overrides()?,
+        })
+    }
+
+    /// Fails on positional arguments beyond the first `count`
+    fn positionals(&self, count: usize) -> anyhow::Result<&[String]> {
+        if let Some(extra) = self.positionals.get(count) {
+            anyhow::bail!("unexpected argument '{}' for {}, see `{} help {}`", extra, self.spec.name, PROGRAM, self.spec.name);
+        }
+        Ok(&self.positionals)
+    }
+}
+
+/// Parses the arguments of the binary, without the program name
+pub fn parse(all: &[String]) -> anyhow::Result<Command> {
+    let Some((command, args)) = all.split_first() else {
+        return view(VIEW.parse(&[])?);
+    };
+    let spec = match command.as_str() {
+        "-h" | "--help" => return Ok(Command::Help(usage())),
+        "help" => {
+            return match args.first() {
+                None => Ok(Command::Help(usage())),
+                Some(name) => COMMANDS
+                    .iter()
+                    .find(|spec| spec.name == name)
+                    .map(|spec| Command::Help(spec.usage()))
+                    .ok_or_else(|| unknown_command(name)),
+            }
+        }
+        // The viewer opens without a command, so its options can come first
+        arg if arg.starts_with('-') => return view(VIEW.parse(all)?),
+        name => COMMANDS.iter().find(|spec| spec.name == name).ok_or_else(|| unknown_command(name))?,
+    };
+    let parsed = spec.parse(args)?;
+    if parsed.has(HELP.name) {
+        return Ok(Command::Help(spec.usage()));
+    }
+    match spec.name {
+        "render" => render(parsed),
+        "view" => view(parsed),
+        "info" => {
+            parsed.positionals(0)?;
+            Ok(Command::Info(InfoOptions {
+                scene: parsed.parse(SCENE.name)?.unwrap_or_default(),
+                overrides: parsed.overrides()?,
+            }))
+        }
+        _ => match parsed.positionals(2)? {
+            [input, output] => Ok(Command::Convert(ConvertOptions {
+                input: input.into(),
+                output: output.into(),
+                subdivision: parsed.parse("subdivide")?,
+            })),
+            _ => anyhow::bail!("convert needs an input and an output file, see `{} help convert`", PROGRAM),
+        },
+    }
+}
+
+fn unknown_command(name: &str) -> anyhow::Error {
+    // The arguments used to be positional, point the scripts that still pass them at the options
+    if name.parse::<u32>().is_ok() {
+        return anyhow::anyhow!(
+            "the arguments are named, e.g. `{} render --resolution=800x600 --spp=64 --output=render.png`, see `{} help render`",
+            PROGRAM,
+            PROGRAM
+        );
+    }
+    anyhow::anyhow!("unknown command '{}', expected render, view, info, convert or help", name)
+}
+
+fn view(parsed: Parsed) -> anyhow::Result<Command> {
+    parsed.positionals(0)?;
+    if parsed.has(HELP.name) {
+        return Ok(Command::Help(VIEW.usage()));
+    }
+    let (width, height) = parsed.resolution()?;
+    Ok(Command::View(ViewOptions { width, height, settings: parsed.settings()? }))
+}
+
+fn render(parsed: Parsed) -> anyhow::Result<Command> {
+    parsed.positionals(0)?;
+    let output = PathBuf::from(parsed.get("output").ok_or_else(|| anyhow::anyhow!("render needs an --output file, see `{} help render`", PROGRAM))?);
+    let (width, height) = parsed.resolution()?;
+    let settings = parsed.settings()?;
+    let adaptive = match parsed.get("noise") {
+        Some(_) if parsed.has("spp") => anyhow::bail!("--spp and --noise can't be combined, give the most samples as --noise=<level>,max=<samples>"),
+        Some(noise) => Some(format!("noise={}", noise).parse::<AdaptiveSettings>()?),
+        None => None,
+    };
+    let samples = match adaptive {
+        Some(adaptive) => adaptive.max_samples,
+        None => parsed.number("spp")?.unwrap_or(128),
+    };
+    let format = match parsed.parse::<OutputFormat>("format")? {
+        Some(format) if !format.matches(&output) => {
+            anyhow::bail!("the {} format is written to .{} files, not '{}'", format, format.extension(), output.display())
+        }
+        Some(format) => format,
+        None => OutputFormat::from_path(&output)?,
+    };
+    let crop = parsed.parse::<Crop>("crop")?;
+    if let Some(crop) = crop {
+        if crop.clamp(width, height).is_none() {
+            anyhow::bail!("the crop window {} is outside the {}x{} film", crop, width, height);
+        }
+    }
+    let checkpoint = parsed.get("checkpoint").map(PathBuf::from);
+    let integrator = settings.integrator.unwrap_or_default();
+    if checkpoint.is_some() && integrator.keeps_own_estimate() {
+        anyhow::bail!("the {} integrator keeps its own estimate between passes and can't be checkpointed", integrator);
+    }
+    if integrator.keeps_own_estimate() {
+        // It renders the whole film every pass, the same number of samples in every pixel
+        if crop.is_some() {
+            anyhow::bail!("the {} integrator renders the whole film and can't be combined with --crop", integrator);
+        }
+        if adaptive.is_some() {
+            anyhow::bail!("the {} integrator samples every pixel alike and can't be combined with --noise", integrator);
+        }
+    }
+    let frames = parsed.parse::<FrameRange>("frames")?;
+    if frames.is_some() {
+        if !is_frame_pattern(&output) {
+            anyhow::bail!("the output of an animation needs a placeholder for the frame number, like frame_%04d.png, got '{}'", output.display());
+        }
+        if let Some(option) = ["checkpoint", "resume", "stats"].into_iter().find(|option| parsed.has(option)) {
+            anyhow::bail!("--{} only works on a single image, not with --frames", option);
+        }
+    } else if let Some(option) = ["fps", "skip-existing"].into_iter().find(|option| parsed.has(option)) {
+        anyhow::bail!("--{} needs --frames", option);
+    }
+    Ok(Command::Render(Box::new(RenderOptions {
+        width,
+        height,
+        settings,
+        samples,
+        adaptive,
+        time_budget: parsed
+            .number::<f64>("time")?
+            .map(Duration::try_from_secs_f64)
+            .transpose()
+            .map_err(|e| anyhow::anyhow!("invalid value for --time: {}", e))?,
+        output,
+        format,
+        denoise: parsed.has("denoise"),
+        aovs: parsed.get("aovs").map(Aov::parse_list).transpose()?.unwrap_or_default(),
+        crop,
+        crop_output: parsed.parse("crop-output")?.unwrap_or_default(),
+        checkpoint,
+        checkpoint_every: Duration::from_secs(parsed.number("checkpoint-every")?.unwrap_or(300)),
+        resume: parsed.get("resume").map(PathBuf::from),
+        stats: parsed.get("stats").map(String::from),
+        frames,
+        fps: parsed.number("fps")?.unwrap_or(24.0),
+        skip_existing: parsed.has("skip-existing"),
+    })))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse, Command};
+    use pathtracer::{
+        animation::FrameRange,
+        scene::{SceneKind, SceneOverride},
+    };
+
+    fn args(line: &str) -> Vec<String> {
+        line.split_whitespace().map(String::from).collect()
+    }
+
+    #[test]
+    fn parses_commands_and_rejects_bad_arguments() {
+        let Ok(Command::Render(options)) = parse(&args("render -o out.exr --resolution=64x48 --spp 16 --seed=7 --set camera.fov=60 --set camera.position=0,1,2 --set camera.shutter=0,0.5 --scene=motion-blur --denoise")) else {
+            panic!("render didn't parse");
+        };
+        assert_eq!((options.width, options.height, options.samples), (64, 48, 16));
+        assert_eq!(options.settings.seed, Some(7));
+        assert_eq!(options.settings.scene, SceneKind::MotionBlur);
+        assert!(options.denoise);
+        assert_eq!(options.settings.overrides.len(), 3);
+        assert_eq!(options.settings.overrides[0], SceneOverride::Fov(60.0));
+        assert_eq!(options.settings.overrides[2], SceneOverride::Shutter(0.0, 0.5));
+
+        let Ok(Command::Render(options)) = parse(&args("render -o out.png --noise=0.05,max=512")) else {
+            panic!("adaptive render didn't parse");
+        };
+        assert_eq!(options.samples, 512);
+        let Ok(Command::Render(options)) = parse(&args("render -o out/frame_%04d.exr --frames=10..20 --fps=30 --skip-existing")) else {
+            panic!("animation didn't parse");
+        };
+        assert_eq!(options.frames, Some(FrameRange { first: 10, last: 20 }));
+        assert!(options.fps == 30.0 && options.skip_existing);
+        assert!(matches!(parse(&args("view -r 320x240")), Ok(Command::View(options)) if options.width == 320));
+        assert!(matches!(parse(&[]), Ok(Command::View(_))));
+        assert!(matches!(parse(&args("help render")), Ok(Command::Help(text)) if text.contains("--checkpoint-every <seconds>")));
+        assert!(matches!(parse(&args("convert in.obj out.ply")), Ok(Command::Convert(options)) if options.subdivision.is_none()));
+        let Ok(Command::Convert(options)) = parse(&args("convert in.obj out.ply --subdivide=2,crease=40")) else {
+            panic!("subdivided conversion didn't parse");
+        };
+        let subdivision = options.subdivision.unwrap();
+        assert!(subdivision.levels == 2 && subdivision.crease_angle == Some(40.0));
+
+        for (line, error) in [
+            ("render", "needs an --output"),
+            ("render -o out.png --spp", "--spp needs a value"),
+            ("render -o out.png --spp=0", "--spp must be positive"),
+            ("render -o out.png --spp=4 --spp=8", "more than once"),
+            ("render -o out.png --sped=4", "unknown option '--sped'"),
+            ("render -o out.png --denoise=yes", "doesn't take a value"),
+            ("render -o out.png --resolution=800", "invalid resolution"),
+            ("render -o out.png --set camera.zoom=2", "unknown scene setting"),
+            ("render -o out.png --set camera.shutter=1,0", "can't close before it opens"),
+            ("info --scene=cornell", "unknown scene 'cornell'"),
+            ("render -o out.png --spp=4 --noise=0.1", "can't be combined"),
+            ("render -o out.png --integrator=sppm --checkpoint=a.ckpt", "can't be checkpointed"),
+            ("render -o out.png --integrator=sppm --crop=0,0,10,10", "can't be combined with --crop"),
+            ("render -o out.png --integrator=mlt --noise=0.05", "can't be combined with --noise"),
+            ("render -o out.png --crop=900,0,10,10", "outside the 800x600 film"),
+            ("render -o out.png --crop=4294967295,0,10,10", "ends past the largest film"),
+            ("render -o out.png --frames=1..4", "needs a placeholder"),
+            ("render -o f%d.png --frames=4..1", "ends before it starts"),
+            ("render -o f%d.png --frames=1..4 --stats=-", "--stats only works on a single image"),
+            ("render -o out.png --fps=30", "--fps needs --frames"),
+            ("render -o out.png extra", "unexpected argument 'extra'"),
+            ("800 600 64 out.png", "the arguments are named"),
+            ("draw", "unknown command 'draw'"),
+            ("convert in.obj", "needs an input and an output"),
+            ("convert in.obj out.ply --subdivide=2,sharp=40", "unknown subdivision option 'sharp=40'"),
+        ] {
+            match parse(&args(line)) {
+                Err(e) => assert!(e.to_string().contains(error), "'{}' failed with '{}'", line, e),
+                Ok(_) => panic!("'{}' parsed", line),
+            }
+        }
+    }
+}
@@ -1,4 +1,4 @@
-use std::ops::{Mul, Add, Div};
+use std::ops::{Mul, Add, AddAssign, Div};
 
 use image::Pixel;
 
@@ -32,7 +32,7 @@ impl ColorF32 {
     }
 
     pub fn lerp(a: Self, b: Self, t: f32) -> Self {
-        assert!(t >= 0.0 && t <= 1.0);
+        assert!((0.0..=1.0).contains(&t));
         a * (1.0 - t) + b * t
     }
 
@@ -44,6 +44,15 @@ impl ColorF32 {
         Self::new(self.r.exp(), self.g.exp(), self.b.exp())
     }
 
+    pub fn max_component(&self) -> f32 {
+        self.r.max(self.g).max(self.b)
+    }
+
+    /// Perceived brightness (Rec. 709 weights)
+    pub fn luminance(&self) -> f32 {
+        0.2126 * self.r + 0.7152 * self.g + 0.0722 * self.b
+    }
+
 }
 
 impl Mul<f32> for ColorF32 {
@@ -104,6 +113,12 @@ impl Add<Self> for ColorF32 {
     }
 }
 
+impl AddAssign<Self> for ColorF32 {
+    fn add_assign(&mut self, rhs: Self) {
+        *self = *self + rhs;
+    }
+}
+
 impl From<ColorF32> for image::Rgba<u8> {
     fn from(val: ColorF32) -> Self {
         image::Rgba::from_slice(&[
@@ -0,0 +1,80 @@
+use std::{fmt::Write as _, fs, io::Write as _, path::Path};
+
+use anyhow::Context;
+
+use crate::{
+    geometry,
+    subdivision::{IndexedMesh, Subdivision},
+};
+
+/// Mesh formats the meshes are converted to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum MeshFormat {
+    /// Wavefront OBJ, in text
+    Obj,
+    /// Stanford PLY, in little endian binary
+    Ply,
+}
+
+impl MeshFormat {
+    fn from_path(path: &Path) -> anyhow::Result<Self> {
+        match path.extension().and_then(|extension| extension.to_str()).map(str::to_ascii_lowercase).as_deref() {
+            Some("obj") => Ok(Self::Obj),
+            Some("ply") => Ok(Self::Ply),
+            _ => anyhow::bail!("can't tell the format of '{}', expected a .obj or .ply file", path.display()),
+        }
+    }
+}
+
+impl IndexedMesh {
+    fn obj(&self) -> Vec<u8> {
+        let mut text = String::new();
+        for vertex in &self.vertices {
+            writeln!(text, "v {} {} {}", vertex.x, vertex.y, vertex.z).unwrap();
+        }
+        for [a, b, c] in &self.faces {
+            writeln!(text, "f {} {} {}", a + 1, b + 1, c + 1).unwrap();
+        }
+        text.into_bytes()
+    }
+
+    fn ply(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        write!(
+            bytes,
+            "ply\nformat binary_little_endian 1.0\nelement vertex {}\nproperty float x\nproperty float y\nproperty float z\nelement face {}\nproperty list uchar uint vertex_indices\nend_header\n",
+            self.vertices.len(),
+            self.faces.len()
+        )
+        .unwrap();
+        for vertex in &self.vertices {
+            for coordinate in [vertex.x, vertex.y, vertex.z] {
+                bytes.extend_from_slice(&coordinate.to_le_bytes());
+            }
+        }
+        for face in &self.faces {
+            bytes.push(3);
+            for index in face {
+                bytes.extend_from_slice(&index.to_le_bytes());
+            }
+        }
+        bytes
+    }
+}
+
+/// Converts the OBJ mesh at `input` to the format of the `output` extension, subdivided if
+/// asked, returning the number of triangles and vertices written
+pub fn convert(input: &Path, output: &Path, subdivision: Option<&Subdivision>) -> anyhow::Result<(usize, usize)> {
+    let format = MeshFormat::from_path(output)?;
+    let triangles = match subdivision {
+        Some(subdivision) => subdivision.apply_polygons(&geometry::read_obj_polygons(input)?),
+        None => geometry::read_obj(input)?,
+    };
+    let mesh = IndexedMesh::new(&triangles);
+    let bytes = match format {
+        MeshFormat::Obj => mesh.obj(),
+        MeshFormat::Ply => mesh.ply(),
+    };
+    fs::write(output, bytes).with_context(|| format!("failed to write '{}'", output.display()))?;
+    Ok((mesh.faces.len(), mesh.vertices.len()))
+}
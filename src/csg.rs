@@ -0,0 +1,286 @@
+use bvh::aabb::AABB;
+use nalgebra::{Vector2, Vector3};
+
+use crate::{
+    checkpoint::Fingerprint,
+    geometry::{Intersectable, Interval, Point, Ray},
+    material::{Diffuse, Material},
+    object::Object,
+    world::Intersection,
+};
+
+/// How a `Csg` combines the insides of its children
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CsgOperation {
+    /// Inside either child
+    Union,
+    /// Inside both children
+    Intersection,
+    /// Inside the first child and outside the second one
+    Difference,
+}
+
+impl CsgOperation {
+    fn inside(&self, left: bool, right: bool) -> bool {
+        match self {
+            Self::Union => left || right,
+            Self::Intersection => left && right,
+            Self::Difference => left && !right,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Child {
+    Left,
+    Right,
+}
+
+/// Where the combined solid starts or ends along the line of a ray, on the surface of a child
+#[derive(Debug, Clone, Copy)]
+struct Boundary {
+    distance: f32,
+    child: Child,
+}
+
+/// Two solids combined by a boolean operation, like a box with a hole drilled by a cylinder. The
+/// children are combined through their `intervals`, so they have to be closed shapes, or other
+/// `Csg`s. A hit takes the normal of the child surface it's on, turned inside out for the
+/// surface of the second child of a difference, and its material, unless the combination is
+/// wrapped, e.g. in an `Instance` that replaces the material.
+///
+/// The combination can't be sampled, so it doesn't light the scene as an emitter would.
+pub struct Csg {
+    operation: CsgOperation,
+    left: Box<dyn Object>,
+    right: Box<dyn Object>,
+    /// A diffuse material of the color of the first child, which doesn't emit so the world
+    /// doesn't take the combination for a light it could sample
+    material: Diffuse,
+}
+
+impl Csg {
+    /// Distance the ray towards a boundary starts before it, to find the hit on the child
+    const PROBE_DISTANCE: f32 = 1e-4;
+
+    pub fn new(operation: CsgOperation, left: Box<dyn Object>, right: Box<dyn Object>) -> Self {
+        let material = Diffuse::new(left.material().color());
+        Self { operation, left, right, material }
+    }
+
+    pub fn union(left: Box<dyn Object>, right: Box<dyn Object>) -> Self {
+        Self::new(CsgOperation::Union, left, right)
+    }
+
+    pub fn intersection(left: Box<dyn Object>, right: Box<dyn Object>) -> Self {
+        Self::new(CsgOperation::Intersection, left, right)
+    }
+
+    /// `left` with `right` carved out of it
+    pub fn difference(left: Box<dyn Object>, right: Box<dyn Object>) -> Self {
+        Self::new(CsgOperation::Difference, left, right)
+    }
+
+    pub fn operation(&self) -> CsgOperation {
+        self.operation
+    }
+
+    fn child(&self, child: Child) -> &dyn Object {
+        match child {
+            Child::Left => self.left.as_ref(),
+            Child::Right => self.right.as_ref(),
+        }
+    }
+
+    /// Whether the surface of `child` faces the other way on the combined solid
+    fn flipped(&self, child: Child) -> bool {
+        self.operation == CsgOperation::Difference && child == Child::Right
+    }
+
+    /// Boundaries of the combined solid along the line of the ray, an entry then an exit
+    fn boundaries(&self, ray: &Ray) -> Vec<Boundary> {
+        let mut events = Vec::new();
+        for child in [Child::Left, Child::Right] {
+            for interval in self.child(child).intervals(ray) {
+                events.push((interval.enter, child, true));
+                events.push((interval.exit, child, false));
+            }
+        }
+        events.sort_by(|a, b| a.0.total_cmp(&b.0));
+        let (mut left, mut right) = (false, false);
+        let mut boundaries = Vec::new();
+        for (distance, child, entering) in events {
+            let was_inside = self.operation.inside(left, right);
+            match child {
+                Child::Left => left = entering,
+                Child::Right => right = entering,
+            }
+            if self.operation.inside(left, right) != was_inside {
+                boundaries.push(Boundary { distance, child });
+            }
+        }
+        boundaries
+    }
+
+    /// The child whose surface passes the closest to a point on the combined surface, looking
+    /// along the normal of each child
+    fn closest_child(&self, point: &Point) -> Child {
+        let gap = |child: Child| {
+            let object = self.child(child);
+            let normal = object.surface_normal(point);
+            let probe = Ray::new(point - normal * Self::PROBE_DISTANCE, normal);
+            object
+                .intervals(&probe)
+                .iter()
+                .flat_map(|interval| [interval.enter, interval.exit])
+                .map(|distance| (distance - Self::PROBE_DISTANCE).abs())
+                .fold(f32::INFINITY, f32::min)
+        };
+        if gap(Child::Right) < gap(Child::Left) { Child::Right } else { Child::Left }
+    }
+}
+
+impl Intersectable for Csg {
+    fn intersect(&self, ray: &Ray) -> Option<f32> {
+        self.boundaries(ray).into_iter().map(|boundary| boundary.distance).find(|&distance| distance >= 0.0)
+    }
+
+    fn intervals(&self, ray: &Ray) -> Vec<Interval> {
+        self.boundaries(ray).chunks_exact(2).map(|pair| Interval::new(pair[0].distance, pair[1].distance)).collect()
+    }
+}
+
+impl Object for Csg {
+    fn surface_normal(&self, point: &Point) -> Vector3<f32> {
+        let child = self.closest_child(point);
+        let normal = self.child(child).surface_normal(point);
+        if self.flipped(child) { -normal } else { normal }
+    }
+
+    fn surface_uv(&self, point: &Point) -> Vector2<f32> {
+        self.child(self.closest_child(point)).surface_uv(point)
+    }
+
+    /// Diffuse in the color of the first child, the hits have the material of the child they're on
+    fn material(&self) -> &dyn Material {
+        &self.material
+    }
+
+    fn fingerprint(&self, fingerprint: &mut Fingerprint) {
+        fingerprint.add_str("csg");
+        fingerprint.add_debug(&self.operation);
+        self.left.fingerprint(fingerprint);
+        self.right.fingerprint(fingerprint);
+    }
+
+    fn bounds(&self) -> Option<AABB> {
+        let (left, right) = (self.left.bounds(), self.right.bounds());
+        match self.operation {
+            CsgOperation::Union => Some(left?.join(&right?)),
+            CsgOperation::Intersection => match (left, right) {
+                (Some(left), Some(right)) => {
+                    let min = bvh::Point3::new(left.min.x.max(right.min.x), left.min.y.max(right.min.y), left.min.z.max(right.min.z));
+                    let max = bvh::Point3::new(left.max.x.min(right.max.x), left.max.y.min(right.max.y), left.max.z.min(right.max.z));
+                    Some(AABB::with_bounds(min, max.max(min)))
+                }
+                (bounds, None) | (None, bounds) => bounds,
+            },
+            CsgOperation::Difference => left,
+        }
+    }
+
+    fn triangle_count(&self) -> usize {
+        self.left.triangle_count() + self.right.triangle_count()
+    }
+
+    fn intersection<'a>(&'a self, r: &Ray, b: &'a dyn Object) -> Option<Intersection<'a>> {
+        let boundary = self.boundaries(r).into_iter().find(|boundary| boundary.distance >= 0.0)?;
+        let child = self.child(boundary.child);
+        let point = r.point_at(boundary.distance);
+        // The child finds its own normal and texture coordinates, and resolves its material,
+        // from just before the boundary, as its closest hit may have been carved away
+        let start = boundary.distance - Self::PROBE_DISTANCE / r.direction.norm();
+        let probe = Ray {
+            origin: r.point_at(start),
+            ..*r
+        };
+        let mut intersection = child.intersection(&probe, child).unwrap_or_else(|| Intersection {
+            distance: 0.0,
+            point,
+            object: child,
+            object_id: 0,
+            normal: child.surface_normal(&point),
+            uv: child.surface_uv(&point),
+            time: r.time,
+        });
+        intersection.distance = boundary.distance;
+        intersection.point = point;
+        // A wrapper resolves the hit to itself, for its own material
+        if !std::ptr::addr_eq(b, self) {
+            intersection.object = b;
+        }
+        if self.flipped(boundary.child) {
+            intersection.normal = -intersection.normal;
+        }
+        Some(intersection)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use nalgebra::{Matrix4, Vector3};
+
+    use super::Csg;
+    use crate::{
+        color,
+        geometry::{Intersectable, Interval, Point, Ray, Sphere},
+        instance::Instance,
+        material::{Diffuse, Emmisive},
+        object::Object,
+        shapes::Cuboid,
+        world::World,
+    };
+
+    #[test]
+    fn combines_the_insides_and_takes_the_surface_of_the_child_hit() {
+        let cube = || Box::new(Cuboid::new(Point::new(-1.0, -1.0, -1.0), Point::new(1.0, 1.0, 1.0), Box::new(Diffuse::new(color::RED))));
+        let bite = || Box::new(Sphere::new_with_material(0.0, 0.0, 1.0, 0.5, Box::new(Diffuse::new(color::BLUE))));
+        let bitten = Csg::difference(cube(), bite());
+        let down = |x: f32| Ray::new(Point::new(x, 0.0, 5.0), -Vector3::z());
+
+        // Next to the bite, the top of the cube
+        let hit = bitten.intersection(&down(0.8), &bitten).unwrap();
+        assert!((hit.distance - 4.0).abs() < 1e-5);
+        assert_eq!(hit.normal, Vector3::z());
+        assert_eq!(hit.object.material().color(), color::RED);
+        // Into the bite, the bottom of the sphere facing up, with the color of the sphere
+        let hit = bitten.intersection(&down(0.0), &bitten).unwrap();
+        assert!((hit.distance - 4.5).abs() < 1e-5);
+        assert!((hit.normal - Vector3::z()).norm() < 1e-4);
+        assert_eq!(hit.object.material().color(), color::BLUE);
+        // From inside the cube, out through the bite
+        let up = Ray::new(Point::origin(), Vector3::z());
+        assert!((bitten.intersect(&up).unwrap() - 0.5).abs() < 1e-5);
+        assert_eq!(bitten.intervals(&up), vec![Interval::new(-1.0, 0.5)]);
+
+        // The rest of the bite once the cube is taken away, nested in a union with a sphere
+        let cap = Csg::intersection(cube(), bite());
+        assert_eq!(cap.intervals(&down(0.0)), vec![Interval::new(4.0, 4.5)]);
+        let both = Csg::union(Box::new(cap), Box::new(Sphere::new_with_material(0.0, 0.0, -3.0, 1.0, Box::new(Diffuse::new(color::GREEN)))));
+        assert_eq!(both.intervals(&down(0.0)), vec![Interval::new(4.0, 4.5), Interval::new(7.0, 9.0)]);
+        assert_eq!(both.intersection(&down(0.6), &both).unwrap().object.material().color(), color::GREEN);
+        assert!(both.intersect(&down(1.5)).is_none());
+        assert_eq!(both.surface_normal(&Point::new(0.0, 0.0, -2.0)), Vector3::z());
+
+        // Wrapped, the hits take the material of the wrapper, and a glowing child doesn't make
+        // the combination a light
+        let glowing = Box::new(Sphere::new_with_material(0.0, 0.0, 1.0, 0.5, Box::new(Emmisive::new(color::WHITE, 5.0))));
+        let instance = Instance::new(Arc::new(Csg::union(cube(), glowing)), Matrix4::identity()).with_material(Box::new(Diffuse::new(color::GREEN)));
+        assert_eq!(instance.intersection(&down(0.0), &instance).unwrap().object.material().color(), color::GREEN);
+        let mut world = World::new();
+        world.add_object(Box::new(instance));
+        assert!(world.emitters().is_empty());
+    }
+}
@@ -0,0 +1,145 @@
+use image::Rgba32FImage;
+use rayon::prelude::{IntoParallelIterator, ParallelIterator};
+
+use crate::{color::ColorF32, film::Features};
+
+/// Edge-avoiding à-trous wavelet filter (Dammertz et al., "Edge-Avoiding À-Trous Wavelet
+/// Transform for fast Global Illumination Filtering"). Each iteration blurs with a 5x5 B3
+/// spline kernel whose taps are twice as far apart as in the previous one, and every tap is
+/// weighted down when its color, normal or albedo differs from the center pixel.
+///
+/// The image is divided by the albedo before filtering, so textures stay sharp and only the
+/// lighting is smoothed.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Denoiser {
+    pub iterations: u32,
+    /// Color difference tolerated in the first iteration, halved at every iteration
+    pub sigma_color: f32,
+    pub sigma_normal: f32,
+    pub sigma_albedo: f32,
+}
+
+impl Default for Denoiser {
+    fn default() -> Self {
+        Self {
+            iterations: 5,
+            sigma_color: 0.6,
+            sigma_normal: 0.3,
+            sigma_albedo: 0.1,
+        }
+    }
+}
+
+impl Denoiser {
+    /// Albedo below which the image isn't divided further, so black surfaces keep their noise
+    const MIN_ALBEDO: f32 = 0.01;
+    const KERNEL: [f32; 5] = [1.0 / 16.0, 1.0 / 4.0, 3.0 / 8.0, 1.0 / 4.0, 1.0 / 16.0];
+
+    /// Filters `image`, guided by the `features` of its pixels, row by row
+    pub fn denoise(&self, image: &Rgba32FImage, features: &[Features]) -> Rgba32FImage {
+        let (width, height) = image.dimensions();
+        let albedo = |i: usize| {
+            let albedo = features[i].albedo;
+            ColorF32::new(
+                albedo.r.max(Self::MIN_ALBEDO),
+                albedo.g.max(Self::MIN_ALBEDO),
+                albedo.b.max(Self::MIN_ALBEDO),
+            )
+        };
+        let mut irradiance: Vec<ColorF32> =
+            image.pixels().enumerate().map(|(i, pixel)| ColorF32::from(pixel) / albedo(i)).collect();
+
+        for iteration in 0..self.iterations {
+            let step = 1i64 << iteration;
+            let sigma_color = self.sigma_color * 0.5f32.powi(iteration as i32);
+            irradiance = (0..height as i64)
+                .into_par_iter()
+                .flat_map_iter(|y| {
+                    let irradiance = &irradiance;
+                    (0..width as i64).map(move |x| {
+                        let center = (y * width as i64 + x) as usize;
+                        let mut sum = ColorF32::new(0.0, 0.0, 0.0);
+                        let mut total_weight = 0.0;
+                        for (ky, kernel_y) in Self::KERNEL.iter().enumerate() {
+                            let qy = y + (ky as i64 - 2) * step;
+                            if qy < 0 || qy >= height as i64 {
+                                continue;
+                            }
+                            for (kx, kernel_x) in Self::KERNEL.iter().enumerate() {
+                                let qx = x + (kx as i64 - 2) * step;
+                                if qx < 0 || qx >= width as i64 {
+                                    continue;
+                                }
+                                let tap = (qy * width as i64 + qx) as usize;
+                                let weight = kernel_x
+                                    * kernel_y
+                                    * gaussian(color_distance(irradiance[center], irradiance[tap]), sigma_color)
+                                    * gaussian((features[center].normal - features[tap].normal).norm_squared(), self.sigma_normal)
+                                    * gaussian(color_distance(features[center].albedo, features[tap].albedo), self.sigma_albedo);
+                                sum += irradiance[tap] * weight;
+                                total_weight += weight;
+                            }
+                        }
+                        // The center tap always has a weight of at least 9/64
+                        sum / total_weight
+                    })
+                })
+                .collect();
+        }
+
+        Rgba32FImage::from_fn(width, height, |x, y| {
+            let i = (y * width + x) as usize;
+            (irradiance[i] * albedo(i)).into()
+        })
+    }
+}
+
+/// exp(-d / sigma²), with `squared_distance` the squared difference of the two guides
+fn gaussian(squared_distance: f32, sigma: f32) -> f32 {
+    (-squared_distance / (sigma * sigma)).exp()
+}
+
+/// Squared distance of two colors after compressing them with c / (1 + c), so a firefly isn't
+/// infinitely far from its neighbours
+fn color_distance(a: ColorF32, b: ColorF32) -> f32 {
+    let compress = |c: f32| c / (1.0 + c.max(0.0));
+    let (dr, dg, db) = (compress(a.r) - compress(b.r), compress(a.g) - compress(b.g), compress(a.b) - compress(b.b));
+    dr * dr + dg * dg + db * db
+}
+
+#[cfg(test)]
+mod tests {
+    use image::Rgba32FImage;
+    use nalgebra::Vector3;
+
+    use crate::{color::ColorF32, film::Features, sampler};
+
+    use super::Denoiser;
+
+    #[test]
+    fn smooths_noise_and_keeps_albedo_edges() {
+        let (width, height) = (32, 32);
+        let albedo = |x: u32| if x < width / 2 { 0.2 } else { 0.8 };
+        let features: Vec<Features> = (0..width * height)
+            .map(|i| Features {
+                albedo: ColorF32::new(albedo(i % width), albedo(i % width), albedo(i % width)),
+                normal: Vector3::new(0.0, 0.0, 1.0),
+            })
+            .collect();
+        let noisy = Rgba32FImage::from_fn(width, height, |x, y| {
+            let noise = 1.0 + (sampler::hash(7, (y * width + x) as u64) as f32 / u64::MAX as f32 - 0.5);
+            let value = albedo(x) * noise;
+            ColorF32::new(value, value, value).into()
+        });
+        let denoised = Denoiser::default().denoise(&noisy, &features);
+
+        let error = |image: &Rgba32FImage| {
+            image.enumerate_pixels().map(|(x, _, pixel)| (pixel[0] - albedo(x)).powi(2)).sum::<f32>() / (width * height) as f32
+        };
+        assert!(error(&denoised) < 0.1 * error(&noisy), "{} against {}", error(&denoised), error(&noisy));
+        for y in 0..height {
+            assert!((denoised.get_pixel(width / 2 - 1, y)[0] - 0.2).abs() < 0.05);
+            assert!((denoised.get_pixel(width / 2, y)[0] - 0.8).abs() < 0.1);
+        }
+    }
+}
@@ -0,0 +1,169 @@
+use std::{fmt, str::FromStr};
+
+use nalgebra::Vector3;
+
+use crate::{
+    checkpoint::{CheckpointReader, CheckpointWriter},
+    color::ColorF32,
+    geometry::Ray,
+    world::{Intersection, World},
+};
+
+/// What the camera sees first through a pixel, averaged over a few jittered rays. It's noise
+/// free, so it guides the denoiser along the edges of the scene.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Features {
+    /// Color of the material hit, or of the background for the rays that escape
+    pub albedo: ColorF32,
+    /// Shading normal of the hit, zero for the rays that escape
+    pub normal: Vector3<f32>,
+}
+
+impl Default for Features {
+    fn default() -> Self {
+        Self {
+            albedo: ColorF32::new(0.0, 0.0, 0.0),
+            normal: Vector3::zeros(),
+        }
+    }
+}
+
+impl Features {
+    /// Features of a camera ray, from what it hit first
+    pub fn first_hit(world: &World, ray: &Ray, hit: Option<&Intersection>) -> Self {
+        match hit {
+            Some(intersection) => Self {
+                albedo: intersection.object.material().color(),
+                normal: intersection.normal,
+            },
+            None => Self {
+                albedo: world.background_color(ray),
+                normal: Vector3::zeros(),
+            },
+        }
+    }
+
+    /// Running average of `samples` features, with `other` being the mean of `added` more
+    pub fn blend(&self, samples: u32, other: &Self, added: u32) -> Self {
+        let weight = added as f32 / (samples + added) as f32;
+        Self {
+            albedo: self.albedo * (1.0 - weight) + other.albedo * weight,
+            normal: self.normal * (1.0 - weight) + other.normal * weight,
+        }
+    }
+}
+
+/// Running mean and variance of the luminance of the samples of a pixel (Welford's algorithm),
+/// to tell when the pixel has converged
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct PixelStats {
+    samples: u64,
+    mean: f64,
+    /// Sum of the squared differences to the mean
+    m2: f64,
+}
+
+impl PixelStats {
+    /// Pixels darker than this are compared to it instead of their own mean, or black pixels
+    /// with a single bright sample would never converge
+    const DARK_LUMINANCE: f64 = 0.01;
+
+    pub fn add(&mut self, color: ColorF32) {
+        let luminance = color.luminance() as f64;
+        self.samples += 1;
+        let delta = luminance - self.mean;
+        self.mean += delta / self.samples as f64;
+        self.m2 += delta * (luminance - self.mean);
+    }
+
+    pub fn samples(&self) -> u64 {
+        self.samples
+    }
+
+    /// Standard error of the mean luminance, relative to the mean
+    pub fn relative_error(&self) -> f32 {
+        if self.samples < 2 {
+            return f32::INFINITY;
+        }
+        let n = self.samples as f64;
+        let variance = self.m2 / (n - 1.0);
+        ((variance / n).sqrt() / self.mean.max(Self::DARK_LUMINANCE)) as f32
+    }
+
+    pub fn write(&self, checkpoint: &mut CheckpointWriter) {
+        checkpoint.u64(self.samples);
+        checkpoint.f64(self.mean);
+        checkpoint.f64(self.m2);
+    }
+
+    pub fn read(checkpoint: &mut CheckpointReader) -> anyhow::Result<Self> {
+        Ok(Self {
+            samples: checkpoint.u64()?,
+            mean: checkpoint.f64()?,
+            m2: checkpoint.f64()?,
+        })
+    }
+
+    /// Whether the pixel can stop sampling, because it's below the noise level or out of samples
+    pub fn converged(&self, settings: &AdaptiveSettings) -> bool {
+        self.samples >= settings.max_samples
+            || (self.samples >= settings.min_samples && self.relative_error() <= settings.noise)
+    }
+}
+
+/// When pixels stop sampling
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AdaptiveSettings {
+    /// Relative error of a pixel below which it stops sampling
+    pub noise: f32,
+    /// Samples every pixel takes before its error is trusted
+    pub min_samples: u64,
+    /// Samples after which a pixel stops even if it's still noisy
+    pub max_samples: u64,
+}
+
+impl Default for AdaptiveSettings {
+    fn default() -> Self {
+        Self {
+            noise: 0.01,
+            min_samples: 64,
+            max_samples: 4096,
+        }
+    }
+}
+
+impl fmt::Display for AdaptiveSettings {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "noise={},min={},max={}", self.noise, self.min_samples, self.max_samples)
+    }
+}
+
+impl FromStr for AdaptiveSettings {
+    type Err = anyhow::Error;
+
+    /// Parses a comma separated list of `key=value` overrides, e.g. `noise=0.02,max=1024`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut settings = Self::default();
+        for option in s.split(',').filter(|option| !option.is_empty()) {
+            let (key, value) = option
+                .split_once('=')
+                .ok_or_else(|| anyhow::anyhow!("expected key=value in adaptive sampling options, got '{}'", option))?;
+            let invalid = |e: &dyn fmt::Display| anyhow::anyhow!("invalid value for adaptive sampling option '{}': {}", key, e);
+            match key {
+                "noise" => {
+                    settings.noise = value.parse().map_err(|e| invalid(&e))?;
+                    if settings.noise <= 0.0 {
+                        return Err(invalid(&"the noise level must be positive"));
+                    }
+                }
+                "min" => settings.min_samples = value.parse().map_err(|e| invalid(&e))?,
+                "max" => settings.max_samples = value.parse().map_err(|e| invalid(&e))?,
+                _ => anyhow::bail!("unknown adaptive sampling option '{}', expected noise, min or max", key),
+            }
+        }
+        if settings.min_samples < 2 || settings.min_samples > settings.max_samples {
+            anyhow::bail!("adaptive sampling needs at least 2 samples and min <= max");
+        }
+        Ok(settings)
+    }
+}
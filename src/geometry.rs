@@ -1,10 +1,9 @@
-use std::{borrow::BorrowMut, cell::RefCell};
+use std::f32::consts::PI;
 
-use crate::{material::{Material, Diffuse}, color::ColorF32, camera::Camera, object::Object, world::Intersection};
-use bvh::{bvh::BVH, aabb::Bounded, bounding_hierarchy::BHShape};
-use nalgebra::{Vector3, Point3};
-use rand::{Rng, distributions::Distribution, thread_rng};
-use lazy_static::lazy_static;
+use crate::{checkpoint::Fingerprint, material::{Material, Diffuse}, color::ColorF32, object::{Object, SurfaceSample}, sampler::Sampler, stats::{self, Counter}, subdivision::Subdivision, world::Intersection};
+use anyhow::Context;
+use bvh::{bvh::{BVH, BVHNode}, aabb::{AABB, Bounded}, bounding_hierarchy::BHShape};
+use nalgebra::{Vector2, Vector3, Point3};
 
 pub type Point = Point3<f32>;
 
@@ -15,7 +14,7 @@ pub const ORIGIN: &Point = &Point::new(0.0, 0.0, 0.0);
 pub struct Sphere {
     pub center: Point,
     pub radius: f32,
-    pub material: Box<dyn Material + Send + Sync>,
+    pub material: Box<dyn Material>,
 }
 
 impl Sphere {
@@ -27,7 +26,7 @@ impl Sphere {
 
         }
     }
-    pub fn new_with_material(x: f32, y: f32, z: f32, radius: f32, material: Box<dyn Material+Send+Sync>) -> Self {
+    pub fn new_with_material(x: f32, y: f32, z: f32, radius: f32, material: Box<dyn Material>) -> Self {
         Self {
             center: Point::new(x, y, z),
             radius,
@@ -45,9 +44,12 @@ pub struct Triangle {
     pub index: usize,
 }
 
+#[derive(Debug, Clone, Copy)]
 pub struct Ray {
     pub origin: Point,
     pub direction: Vector3<f32>,
+    /// Moment the ray is traced at, within the shutter of the camera
+    pub time: f32,
 }
 
 impl Ray {
@@ -55,118 +57,145 @@ impl Ray {
         Self {
             origin,
             direction,
+            time: 0.0,
         }
     }
 
-    pub fn new_prime (x : u32, y : u32, camera : &Camera) -> Self {
-        let camera_direction = camera.direction().to_owned();
-        let camera_origin = camera.origin().to_owned();
-        let fov = camera.fov().to_radians();
-        let width = camera.width();
-        let height = camera.height();
-
-        let aspect_ratio = width as f32 / height as f32;
-        let sensor_x = (((x as f32 + 0.5) / width as f32) * 2.0 - 1.0) * aspect_ratio * (fov / 2.0).tan();
-        let sensor_y = (1.0 - ((y as f32 + 0.5) / height as f32) * 2.0) * (fov / 2.0).tan();
-
-        let direction = Vector3::new(sensor_x, sensor_y, -1.0).normalize();
-        
-        // Now we have a direction vector, but it's in camera space. We need to transform it into world space.
-        // We can do this by rotating the vector by the camera's rotation matrix.
-
-
-        Self {
-            origin: camera_origin,
-            direction,
-        }
+    pub fn with_time(mut self, time: f32) -> Self {
+        self.time = time;
+        self
     }
 
-    pub(crate) fn point_at(&self, distance: f32) -> nalgebra::OPoint<f32, nalgebra::Const<3>> {
+    pub fn point_at(&self, distance: f32) -> nalgebra::OPoint<f32, nalgebra::Const<3>> {
         self.origin + (self.direction * distance)
     }
 
-    pub(crate) fn new_random_hemi(point: nalgebra::OPoint<f32, nalgebra::Const<3>>, normal: Vector3<f32>) -> Ray {
-        let mut rng = rand::thread_rng();
-        let mut direction = Vector3::new(rng.gen_range(-1.0..1.0), rng.gen_range(0.0..1.0), rng.gen_range(-1.0..1.0));
-        if direction.dot(&normal) < 0.0 {
-            direction = -direction;
-        }
-        Ray {
-            origin: point,
-            direction: direction.normalize(),
-        }
+    pub fn new_with_eps(point: Point, direction: Vector3<f32>, eps: f32) -> Ray {
+        Self::new(point + (direction * eps), direction)
     }
+}
 
-    pub(crate) fn new_with_eps(point: Point, direction: Vector3<f32>, eps: f32) -> Ray {
-        Self { origin: point + (direction * eps), direction }
-    }
+/// Uniformly distributed direction
+pub fn random_unit_vector(sampler: &mut dyn Sampler) -> Vector3<f32> {
+    let (u, v) = sampler.get_2d();
+    let z = 1.0 - 2.0 * u;
+    let r = (1.0 - z * z).max(0.0).sqrt();
+    let phi = 2.0 * PI * v;
+    Vector3::new(r * phi.cos(), r * phi.sin(), z)
 }
 
-lazy_static! {
-    pub static ref DISTRIBUTION : rand::distributions::Uniform<f32> = rand::distributions::Uniform::new(-1.0, 1.0);
+/// Uniformly distributed point inside the unit sphere. It always takes three numbers, unlike
+/// rejection sampling, so small changes to the numbers only move the point a little.
+pub fn random_in_unit_sphere(sampler: &mut dyn Sampler) -> Vector3<f32> {
+    random_unit_vector(sampler) * sampler.get_1d().cbrt()
 }
 
-thread_local! {
-    pub static RNG : RefCell<rand_pcg::Pcg64> = RefCell::new(rand_pcg::Pcg64::new(0xcafef00dd15ea5e5, 0xa02bdbf7bb3c0a7));
+pub fn random_lambertian(normal: Vector3<f32>, sampler: &mut dyn Sampler) -> Vector3<f32> {
+    random_unit_vector(sampler) + normal
 }
 
-pub fn random_in_unit_sphere() -> Vector3<f32> {
-    RNG.with(|rng| {
-        let mut rng = rng.borrow_mut();
-        // let mut rng = thread_rng();
-        let mut a = Vector3::new(rng.sample(*DISTRIBUTION), rng.sample(*DISTRIBUTION), rng.sample(*DISTRIBUTION));
-        while a.magnitude_squared() >= 1.0 {
-            a = Vector3::new(rng.sample(*DISTRIBUTION), rng.sample(*DISTRIBUTION), rng.sample(*DISTRIBUTION));
-        }
-        a
-    })
+/// Builds two unit vectors that form an orthonormal basis together with `normal`
+pub fn orthonormal_basis(normal: &Vector3<f32>) -> (Vector3<f32>, Vector3<f32>) {
+    let helper = if normal.x.abs() > 0.9 { Vector3::y() } else { Vector3::x() };
+    let tangent = normal.cross(&helper).normalize();
+    let bitangent = normal.cross(&tangent);
+    (tangent, bitangent)
 }
 
-fn random_in_hemisphere(normal: Vector3<f32>) -> Vector3<f32> {
-    let a = random_in_unit_sphere().normalize();
-    if a.dot(&normal) > 0.0 {
-        a
-    } else {
-        -a
-    }
+/// Cosine weighted direction on the hemisphere around `normal`
+pub fn random_cosine_direction(normal: &Vector3<f32>, sampler: &mut dyn Sampler) -> Vector3<f32> {
+    let (tangent, bitangent) = orthonormal_basis(normal);
+    let (r1, r2) = sampler.get_2d();
+    let phi = 2.0 * PI * r1;
+    let r = r2.sqrt();
+    (tangent * (r * phi.cos()) + bitangent * (r * phi.sin()) + normal * (1.0 - r2).max(0.0).sqrt()).normalize()
 }
 
-pub fn random_lambertian(normal: Vector3<f32>) -> Vector3<f32> {
-    let a = random_in_unit_sphere().normalize();
-    a + normal
+/// A stretch of the line of a ray inside a solid, from where it enters to where it exits, in
+/// distances along the ray. Either end can be behind the origin of the ray, or infinitely far
+/// for unbounded solids like the half-space of a plane.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Interval {
+    pub enter: f32,
+    pub exit: f32,
 }
 
+impl Interval {
+    pub fn new(enter: f32, exit: f32) -> Self {
+        Self { enter, exit }
+    }
+
+    /// The whole line
+    pub fn everywhere() -> Self {
+        Self::new(f32::NEG_INFINITY, f32::INFINITY)
+    }
+
+    /// The part of both intervals, if they overlap
+    pub fn overlap(&self, other: &Interval) -> Option<Interval> {
+        let overlap = Interval::new(self.enter.max(other.enter), self.exit.min(other.exit));
+        (overlap.enter <= overlap.exit).then_some(overlap)
+    }
+}
+
+/// Shapes a ray can be intersected with
 pub trait Intersectable {
+    /// Distance along the ray to the closest hit in front of its origin, if any
     fn intersect(&self, ray: &Ray) -> Option<f32>;
+    /// Where the whole line of the ray is inside the shape, in order and apart from each other,
+    /// for constructive solid geometry. Shapes without an inside, like quads, disks and meshes,
+    /// have none.
+    fn intervals(&self, _ray: &Ray) -> Vec<Interval> {
+        Vec::new()
+    }
+}
+
+/// Distances to where the line of the ray enters and exits the sphere of `center` and `radius`
+fn sphere_interval(center: &Point, radius: f32, ray: &Ray) -> Option<Interval> {
+    // https://en.wikipedia.org/wiki/Line%E2%80%93sphere_intersection
+    // The direction isn't necessarily normalized, so keep its squared length around
+    let ctor = center - ray.origin;
+    let a = ray.direction.magnitude_squared();
+    let v = ctor.dot(&ray.direction);
+    let discriminant = v * v - a * (ctor.dot(&ctor) - radius * radius);
+    if discriminant < 0.0 {
+        return None;
+    }
+    let d = discriminant.sqrt();
+    Some(Interval::new((v - d) / a, (v + d) / a))
+}
+
+/// Distance to the closest hit in front of the ray on the sphere of `center` and `radius`
+fn intersect_sphere(center: &Point, radius: f32, ray: &Ray) -> Option<f32> {
+    let interval = sphere_interval(center, radius, ray)?;
+    if interval.exit < 0.0 {
+        return None;
+    }
+    Some(if interval.enter < 0.0 { interval.exit } else { interval.enter })
+}
+
+/// Box around the sphere of `center` and `radius`
+fn sphere_bounds(center: &Point, radius: f32) -> AABB {
+    AABB::with_bounds(
+        point_to_bvh_point(&(center - Vector3::repeat(radius))),
+        point_to_bvh_point(&(center + Vector3::repeat(radius))),
+    )
+}
+
+/// Texture coordinates of a direction from the center of a sphere
+fn sphere_uv(n: &Vector3<f32>) -> Vector2<f32> {
+    let u = 0.5 + n.z.atan2(n.x) / (2.0 * PI);
+    let v = 0.5 - n.y.clamp(-1.0, 1.0).asin() / PI;
+    Vector2::new(u, v)
 }
 
 impl Intersectable for Sphere {
     fn intersect(&self, ray: &Ray) -> Option<f32> {
-        // https://en.wikipedia.org/wiki/Line%E2%80%93sphere_intersection
-        let ctor = self.center- ray.origin;
-        let v = ctor.dot(&ray.direction);
-        let discriminant = (self.radius * self.radius) - (ctor.dot(&ctor) - v * v);
-        if discriminant < 0.0 {
-            return None;
-        }
-        let d = discriminant.sqrt();
-        let t1 = v - d;
-        let t2 = v + d;
-        if t1 < 0.0 && t2 < 0.0 {
-            return None;
-        }
-        if t1 < 0.0 {
-            return Some(t2);
-        }
-        if t2 < 0.0 {
-            return Some(t1);
-        }
+        intersect_sphere(&self.center, self.radius, ray)
+    }
 
-        let t = if t1 < t2 { t1 } else { t2 };
-    
-        Some(t)
+    fn intervals(&self, ray: &Ray) -> Vec<Interval> {
+        sphere_interval(&self.center, self.radius, ray).into_iter().collect()
     }
-    
 }
 
 impl Object for Sphere {
@@ -174,19 +203,132 @@ impl Object for Sphere {
         (point - self.center).normalize()
     }
 
-    fn material(&self) -> &Box<dyn Material + Send + Sync> {
-        &self.material
+    fn surface_uv(&self, point: &Point) -> Vector2<f32> {
+        sphere_uv(&self.surface_normal(point))
+    }
+
+    fn sample_surface(&self, _time: f32, sampler: &mut dyn Sampler) -> Option<SurfaceSample> {
+        let normal = random_unit_vector(sampler);
+        Some(SurfaceSample {
+            point: self.center + normal * self.radius,
+            normal,
+            pdf: 1.0 / (4.0 * PI * self.radius * self.radius),
+        })
+    }
+
+    fn surface_pdf(&self, _point: &Point, _normal: &Vector3<f32>, _time: f32) -> f32 {
+        1.0 / (4.0 * PI * self.radius * self.radius)
+    }
+
+    fn bounds(&self) -> Option<AABB> {
+        Some(sphere_bounds(&self.center, self.radius))
+    }
+
+    fn material(&self) -> &dyn Material {
+        self.material.as_ref()
+    }
+
+    fn fingerprint(&self, fingerprint: &mut Fingerprint) {
+        fingerprint.add_str("sphere");
+        fingerprint.add_debug(&(self.center, self.radius));
+        self.material.fingerprint(fingerprint);
+    }
+}
+
+/// A sphere moving in a straight line, from `from` at time 0 to `to` at time 1. It stays at
+/// the ends outside of that interval.
+pub struct MovingSphere {
+    pub from: Point,
+    pub to: Point,
+    pub radius: f32,
+    pub material: Box<dyn Material>,
+}
+
+impl MovingSphere {
+    pub fn new(from: Point, to: Point, radius: f32, material: Box<dyn Material>) -> Self {
+        Self {
+            from,
+            to,
+            radius,
+            material,
+        }
+    }
+
+    pub fn center(&self, time: f32) -> Point {
+        self.from + (self.to - self.from) * time.clamp(0.0, 1.0)
+    }
+}
+
+impl Intersectable for MovingSphere {
+    fn intersect(&self, ray: &Ray) -> Option<f32> {
+        intersect_sphere(&self.center(ray.time), self.radius, ray)
+    }
+
+    fn intervals(&self, ray: &Ray) -> Vec<Interval> {
+        sphere_interval(&self.center(ray.time), self.radius, ray).into_iter().collect()
+    }
+}
+
+impl Object for MovingSphere {
+    fn surface_normal(&self, point: &Point) -> Vector3<f32> {
+        (point - self.from).normalize()
+    }
+
+    fn surface_uv(&self, point: &Point) -> Vector2<f32> {
+        sphere_uv(&self.surface_normal(point))
+    }
+
+    fn sample_surface(&self, time: f32, sampler: &mut dyn Sampler) -> Option<SurfaceSample> {
+        let normal = random_unit_vector(sampler);
+        Some(SurfaceSample {
+            point: self.center(time) + normal * self.radius,
+            normal,
+            pdf: 1.0 / (4.0 * PI * self.radius * self.radius),
+        })
+    }
+
+    fn surface_pdf(&self, _point: &Point, _normal: &Vector3<f32>, _time: f32) -> f32 {
+        1.0 / (4.0 * PI * self.radius * self.radius)
+    }
+
+    fn bounds(&self) -> Option<AABB> {
+        Some(sphere_bounds(&self.from, self.radius).join(&sphere_bounds(&self.to, self.radius)))
+    }
+
+    fn material(&self) -> &dyn Material {
+        self.material.as_ref()
+    }
+
+    fn fingerprint(&self, fingerprint: &mut Fingerprint) {
+        fingerprint.add_str("moving sphere");
+        fingerprint.add_debug(&(self.from, self.to, self.radius));
+        self.material.fingerprint(fingerprint);
+    }
+
+    fn intersection<'a>(&'a self, r: &Ray, b: &'a dyn Object) -> Option<Intersection<'a>> {
+        let distance = self.intersect(r)?;
+        let point = r.point_at(distance);
+        let normal = (point - self.center(r.time)).normalize();
+        Some(Intersection {
+            distance,
+            point,
+            object: b,
+            object_id: 0,
+            normal,
+            uv: sphere_uv(&normal),
+            time: r.time,
+        })
     }
 }
 
 pub struct Plane {
     pub origin: Point,
     pub normal: Vector3<f32>,
-    pub material: Box<dyn Material + Send + Sync>,
+    pub material: Box<dyn Material>,
 }
 
 impl Plane {
-    pub fn new(origin: Point, normal: Vector3<f32>, material: Box<dyn Material + Send + Sync>) -> Self {
+    pub fn new(origin: Point, normal: Vector3<f32>, material: Box<dyn Material>) -> Self {
         Self {
             origin,
             normal,
@@ -212,6 +354,21 @@ impl Intersectable for Plane {
         Some(t)
 
     }
+
+    /// The plane bounds the half-space behind its normal
+    fn intervals(&self, ray: &Ray) -> Vec<Interval> {
+        let denom = self.normal.dot(&ray.direction);
+        let behind = (ray.origin - self.origin).dot(&self.normal);
+        if denom == 0.0 {
+            return if behind <= 0.0 { vec![Interval::everywhere()] } else { Vec::new() };
+        }
+        let t = -behind / denom;
+        if denom > 0.0 {
+            vec![Interval::new(f32::NEG_INFINITY, t)]
+        } else {
+            vec![Interval::new(t, f32::INFINITY)]
+        }
+    }
 }
 
 impl Object for Plane {
@@ -219,8 +376,21 @@ impl Object for Plane {
         self.normal
     }
 
-    fn material(&self) -> &Box<dyn Material + Send  + Sync> {
-        &self.material
+    fn surface_uv(&self, point: &Point) -> Vector2<f32> {
+        // Unit tiles along two axes perpendicular to the normal
+        let (tangent, bitangent) = orthonormal_basis(&self.normal);
+        let offset = point - self.origin;
+        Vector2::new(offset.dot(&tangent).rem_euclid(1.0), offset.dot(&bitangent).rem_euclid(1.0))
+    }
+
+    fn material(&self) -> &dyn Material {
+        self.material.as_ref()
+    }
+
+    fn fingerprint(&self, fingerprint: &mut Fingerprint) {
+        fingerprint.add_str("plane");
+        fingerprint.add_debug(&(self.origin, self.normal));
+        self.material.fingerprint(fingerprint);
     }
 }
 
@@ -235,8 +405,9 @@ impl Triangle {
     }
 }
 
-impl Intersectable for Triangle {
-    fn intersect(&self, ray: &Ray) -> Option<f32> {
+impl Triangle {
+    /// Möller–Trumbore intersection, returning the distance and the barycentric coordinates of the hit
+    pub fn intersect_barycentric(&self, ray: &Ray) -> Option<(f32, Vector2<f32>)> {
         // https://en.wikipedia.org/wiki/M%C3%B6ller%E2%80%93Trumbore_intersection_algorithm
         let epsilon = 0.000001;
         let edge1 = self.b - self.a;
@@ -262,11 +433,25 @@ impl Intersectable for Triangle {
         let t = f * edge2.dot(&q);
 
         if t > epsilon {
-            return Some(t);
+            Some((t, Vector2::new(u, v)))
         } else {
-            return None;
+            None
         }
     }
+
+    pub fn normal(&self) -> Vector3<f32> {
+        (self.a - self.b).cross(&(self.a - self.c)).normalize()
+    }
+
+    pub fn area(&self) -> f32 {
+        (self.b - self.a).cross(&(self.c - self.a)).magnitude() * 0.5
+    }
+}
+
+impl Intersectable for Triangle {
+    fn intersect(&self, ray: &Ray) -> Option<f32> {
+        self.intersect_barycentric(ray).map(|(t, _)| t)
+    }
 }
 
 fn point_to_bvh_point(point: &Point) -> bvh::Point3 {
@@ -302,84 +487,119 @@ impl BHShape for Triangle {
 pub struct Mesh {
     triangles: Vec<Triangle>,
     aabb: Option<BVH>,
-    material: Box<dyn Material + Send + Sync>,
+    material: Box<dyn Material>,
+    /// Running sum of the triangle areas, used to sample points on the surface
+    cumulative_area: Vec<f32>,
 }
 
 impl Mesh {
-    pub fn empty(material : Box<dyn Material + Send + Sync>) -> Self {
-        Self {
-            triangles: Vec::new(),
-            aabb: None,
-            material
-        }
+    pub fn empty(material : Box<dyn Material>) -> Self {
+        Self::from_triangles(Vec::new(), material)
     }
 
-    pub fn from_triangles(triangles: Vec<Triangle>, material : Box<dyn Material + Send + Sync>) -> Self {
+    pub fn from_triangles(triangles: Vec<Triangle>, material : Box<dyn Material>) -> Self {
         Self {
+            cumulative_area: Self::cumulative_area(&triangles),
             triangles,
             aabb: None,
-            material
+            material,
         }
     }
 
+    fn cumulative_area(triangles: &[Triangle]) -> Vec<f32> {
+        triangles.iter().scan(0.0, |total, triangle| {
+            *total += triangle.area();
+            Some(*total)
+        }).collect()
+    }
+
+    pub fn area(&self) -> f32 {
+        self.cumulative_area.last().copied().unwrap_or(0.0)
+    }
+
+    /// Replaces the triangles by their Loop subdivision, to be done before building the BVH
+    pub fn subdivide(&mut self, subdivision: &Subdivision) {
+        self.triangles = subdivision.apply(&self.triangles);
+        self.cumulative_area = Self::cumulative_area(&self.triangles);
+        self.aabb = None;
+    }
+
     pub fn build_bvh(&mut self) {
         self.aabb = Some(BVH::build(&mut self.triangles));
     }
 
-    pub fn from_obj_text( text: &str, material : Box<dyn Material + Send + Sync>) -> Self {
-        let mut vertices = Vec::new();
-        let mut triangles = Vec::new();
-        for line in text.lines() {
-            let mut parts = line.split_whitespace();
-            match parts.next() {
-                Some("v") => {
-                    let x = parts.next().unwrap().parse::<f32>().unwrap();
-                    let y = parts.next().unwrap().parse::<f32>().unwrap();
-                    let z = parts.next().unwrap().parse::<f32>().unwrap();
-                    vertices.push(Point::new(x, y, z));
-                },
-                Some("f") => {
-                    // Has to accept f v/vt/vn v/vt/vn v/vt/vn
-                    // Or f v//vn v//vn v//vn
-                    // Or f v v v
-                    // Find out which one it is
-                    let has_slash = line.contains("/");
-                    let (a, b, c) = if has_slash {
-                        let mut parts = line.split_whitespace();
-                        parts.next();
-                        let a = parts.next().unwrap();
-                        let b = parts.next().unwrap();
-                        let c = parts.next().unwrap();
-                        let a = a.split("/").next().unwrap().parse::<usize>().unwrap() - 1;
-                        let b = b.split("/").next().unwrap().parse::<usize>().unwrap() - 1;
-                        let c = c.split("/").next().unwrap().parse::<usize>().unwrap() - 1;
-                        (a, b, c)
-                    } else {
-                        let mut parts = line.split_whitespace();
-                        parts.next();
-                        let a = parts.next().unwrap().parse::<usize>().unwrap() - 1;
-                        let b = parts.next().unwrap().parse::<usize>().unwrap() - 1;
-                        let c = parts.next().unwrap().parse::<usize>().unwrap() - 1;
-                        (a, b, c)
-                    };
-
-
-                    println!("{} {} {} ", a, b, c);
-                    triangles.push(Triangle::new(vertices[a], vertices[b], vertices[c]));
-                },
-                _ => {}
-            }
-        }
-        Self::from_triangles(triangles, material)
+    pub fn from_obj(path: &std::path::Path, material : Box<dyn Material>) -> anyhow::Result<Self> {
+        Ok(Self::from_triangles(read_obj(path)?, material))
     }
 
-    pub fn from_obj(path: &std::path::Path, material : Box<dyn Material + Send + Sync>) -> Self {
-        let text = std::fs::read_to_string(path).unwrap();
-        Self::from_obj_text(&text, material)
+    /// Loads the OBJ file and subdivides its faces before they're split in triangles, so meshes
+    /// with quads are smoothed by Catmull-Clark rather than by Loop
+    pub fn from_obj_subdivided(path: &std::path::Path, subdivision: &Subdivision, material : Box<dyn Material>) -> anyhow::Result<Self> {
+        Ok(Self::from_triangles(subdivision.apply_polygons(&read_obj_polygons(path)?), material))
     }
 
 }
 
+/// Triangles of the faces of an OBJ file, polygons split in fans around their first vertex
+pub fn parse_obj(text: &str) -> anyhow::Result<Vec<Triangle>> {
+    Ok(parse_obj_polygons(text)?.iter().flat_map(|corners| fan(corners)).collect())
+}
+
+/// Triangles splitting a polygon in a fan around its first corner
+pub fn fan(corners: &[Point]) -> impl Iterator<Item = Triangle> + '_ {
+    (1..corners.len().saturating_sub(1)).map(|i| Triangle::new(corners[0], corners[i], corners[i + 1]))
+}
+
+/// Corners of the faces of an OBJ file, polygons kept whole for the subdivision of quads.
+/// Faces can be given as `f v v v`, `f v/vt v/vt v/vt`, `f v//vn v//vn v//vn` or
+/// `f v/vt/vn v/vt/vn v/vt/vn`, and negative indices count back from the last vertex.
+pub fn parse_obj_polygons(text: &str) -> anyhow::Result<Vec<Vec<Point>>> {
+    let mut vertices = Vec::new();
+    let mut polygons = Vec::new();
+    for (number, line) in text.lines().enumerate() {
+        let error = |message: String| anyhow::anyhow!("line {}: {}", number + 1, message);
+        let mut parts = line.split_whitespace();
+        match parts.next() {
+            Some("v") => {
+                let mut coordinate = || -> anyhow::Result<f32> {
+                    let value = parts.next().ok_or_else(|| error("a vertex needs 3 coordinates".to_string()))?;
+                    value.parse::<f32>().map_err(|e| error(format!("invalid coordinate '{}': {}", value, e)))
+                };
+                vertices.push(Point::new(coordinate()?, coordinate()?, coordinate()?));
+            },
+            Some("f") => {
+                let corners = parts
+                    .map(|corner| {
+                        let index = corner.split('/').next().unwrap_or_default();
+                        let index = index.parse::<i64>().map_err(|e| error(format!("invalid vertex index '{}': {}", index, e)))?;
+                        let resolved = if index < 0 { vertices.len() as i64 + index } else { index - 1 };
+                        vertices
+                            .get(resolved as usize)
+                            .copied()
+                            .filter(|_| resolved >= 0)
+                            .ok_or_else(|| error(format!("vertex {} isn't defined yet", index)))
+                    })
+                    .collect::<anyhow::Result<Vec<_>>>()?;
+                if corners.len() < 3 {
+                    return Err(error(format!("a face needs at least 3 vertices, got {}", corners.len())));
+                }
+                polygons.push(corners);
+            },
+            _ => {}
+        }
+    }
+    Ok(polygons)
+}
+
+pub fn read_obj(path: &std::path::Path) -> anyhow::Result<Vec<Triangle>> {
+    Ok(read_obj_polygons(path)?.iter().flat_map(|corners| fan(corners)).collect())
+}
+
+pub fn read_obj_polygons(path: &std::path::Path) -> anyhow::Result<Vec<Vec<Point>>> {
+    let text = std::fs::read_to_string(path).with_context(|| format!("failed to read '{}'", path.display()))?;
+    parse_obj_polygons(&text).with_context(|| format!("invalid OBJ file '{}'", path.display()))
+}
+
 fn vector_into_bvh_vector(vector: &Vector3<f32>) -> bvh::Vector3 {
     bvh::Vector3::new(vector.x, vector.y, vector.z)
 }
@@ -403,26 +623,62 @@ impl From<bvh::ray::Ray> for Ray {
 }
 
 
-impl Intersectable for Mesh {
-    fn intersect(&self, ray: &Ray) -> Option<f32> {
-        if let Some(bvh) = &self.aabb {
-            let bvh_ray: bvh::ray::Ray = ray.into();
-            let hit_aabbs = bvh.traverse(&bvh_ray, &self.triangles);
-            let mut closest: Option<f32> = None;
-            for hit_aabb in hit_aabbs {
-                if let Some(distance) = hit_aabb.intersect(ray) {
-                    if closest.is_none() || distance < closest.unwrap() {
-                        closest = Some(distance);
+impl Mesh {
+    /// The closest triangle hit by the ray, with its distance and barycentric coordinates. Walks
+    /// the BVH like `BVH::traverse`, without collecting the triangles first, and counts the nodes
+    /// visited.
+    fn closest_triangle(&self, ray: &Ray) -> Option<(&Triangle, f32, Vector2<f32>)> {
+        let Some(bvh) = &self.aabb else {
+            panic!("Mesh has no BVH")
+        };
+        if bvh.nodes.is_empty() {
+            return None;
+        }
+        let bvh_ray: bvh::ray::Ray = ray.into();
+        let mut closest = None;
+        let mut visits = 0;
+        self.intersect_node(&bvh.nodes, 0, ray, &bvh_ray, &mut closest, &mut visits);
+        stats::count(Counter::BvhNodes, visits);
+        closest
+    }
+
+    fn intersect_node<'a>(
+        &'a self,
+        nodes: &[BVHNode],
+        node: usize,
+        ray: &Ray,
+        bvh_ray: &bvh::ray::Ray,
+        closest: &mut Option<(&'a Triangle, f32, Vector2<f32>)>,
+        visits: &mut u64,
+    ) {
+        *visits += 1;
+        match &nodes[node] {
+            BVHNode::Node { child_l_index, child_l_aabb, child_r_index, child_r_aabb, .. } => {
+                if bvh_ray.intersects_aabb(child_l_aabb) {
+                    self.intersect_node(nodes, *child_l_index, ray, bvh_ray, closest, visits);
+                }
+                if bvh_ray.intersects_aabb(child_r_aabb) {
+                    self.intersect_node(nodes, *child_r_index, ray, bvh_ray, closest, visits);
+                }
+            }
+            BVHNode::Leaf { shape_index, .. } => {
+                let triangle = &self.triangles[*shape_index];
+                if let Some((distance, uv)) = triangle.intersect_barycentric(ray) {
+                    if closest.is_none_or(|(_, closest, _)| distance < closest) {
+                        *closest = Some((triangle, distance, uv));
                     }
                 }
             }
-            closest
-        } else {
-            panic!("Mesh has no BVH")
         }
     }
 }
 
+impl Intersectable for Mesh {
+    fn intersect(&self, ray: &Ray) -> Option<f32> {
+        self.closest_triangle(ray).map(|(_, distance, _)| distance)
+    }
+}
+
 impl Object for Mesh {
     fn surface_normal(&self, point: &Point) -> Vector3<f32> {
         // Slow, transverse all triangles
@@ -453,39 +709,68 @@ impl Object for Mesh {
         }
     }
 
-    fn material(&self) -> &Box<dyn Material + Send + Sync> {
-       &self.material
+    fn material(&self) -> &dyn Material {
+       self.material.as_ref()
     }
 
-    fn intersection<'a>(&'a self, r: &crate::geometry::Ray, b: &'a Box<dyn Object + Send + Sync>) -> Option<crate::world::Intersection> {
-        // Fast intersection with normal
-
-        if let Some(bvh) = &self.aabb {
-            let bvh_ray: bvh::ray::Ray = r.into();
-            let hit_aabbs = bvh.traverse(&bvh_ray, &self.triangles);
-            let mut closest: Option<(&Triangle, f32)> = None;
-            for hit_aabb in hit_aabbs {
-                if let Some(distance) = hit_aabb.intersect(r) {
-                    if closest.is_none() || distance < closest.unwrap().1 {
-                        closest = Some((hit_aabb, distance));
-                    }
-                }
+    fn fingerprint(&self, fingerprint: &mut Fingerprint) {
+        fingerprint.add_str("mesh");
+        fingerprint.add(self.triangles.len() as u64);
+        for triangle in &self.triangles {
+            for vertex in [triangle.a, triangle.b, triangle.c] {
+                vertex.iter().for_each(|&value| fingerprint.add_f32(value));
             }
-            Some(
-                Intersection {
-                    distance: closest?.1,
-                    point: r.point_at(closest.unwrap().1),
-                    object: b,
-                    normal: {
-                        // Calculate the normal of the triangle
-                        let triangle = closest?.0;
-                        (triangle.a - triangle.b).cross(&(triangle.a - triangle.c)).normalize()
-                    }
-                }
-            )
-        } else {
-            panic!("Mesh has no BVH")
         }
+        self.material.fingerprint(fingerprint);
+    }
 
+    fn sample_surface(&self, _time: f32, sampler: &mut dyn Sampler) -> Option<SurfaceSample> {
+        let area = self.area();
+        if area <= 0.0 {
+            return None;
+        }
+        // Pick a triangle proportionally to its area, then a uniform point inside it
+        let target = sampler.get_1d() * area;
+        let index = self.cumulative_area.partition_point(|&a| a < target).min(self.triangles.len() - 1);
+        let triangle = &self.triangles[index];
+        let (r1, r2) = sampler.get_2d();
+        let r1 = r1.sqrt();
+        let point = triangle.a + (triangle.b - triangle.a) * (r1 * (1.0 - r2)) + (triangle.c - triangle.a) * (r1 * r2);
+        Some(SurfaceSample {
+            point,
+            normal: triangle.normal(),
+            pdf: 1.0 / area,
+        })
+    }
+
+    fn surface_pdf(&self, _point: &Point, _normal: &Vector3<f32>, _time: f32) -> f32 {
+        let area = self.area();
+        if area > 0.0 { 1.0 / area } else { 0.0 }
+    }
+
+    fn bounds(&self) -> Option<AABB> {
+        let bounds = self.triangles.iter().fold(AABB::empty(), |bounds, triangle| bounds.join_bounded(triangle));
+        (!bounds.is_empty()).then_some(bounds)
+    }
+
+    fn triangle_count(&self) -> usize {
+        self.triangles.len()
+    }
+
+    fn intersection<'a>(&'a self, r: &crate::geometry::Ray, b: &'a dyn Object) -> Option<crate::world::Intersection<'a>> {
+        // Fast intersection with normal
+        let (triangle, distance, uv) = self.closest_triangle(r)?;
+        Some(
+            Intersection {
+                distance,
+                point: r.point_at(distance),
+                object: b,
+                object_id: 0,
+                // The mesh has no texture coordinates, so use the barycentric ones
+                normal: triangle.normal(),
+                uv,
+                time: r.time,
+            }
+        )
     }
 }
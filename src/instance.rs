@@ -0,0 +1,261 @@
+use std::{borrow::Cow, sync::Arc};
+
+use bvh::aabb::AABB;
+use nalgebra::{Matrix3, Matrix4, Vector3};
+
+use crate::{
+    checkpoint::Fingerprint,
+    geometry::{Intersectable, Interval, Point, Ray},
+    material::Material,
+    motion::{self, AnimatedTransform},
+    object::{Object, SurfaceSample},
+    sampler::Sampler,
+    world::Intersection,
+};
+
+/// Matrices of an affine transform, from object space to world space and back
+#[derive(Clone)]
+struct Placement {
+    transform: Matrix4<f32>,
+    inverse: Matrix4<f32>,
+    /// Inverse transpose of the linear part, which takes normals to world space
+    normal_matrix: Matrix3<f32>,
+}
+
+impl Placement {
+    fn new(transform: Matrix4<f32>) -> Self {
+        let inverse = transform.try_inverse().expect("the transform of an instance must be invertible");
+        let normal_matrix = inverse.fixed_view::<3, 3>(0, 0).transpose();
+        Self {
+            transform,
+            inverse,
+            normal_matrix,
+        }
+    }
+
+    /// The ray in object space. Its direction isn't normalized, so distances along it are the
+    /// same as along the world space ray.
+    fn to_object(&self, ray: &Ray) -> Ray {
+        Ray::new(self.inverse.transform_point(&ray.origin), self.inverse.transform_vector(&ray.direction)).with_time(ray.time)
+    }
+
+    /// A normal of the object in world space, and how much the transform scales areas around it
+    fn normal_to_world(&self, normal: &Vector3<f32>) -> (Vector3<f32>, f32) {
+        let transformed = self.normal_matrix * normal.normalize();
+        let length = transformed.norm();
+        (transformed / length, self.transform.fixed_view::<3, 3>(0, 0).determinant().abs() * length)
+    }
+}
+
+/// A shared object placed in the world by an affine transform, so one mesh and its BVH can be
+/// drawn many times, and shapes defined in world coordinates can be rotated and scaled. An
+/// animated transform moves the object over the shutter, blurring it.
+pub struct Instance {
+    object: Arc<dyn Object>,
+    placement: Placement,
+    motion: Option<AnimatedTransform>,
+    /// Replaces the material of the object, when set
+    material: Option<Box<dyn Material>>,
+}
+
+impl Instance {
+    /// Places `object` by `transform`, e.g. `Matrix4::new_translation`, `new_scaling` or the
+    /// homogeneous matrix of a rotation. Panics if the transform can't be inverted.
+    pub fn new(object: Arc<dyn Object>, transform: Matrix4<f32>) -> Self {
+        Self {
+            object,
+            placement: Placement::new(transform),
+            motion: None,
+            material: None,
+        }
+    }
+
+    /// Moves `object` along `motion`, placing it at the time of each ray
+    pub fn animated(object: Arc<dyn Object>, motion: AnimatedTransform) -> Self {
+        Self {
+            object,
+            placement: Placement::new(motion.matrix_at(0.0)),
+            motion: Some(motion),
+            material: None,
+        }
+    }
+
+    /// Draws the instance with `material` instead of the one of the object
+    pub fn with_material(mut self, material: Box<dyn Material>) -> Self {
+        self.material = Some(material);
+        self
+    }
+
+    /// The transform of the instance, at time 0 when it moves
+    pub fn transform(&self) -> &Matrix4<f32> {
+        &self.placement.transform
+    }
+
+    pub fn motion(&self) -> Option<&AnimatedTransform> {
+        self.motion.as_ref()
+    }
+
+    fn placement_at(&self, time: f32) -> Cow<'_, Placement> {
+        match &self.motion {
+            Some(motion) => Cow::Owned(Placement::new(motion.matrix_at(time))),
+            None => Cow::Borrowed(&self.placement),
+        }
+    }
+}
+
+impl Intersectable for Instance {
+    fn intersect(&self, ray: &Ray) -> Option<f32> {
+        self.object.intersect(&self.placement_at(ray.time).to_object(ray))
+    }
+
+    fn intervals(&self, ray: &Ray) -> Vec<Interval> {
+        self.object.intervals(&self.placement_at(ray.time).to_object(ray))
+    }
+}
+
+impl Object for Instance {
+    fn surface_normal(&self, point: &Point) -> Vector3<f32> {
+        let normal = self.object.surface_normal(&self.placement.inverse.transform_point(point));
+        self.placement.normal_to_world(&normal).0
+    }
+
+    fn surface_uv(&self, point: &Point) -> nalgebra::Vector2<f32> {
+        self.object.surface_uv(&self.placement.inverse.transform_point(point))
+    }
+
+    fn material(&self) -> &dyn Material {
+        match &self.material {
+            Some(material) => material.as_ref(),
+            None => self.object.material(),
+        }
+    }
+
+    fn fingerprint(&self, fingerprint: &mut Fingerprint) {
+        fingerprint.add_str("instance");
+        fingerprint.add_debug(&(self.placement.transform, &self.motion));
+        self.object.fingerprint(fingerprint);
+        match &self.material {
+            Some(material) => material.fingerprint(fingerprint),
+            None => fingerprint.add_str("material of the object"),
+        }
+    }
+
+    fn sample_surface(&self, time: f32, sampler: &mut dyn Sampler) -> Option<SurfaceSample> {
+        let placement = self.placement_at(time);
+        let sample = self.object.sample_surface(time, sampler)?;
+        let (normal, scale) = placement.normal_to_world(&sample.normal);
+        Some(SurfaceSample {
+            point: placement.transform.transform_point(&sample.point),
+            normal,
+            pdf: sample.pdf / scale,
+        })
+    }
+
+    fn surface_pdf(&self, point: &Point, normal: &Vector3<f32>, time: f32) -> f32 {
+        let placement = self.placement_at(time);
+        // Normals go back to object space by the transpose of the linear part
+        let local_normal = (placement.transform.fixed_view::<3, 3>(0, 0).transpose() * normal).normalize();
+        let (_, scale) = placement.normal_to_world(&local_normal);
+        self.object.surface_pdf(&placement.inverse.transform_point(point), &local_normal, time) / scale
+    }
+
+    fn bounds(&self) -> Option<AABB> {
+        let bounds = self.object.bounds()?;
+        match &self.motion {
+            Some(motion) => Some(motion.bounds(&bounds)),
+            None => Some(motion::transform_bounds(&self.placement.transform, &bounds)),
+        }
+    }
+
+    fn triangle_count(&self) -> usize {
+        self.object.triangle_count()
+    }
+
+    fn intersection<'a>(&'a self, r: &Ray, b: &'a dyn Object) -> Option<Intersection<'a>> {
+        let placement = self.placement_at(r.time);
+        let mut intersection = self.object.intersection(&placement.to_object(r), b)?;
+        intersection.point = r.point_at(intersection.distance);
+        intersection.normal = placement.normal_to_world(&intersection.normal).0;
+        Some(intersection)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{f32::consts::PI, sync::Arc};
+
+    use nalgebra::{Matrix4, Rotation3, Vector3};
+
+    use super::Instance;
+    use crate::{
+        color,
+        geometry::{MovingSphere, Point, Ray, Sphere},
+        material::Diffuse,
+        motion::AnimatedTransform,
+        object::Object,
+        sampler::SamplerKind,
+    };
+
+    #[test]
+    fn transformed_sphere_matches_the_sphere_in_world_space() {
+        let unit = Arc::new(Sphere::new_with_material(0.0, 0.0, 0.0, 1.0, Box::new(Diffuse::new(color::WHITE))));
+        let placed = Sphere::new_with_material(1.0, 2.0, -5.0, 2.0, Box::new(Diffuse::new(color::WHITE)));
+        let rotation = Rotation3::from_axis_angle(&Vector3::y_axis(), 0.7).to_homogeneous();
+        let transform = Matrix4::new_translation(&Vector3::new(1.0, 2.0, -5.0)) * rotation * Matrix4::new_scaling(2.0);
+        let instance = Instance::new(unit.clone(), transform);
+        for direction in [Vector3::new(1.0, 2.0, -5.0), Vector3::new(1.3, 2.0, -5.0), Vector3::new(1.0, 2.6, -5.0)] {
+            let ray = Ray::new(Point::origin(), direction.normalize());
+            let expected = placed.intersection(&ray, &placed).unwrap();
+            let hit = instance.intersection(&ray, &instance).unwrap();
+            assert!((hit.distance - expected.distance).abs() < 1e-4);
+            assert!((hit.point - expected.point).norm() < 1e-4);
+            assert!((hit.normal - expected.normal).norm() < 1e-4);
+        }
+
+        // Squashed along y, the normals tilt towards the flat top and bottom
+        let ellipsoid = Instance::new(unit, Matrix4::new_nonuniform_scaling(&Vector3::new(1.0, 0.5, 1.0)));
+        let point = Point::new(0.6, 0.4, 0.0);
+        assert!((ellipsoid.surface_normal(&point) - Vector3::new(0.6, 1.6, 0.0).normalize()).norm() < 1e-5);
+
+        // Sampled points lie on the ellipsoid, and the densities add up to its area
+        let mut sampler = SamplerKind::Independent.build(0, 1);
+        let count = 20000;
+        let mut inverse_area = 0.0;
+        for i in 0..count {
+            sampler.start_pixel_sample(0, 0, i);
+            let sample = ellipsoid.sample_surface(0.0, sampler.as_mut()).unwrap();
+            let p = sample.point;
+            assert!((p.x * p.x + 4.0 * p.y * p.y + p.z * p.z - 1.0).abs() < 1e-4);
+            assert!((ellipsoid.surface_pdf(&p, &sample.normal, 0.0) - sample.pdf).abs() < 1e-4);
+            inverse_area += 1.0 / sample.pdf / count as f32;
+        }
+        // Area of an oblate spheroid with radii 1 and 0.5
+        let e = (1.0f32 - 0.25).sqrt();
+        let area = 2.0 * PI * (1.0 + 0.25 / e * ((1.0 + e) / (1.0 - e)).ln() / 2.0);
+        assert!((inverse_area - area).abs() / area < 0.02, "{} against {}", inverse_area, area);
+    }
+
+    #[test]
+    fn animated_instance_is_hit_where_it_is_at_the_time_of_the_ray() {
+        let unit = Arc::new(Sphere::new_with_material(0.0, 0.0, 0.0, 1.0, Box::new(Diffuse::new(color::WHITE))));
+        let moving = MovingSphere::new(Point::new(0.0, 0.0, -5.0), Point::new(0.0, 2.0, -5.0), 1.0, Box::new(Diffuse::new(color::WHITE)));
+        let motion = AnimatedTransform::linear(Vector3::new(0.0, 2.0, 0.0));
+        let instance = Instance::animated(unit, motion.clone());
+        let instance = Instance::new(Arc::new(instance), Matrix4::new_translation(&Vector3::new(0.0, 0.0, -5.0)));
+        for (time, height) in [(0.0, 0.8), (0.0, 2.5), (0.3, 0.8), (0.5, 2.5), (1.0, 0.8), (1.0, 2.5)] {
+            let ray = Ray::new(Point::origin(), Vector3::new(0.0, height, -5.0).normalize()).with_time(time);
+            let expected = moving.intersection(&ray, &moving);
+            let hit = instance.intersection(&ray, &instance);
+            assert_eq!(hit.is_some(), expected.is_some(), "at {}", time);
+            if let (Some(hit), Some(expected)) = (hit, expected) {
+                assert!((hit.distance - expected.distance).abs() < 1e-4);
+                assert!((hit.normal - expected.normal).norm() < 1e-4);
+            }
+        }
+        // The bounds hold the sphere at both ends of its motion
+        let bounds = instance.bounds().unwrap();
+        assert!(bounds.approx_contains_eps(&bvh::Point3::new(0.0, -1.0, -5.0), 1e-5));
+        assert!(bounds.approx_contains_eps(&bvh::Point3::new(0.0, 3.0, -5.0), 1e-5));
+        assert!(!bounds.contains(&bvh::Point3::new(0.0, 3.5, -5.0)));
+    }
+}
@@ -0,0 +1,39 @@
+use crate::{
+    color::ColorF32,
+    geometry::{self, Ray},
+};
+
+use super::{facing_normal, Integrator, TraceContext};
+
+/// Ambient occlusion: the fraction of the hemisphere around the first hit that is free of
+/// geometry closer than `radius`, weighted by the cosine.
+pub struct AmbientOcclusionIntegrator {
+    radius: f32,
+}
+
+impl AmbientOcclusionIntegrator {
+    pub fn new(radius: f32) -> Self {
+        Self { radius }
+    }
+}
+
+impl Integrator for AmbientOcclusionIntegrator {
+    fn li(&self, ray: &Ray, ctx: &mut TraceContext) -> ColorF32 {
+        let world = ctx.world;
+        let intersection = match world.intersect(ray) {
+            Some(intersection) => intersection,
+            None => return ColorF32::new(0.0, 0.0, 0.0),
+        };
+        let normal = facing_normal(&intersection, ray);
+        let direction = geometry::random_cosine_direction(&normal, ctx.sampler);
+        let occlusion_ray = Ray::new_with_eps(intersection.point, direction, 0.001).with_time(ray.time);
+        let occluded = world
+            .intersect(&occlusion_ray)
+            .is_some_and(|hit| hit.distance < self.radius);
+        if occluded {
+            ColorF32::new(0.0, 0.0, 0.0)
+        } else {
+            ColorF32::new(1.0, 1.0, 1.0)
+        }
+    }
+}
@@ -0,0 +1,548 @@
+use nalgebra::Vector3;
+
+use crate::{
+    camera::Camera,
+    color::ColorF32,
+    geometry::{Point, Ray},
+    sampler::Sampler,
+    world::{Intersection, World},
+};
+
+use super::{emission_pdf, pick_emitter, sample_emission, Integrator, TraceContext};
+
+// Bidirectional path tracing following Veach's thesis and the structure of pbrt-v3:
+// a camera subpath and a light subpath are generated independently, every prefix of one
+// is connected to every prefix of the other, and each connection strategy is weighted
+// with the balance heuristic over all the strategies that could have produced the path.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum VertexKind {
+    Camera,
+    /// Point sampled on an emitter to start a light subpath
+    Light,
+    Surface,
+    /// A camera subpath that left the scene and sees the sky
+    Sky,
+}
+
+#[derive(Clone)]
+struct Vertex<'a> {
+    kind: VertexKind,
+    point: Point,
+    /// Geometric normal, zero for vertices that are not on a surface
+    normal: Vector3<f32>,
+    intersection: Option<Intersection<'a>>,
+    /// Emitting object at this vertex, if any
+    emitter: Option<usize>,
+    /// Product of the path contributions divided by the densities up to this vertex
+    beta: ColorF32,
+    /// Scattered by a specular material, so it can't be connected to
+    delta: bool,
+    /// Area density of this vertex when sampled from its predecessor on the subpath
+    pdf_fwd: f32,
+    /// Area density of this vertex if the subpath had been sampled in the other direction
+    pdf_rev: f32,
+    /// Time of the path, all its vertices are at the same moment
+    time: f32,
+}
+
+impl<'a> Vertex<'a> {
+    fn camera(camera: &Camera, time: f32, beta: ColorF32) -> Self {
+        Self {
+            kind: VertexKind::Camera,
+            point: *camera.origin(),
+            normal: Vector3::zeros(),
+            intersection: None,
+            emitter: None,
+            beta,
+            delta: false,
+            pdf_fwd: 0.0,
+            pdf_rev: 0.0,
+            time,
+        }
+    }
+
+    fn light(point: Point, normal: Vector3<f32>, emitter: usize, time: f32, beta: ColorF32, pdf_fwd: f32) -> Self {
+        Self {
+            kind: VertexKind::Light,
+            point,
+            normal,
+            intersection: None,
+            emitter: Some(emitter),
+            beta,
+            delta: false,
+            pdf_fwd,
+            pdf_rev: 0.0,
+            time,
+        }
+    }
+
+    fn surface(world: &World, intersection: Intersection<'a>, beta: ColorF32) -> Self {
+        let emitter = Some(intersection.object_id).filter(|&id| world.is_emitter(id));
+        let time = intersection.time;
+        Self {
+            kind: VertexKind::Surface,
+            point: intersection.point,
+            normal: intersection.normal,
+            intersection: Some(intersection),
+            emitter,
+            beta,
+            delta: false,
+            pdf_fwd: 0.0,
+            pdf_rev: 0.0,
+            time,
+        }
+    }
+
+    fn sky(ray: &Ray, beta: ColorF32, pdf_fwd: f32) -> Self {
+        Self {
+            kind: VertexKind::Sky,
+            // Kept one unit away in the escape direction so the direction can be recovered
+            point: ray.origin + ray.direction.normalize(),
+            normal: Vector3::zeros(),
+            intersection: None,
+            emitter: None,
+            beta,
+            delta: false,
+            pdf_fwd,
+            pdf_rev: 0.0,
+            time: ray.time,
+        }
+    }
+
+    fn on_surface(&self) -> bool {
+        self.normal != Vector3::zeros()
+    }
+
+    fn is_connectible(&self) -> bool {
+        match self.kind {
+            VertexKind::Camera | VertexKind::Light => true,
+            VertexKind::Surface => !self.delta,
+            VertexKind::Sky => false,
+        }
+    }
+
+    fn is_light(&self) -> bool {
+        self.emitter.is_some() || self.kind == VertexKind::Sky
+    }
+
+    /// Radiance emitted from this vertex towards `towards`
+    fn le(&self, world: &World, towards: &Vertex) -> ColorF32 {
+        match (self.kind, self.emitter) {
+            (VertexKind::Sky, _) => {
+                let direction = self.point - towards.point;
+                world.background_color(&Ray::new(towards.point, direction))
+            }
+            (_, Some(id)) => world.objects[id].material().emissivity(),
+            _ => ColorF32::new(0.0, 0.0, 0.0),
+        }
+    }
+
+    /// BSDF for light travelling between `next` and this vertex, seen from the predecessor `prev`
+    fn f(&self, prev: &Vertex, next: &Vertex) -> ColorF32 {
+        match &self.intersection {
+            Some(intersection) => {
+                let wo = (prev.point - self.point).normalize();
+                let wi = (next.point - self.point).normalize();
+                intersection.object.material().eval(intersection, &wo, &wi)
+            }
+            None => ColorF32::new(0.0, 0.0, 0.0),
+        }
+    }
+
+    /// Converts a solid angle density at this vertex into an area density at `next`
+    fn convert_density(&self, pdf: f32, next: &Vertex) -> f32 {
+        if next.kind == VertexKind::Sky {
+            return pdf;
+        }
+        let w = next.point - self.point;
+        let inv_distance_squared = 1.0 / w.magnitude_squared();
+        let mut pdf = pdf * inv_distance_squared;
+        if next.on_surface() {
+            pdf *= next.normal.dot(&(w * inv_distance_squared.sqrt())).abs();
+        }
+        pdf
+    }
+
+    /// Area density of sampling `next` from this vertex, having arrived from `prev`
+    fn pdf(&self, world: &World, camera: &Camera, prev: Option<&Vertex>, next: &Vertex) -> f32 {
+        match self.kind {
+            VertexKind::Light => self.pdf_light(world, next),
+            VertexKind::Camera => self.convert_density(camera.pdf_direction(&(next.point - self.point)), next),
+            VertexKind::Sky => 0.0,
+            VertexKind::Surface => {
+                let (intersection, prev) = match (&self.intersection, prev) {
+                    (Some(intersection), Some(prev)) => (intersection, prev),
+                    _ => return 0.0,
+                };
+                let wo = (prev.point - self.point).normalize();
+                let wi = (next.point - self.point).normalize();
+                let pdf = intersection.object.material().pdf(intersection, &wo, &wi);
+                self.convert_density(pdf, next)
+            }
+        }
+    }
+
+    /// Area density at `next` of an emitted ray leaving this light vertex
+    fn pdf_light(&self, _world: &World, next: &Vertex) -> f32 {
+        if self.emitter.is_none() {
+            return 0.0;
+        }
+        let w = (next.point - self.point).normalize();
+        let pdf_direction = emission_pdf(self.normal.dot(&w).abs());
+        self.convert_density(pdf_direction, next)
+    }
+
+    /// Area density of picking this point on the emitters when starting a light subpath
+    fn pdf_light_origin(&self, world: &World) -> f32 {
+        match self.emitter {
+            Some(id) => world.objects[id].surface_pdf(&self.point, &self.normal, self.time) / world.emitters().len() as f32,
+            None => 0.0,
+        }
+    }
+}
+
+fn remap0(pdf: f32) -> f32 {
+    if pdf != 0.0 { pdf } else { 1.0 }
+}
+
+fn is_black(color: &ColorF32) -> bool {
+    color.r == 0.0 && color.g == 0.0 && color.b == 0.0
+}
+
+/// Bidirectional path tracer, good at caustics and light coming through small openings
+pub struct BdptIntegrator {
+    max_depth: u16,
+}
+
+impl BdptIntegrator {
+    pub fn new(max_depth: u16) -> Self {
+        Self { max_depth }
+    }
+
+    /// Extends a subpath from its last vertex, recording the forward and reverse densities
+    #[allow(clippy::too_many_arguments)]
+    fn random_walk<'a>(world: &'a World, sampler: &mut dyn Sampler, ray: &Ray, beta: ColorF32, pdf: f32, max_vertices: usize, radiance: bool, path: &mut Vec<Vertex<'a>>) {
+        if max_vertices == 0 {
+            return;
+        }
+        let mut ray = *ray;
+        let mut beta = beta;
+        let mut pdf_fwd = pdf;
+        let mut vertices = 0;
+        loop {
+            let intersection = match world.intersect(&ray) {
+                Some(intersection) => intersection,
+                None => {
+                    // Only camera subpaths can see the sky, it can't be sampled as a light
+                    if radiance {
+                        path.push(Vertex::sky(&ray, beta, pdf_fwd));
+                    }
+                    break;
+                }
+            };
+            let prev = path.len() - 1;
+            let mut vertex = Vertex::surface(world, intersection, beta);
+            vertex.pdf_fwd = path[prev].convert_density(pdf_fwd, &vertex);
+            path.push(vertex);
+            vertices += 1;
+            if vertices >= max_vertices {
+                break;
+            }
+
+            let current = path.len() - 1;
+            let intersection = path[current].intersection.clone().unwrap();
+            let material = intersection.object.material();
+            let scatter = match material.scatter(&ray, &intersection, sampler) {
+                Some(scatter) => scatter,
+                None => break,
+            };
+            let wo = -ray.direction.normalize();
+            let wi = scatter.ray.direction.normalize();
+            let pdf_rev = if material.is_specular() {
+                path[current].delta = true;
+                pdf_fwd = 0.0;
+                0.0
+            } else {
+                pdf_fwd = material.pdf(&intersection, &wo, &wi);
+                material.pdf(&intersection, &wi, &wo)
+            };
+            if pdf_fwd == 0.0 && !path[current].delta {
+                break;
+            }
+            beta = beta * scatter.attenuation;
+            path[prev].pdf_rev = path[current].convert_density(pdf_rev, &path[prev]);
+            ray = scatter.ray;
+        }
+    }
+
+    fn camera_subpath<'a>(&self, ray: &Ray, world: &'a World, camera: &Camera, sampler: &mut dyn Sampler) -> Vec<Vertex<'a>> {
+        let max_vertices = self.max_depth as usize + 2;
+        let mut path = Vec::with_capacity(max_vertices);
+        path.push(Vertex::camera(camera, ray.time, ColorF32::new(1.0, 1.0, 1.0)));
+        let pdf = camera.pdf_direction(&ray.direction);
+        Self::random_walk(world, sampler, ray, ColorF32::new(1.0, 1.0, 1.0), pdf, max_vertices - 1, true, &mut path);
+        path
+    }
+
+    fn light_subpath<'a>(&self, world: &'a World, time: f32, sampler: &mut dyn Sampler) -> Vec<Vertex<'a>> {
+        let max_vertices = self.max_depth as usize + 1;
+        let mut path = Vec::with_capacity(max_vertices);
+        let emission = match sample_emission(world, time, sampler) {
+            Some(emission) => emission,
+            None => return path,
+        };
+        path.push(Vertex::light(emission.point, emission.normal, emission.emitter, time, emission.le, emission.pdf_position));
+        Self::random_walk(world, sampler, &emission.ray, emission.flux(), emission.pdf_direction, max_vertices - 1, false, &mut path);
+        path
+    }
+
+    /// Contribution of the path made of `s` light and `t` camera vertices, already MIS weighted.
+    /// Strategies with a single camera vertex land on another pixel and are splatted. `camera`
+    /// is where the camera is at the time of the path.
+    fn connect(&self, ctx: &mut TraceContext, camera: &Camera, light: &[Vertex], camera_path: &[Vertex], s: usize, t: usize) -> ColorF32 {
+        let world = ctx.world;
+        let time = camera_path[0].time;
+        let black = ColorF32::new(0.0, 0.0, 0.0);
+        if t > 1 && s != 0 && camera_path[t - 1].kind == VertexKind::Sky {
+            return black;
+        }
+
+        let mut sampled = None;
+        let mut splat_at = None;
+        let contribution = if s == 0 {
+            // The camera subpath found a light by itself
+            let pt = &camera_path[t - 1];
+            if pt.is_light() { pt.le(world, &camera_path[t - 2]) * pt.beta } else { black }
+        } else if t == 1 {
+            // Light tracing: connect the light subpath to the camera
+            let qs = &light[s - 1];
+            if !qs.is_connectible() {
+                return black;
+            }
+            let (film_x, film_y) = match camera.project(&qs.point) {
+                Some(position) => position,
+                None => return black,
+            };
+            let to_camera = camera.origin() - qs.point;
+            let distance_squared = to_camera.magnitude_squared();
+            let wi = to_camera / distance_squared.sqrt();
+            let importance = camera.importance(&-wi);
+            let cos_camera = camera.basis().2.dot(&-wi);
+            if importance == 0.0 || cos_camera <= 0.0 {
+                return black;
+            }
+            // Solid angle density of sampling the pinhole from `qs`
+            let pdf = distance_squared / cos_camera;
+            let vertex = Vertex::camera(camera, time, ColorF32::new(1.0, 1.0, 1.0) * (importance / pdf));
+            let mut l = qs.beta * qs.f(&light[s - 2], &vertex) * vertex.beta;
+            if qs.on_surface() {
+                l = l * qs.normal.dot(&wi).abs();
+            }
+            if is_black(&l) || world.occluded(&qs.point, camera.origin(), time) {
+                return black;
+            }
+            splat_at = Some((film_x, film_y));
+            sampled = Some(vertex);
+            l
+        } else if s == 1 {
+            // Next event estimation: connect the camera subpath to a fresh point on a light
+            let pt = &camera_path[t - 1];
+            if !pt.is_connectible() {
+                return black;
+            }
+            let (emitter, light_pdf) = match pick_emitter(world, ctx.sampler) {
+                Some(pick) => pick,
+                None => return black,
+            };
+            let sample = match world.objects[emitter].sample_surface(time, ctx.sampler) {
+                Some(sample) => sample,
+                None => return black,
+            };
+            let offset = sample.point - pt.point;
+            let distance_squared = offset.magnitude_squared();
+            let wi = offset / distance_squared.sqrt();
+            let cos_light = sample.normal.dot(&wi).abs();
+            if cos_light <= 0.0 || sample.pdf <= 0.0 {
+                return black;
+            }
+            let pdf = sample.pdf * distance_squared / cos_light;
+            let le = world.objects[emitter].material().emissivity();
+            let mut vertex = Vertex::light(sample.point, sample.normal, emitter, time, le / (pdf * light_pdf), 0.0);
+            vertex.pdf_fwd = vertex.pdf_light_origin(world);
+            let mut l = pt.beta * pt.f(&camera_path[t - 2], &vertex) * vertex.beta;
+            if pt.on_surface() {
+                l = l * pt.normal.dot(&wi).abs();
+            }
+            if is_black(&l) || world.occluded(&pt.point, &sample.point, time) {
+                return black;
+            }
+            sampled = Some(vertex);
+            l
+        } else {
+            let qs = &light[s - 1];
+            let pt = &camera_path[t - 1];
+            if !qs.is_connectible() || !pt.is_connectible() {
+                return black;
+            }
+            let l = qs.beta * qs.f(&light[s - 2], pt) * pt.f(&camera_path[t - 2], qs) * pt.beta;
+            if is_black(&l) {
+                return black;
+            }
+            let offset = qs.point - pt.point;
+            let distance_squared = offset.magnitude_squared();
+            let w = offset / distance_squared.sqrt();
+            let mut g = 1.0 / distance_squared;
+            if qs.on_surface() {
+                g *= qs.normal.dot(&w).abs();
+            }
+            if pt.on_surface() {
+                g *= pt.normal.dot(&w).abs();
+            }
+            if g == 0.0 || world.occluded(&pt.point, &qs.point, time) {
+                return black;
+            }
+            l * g
+        };
+
+        if is_black(&contribution) {
+            return black;
+        }
+        let weighted = contribution * Self::mis_weight(world, camera, light, camera_path, sampled, s, t);
+        match splat_at {
+            Some((film_x, film_y)) => {
+                ctx.splat(film_x, film_y, weighted);
+                black
+            }
+            None => weighted,
+        }
+    }
+
+    /// Balance heuristic weight of the strategy (s, t) among all the ways to sample the same path
+    fn mis_weight(world: &World, camera: &Camera, light: &[Vertex], camera_path: &[Vertex], sampled: Option<Vertex>, s: usize, t: usize) -> f32 {
+        if s + t == 2 {
+            return 1.0;
+        }
+        // The sky can only be found by the camera subpath
+        if s == 0 && camera_path[t - 1].kind == VertexKind::Sky {
+            return 1.0;
+        }
+
+        let mut light = light[..s].to_vec();
+        let mut camera_path = camera_path[..t].to_vec();
+        if let Some(sampled) = sampled {
+            if s == 1 {
+                light[0] = sampled;
+            } else if t == 1 {
+                camera_path[0] = sampled;
+            }
+        }
+        // The connection endpoints are never specular
+        camera_path[t - 1].delta = false;
+        if s > 0 {
+            light[s - 1].delta = false;
+        }
+
+        // Densities of the connection endpoints and their predecessors in the reverse direction
+        let pt_pdf_rev = if s > 0 {
+            let prev = if s > 1 { Some(&light[s - 2]) } else { None };
+            light[s - 1].pdf(world, camera, prev, &camera_path[t - 1])
+        } else {
+            camera_path[t - 1].pdf_light_origin(world)
+        };
+        let pt_minus_pdf_rev = if t > 1 {
+            Some(if s > 0 {
+                camera_path[t - 1].pdf(world, camera, Some(&light[s - 1]), &camera_path[t - 2])
+            } else {
+                camera_path[t - 1].pdf_light(world, &camera_path[t - 2])
+            })
+        } else {
+            None
+        };
+        let qs_pdf_rev = if s > 0 {
+            let prev = if t > 1 { Some(&camera_path[t - 2]) } else { None };
+            Some(camera_path[t - 1].pdf(world, camera, prev, &light[s - 1]))
+        } else {
+            None
+        };
+        let qs_minus_pdf_rev = if s > 1 {
+            Some(light[s - 1].pdf(world, camera, Some(&camera_path[t - 1]), &light[s - 2]))
+        } else {
+            None
+        };
+        camera_path[t - 1].pdf_rev = pt_pdf_rev;
+        if let Some(pdf) = pt_minus_pdf_rev {
+            camera_path[t - 2].pdf_rev = pdf;
+        }
+        if let Some(pdf) = qs_pdf_rev {
+            light[s - 1].pdf_rev = pdf;
+        }
+        if let Some(pdf) = qs_minus_pdf_rev {
+            light[s - 2].pdf_rev = pdf;
+        }
+
+        let mut sum_ri = 0.0;
+        let mut ri = 1.0;
+        for i in (1..t).rev() {
+            ri *= remap0(camera_path[i].pdf_rev) / remap0(camera_path[i].pdf_fwd);
+            if !camera_path[i].delta && !camera_path[i - 1].delta {
+                sum_ri += ri;
+            }
+        }
+        let mut ri = 1.0;
+        for i in (0..s).rev() {
+            ri *= remap0(light[i].pdf_rev) / remap0(light[i].pdf_fwd);
+            // Area lights are never delta distributions
+            let delta_light_vertex = if i > 0 { light[i - 1].delta } else { false };
+            if !light[i].delta && !delta_light_vertex {
+                sum_ri += ri;
+            }
+        }
+        1.0 / (1.0 + sum_ri)
+    }
+}
+
+impl Integrator for BdptIntegrator {
+    fn li(&self, ray: &Ray, ctx: &mut TraceContext) -> ColorF32 {
+        let world = ctx.world;
+        let camera = ctx.camera.at_time(ray.time);
+        let camera_path = self.camera_subpath(ray, world, &camera, ctx.sampler);
+        let light_path = self.light_subpath(world, ray.time, ctx.sampler);
+
+        let mut radiance = ColorF32::new(0.0, 0.0, 0.0);
+        for t in 1..=camera_path.len() {
+            for s in 0..=light_path.len() {
+                let depth = t as i32 + s as i32 - 2;
+                if (s == 1 && t == 1) || depth < 0 || depth > self.max_depth as i32 {
+                    continue;
+                }
+                radiance += self.connect(ctx, &camera, &light_path, &camera_path, s, t);
+            }
+        }
+        radiance
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use nalgebra::Vector3;
+
+    use crate::{
+        camera::Camera,
+        geometry::Point,
+        integrator::tests::{closed_room, mean, path_traced_image, traced_image},
+    };
+
+    use super::BdptIntegrator;
+
+    #[test]
+    fn converges_to_the_path_tracer() {
+        let world = closed_room();
+        let camera = Camera::new(Point::new(0.0, 0.0, 2.0), Vector3::new(0.0, 0.0, -1.0), 60.0, 8, 6);
+        let path = mean(&path_traced_image(&world, &camera, 2048));
+        let bdpt = mean(&traced_image(&BdptIntegrator::new(16), &world, &camera, 2048));
+        for (p, b) in [(path.r, bdpt.r), (path.g, bdpt.g), (path.b, bdpt.b)] {
+            assert!((p - b).abs() / p < 0.05, "path {:?} bdpt {:?}", path, bdpt);
+        }
+    }
+}
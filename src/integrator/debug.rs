@@ -0,0 +1,149 @@
+use std::{fmt, str::FromStr};
+
+use crate::{
+    color::{self, ColorF32},
+    geometry::Ray,
+    sampler::Sampler,
+    world::World,
+};
+
+use super::{Integrator, TraceContext};
+
+/// What the debug integrator shows for the first hit of each camera ray
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DebugView {
+    Normals,
+    Depth,
+    Albedo,
+    Uv,
+    ObjectId,
+    /// Number of bounces until the path leaves the scene or is absorbed
+    Bounces,
+}
+
+impl DebugView {
+    const ALL: [DebugView; 6] = [
+        DebugView::Normals,
+        DebugView::Depth,
+        DebugView::Albedo,
+        DebugView::Uv,
+        DebugView::ObjectId,
+        DebugView::Bounces,
+    ];
+
+    pub fn next(&self) -> Option<Self> {
+        let index = Self::ALL.iter().position(|view| view == self)?;
+        Self::ALL.get(index + 1).copied()
+    }
+
+    /// Names of the views, in the order the viewer cycles through them
+    pub(crate) fn names() -> Vec<&'static str> {
+        Self::ALL.iter().map(|view| view.name()).collect()
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            Self::Normals => "normals",
+            Self::Depth => "depth",
+            Self::Albedo => "albedo",
+            Self::Uv => "uv",
+            Self::ObjectId => "object-id",
+            Self::Bounces => "bounces",
+        }
+    }
+}
+
+impl fmt::Display for DebugView {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for DebugView {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::ALL
+            .iter()
+            .find(|view| view.name() == s)
+            .copied()
+            .ok_or_else(|| anyhow::anyhow!("unknown debug view '{}', expected one of {}", s, Self::names().join(", ")))
+    }
+}
+
+/// Shows geometric information instead of light transport
+pub struct DebugIntegrator {
+    view: DebugView,
+}
+
+impl DebugIntegrator {
+    /// Distance at which the depth view reaches half brightness
+    const DEPTH_SCALE: f32 = 10.0;
+    /// Bounce count shown in full red
+    const MAX_BOUNCES: u16 = 16;
+
+    pub fn new(view: DebugView) -> Self {
+        Self { view }
+    }
+
+    fn count_bounces(ray: &Ray, world: &World, sampler: &mut dyn Sampler) -> u16 {
+        let mut bounces = 0;
+        let mut ray = *ray;
+        while bounces < Self::MAX_BOUNCES {
+            let intersection = match world.intersect(&ray) {
+                Some(intersection) => intersection,
+                None => break,
+            };
+            match intersection.object.material().scatter(&ray, &intersection, sampler) {
+                Some(scatter) => ray = scatter.ray,
+                None => break,
+            }
+            bounces += 1;
+        }
+        bounces
+    }
+}
+
+/// A distinct, stable color for each object id
+fn id_color(id: usize) -> ColorF32 {
+    // Golden ratio steps spread consecutive ids around the hue circle
+    let hue = (id as f32 * 0.618_034).fract() * 6.0;
+    let x = 1.0 - (hue % 2.0 - 1.0).abs();
+    let (r, g, b) = match hue as u32 {
+        0 => (1.0, x, 0.0),
+        1 => (x, 1.0, 0.0),
+        2 => (0.0, 1.0, x),
+        3 => (0.0, x, 1.0),
+        4 => (x, 0.0, 1.0),
+        _ => (1.0, 0.0, x),
+    };
+    ColorF32::new(r, g, b)
+}
+
+impl Integrator for DebugIntegrator {
+    fn li(&self, ray: &Ray, ctx: &mut TraceContext) -> ColorF32 {
+        let world = ctx.world;
+        if self.view == DebugView::Bounces {
+            let bounces = Self::count_bounces(ray, world, ctx.sampler);
+            return ColorF32::lerp(color::BLUE, color::RED, bounces as f32 / Self::MAX_BOUNCES as f32);
+        }
+        let intersection = match world.intersect(ray) {
+            Some(intersection) => intersection,
+            None => return ColorF32::new(0.0, 0.0, 0.0),
+        };
+        match self.view {
+            DebugView::Normals => {
+                let n = intersection.normal * 0.5 + nalgebra::Vector3::new(0.5, 0.5, 0.5);
+                ColorF32::new(n.x, n.y, n.z)
+            }
+            DebugView::Depth => {
+                let depth = Self::DEPTH_SCALE / (intersection.distance * ray.direction.magnitude() + Self::DEPTH_SCALE);
+                ColorF32::new(depth, depth, depth)
+            }
+            DebugView::Albedo => intersection.object.material().color(),
+            DebugView::Uv => ColorF32::new(intersection.uv.x, intersection.uv.y, 0.0),
+            DebugView::ObjectId => id_color(intersection.object_id),
+            DebugView::Bounces => unreachable!(),
+        }
+    }
+}
@@ -0,0 +1,65 @@
+use crate::{
+    color::ColorF32,
+    geometry::Ray,
+    sampler::Sampler,
+    world::World,
+};
+
+use super::{sample_emitter, Integrator, TraceContext};
+
+/// Only computes light that reaches a surface straight from the emitters or the sky,
+/// following mirror and glass bounces but ignoring indirect diffuse light.
+pub struct DirectLightingIntegrator {
+    max_specular_depth: u16,
+}
+
+impl DirectLightingIntegrator {
+    const MAX_SPECULAR_DEPTH: u16 = 8;
+
+    pub fn new() -> Self {
+        Self {
+            max_specular_depth: Self::MAX_SPECULAR_DEPTH,
+        }
+    }
+
+    fn trace(&self, ray: &Ray, world: &World, sampler: &mut dyn Sampler, depth: u16) -> ColorF32 {
+        let intersection = match world.intersect(ray) {
+            Some(intersection) => intersection,
+            None => return world.background_color(ray),
+        };
+        let material = intersection.object.material();
+        let emitted = material.emissivity();
+
+        if material.is_specular() {
+            if depth >= self.max_specular_depth {
+                return emitted;
+            }
+            return match material.scatter(ray, &intersection, sampler) {
+                Some(scatter) => emitted + self.trace(&scatter.ray, world, sampler, depth + 1) * scatter.attenuation,
+                None => emitted,
+            };
+        }
+
+        let wo = -ray.direction.normalize();
+        let mut radiance = emitted + sample_emitter(world, &intersection, &wo, sampler);
+        // The sky isn't an object, so it is reached by sampling the material instead
+        if let Some(scatter) = material.scatter(ray, &intersection, sampler) {
+            if world.intersect(&scatter.ray).is_none() {
+                radiance += world.background_color(&scatter.ray) * scatter.attenuation;
+            }
+        }
+        radiance
+    }
+}
+
+impl Default for DirectLightingIntegrator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Integrator for DirectLightingIntegrator {
+    fn li(&self, ray: &Ray, ctx: &mut TraceContext) -> ColorF32 {
+        self.trace(ray, ctx.world, ctx.sampler, 0)
+    }
+}
@@ -0,0 +1,397 @@
+use std::{f32::consts::PI, fmt, str::FromStr};
+
+use rand::{Rng, SeedableRng};
+use rand_pcg::Pcg64;
+use rayon::prelude::{IntoParallelIterator, IntoParallelRefMutIterator, ParallelIterator};
+
+use crate::{
+    camera::Camera,
+    color::ColorF32,
+    geometry::Ray,
+    sampler::{self, Sampler},
+    world::World,
+};
+
+use super::{AtomicColor, Integrator, PathIntegrator, PathSettings, TraceContext};
+
+/// Parameters of the Metropolis integrator
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MltSettings {
+    /// Paths traced up front to estimate the brightness of the image and seed the chains
+    pub bootstrap_samples: u32,
+    pub chains: u32,
+    /// Probability of replacing every primary sample instead of perturbing them
+    pub large_step_probability: f32,
+    /// Standard deviation of the small step perturbation
+    pub sigma: f32,
+}
+
+impl Default for MltSettings {
+    fn default() -> Self {
+        Self {
+            bootstrap_samples: 100_000,
+            chains: 1000,
+            large_step_probability: 0.3,
+            sigma: 0.01,
+        }
+    }
+}
+
+impl fmt::Display for MltSettings {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "bootstrap={},chains={},large={},sigma={}",
+            self.bootstrap_samples, self.chains, self.large_step_probability, self.sigma
+        )
+    }
+}
+
+impl FromStr for MltSettings {
+    type Err = anyhow::Error;
+
+    /// Parses a comma separated list of `key=value` overrides, e.g. `chains=100,large=0.5`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut settings = Self::default();
+        for option in s.split(',').filter(|option| !option.is_empty()) {
+            let (key, value) = option
+                .split_once('=')
+                .ok_or_else(|| anyhow::anyhow!("expected key=value in mlt options, got '{}'", option))?;
+            let invalid = |e: &dyn fmt::Display| anyhow::anyhow!("invalid value for mlt option '{}': {}", key, e);
+            match key {
+                "bootstrap" => settings.bootstrap_samples = value.parse().map_err(|e| invalid(&e))?,
+                "chains" => settings.chains = value.parse().map_err(|e| invalid(&e))?,
+                "large" => {
+                    settings.large_step_probability = value.parse().map_err(|e| invalid(&e))?;
+                    if !(0.0..=1.0).contains(&settings.large_step_probability) {
+                        return Err(invalid(&"the probability must be between 0 and 1"));
+                    }
+                }
+                "sigma" => {
+                    settings.sigma = value.parse().map_err(|e| invalid(&e))?;
+                    if settings.sigma <= 0.0 {
+                        return Err(invalid(&"sigma must be positive"));
+                    }
+                }
+                _ => anyhow::bail!("unknown mlt option '{}', expected bootstrap, chains, large or sigma", key),
+            }
+        }
+        if settings.bootstrap_samples == 0 || settings.chains == 0 {
+            anyhow::bail!("mlt needs at least one bootstrap sample and one chain");
+        }
+        Ok(settings)
+    }
+}
+
+/// One coordinate of the primary sample space, with the state to undo a rejected mutation
+#[derive(Clone, Copy, Default)]
+struct PrimarySample {
+    value: f32,
+    /// Iteration in which `value` was last changed
+    last_modified: u64,
+    backup: f32,
+    backup_modified: u64,
+}
+
+/// Sampler that hands out the coordinates of a point in primary sample space and mutates that
+/// point between iterations. Coordinates are only mutated once they are asked for, so paths
+/// of any length can be explored.
+struct MltSampler {
+    rng: Pcg64,
+    sigma: f32,
+    large_step_probability: f32,
+    samples: Vec<PrimarySample>,
+    iteration: u64,
+    large_step: bool,
+    last_large_step: u64,
+    index: usize,
+}
+
+impl MltSampler {
+    fn new(seed: u64, settings: &MltSettings) -> Self {
+        Self {
+            rng: Pcg64::seed_from_u64(seed),
+            sigma: settings.sigma,
+            large_step_probability: settings.large_step_probability,
+            samples: Vec::new(),
+            iteration: 0,
+            // The first path is drawn from scratch
+            large_step: true,
+            last_large_step: 0,
+            index: 0,
+        }
+    }
+
+    /// Switches the mutations to another random stream, keeping the current point
+    fn reseed(&mut self, seed: u64) {
+        self.rng = Pcg64::seed_from_u64(seed);
+    }
+
+    /// Random number that isn't part of the primary sample, for the chain's own decisions
+    fn uniform(&mut self) -> f32 {
+        self.rng.gen()
+    }
+
+    fn start_iteration(&mut self) {
+        self.iteration += 1;
+        self.large_step = self.uniform() < self.large_step_probability;
+        self.index = 0;
+    }
+
+    fn accept(&mut self) {
+        if self.large_step {
+            self.last_large_step = self.iteration;
+        }
+    }
+
+    fn reject(&mut self) {
+        let iteration = self.iteration;
+        for sample in self.samples.iter_mut().filter(|sample| sample.last_modified == iteration) {
+            sample.value = sample.backup;
+            sample.last_modified = sample.backup_modified;
+        }
+        self.iteration -= 1;
+    }
+
+    /// Brings a coordinate up to date with the mutations of the iterations it missed
+    fn ensure_ready(&mut self, index: usize) {
+        if index >= self.samples.len() {
+            self.samples.resize(index + 1, PrimarySample::default());
+        }
+        let mut sample = self.samples[index];
+        // A large step happened since it was last used, so its value is a fresh one
+        if sample.last_modified < self.last_large_step {
+            sample.value = self.rng.gen();
+            sample.last_modified = self.last_large_step;
+        }
+        sample.backup = sample.value;
+        sample.backup_modified = sample.last_modified;
+        if self.large_step {
+            sample.value = self.rng.gen();
+        } else {
+            // The small steps it missed add up to a wider gaussian
+            let steps = (self.iteration - sample.last_modified) as f32;
+            let sigma = self.sigma * steps.sqrt();
+            sample.value += self.normal() * sigma;
+            sample.value -= sample.value.floor();
+        }
+        sample.last_modified = self.iteration;
+        self.samples[index] = sample;
+    }
+
+    /// Standard normal number, with the Box-Muller transform
+    fn normal(&mut self) -> f32 {
+        let u1 = 1.0 - self.rng.gen::<f32>();
+        let u2: f32 = self.rng.gen();
+        (-2.0 * u1.ln()).sqrt() * (2.0 * PI * u2).cos()
+    }
+}
+
+impl Sampler for MltSampler {
+    fn start_pixel_sample(&mut self, _x: u32, _y: u32, _index: u64) {
+        // The position on the film is one of the primary samples, not something the caller picks
+    }
+
+    fn get_1d(&mut self) -> f32 {
+        let index = self.index;
+        self.index += 1;
+        self.ensure_ready(index);
+        // Wrapping can round up to exactly one
+        self.samples[index].value.min(1.0 - f32::EPSILON)
+    }
+}
+
+/// A path through the film, with the scalar contribution the chains distribute samples by
+#[derive(Clone, Copy)]
+struct PathSample {
+    film_x: f32,
+    film_y: f32,
+    color: ColorF32,
+    contribution: f32,
+}
+
+struct Chain {
+    sampler: MltSampler,
+    current: PathSample,
+}
+
+/// Primary sample space Metropolis light transport (Kelemen et al.). Paths are generated by the
+/// path tracer from a vector of uniform numbers, and Markov chains explore that space with
+/// small perturbations and occasional large steps, so once a chain finds a bright but hard to
+/// reach path it keeps sampling around it. Both the proposed and the current path are splatted,
+/// weighted by the acceptance probability.
+pub struct MltIntegrator {
+    settings: MltSettings,
+    path: PathIntegrator,
+    /// Average contribution of a path, estimated by the bootstrap, which scales the chains'
+    /// relative estimate to absolute radiance
+    normalization: f32,
+    chains: Vec<Chain>,
+    /// Sum of the splats of all the mutations so far
+    film: Vec<ColorF32>,
+    mutations: u64,
+}
+
+impl MltIntegrator {
+    /// Streams at or above this one are for the chains' mutations, below it for the bootstrap paths
+    const CHAIN_STREAMS: u64 = 1 << 63;
+
+    pub fn new(settings: MltSettings) -> Self {
+        Self {
+            settings,
+            path: PathIntegrator::new(PathSettings::default()),
+            normalization: 0.0,
+            chains: Vec::new(),
+            film: Vec::new(),
+            mutations: 0,
+        }
+    }
+
+    /// Maps the next primary samples to a point on the film and traces the path through it
+    fn sample_path(path: &PathIntegrator, world: &World, camera: &Camera, sampler: &mut MltSampler) -> PathSample {
+        let (u, v) = sampler.get_pixel_2d();
+        let (film_x, film_y) = (u * camera.width() as f32, v * camera.height() as f32);
+        let ray = camera.generate_ray(film_x, film_y, camera.sample_time(sampler.get_1d()));
+        let mut ctx = TraceContext::new(world, camera, sampler);
+        let color = path.li(&ray, &mut ctx);
+        let contribution = color.luminance();
+        PathSample {
+            film_x,
+            film_y,
+            color,
+            contribution: if contribution.is_finite() { contribution.max(0.0) } else { 0.0 },
+        }
+    }
+
+    /// Estimates the normalization and starts every chain on a bootstrap path picked
+    /// proportionally to its contribution
+    fn bootstrap(&mut self, world: &World, camera: &Camera, seed: u64) {
+        let settings = self.settings;
+        let path = &self.path;
+        let contributions = (0..settings.bootstrap_samples as u64)
+            .into_par_iter()
+            .map(|index| Self::sample_path(path, world, camera, &mut MltSampler::new(sampler::hash(seed, index), &settings)).contribution)
+            .collect::<Vec<_>>();
+        let cumulative = contributions
+            .iter()
+            .scan(0.0f64, |sum, &contribution| {
+                *sum += contribution as f64;
+                Some(*sum)
+            })
+            .collect::<Vec<_>>();
+        let total = cumulative.last().copied().unwrap_or(0.0);
+        self.normalization = (total / settings.bootstrap_samples as f64) as f32;
+        if total <= 0.0 {
+            return;
+        }
+
+        let chains = (0..settings.chains as u64)
+            .into_par_iter()
+            .map(|chain| {
+                let chain_seed = sampler::hash(seed, Self::CHAIN_STREAMS | chain);
+                let target = Pcg64::seed_from_u64(chain_seed).gen::<f64>() * total;
+                let index = cumulative.partition_point(|&sum| sum <= target).min(cumulative.len() - 1);
+                // Replaying the bootstrap seed reproduces the same path, then the chain gets its
+                // own stream so chains starting from the same path don't stay identical
+                let mut sampler = MltSampler::new(sampler::hash(seed, index as u64), &settings);
+                let current = Self::sample_path(path, world, camera, &mut sampler);
+                sampler.reseed(chain_seed);
+                Chain { sampler, current }
+            })
+            .collect();
+        self.chains = chains;
+    }
+}
+
+impl Integrator for MltIntegrator {
+    fn li(&self, ray: &Ray, ctx: &mut TraceContext) -> ColorF32 {
+        // A single ray can't run a chain, fall back to the path tracer generating the paths
+        self.path.li(ray, ctx)
+    }
+
+    fn render_pass(&mut self, world: &World, camera: &Camera, samples_per_pixel: u32, seed: u64) -> Option<Vec<ColorF32>> {
+        let (width, height) = (camera.width(), camera.height());
+        let pixel_count = (width * height) as usize;
+        if self.film.len() != pixel_count {
+            self.reset();
+            self.film = vec![ColorF32::new(0.0, 0.0, 0.0); pixel_count];
+            self.bootstrap(world, camera, seed);
+        }
+        if self.chains.is_empty() {
+            // Nothing in the scene gives off light
+            return Some(self.film.clone());
+        }
+
+        let mutations_per_chain = (pixel_count as u64 * samples_per_pixel as u64).div_ceil(self.chains.len() as u64);
+        let film = (0..pixel_count).map(|_| AtomicColor::default()).collect::<Vec<_>>();
+        let splat = |sample: &PathSample, weight: f32| {
+            if weight > 0.0 {
+                let x = (sample.film_x as u32).min(width - 1);
+                let y = (sample.film_y as u32).min(height - 1);
+                film[(y * width + x) as usize].add(sample.color * (weight / sample.contribution));
+            }
+        };
+        let path = &self.path;
+        self.chains.par_iter_mut().for_each(|chain| {
+            for _ in 0..mutations_per_chain {
+                chain.sampler.start_iteration();
+                let proposal = Self::sample_path(path, world, camera, &mut chain.sampler);
+                let accept = (proposal.contribution / chain.current.contribution).min(1.0);
+                // Expected value splatting: both paths get the share of the sample they'd get on average
+                splat(&proposal, accept);
+                splat(&chain.current, 1.0 - accept);
+                if chain.sampler.uniform() < accept {
+                    chain.current = proposal;
+                    chain.sampler.accept();
+                } else {
+                    chain.sampler.reject();
+                }
+            }
+        });
+        self.mutations += mutations_per_chain * self.chains.len() as u64;
+        for (sum, pass) in self.film.iter_mut().zip(&film) {
+            *sum += pass.load();
+        }
+
+        let scale = self.normalization * pixel_count as f32 / self.mutations as f32;
+        Some(self.film.iter().map(|&color| color * scale).collect())
+    }
+
+    fn reset(&mut self) {
+        self.normalization = 0.0;
+        self.chains.clear();
+        self.film.clear();
+        self.mutations = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use nalgebra::Vector3;
+
+    use crate::{
+        camera::Camera,
+        geometry::Point,
+        integrator::{
+            tests::{closed_room, path_traced_image},
+            Integrator,
+        },
+    };
+
+    use super::{MltIntegrator, MltSettings};
+
+    #[test]
+    fn converges_to_the_path_tracer() {
+        let world = closed_room();
+        let camera = Camera::new(Point::new(0.0, 0.0, 2.0), Vector3::new(0.0, 0.0, -1.0), 60.0, 2, 2);
+        let expected = path_traced_image(&world, &camera, 40_000);
+
+        let mut mlt = MltIntegrator::new(MltSettings { bootstrap_samples: 100_000, chains: 512, ..MltSettings::default() });
+        let image = mlt.render_pass(&world, &camera, 40_000, 0).unwrap();
+
+        for (expected, actual) in expected.iter().zip(&image) {
+            let (e, a) = (expected.luminance(), actual.luminance());
+            assert!((e - a).abs() / e < 0.1, "path {:?} mlt {:?}", expected, actual);
+        }
+    }
+}
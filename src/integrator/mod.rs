@@ -0,0 +1,412 @@
+use std::{
+    f32::consts::PI,
+    fmt,
+    str::FromStr,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use nalgebra::Vector3;
+
+use crate::{
+    camera::Camera,
+    color::ColorF32,
+    geometry::{self, Point, Ray},
+    sampler::Sampler,
+    world::{Intersection, World},
+};
+
+pub mod ao;
+pub mod bdpt;
+pub mod debug;
+pub mod direct;
+pub mod mlt;
+pub mod path;
+pub mod sppm;
+
+pub use ao::AmbientOcclusionIntegrator;
+pub use bdpt::BdptIntegrator;
+pub use debug::{DebugIntegrator, DebugView};
+pub use direct::DirectLightingIntegrator;
+pub use mlt::{MltIntegrator, MltSettings};
+pub use path::{PathIntegrator, PathSettings};
+pub use sppm::{SppmIntegrator, SppmSettings};
+
+/// Light transport algorithm used by the `Pathtracer` to compute the color of a camera ray.
+pub trait Integrator: Send + Sync {
+    /// Estimates the radiance arriving at the camera along `ray`
+    fn li(&self, ray: &Ray, ctx: &mut TraceContext) -> ColorF32;
+
+    /// Renders a whole pass for integrators whose estimate isn't an average of independent
+    /// camera samples, like photon mapping. Returns the current estimate of every pixel, row by
+    /// row, or `None` to let the `Pathtracer` sample each pixel with `li`. All the randomness
+    /// must derive from `seed`, so a pass is the same whatever the number of threads.
+    fn render_pass(&mut self, _world: &World, _camera: &Camera, _samples_per_pixel: u32, _seed: u64) -> Option<Vec<ColorF32>> {
+        None
+    }
+
+    /// Drops the state kept between passes, e.g. when the camera moves
+    fn reset(&mut self) {}
+}
+
+/// Contribution of a sample to an arbitrary pixel of the film, instead of the one being traced
+#[derive(Debug, Clone, Copy)]
+pub struct Splat {
+    pub x: u32,
+    pub y: u32,
+    pub color: ColorF32,
+}
+
+/// Where the light of a contribution was emitted, for the light group AOVs
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LightSource {
+    Background,
+    /// Index of the emissive object in `World::objects`
+    Emitter(usize),
+}
+
+/// Part of the radiance of a camera sample, for the lighting AOVs
+#[derive(Debug, Clone, Copy)]
+pub struct LightContribution {
+    /// Bounces between the camera and the light: 0 when the camera sees it, 1 for direct light
+    pub bounce: u16,
+    pub source: LightSource,
+    pub color: ColorF32,
+}
+
+/// What an integrator gets to trace one camera sample
+pub struct TraceContext<'a> {
+    pub world: &'a World,
+    pub camera: &'a Camera,
+    /// Source of every random decision taken for this sample
+    pub sampler: &'a mut dyn Sampler,
+    /// Splats are summed over all samples and divided by the samples per pixel, like the pixels
+    pub splats: Vec<Splat>,
+    /// Split of the radiance of the sample by bounce and light, when the film asks for it.
+    /// Only the path tracer fills it in, the other integrators leave the lighting AOVs black.
+    pub lighting: Option<Vec<LightContribution>>,
+}
+
+impl<'a> TraceContext<'a> {
+    pub fn new(world: &'a World, camera: &'a Camera, sampler: &'a mut dyn Sampler) -> Self {
+        Self {
+            world,
+            camera,
+            sampler,
+            splats: Vec::new(),
+            lighting: None,
+        }
+    }
+
+    /// Records that `color`, part of the returned radiance, was emitted by `source`
+    pub fn record_light(&mut self, bounce: u16, source: LightSource, color: ColorF32) {
+        if let Some(lighting) = &mut self.lighting {
+            if color.max_component() > 0.0 {
+                lighting.push(LightContribution { bounce, source, color });
+            }
+        }
+    }
+
+    pub fn splat(&mut self, film_x: f32, film_y: f32, color: ColorF32) {
+        self.splats.push(Splat {
+            x: film_x as u32,
+            y: film_y as u32,
+            color,
+        });
+    }
+}
+
+/// A color that can be added to from several threads. It is kept in fixed point, as integer
+/// additions give the same sum in any order, so the result doesn't depend on the scheduling.
+#[derive(Default)]
+pub(crate) struct AtomicColor([AtomicU64; 3]);
+
+impl AtomicColor {
+    /// Fixed point scale, a resolution of about 6e-8 leaves room for sums up to 1e12
+    const SCALE: f64 = (1u64 << 24) as f64;
+
+    /// Adds a non negative color
+    pub fn add(&self, color: ColorF32) {
+        for (channel, value) in self.0.iter().zip([color.r, color.g, color.b]) {
+            channel.fetch_add((value as f64 * Self::SCALE).round() as u64, Ordering::Relaxed);
+        }
+    }
+
+    pub fn load(&self) -> ColorF32 {
+        let [r, g, b] = self.0.each_ref().map(|channel| (channel.load(Ordering::Relaxed) as f64 / Self::SCALE) as f32);
+        ColorF32::new(r, g, b)
+    }
+}
+
+/// The integrators that can be picked from the command line or the viewer
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum IntegratorKind {
+    Path(PathSettings),
+    Direct,
+    AmbientOcclusion { radius: f32 },
+    Bidirectional { max_depth: u16 },
+    PhotonMapping(SppmSettings),
+    Metropolis(MltSettings),
+    Debug(DebugView),
+}
+
+impl IntegratorKind {
+    const DEFAULT_AO_RADIUS: f32 = 1.0;
+    const DEFAULT_BDPT_DEPTH: u16 = 8;
+
+    pub fn build(&self) -> Box<dyn Integrator> {
+        match *self {
+            Self::Path(settings) => Box::new(PathIntegrator::new(settings)),
+            Self::Direct => Box::new(DirectLightingIntegrator::new()),
+            Self::AmbientOcclusion { radius } => Box::new(AmbientOcclusionIntegrator::new(radius)),
+            Self::Bidirectional { max_depth } => Box::new(BdptIntegrator::new(max_depth)),
+            Self::PhotonMapping(settings) => Box::new(SppmIntegrator::new(settings)),
+            Self::Metropolis(settings) => Box::new(MltIntegrator::new(settings)),
+            Self::Debug(view) => Box::new(DebugIntegrator::new(view)),
+        }
+    }
+
+    /// Whether the integrator renders whole passes with its own running estimate, instead of
+    /// sampling each pixel with `li`
+    pub fn keeps_own_estimate(&self) -> bool {
+        matches!(self, Self::PhotonMapping(_) | Self::Metropolis(_))
+    }
+
+    /// The integrator after this one, used to cycle through them in the viewer
+    pub fn next(&self) -> Self {
+        match *self {
+            Self::Path(_) => Self::Bidirectional { max_depth: Self::DEFAULT_BDPT_DEPTH },
+            Self::Bidirectional { .. } => Self::PhotonMapping(SppmSettings::default()),
+            Self::PhotonMapping(_) => Self::Metropolis(MltSettings::default()),
+            Self::Metropolis(_) => Self::Direct,
+            Self::Direct => Self::AmbientOcclusion { radius: Self::DEFAULT_AO_RADIUS },
+            Self::AmbientOcclusion { .. } => Self::Debug(DebugView::Normals),
+            Self::Debug(view) => match view.next() {
+                Some(view) => Self::Debug(view),
+                None => Self::default(),
+            },
+        }
+    }
+}
+
+impl Default for IntegratorKind {
+    fn default() -> Self {
+        Self::Path(PathSettings::default())
+    }
+}
+
+impl fmt::Display for IntegratorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Path(settings) if *settings == PathSettings::default() => write!(f, "path"),
+            Self::Path(settings) => write!(f, "path:{}", settings),
+            Self::Direct => write!(f, "direct"),
+            Self::AmbientOcclusion { radius } => write!(f, "ao:{}", radius),
+            Self::Bidirectional { max_depth } => write!(f, "bdpt:{}", max_depth),
+            Self::PhotonMapping(settings) if *settings == SppmSettings::default() => write!(f, "sppm"),
+            Self::PhotonMapping(settings) => write!(f, "sppm:{}", settings),
+            Self::Metropolis(settings) if *settings == MltSettings::default() => write!(f, "mlt"),
+            Self::Metropolis(settings) => write!(f, "mlt:{}", settings),
+            Self::Debug(view) => write!(f, "{}", view),
+        }
+    }
+}
+
+impl FromStr for IntegratorKind {
+    type Err = anyhow::Error;
+
+    /// Parses `path`, `path:<options>`, `bdpt`, `bdpt:<max depth>`, `sppm`, `sppm:<options>`,
+    /// `mlt`, `mlt:<options>`, `direct`, `ao`, `ao:<radius>` or the name of a debug view
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "path" => Ok(Self::default()),
+            "bdpt" => Ok(Self::Bidirectional { max_depth: Self::DEFAULT_BDPT_DEPTH }),
+            "sppm" => Ok(Self::PhotonMapping(SppmSettings::default())),
+            "mlt" => Ok(Self::Metropolis(MltSettings::default())),
+            "direct" => Ok(Self::Direct),
+            "ao" => Ok(Self::AmbientOcclusion { radius: Self::DEFAULT_AO_RADIUS }),
+            _ => {
+                if let Some(options) = s.strip_prefix("path:") {
+                    options.parse::<PathSettings>().map(Self::Path)
+                } else if let Some(options) = s.strip_prefix("sppm:") {
+                    options.parse::<SppmSettings>().map(Self::PhotonMapping)
+                } else if let Some(options) = s.strip_prefix("mlt:") {
+                    options.parse::<MltSettings>().map(Self::Metropolis)
+                } else if let Some(depth) = s.strip_prefix("bdpt:") {
+                    let max_depth = depth.parse::<u16>()
+                        .map_err(|e| anyhow::anyhow!("invalid bidirectional path tracer depth '{}': {}", depth, e))?;
+                    Ok(Self::Bidirectional { max_depth })
+                } else if let Some(radius) = s.strip_prefix("ao:") {
+                    let radius = radius.parse::<f32>()
+                        .map_err(|e| anyhow::anyhow!("invalid ambient occlusion radius '{}': {}", radius, e))?;
+                    if radius <= 0.0 {
+                        anyhow::bail!("ambient occlusion radius must be positive, got {}", radius);
+                    }
+                    Ok(Self::AmbientOcclusion { radius })
+                } else {
+                    s.parse::<DebugView>().map(Self::Debug).map_err(|_| {
+                        anyhow::anyhow!(
+                            "unknown integrator '{}', expected path, path:<options>, bdpt, bdpt:<max depth>, sppm, sppm:<options>, mlt, mlt:<options>, direct, ao, ao:<radius> or one of {}",
+                            s,
+                            DebugView::names().join(", ")
+                        )
+                    })
+                }
+            }
+        }
+    }
+}
+
+/// Normal of the intersection flipped to the side the ray came from
+pub(crate) fn facing_normal(intersection: &Intersection, ray: &Ray) -> Vector3<f32> {
+    if intersection.normal.dot(&ray.direction) > 0.0 {
+        -intersection.normal
+    } else {
+        intersection.normal
+    }
+}
+
+/// Picks one of the emitters uniformly, returning its object index and the probability of the pick
+pub(crate) fn pick_emitter(world: &World, sampler: &mut dyn Sampler) -> Option<(usize, f32)> {
+    let emitters = world.emitters();
+    if emitters.is_empty() {
+        return None;
+    }
+    let choice = ((sampler.get_1d() * emitters.len() as f32) as usize).min(emitters.len() - 1);
+    Some((emitters[choice], 1.0 / emitters.len() as f32))
+}
+
+/// A ray leaving a point sampled on one of the emitters
+pub(crate) struct Emission {
+    pub ray: Ray,
+    pub point: Point,
+    pub normal: Vector3<f32>,
+    pub emitter: usize,
+    /// Emitted radiance
+    pub le: ColorF32,
+    /// Area density of the point, including the choice of the emitter
+    pub pdf_position: f32,
+    /// Solid angle density of the direction
+    pub pdf_direction: f32,
+    /// Cosine between the direction and the normal
+    pub cos: f32,
+}
+
+impl Emission {
+    /// Radiance carried by the ray divided by the densities that produced it
+    pub fn flux(&self) -> ColorF32 {
+        self.le * (self.cos / (self.pdf_position * self.pdf_direction))
+    }
+}
+
+/// Emitters are two sided and emit with a cosine distribution on each side
+pub(crate) fn emission_pdf(cos: f32) -> f32 {
+    0.5 * cos / PI
+}
+
+/// Starts a light path at `time`: picks an emitter, a point on it and a direction to leave in
+pub(crate) fn sample_emission(world: &World, time: f32, sampler: &mut dyn Sampler) -> Option<Emission> {
+    let (emitter, pick_pdf) = pick_emitter(world, sampler)?;
+    let object = &world.objects[emitter];
+    let sample = object.sample_surface(time, sampler)?;
+    // Pick a side, then a cosine weighted direction on it
+    let side = if sampler.get_1d() < 0.5 { sample.normal } else { -sample.normal };
+    let direction = geometry::random_cosine_direction(&side, sampler);
+    let cos = side.dot(&direction);
+    if cos <= 0.0 || sample.pdf <= 0.0 {
+        return None;
+    }
+    Some(Emission {
+        ray: Ray::new_with_eps(sample.point, direction, 0.001).with_time(time),
+        point: sample.point,
+        normal: sample.normal,
+        emitter,
+        le: object.material().emissivity(),
+        pdf_position: sample.pdf * pick_pdf,
+        pdf_direction: emission_pdf(cos),
+        cos,
+    })
+}
+
+/// Estimates the light arriving at `intersection` directly from one of the emitters,
+/// picked uniformly, by sampling a point on its surface.
+pub(crate) fn sample_emitter(world: &World, intersection: &Intersection, wo: &Vector3<f32>, sampler: &mut dyn Sampler) -> ColorF32 {
+    let (id, pick_pdf) = match pick_emitter(world, sampler) {
+        Some(pick) => pick,
+        None => return ColorF32::new(0.0, 0.0, 0.0),
+    };
+    let emitter = &world.objects[id];
+    let sample = match emitter.sample_surface(intersection.time, sampler) {
+        Some(sample) => sample,
+        None => return ColorF32::new(0.0, 0.0, 0.0),
+    };
+
+    let offset = sample.point - intersection.point;
+    let distance_squared = offset.magnitude_squared();
+    let wi = offset / distance_squared.sqrt();
+    let cos_light = sample.normal.dot(&wi).abs();
+    if cos_light <= 0.0 || world.occluded(&intersection.point, &sample.point, intersection.time) {
+        return ColorF32::new(0.0, 0.0, 0.0);
+    }
+    // Only the light on the side of the surface the path arrived from
+    let cos_surface = intersection.normal.dot(&wi);
+    if cos_surface * intersection.normal.dot(wo) <= 0.0 {
+        return ColorF32::new(0.0, 0.0, 0.0);
+    }
+    let f = intersection.object.material().eval(intersection, wo, &wi);
+    // Convert the area density to solid angle and account for the emitter choice
+    let pdf = sample.pdf * distance_squared / cos_light * pick_pdf;
+    emitter.material().emissivity() * f * (cos_surface.abs() / pdf)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        camera::Camera,
+        color::{self, ColorF32},
+        geometry::Sphere,
+        material::{Diffuse, Emmisive},
+        sampler::IndependentSampler,
+        world::World,
+    };
+
+    use super::{Integrator, PathIntegrator, PathSettings, TraceContext};
+
+    /// A closed room lit by a small emitter behind a camera at (0, 0, 2) looking down -z, so the
+    /// sky adds nothing and all of the image is indirect light
+    pub(super) fn closed_room() -> World {
+        let mut world = World::new();
+        world.add_object(Box::new(Sphere::new_with_material(0.0, 0.0, 0.0, 6.0, Box::new(Diffuse::new(color::GRAY)))));
+        world.add_object(Box::new(Sphere::new_with_material(0.0, -1.0, -3.0, 1.0, Box::new(Diffuse::new(color::WHITE)))));
+        world.add_object(Box::new(Sphere::new_with_material(1.5, 2.0, 4.0, 1.0, Box::new(Emmisive::new(color::WHITE, 1.0)))));
+        world
+    }
+
+    /// Average color of each pixel over `samples` rays traced by `integrator`, splats included
+    pub(super) fn traced_image(integrator: &dyn Integrator, world: &World, camera: &Camera, samples: u32) -> Vec<ColorF32> {
+        let mut sampler = IndependentSampler::new(7);
+        let mut ctx = TraceContext::new(world, camera, &mut sampler);
+        let mut image = vec![ColorF32::new(0.0, 0.0, 0.0); (camera.width() * camera.height()) as usize];
+        for (index, pixel) in image.iter_mut().enumerate() {
+            let (x, y) = (index as u32 % camera.width(), index as u32 / camera.width());
+            for _ in 0..samples {
+                let (dx, dy) = ctx.sampler.get_pixel_2d();
+                let ray = camera.generate_ray(x as f32 + dx, y as f32 + dy, 0.0);
+                *pixel += integrator.li(&ray, &mut ctx);
+            }
+        }
+        for splat in &ctx.splats {
+            image[(splat.y * camera.width() + splat.x) as usize] += splat.color;
+        }
+        image.into_iter().map(|color| color / samples as f32).collect()
+    }
+
+    /// The image of the path tracer, the reference the other integrators converge to
+    pub(super) fn path_traced_image(world: &World, camera: &Camera, samples: u32) -> Vec<ColorF32> {
+        let path = PathIntegrator::new(PathSettings { max_diffuse_depth: 16, ..PathSettings::default() });
+        traced_image(&path, world, camera, samples)
+    }
+
+    pub(super) fn mean(image: &[ColorF32]) -> ColorF32 {
+        image.iter().fold(ColorF32::new(0.0, 0.0, 0.0), |sum, &color| sum + color) / image.len() as f32
+    }
+}
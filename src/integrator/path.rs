@@ -0,0 +1,135 @@
+use std::{fmt, str::FromStr};
+
+use crate::{
+    color::ColorF32,
+    geometry::Ray,
+    material::ScatterKind,
+};
+
+use super::{Integrator, LightSource, TraceContext};
+
+/// Bounce limits of the path tracer. Each kind of bounce has its own limit so glass and
+/// mirrors can be followed much further than diffuse interreflections.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PathSettings {
+    /// Number of bounces before Russian roulette may terminate the path
+    pub rr_depth: u16,
+    pub max_diffuse_depth: u16,
+    pub max_specular_depth: u16,
+    pub max_transmission_depth: u16,
+}
+
+impl Default for PathSettings {
+    fn default() -> Self {
+        Self {
+            rr_depth: 3,
+            max_diffuse_depth: 8,
+            max_specular_depth: 32,
+            max_transmission_depth: 32,
+        }
+    }
+}
+
+impl fmt::Display for PathSettings {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "rr={},diffuse={},specular={},transmission={}",
+            self.rr_depth, self.max_diffuse_depth, self.max_specular_depth, self.max_transmission_depth
+        )
+    }
+}
+
+impl FromStr for PathSettings {
+    type Err = anyhow::Error;
+
+    /// Parses a comma separated list of `key=value` overrides, e.g. `rr=5,diffuse=4`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut settings = Self::default();
+        for option in s.split(',').filter(|option| !option.is_empty()) {
+            let (key, value) = option
+                .split_once('=')
+                .ok_or_else(|| anyhow::anyhow!("expected key=value in path options, got '{}'", option))?;
+            let value = value
+                .parse::<u16>()
+                .map_err(|e| anyhow::anyhow!("invalid value for path option '{}': {}", key, e))?;
+            match key {
+                "rr" => settings.rr_depth = value,
+                "diffuse" => settings.max_diffuse_depth = value,
+                "specular" => settings.max_specular_depth = value,
+                "transmission" => settings.max_transmission_depth = value,
+                _ => anyhow::bail!(
+                    "unknown path option '{}', expected rr, diffuse, specular or transmission",
+                    key
+                ),
+            }
+        }
+        Ok(settings)
+    }
+}
+
+/// Unidirectional path tracer following the directions picked by `Material::scatter`
+pub struct PathIntegrator {
+    settings: PathSettings,
+}
+
+impl PathIntegrator {
+    /// Highest survival probability of Russian roulette, so bright paths still end eventually
+    const MAX_SURVIVAL: f32 = 0.95;
+
+    pub fn new(settings: PathSettings) -> Self {
+        Self { settings }
+    }
+}
+
+impl Integrator for PathIntegrator {
+    fn li(&self, ray: &Ray, ctx: &mut TraceContext) -> ColorF32 {
+        let world = ctx.world;
+        let mut radiance = ColorF32::new(0.0, 0.0, 0.0);
+        let mut throughput = ColorF32::new(1.0, 1.0, 1.0);
+        let mut ray = *ray;
+        let (mut diffuse, mut specular, mut transmission) = (0, 0, 0);
+
+        for bounce in 0.. {
+            let intersection = match world.intersect(&ray) {
+                Some(intersection) => intersection,
+                None => {
+                    let background = throughput * world.background_color(&ray);
+                    radiance += background;
+                    ctx.record_light(bounce, LightSource::Background, background);
+                    break;
+                }
+            };
+            let material = intersection.object.material();
+            let emitted = throughput * material.emissivity();
+            radiance += emitted;
+            ctx.record_light(bounce, LightSource::Emitter(intersection.object_id), emitted);
+
+            let scatter = match material.scatter(&ray, &intersection, ctx.sampler) {
+                Some(scatter) => scatter,
+                None => break,
+            };
+            // Once a limit is hit the path is absorbed: nothing is added for the missing bounces
+            let (depth, max_depth) = match scatter.kind {
+                ScatterKind::Diffuse => (&mut diffuse, self.settings.max_diffuse_depth),
+                ScatterKind::Specular => (&mut specular, self.settings.max_specular_depth),
+                ScatterKind::Transmission => (&mut transmission, self.settings.max_transmission_depth),
+            };
+            if *depth >= max_depth {
+                break;
+            }
+            *depth += 1;
+            throughput = throughput * scatter.attenuation;
+
+            if bounce >= self.settings.rr_depth {
+                let survival = throughput.max_component().min(Self::MAX_SURVIVAL);
+                if survival <= 0.0 || ctx.sampler.get_1d() >= survival {
+                    break;
+                }
+                throughput = throughput / survival;
+            }
+            ray = scatter.ray;
+        }
+        radiance
+    }
+}
@@ -0,0 +1,375 @@
+use std::{
+    collections::HashMap,
+    f32::consts::PI,
+    fmt,
+    str::FromStr,
+    sync::atomic::{AtomicU32, Ordering},
+};
+
+use nalgebra::Vector3;
+use rayon::prelude::{IntoParallelIterator, ParallelIterator};
+
+use crate::{
+    camera::Camera,
+    color::ColorF32,
+    geometry::{Point, Ray},
+    sampler::{IndependentSampler, Sampler},
+    world::{Intersection, World},
+};
+
+use super::{sample_emission, sample_emitter, AtomicColor, Integrator, TraceContext};
+
+/// Parameters of the photon mapping integrator
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SppmSettings {
+    /// Gather radius of every pixel in the first pass, it shrinks from there
+    pub initial_radius: f32,
+    /// Photons shot for each sample per pixel, a pass of the `Pathtracer` runs one iteration
+    /// per sample so this is kept low enough for a pass to stay short
+    pub photons_per_iteration: u32,
+    pub max_depth: u16,
+}
+
+impl Default for SppmSettings {
+    fn default() -> Self {
+        Self {
+            initial_radius: 0.25,
+            photons_per_iteration: 4_000,
+            max_depth: 16,
+        }
+    }
+}
+
+impl fmt::Display for SppmSettings {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "radius={},photons={},depth={}",
+            self.initial_radius, self.photons_per_iteration, self.max_depth
+        )
+    }
+}
+
+impl FromStr for SppmSettings {
+    type Err = anyhow::Error;
+
+    /// Parses a comma separated list of `key=value` overrides, e.g. `radius=0.1,photons=2000`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut settings = Self::default();
+        for option in s.split(',').filter(|option| !option.is_empty()) {
+            let (key, value) = option
+                .split_once('=')
+                .ok_or_else(|| anyhow::anyhow!("expected key=value in sppm options, got '{}'", option))?;
+            let invalid = |e: &dyn fmt::Display| anyhow::anyhow!("invalid value for sppm option '{}': {}", key, e);
+            match key {
+                "radius" => {
+                    settings.initial_radius = value.parse().map_err(|e| invalid(&e))?;
+                    if settings.initial_radius <= 0.0 {
+                        return Err(invalid(&"the radius must be positive"));
+                    }
+                }
+                "photons" => settings.photons_per_iteration = value.parse().map_err(|e| invalid(&e))?,
+                "depth" => settings.max_depth = value.parse().map_err(|e| invalid(&e))?,
+                _ => anyhow::bail!("unknown sppm option '{}', expected radius, photons or depth", key),
+            }
+        }
+        Ok(settings)
+    }
+}
+
+/// Statistics of one pixel that carry over from one iteration to the next
+#[derive(Clone)]
+struct SppmPixel {
+    radius: f32,
+    /// Sum over the iterations of the light reaching the camera without photons
+    direct: ColorF32,
+    /// Number of photons accumulated so far, after the radius reduction
+    photons: f32,
+    /// Flux of the accumulated photons
+    tau: ColorF32,
+}
+
+/// First non specular surface seen through a pixel, where the photons are gathered
+struct VisiblePoint<'a> {
+    intersection: Intersection<'a>,
+    wo: Vector3<f32>,
+    beta: ColorF32,
+    radius: f32,
+}
+
+/// Uniform grid over the visible points, hashed so only occupied cells take memory
+struct HashGrid {
+    min: Point,
+    cell_size: f32,
+    cells: HashMap<(i32, i32, i32), Vec<usize>>,
+}
+
+impl HashGrid {
+    fn build(points: &[Option<VisiblePoint>]) -> Option<Self> {
+        let mut min = Point::new(f32::MAX, f32::MAX, f32::MAX);
+        let mut max_radius: f32 = 0.0;
+        for vp in points.iter().flatten() {
+            min = min.inf(&(vp.intersection.point - Vector3::repeat(vp.radius)));
+            max_radius = max_radius.max(vp.radius);
+        }
+        if max_radius == 0.0 {
+            return None;
+        }
+        let mut grid = Self {
+            min,
+            cell_size: 2.0 * max_radius,
+            cells: HashMap::new(),
+        };
+        for (index, vp) in points.iter().enumerate() {
+            if let Some(vp) = vp {
+                let lower = grid.cell(&(vp.intersection.point - Vector3::repeat(vp.radius)));
+                let upper = grid.cell(&(vp.intersection.point + Vector3::repeat(vp.radius)));
+                for x in lower.0..=upper.0 {
+                    for y in lower.1..=upper.1 {
+                        for z in lower.2..=upper.2 {
+                            grid.cells.entry((x, y, z)).or_default().push(index);
+                        }
+                    }
+                }
+            }
+        }
+        Some(grid)
+    }
+
+    fn cell(&self, point: &Point) -> (i32, i32, i32) {
+        let offset = (point - self.min) / self.cell_size;
+        (offset.x.floor() as i32, offset.y.floor() as i32, offset.z.floor() as i32)
+    }
+
+    fn query(&self, point: &Point) -> &[usize] {
+        self.cells.get(&self.cell(point)).map_or(&[], |cell| cell.as_slice())
+    }
+}
+
+/// Stochastic progressive photon mapping (Hachisuka and Jensen). Each iteration finds the first
+/// non specular surface behind every pixel, shoots photons from the emitters and gathers them
+/// around those points with a radius that shrinks from one iteration to the next. The estimate
+/// is consistent, which makes caustics seen through glass converge.
+///
+/// Only the emitters shoot photons; light from the sky is added where it reaches a visible point
+/// directly, but its indirect bounces are not captured.
+pub struct SppmIntegrator {
+    settings: SppmSettings,
+    pixels: Vec<SppmPixel>,
+    iterations: u32,
+}
+
+impl SppmIntegrator {
+    /// Fraction of the new photons kept at every radius reduction
+    const ALPHA: f32 = 2.0 / 3.0;
+    /// Sampler streams at or above this one are for photons, below it for pixels
+    const PHOTON_STREAMS: u64 = 1 << 63;
+
+    pub fn new(settings: SppmSettings) -> Self {
+        Self {
+            settings,
+            pixels: Vec::new(),
+            iterations: 0,
+        }
+    }
+
+    /// Follows the camera ray through specular surfaces up to the first diffuse one, collecting
+    /// the light that reaches the camera directly along the way
+    fn visible_point<'a>(&self, world: &'a World, sampler: &mut dyn Sampler, ray: &Ray, radius: f32) -> (ColorF32, Option<VisiblePoint<'a>>) {
+        let mut direct = ColorF32::new(0.0, 0.0, 0.0);
+        let mut beta = ColorF32::new(1.0, 1.0, 1.0);
+        let mut ray = *ray;
+        for _ in 0..self.settings.max_depth {
+            let intersection = match world.intersect(&ray) {
+                Some(intersection) => intersection,
+                None => {
+                    direct += beta * world.background_color(&ray);
+                    break;
+                }
+            };
+            let material = intersection.object.material();
+            direct += beta * material.emissivity();
+            let wo = -ray.direction.normalize();
+            if !material.is_specular() {
+                direct += beta * sample_emitter(world, &intersection, &wo, sampler);
+                // The sky isn't an emitter, so it only reaches the point directly
+                if let Some(scatter) = material.scatter(&ray, &intersection, sampler) {
+                    if world.intersect(&scatter.ray).is_none() {
+                        direct += beta * scatter.attenuation * world.background_color(&scatter.ray);
+                    }
+                }
+                return (direct, Some(VisiblePoint { intersection, wo, beta, radius }));
+            }
+            match material.scatter(&ray, &intersection, sampler) {
+                Some(scatter) => {
+                    beta = beta * scatter.attenuation;
+                    ray = scatter.ray;
+                }
+                None => break,
+            }
+        }
+        (direct, None)
+    }
+
+    /// Traces one photon at `time` and adds its flux to the visible points around every diffuse
+    /// hit. With motion blur the visible points were found at other times, so the indirect
+    /// light of moving objects is blurred over the shutter too.
+    #[allow(clippy::too_many_arguments)]
+    fn trace_photon(&self, world: &World, time: f32, sampler: &mut dyn Sampler, grid: &HashGrid, points: &[Option<VisiblePoint>], flux: &[AtomicColor], counts: &[AtomicU32]) {
+        let emission = match sample_emission(world, time, sampler) {
+            Some(emission) => emission,
+            None => return,
+        };
+        let mut beta = emission.flux();
+        let mut ray = emission.ray;
+        for depth in 0..self.settings.max_depth {
+            let intersection = match world.intersect(&ray) {
+                Some(intersection) => intersection,
+                None => break,
+            };
+            let material = intersection.object.material();
+            // Photons reaching a surface straight from the emitter are already in the direct light
+            if depth > 0 && !material.is_specular() {
+                let wi = -ray.direction.normalize();
+                for &index in grid.query(&intersection.point) {
+                    let vp = match &points[index] {
+                        Some(vp) => vp,
+                        None => continue,
+                    };
+                    if (vp.intersection.point - intersection.point).magnitude_squared() > vp.radius * vp.radius {
+                        continue;
+                    }
+                    let f = vp.intersection.object.material().eval(&vp.intersection, &vp.wo, &wi);
+                    flux[index].add(vp.beta * f * beta);
+                    counts[index].fetch_add(1, Ordering::Relaxed);
+                }
+            }
+            let scatter = match material.scatter(&ray, &intersection, sampler) {
+                Some(scatter) => scatter,
+                None => break,
+            };
+            let new_beta = beta * scatter.attenuation;
+            // Russian roulette keeps the photon power roughly constant
+            let survival = (new_beta.max_component() / beta.max_component()).min(1.0);
+            if survival.is_nan() || survival <= 0.0 || sampler.get_1d() >= survival {
+                break;
+            }
+            beta = new_beta / survival;
+            ray = scatter.ray;
+        }
+    }
+
+    fn iterate(&mut self, world: &World, camera: &Camera, seed: u64) {
+        let width = camera.width();
+        let settings = self.settings;
+        let this = &*self;
+        let (direct, points): (Vec<_>, Vec<_>) = (0..this.pixels.len())
+            .into_par_iter()
+            .map(|index| {
+                let (x, y) = (index as u32 % width, index as u32 / width);
+                let mut sampler = IndependentSampler::new(seed);
+                sampler.start_pixel_sample(x, y, this.iterations as u64);
+                let (dx, dy) = sampler.get_pixel_2d();
+                let time = camera.sample_time(sampler.get_1d());
+                let ray = camera.generate_ray(x as f32 + dx, y as f32 + dy, time);
+                this.visible_point(world, &mut sampler, &ray, this.pixels[index].radius)
+            })
+            .unzip();
+
+        let flux = (0..points.len()).map(|_| AtomicColor::default()).collect::<Vec<_>>();
+        let counts = (0..points.len()).map(|_| AtomicU32::new(0)).collect::<Vec<_>>();
+        if let Some(grid) = HashGrid::build(&points) {
+            (0..settings.photons_per_iteration)
+                .into_par_iter()
+                .for_each(|photon| {
+                    let mut sampler = IndependentSampler::new(seed);
+                    sampler.start_stream(Self::PHOTON_STREAMS | this.iterations as u64, photon as u64);
+                    let time = camera.sample_time(sampler.get_1d());
+                    this.trace_photon(world, time, &mut sampler, &grid, &points, &flux, &counts)
+                });
+        }
+
+        for (index, pixel) in self.pixels.iter_mut().enumerate() {
+            pixel.direct += direct[index];
+            let m = counts[index].load(Ordering::Relaxed) as f32;
+            if m > 0.0 {
+                let photons = pixel.photons + Self::ALPHA * m;
+                let radius = pixel.radius * (photons / (pixel.photons + m)).sqrt();
+                pixel.tau = (pixel.tau + flux[index].load()) * ((radius * radius) / (pixel.radius * pixel.radius));
+                pixel.photons = photons;
+                pixel.radius = radius;
+            }
+        }
+        self.iterations += 1;
+    }
+}
+
+impl Integrator for SppmIntegrator {
+    fn li(&self, ray: &Ray, ctx: &mut TraceContext) -> ColorF32 {
+        // Single rays can't gather photons, only the light reaching the visible point directly
+        self.visible_point(ctx.world, ctx.sampler, ray, self.settings.initial_radius).0
+    }
+
+    fn render_pass(&mut self, world: &World, camera: &Camera, samples_per_pixel: u32, seed: u64) -> Option<Vec<ColorF32>> {
+        let pixel_count = (camera.width() * camera.height()) as usize;
+        if self.pixels.len() != pixel_count {
+            self.reset();
+            self.pixels = vec![
+                SppmPixel {
+                    radius: self.settings.initial_radius,
+                    direct: ColorF32::new(0.0, 0.0, 0.0),
+                    photons: 0.0,
+                    tau: ColorF32::new(0.0, 0.0, 0.0),
+                };
+                pixel_count
+            ];
+        }
+        for _ in 0..samples_per_pixel {
+            self.iterate(world, camera, seed);
+        }
+
+        let iterations = self.iterations as f32;
+        let photons = iterations * self.settings.photons_per_iteration as f32;
+        Some(
+            self.pixels
+                .iter()
+                .map(|pixel| pixel.direct / iterations + pixel.tau / (photons * PI * pixel.radius * pixel.radius))
+                .collect(),
+        )
+    }
+
+    fn reset(&mut self) {
+        self.pixels.clear();
+        self.iterations = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use nalgebra::Vector3;
+
+    use crate::{
+        camera::Camera,
+        geometry::Point,
+        integrator::{
+            tests::{closed_room, mean, path_traced_image},
+            Integrator,
+        },
+    };
+
+    use super::{SppmIntegrator, SppmSettings};
+
+    #[test]
+    fn converges_to_the_path_tracer() {
+        let world = closed_room();
+        let camera = Camera::new(Point::new(0.0, 0.0, 2.0), Vector3::new(0.0, 0.0, -1.0), 60.0, 8, 6);
+        let path = mean(&path_traced_image(&world, &camera, 2048));
+
+        let mut sppm = SppmIntegrator::new(SppmSettings { initial_radius: 0.5, photons_per_iteration: 2000, max_depth: 16 });
+        let sppm = mean(&sppm.render_pass(&world, &camera, 64, 0).unwrap());
+
+        for (p, s) in [(path.r, sppm.r), (path.g, sppm.g), (path.b, sppm.b)] {
+            assert!((p - s).abs() / p < 0.1, "path {:?} sppm {:?}", path, sppm);
+        }
+    }
+}
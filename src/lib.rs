@@ -0,0 +1,139 @@
+//! A CPU path tracer. A [`Pathtracer`] renders a [`World`] of [`Object`]s seen from a
+//! [`Camera`], pass after pass, and saves the accumulated image.
+//!
+//! Objects and materials are traits, so other crates can bring their own:
+//!
+//! ```
+//! use nalgebra::Vector3;
+//! use pathtracer::{
+//!     checkpoint::Fingerprint,
+//!     color::ColorF32,
+//!     geometry::{Intersectable, Point, Ray},
+//!     material::{Material, Scattering},
+//!     sampler::Sampler,
+//!     Intersection, Object, Pathtracer,
+//! };
+//!
+//! /// An infinite floor one unit below the camera
+//! struct Floor {
+//!     material: Box<dyn Material>,
+//! }
+//!
+//! impl Intersectable for Floor {
+//!     fn intersect(&self, ray: &Ray) -> Option<f32> {
+//!         let distance = (-1.0 - ray.origin.y) / ray.direction.y;
+//!         (distance > 0.0).then_some(distance)
+//!     }
+//! }
+//!
+//! impl Object for Floor {
+//!     fn surface_normal(&self, _point: &Point) -> Vector3<f32> {
+//!         Vector3::y()
+//!     }
+//!
+//!     fn material(&self) -> &dyn Material {
+//!         self.material.as_ref()
+//!     }
+//!
+//!     fn fingerprint(&self, fingerprint: &mut Fingerprint) {
+//!         fingerprint.add_str("floor");
+//!         self.material.fingerprint(fingerprint);
+//!     }
+//! }
+//!
+//! /// A surface that glows and reflects nothing
+//! struct Glow(ColorF32);
+//!
+//! impl Material for Glow {
+//!     fn color(&self) -> ColorF32 {
+//!         self.0
+//!     }
+//!
+//!     fn emissivity(&self) -> ColorF32 {
+//!         self.0
+//!     }
+//!
+//!     fn scatter(&self, _ray: &Ray, _intersection: &Intersection, _sampler: &mut dyn Sampler) -> Option<Scattering> {
+//!         None
+//!     }
+//!
+//!     fn fingerprint(&self, fingerprint: &mut Fingerprint) {
+//!         fingerprint.add_str("glow");
+//!         fingerprint.add_debug(&self.0);
+//!     }
+//! }
+//!
+//! let mut pathtracer = Pathtracer::new(32, 24);
+//! let glow = Glow(ColorF32::new(1.0, 0.5, 0.2));
+//! pathtracer.world().add_object(Box::new(Floor { material: Box::new(glow) }));
+//! pathtracer.render();
+//! let image = pathtracer.present().to_rgba8();
+//! // The floor fills the bottom of the image
+//! assert!(image.get_pixel(16, 23)[0] > 0);
+//! ```
+//!
+//! The `pathtracer-rs` binary renders the built-in scene of [`scene::build_scene`] from the
+//! command line, or in the interactive viewer.
+
+/// Camera paths through keys and the frames of animations
+pub mod animation;
+/// Outputs of the renderer besides the beauty pass, like depth, normals and lighting
+pub mod aov;
+/// The pinhole camera the film is seen through
+pub mod camera;
+/// Crash safe snapshots of an accumulation, to resume long renders
+pub mod checkpoint;
+/// Colors and their arithmetic
+pub mod color;
+/// Conversions of meshes between file formats
+pub mod convert;
+/// Boolean combinations of solids: unions, intersections and differences
+pub mod csg;
+/// Denoising of the accumulated image, guided by the albedo and normals
+pub mod denoise;
+/// Per pixel statistics of the samples, for adaptive sampling
+pub mod film;
+/// Rays and the built-in shapes: spheres, planes, triangles and meshes
+pub mod geometry;
+/// Shared objects placed in the world by affine transforms
+pub mod instance;
+/// Light transport algorithms
+pub mod integrator;
+/// Point and directional lights
+pub mod light;
+/// How surfaces scatter and emit light
+pub mod material;
+/// Keyframed transforms that move objects and the camera while the shutter is open
+pub mod motion;
+/// The trait of everything a ray can hit
+pub mod object;
+/// Image formats the renders are saved in
+pub mod output;
+/// The renderer, which accumulates the passes of an integrator over the film
+pub mod raytracer;
+/// Random and low discrepancy sample sequences
+pub mod sampler;
+/// Shapes given by signed distance functions, rendered by sphere tracing
+pub mod sdf;
+/// Boxes, quads, disks, cylinders, cones and tori
+pub mod shapes;
+/// The built-in scene, and the settings of a scene that can be overridden
+pub mod scene;
+/// Smoothing meshes by subdivision, and displacing their surface
+pub mod subdivision;
+/// Counters and timings of the work done while rendering
+pub mod stats;
+/// Splitting the film into tiles, and crop windows
+pub mod tiles;
+/// Mapping of the linear radiance to displayable colors
+pub mod tonemap;
+/// The objects of a scene and the sky around them
+pub mod world;
+
+pub use camera::Camera;
+pub use instance::Instance;
+pub use light::Light;
+pub use material::Material;
+pub use object::Object;
+pub use raytracer::Pathtracer;
+pub use world::{Intersection, World};
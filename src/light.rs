@@ -2,6 +2,8 @@ use nalgebra::Vector3;
 
 use crate::{geometry::{Intersectable, Point, ORIGIN}, color::ColorF32};
 
+/// Lights that aren't surfaces. The integrators only sample emissive objects for now, so these
+/// can't be added to a `World` yet.
 pub trait Light : Intersectable {
     fn position(&self) -> &Point;
     fn color(&self) -> &ColorF32;
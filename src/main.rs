@@ -4,19 +4,11 @@ use winit::{
     window::WindowBuilder,
 };
 
-use crate::renderer::state;
+use pathtracer::{animation, convert, denoise, raytracer, stats};
 
-mod color;
-mod geometry;
-mod raytracer;
+mod cli;
+mod progress;
 mod renderer;
-mod material;
-mod camera;
-mod object;
-mod light;
-mod world;
-mod scene;
-
 
 
 #[tokio::main]
@@ -26,84 +18,266 @@ async fn main() {
 
 async fn run() {
     env_logger::init();
+    setup_panic();
+
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let result = match cli::parse(&args) {
+        Ok(cli::Command::Render(options)) => render(*options),
+        Ok(cli::Command::View(options)) => view(options).await,
+        Ok(cli::Command::Info(options)) => info(options),
+        Ok(cli::Command::Convert(options)) => convert(options),
+        Ok(cli::Command::Help(usage)) => {
+            print!("{}", usage);
+            Ok(())
+        }
+        Err(e) => Err(e),
+    };
+    if let Err(e) = result {
+        eprintln!("{:#}", e);
+        std::process::exit(1);
+    }
+}
+
+// The macro still names the argument of the panic hook PanicInfo
+#[allow(deprecated)]
+fn setup_panic() {
     human_panic::setup_panic!();
+}
+
+/// Renders the scene without a window, until the samples, the noise level or the time budget
+/// is reached, and saves the image
+fn render(options: cli::RenderOptions) -> anyhow::Result<()> {
+    options.settings.init_threads()?;
+    let mut pathtracer = raytracer::Pathtracer::new(options.width, options.height);
+
+    // Load scene
+    let scene_started = std::time::Instant::now();
+    options.settings.scene.build(&mut pathtracer)?;
+    let scene_time = scene_started.elapsed();
 
-    if cfg!(feature = "cli_mode") {
-        let mut args = std::env::args();
-        args.next();
-        let width = args.next().unwrap().parse::<u32>().unwrap();
-        let height = args.next().unwrap().parse::<u32>().unwrap();
-        let mut pathtracer = raytracer::Pathtracer::new(width, height);
-
-        // Load scene
-        let w = pathtracer.world();
-        scene::build_scene(w);
-        
-
-        let samples = args.next().unwrap().parse::<u64>().unwrap();
-        while pathtracer.samples() < samples {
-            pathtracer.render();
+    options.settings.apply(&mut pathtracer);
+    pathtracer.set_crop(options.crop);
+    pathtracer.set_crop_output(options.crop_output);
+    pathtracer.set_adaptive(options.adaptive);
+    pathtracer.set_aovs(options.aovs.clone());
+    if options.denoise {
+        pathtracer.set_denoiser(Some(denoise::Denoiser::default()));
+    }
+    if let Some(resume) = &options.resume {
+        pathtracer.resume(resume)?;
+        println!("Resuming from {} samples per pixel", pathtracer.samples());
+    }
+    if let Some(frames) = options.frames {
+        return render_frames(&mut pathtracer, &options, frames);
+    }
+    let mut progress = progress::ProgressBar::new();
+    let stopped_by = accumulate(&mut pathtracer, &options, &mut progress);
+    progress.finish();
+    let elapsed = progress.elapsed();
+    println!(
+        "Rendered {:.0} samples per pixel in {:.1?}, stopped by the {}",
+        pathtracer.samples_per_pixel(),
+        elapsed,
+        match stopped_by {
+            "samples" => "sample count",
+            "noise" => "noise level",
+            _ => "time budget",
+        }
+    );
+    if let Some(checkpoint) = &options.checkpoint {
+        if let Err(e) = pathtracer.save_checkpoint(checkpoint) {
+            eprintln!("{:#}", e);
         }
-        let output = args.next().unwrap();
-        pathtracer.save_as(output);
     }
-    else {
-        let event_loop = EventLoop::new();
-        let window = WindowBuilder::new()
-            .with_title("Rust Raytracer")
-            .build(&event_loop)
-            .unwrap();
-
-        let mut state = renderer::state::State::new(window).await;
-        state.init();
-
-        event_loop.run(move |event, _, control_flow| match event {
-            Event::WindowEvent {
-                ref event,
-                window_id,
-            } if window_id == state.window().id() => match event {
-                WindowEvent::CloseRequested
-                | WindowEvent::KeyboardInput {
-                    input:
-                        KeyboardInput {
-                            state: ElementState::Pressed,
-                            virtual_keycode: Some(VirtualKeyCode::Escape),
-                            ..
-                        },
-                    ..
-                } => *control_flow = ControlFlow::Exit,
-                WindowEvent::Resized(physical_size) => {
-                    state.resize(*physical_size);
-                }
-                WindowEvent::ScaleFactorChanged { new_inner_size, .. } => {
-                    state.resize(**new_inner_size);
-                }
-                WindowEvent::KeyboardInput{..} => {
-                    state.input(event);
-                },
-                WindowEvent::CursorMoved { ..} => {
-                    state.input(event);
-                },
-                WindowEvent::MouseInput { ..} => {
-                    state.input(event);
-                },
-                _ => {}
-            },
-            Event::RedrawRequested(window_id) if window_id == state.window().id() => {
-                state.update();
-                match state.render() {
-                    Ok(_) => {}
-                    Err(wgpu::SurfaceError::Lost) => state.resize(state.size),
-                    Err(wgpu::SurfaceError::OutOfMemory) => *control_flow = ControlFlow::Exit,
-                    Err(e) => eprintln!("{:?}", e),
-                }
+    let output_started = std::time::Instant::now();
+    let saved = pathtracer.save_as(&options.output, options.format)?;
+    if saved != options.output {
+        println!("'{}' already exists, saved to '{}' instead", options.output.display(), saved.display());
+    }
+    if let Some(stats_output) = options.stats {
+        let mut timings = pathtracer.timings();
+        timings.add(stats::Phase::Scene, scene_time);
+        timings.add(stats::Phase::Output, output_started.elapsed());
+        let report = stats::Report {
+            width: options.width,
+            height: options.height,
+            integrator: pathtracer.integrator_kind().to_string(),
+            sampler: pathtracer.sampler_kind().to_string(),
+            seed: pathtracer.seed(),
+            samples_per_pixel: pathtracer.samples_per_pixel(),
+            stopped_by,
+            elapsed,
+            timings,
+        };
+        if stats_output == "-" {
+            print!("{}", report.to_json());
+        } else {
+            std::fs::write(&stats_output, report.to_json())
+                .map_err(|e| anyhow::anyhow!("failed to write the stats '{}': {}", stats_output, e))?;
+        }
+    }
+    Ok(())
+}
+
+/// Renders each frame of the animation from scratch, with the shutter of the camera moved to
+/// the time of the frame, and saves it under its number
+fn render_frames(pathtracer: &mut raytracer::Pathtracer, options: &cli::RenderOptions, frames: animation::FrameRange) -> anyhow::Result<()> {
+    // The shutter of the scene is in frames, e.g. 0,0.5 for a 180 degree shutter
+    let (open, close) = pathtracer.camera().shutter();
+    for frame in frames.frames() {
+        let output = animation::frame_path(&options.output, frame);
+        if options.skip_existing && output.exists() {
+            println!("Frame {} skipped, '{}' already exists", frame, output.display());
+            continue;
+        }
+        let time = frame as f32 / options.fps;
+        pathtracer.camera_mut().set_shutter(time + open / options.fps, time + close / options.fps);
+        pathtracer.reset();
+        let mut progress = progress::ProgressBar::new();
+        accumulate(pathtracer, options, &mut progress);
+        progress.finish();
+        // Frames replace the ones of a previous render, unless --skip-existing kept them
+        pathtracer.overwrite(&output, options.format)?;
+        println!(
+            "Frame {} ({} of {}) rendered with {:.0} samples per pixel in {:.1?} to '{}'",
+            frame,
+            frame - frames.first + 1,
+            frames.count(),
+            pathtracer.samples_per_pixel(),
+            progress.elapsed(),
+            output.display()
+        );
+    }
+    Ok(())
+}
+
+/// Renders passes until the samples, the noise level or the time budget is reached, and says
+/// which one stopped it
+fn accumulate(pathtracer: &mut raytracer::Pathtracer, options: &cli::RenderOptions, progress: &mut progress::ProgressBar) -> &'static str {
+    let deadline = options.time_budget.map(|budget| std::time::Instant::now() + budget);
+    let mut last_checkpoint = std::time::Instant::now();
+    loop {
+        // Adaptive renders reach the maximum once every pixel converged or hit it
+        if pathtracer.converged() {
+            break "noise";
+        }
+        if pathtracer.samples() >= options.samples {
+            break "samples";
+        }
+        if deadline.is_some_and(|deadline| std::time::Instant::now() >= deadline) {
+            break "time";
+        }
+        // Come back every second to redraw the progress, passes can take much longer
+        let redraw = std::time::Instant::now() + std::time::Duration::from_secs(1);
+        pathtracer.render_until(Some(deadline.map_or(redraw, |deadline| deadline.min(redraw))));
+        let passes = pathtracer.samples_done() / options.samples as f64;
+        let noise = pathtracer.converged_fraction().unwrap_or(0.0) as f64;
+        let time = options.time_budget.map_or(0.0, |budget| progress.elapsed().as_secs_f64() / budget.as_secs_f64());
+        progress.update(passes.max(noise).max(time), pathtracer.samples_per_pixel());
+        if let Some(checkpoint) = options.checkpoint.as_ref().filter(|_| last_checkpoint.elapsed() >= options.checkpoint_every) {
+            // A failed checkpoint doesn't stop the render, the next one may succeed
+            if let Err(e) = pathtracer.save_checkpoint(checkpoint) {
+                eprintln!("{:#}", e);
             }
-            Event::MainEventsCleared => {
-                // RedrawRequested will only trigger once, unless we manually
-                // request it.
-                state.window().request_redraw();
+            last_checkpoint = std::time::Instant::now();
+        }
+    }
+}
+
+/// Prints what the scene is made of, and where the camera looks from
+fn info(options: cli::InfoOptions) -> anyhow::Result<()> {
+    let mut pathtracer = raytracer::Pathtracer::new(1, 1);
+    let started = std::time::Instant::now();
+    options.scene.build(&mut pathtracer)?;
+    let scene_time = started.elapsed();
+    for scene_override in &options.overrides {
+        scene_override.apply(pathtracer.camera_mut());
+    }
+    let world = pathtracer.world();
+    let meshes = world.objects.iter().filter(|object| object.triangle_count() > 0).count();
+    let triangles: usize = world.objects.iter().map(|object| object.triangle_count()).sum();
+    println!("Scene built in {:.1?}", scene_time);
+    println!("Objects: {} ({} emitters)", world.objects.len(), world.emitters().len());
+    println!("Meshes: {} with {} triangles", meshes, triangles);
+    let camera = pathtracer.camera_mut();
+    let (origin, direction) = (camera.origin(), camera.direction());
+    let (open, close) = camera.shutter();
+    println!(
+        "Camera: position ({}, {}, {}), direction ({}, {}, {}), field of view {} degrees, shutter open from {} to {}",
+        origin.x, origin.y, origin.z, direction.x, direction.y, direction.z, camera.fov(), open, close
+    );
+    if let Some(path) = camera.path() {
+        let keys = path.keys();
+        println!("Camera path: {} keys from {} to {} seconds", keys.len(), keys[0].time, keys[keys.len() - 1].time);
+    }
+    Ok(())
+}
+
+fn convert(options: cli::ConvertOptions) -> anyhow::Result<()> {
+    let (triangles, vertices) = convert::convert(&options.input, &options.output, options.subdivision.as_ref())?;
+    println!("Wrote {} triangles and {} vertices to '{}'", triangles, vertices, options.output.display());
+    Ok(())
+}
+
+/// Opens the viewer, which runs until its window is closed
+async fn view(options: cli::ViewOptions) -> anyhow::Result<()> {
+    options.settings.init_threads()?;
+    let event_loop = EventLoop::new();
+    let window = WindowBuilder::new()
+        .with_title("Rust Raytracer")
+        .with_inner_size(winit::dpi::PhysicalSize::new(options.width, options.height))
+        .build(&event_loop)?;
+
+    let mut state = renderer::state::State::new(window).await;
+    state.init(options.settings.scene)?;
+    options.settings.apply(state.pathtracer_mut());
+
+    event_loop.run(move |event, _, control_flow| match event {
+        Event::WindowEvent {
+            ref event,
+            window_id,
+        } if window_id == state.window().id() => match event {
+            WindowEvent::CloseRequested
+            | WindowEvent::KeyboardInput {
+                input:
+                    KeyboardInput {
+                        state: ElementState::Pressed,
+                        virtual_keycode: Some(VirtualKeyCode::Escape),
+                        ..
+                    },
+                ..
+            } => *control_flow = ControlFlow::Exit,
+            WindowEvent::Resized(physical_size) => {
+                state.resize(*physical_size);
             }
+            WindowEvent::ScaleFactorChanged { new_inner_size, .. } => {
+                state.resize(**new_inner_size);
+            }
+            WindowEvent::KeyboardInput{..} => {
+                state.input(event);
+            },
+            WindowEvent::CursorMoved { ..} => {
+                state.input(event);
+            },
+            WindowEvent::MouseInput { ..} => {
+                state.input(event);
+            },
             _ => {}
-        });
-    }
+        },
+        Event::RedrawRequested(window_id) if window_id == state.window().id() => {
+            state.update();
+            match state.render() {
+                Ok(_) => {}
+                Err(wgpu::SurfaceError::Lost) => state.resize(state.size),
+                Err(wgpu::SurfaceError::OutOfMemory) => *control_flow = ControlFlow::Exit,
+                Err(e) => eprintln!("{:?}", e),
+            }
+        }
+        Event::MainEventsCleared => {
+            // RedrawRequested will only trigger once, unless we manually
+            // request it.
+            state.window().request_redraw();
+        }
+        _ => {}
+    });
 }
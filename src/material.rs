@@ -1,21 +1,53 @@
+use std::f32::consts::PI;
+
 use nalgebra::Vector3;
 
-use crate::{color::ColorF32, geometry::{Intersectable, Ray, self}, world::Intersection};
+use crate::{checkpoint::Fingerprint, color::ColorF32, geometry::{Ray, self}, sampler::Sampler, world::Intersection};
+
+/// Which kind of lobe a scattered ray was sampled from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScatterKind {
+    Diffuse,
+    /// Mirror-like reflection
+    Specular,
+    /// Refraction through the surface
+    Transmission,
+}
 
+/// A ray leaving the surface, and how much of the light it carries comes through
 pub struct Scattering {
     pub ray: Ray,
     pub attenuation: ColorF32,
+    pub kind: ScatterKind,
 }
 
+/// How a surface reflects, refracts and emits light. Implement it to bring a custom material
+/// to the built-in shapes or to custom objects.
 pub trait Material : Send + Sync{ 
+    /// Base color, the albedo written for the denoiser and the AOVs
     fn color(&self) -> ColorF32;
-    fn is_reflective(&self) -> bool {
+    /// Radiance emitted by the surface, the objects that emit are the lights of the scene
+    fn emissivity(&self) -> ColorF32 {
+        ColorF32::new(0.0, 0.0, 0.0)
+    }
+    /// Samples the direction the light arriving along `ray` leaves in, `None` if it's absorbed
+    fn scatter(&self, ray: &Ray, intersection: &Intersection, sampler: &mut dyn Sampler) -> Option<Scattering>;
+    /// Materials whose `scatter` follows a (near) perfect mirror or refraction direction.
+    /// Integrators can't connect those to light samples, only follow them.
+    fn is_specular(&self) -> bool {
         false
     }
-    fn emissivity(&self) -> ColorF32 {
+    /// BSDF value for light coming from `wi` and leaving towards `wo`, both pointing away from the surface
+    fn eval(&self, _intersection: &Intersection, _wo: &Vector3<f32>, _wi: &Vector3<f32>) -> ColorF32 {
         ColorF32::new(0.0, 0.0, 0.0)
     }
-    fn scatter(&self, ray: &Ray, intersection: &Intersection) -> Option<Scattering>;
+    /// Solid angle density with which `scatter` picks `wi`
+    fn pdf(&self, _intersection: &Intersection, _wo: &Vector3<f32>, _wi: &Vector3<f32>) -> f32 {
+        0.0
+    }
+    /// Adds the kind of material and every parameter it scatters and emits by, so a checkpoint
+    /// isn't resumed after the material changed
+    fn fingerprint(&self, fingerprint: &mut Fingerprint);
 }
 
 pub struct Diffuse {
@@ -33,21 +65,53 @@ impl Material for Diffuse {
     fn color(&self) -> ColorF32 {
         self.color
     }
-    fn scatter(&self, ray: &Ray, intersection: &Intersection) -> Option<Scattering> {
-        let direction = geometry::random_lambertian(intersection.normal);
-        let direction = if direction.magnitude_squared() < 0.0001 {
+    fn scatter(&self, ray: &Ray, intersection: &Intersection, sampler: &mut dyn Sampler) -> Option<Scattering> {
+        // Two sided: scatter back to the side the ray came from
+        let normal = if ray.direction.dot(&intersection.normal) > 0.0 {
+            -intersection.normal
+        } else {
             intersection.normal
+        };
+        let direction = geometry::random_lambertian(normal, sampler);
+        let direction = if direction.magnitude_squared() < 0.0001 {
+            normal
         } else {
             direction
         };
-        let random_ray = Ray::new_with_eps(intersection.point, direction, 0.001);
+        let random_ray = Ray::new_with_eps(intersection.point, direction, 0.001).with_time(ray.time);
         Some(
             Scattering {
                 ray: random_ray,
                 attenuation: self.color,
+                kind: ScatterKind::Diffuse,
             }
         )
     }
+
+    fn eval(&self, intersection: &Intersection, wo: &Vector3<f32>, wi: &Vector3<f32>) -> ColorF32 {
+        if same_side(intersection, wo, wi) {
+            self.color / PI
+        } else {
+            ColorF32::new(0.0, 0.0, 0.0)
+        }
+    }
+
+    fn pdf(&self, intersection: &Intersection, wo: &Vector3<f32>, wi: &Vector3<f32>) -> f32 {
+        if same_side(intersection, wo, wi) {
+            intersection.normal.dot(wi).abs() / PI
+        } else {
+            0.0
+        }
+    }
+
+    fn fingerprint(&self, fingerprint: &mut Fingerprint) {
+        fingerprint.add_str("diffuse");
+        fingerprint.add_debug(&self.color);
+    }
+}
+
+fn same_side(intersection: &Intersection, wo: &Vector3<f32>, wi: &Vector3<f32>) -> bool {
+    intersection.normal.dot(wo) * intersection.normal.dot(wi) > 0.0
 }
 
 
@@ -74,9 +138,14 @@ impl Material for Emmisive {
         self.color * self.intensity
     }
 
-    fn scatter(&self, ray: &Ray, intersection: &Intersection) -> Option<Scattering> {
+    fn scatter(&self, _ray: &Ray, _intersection: &Intersection, _sampler: &mut dyn Sampler) -> Option<Scattering> {
         None
     }
+
+    fn fingerprint(&self, fingerprint: &mut Fingerprint) {
+        fingerprint.add_str("emissive");
+        fingerprint.add_debug(&(self.color, self.intensity));
+    }
 }
 
 pub fn reflect (v : Vector3<f32>, n : Vector3<f32>) -> Vector3<f32> {
@@ -102,21 +171,30 @@ impl Material for Metal {
         self.color
     }
 
-    fn scatter(&self, ray: &Ray, intersection: &Intersection) -> Option<Scattering> {
+    fn scatter(&self, ray: &Ray, intersection: &Intersection, sampler: &mut dyn Sampler) -> Option<Scattering> {
         let reflected = reflect(ray.direction, intersection.normal);
-        let random_ray = Ray::new_with_eps(intersection.point, reflected + self.fuzz * geometry::random_in_unit_sphere(), 0.001);
-        if random_ray.direction.dot(&intersection.object.surface_normal(&intersection.point)) > 0.0 {
+        let random_ray = Ray::new_with_eps(intersection.point, reflected + self.fuzz * geometry::random_in_unit_sphere(sampler), 0.001).with_time(ray.time);
+        if random_ray.direction.dot(&intersection.normal) > 0.0 {
             Some(
                 Scattering {
                     ray: random_ray,
                     attenuation: self.color,
+                    kind: ScatterKind::Specular,
                 }
             )
         } else {
             None
         }
     }
-    
+
+    fn is_specular(&self) -> bool {
+        true
+    }
+
+    fn fingerprint(&self, fingerprint: &mut Fingerprint) {
+        fingerprint.add_str("metal");
+        fingerprint.add_debug(&(self.color, self.fuzz));
+    }
 }
 
 pub struct Dielectric {
@@ -154,7 +232,7 @@ impl Material for Dielectric {
         self.color
     }
 
-    fn scatter(&self, ray: &Ray, intersection: &Intersection) -> Option<Scattering> {
+    fn scatter(&self, ray: &Ray, intersection: &Intersection, sampler: &mut dyn Sampler) -> Option<Scattering> {
         let attenuation = self.color;
         let front_face = ray.direction.dot(&intersection.normal) < 0.0;
         let refraction_ratio = if front_face {
@@ -170,19 +248,29 @@ impl Material for Dielectric {
         let cos_theta = (-ray.direction).dot(&normal).min(1.0);
         let sin_theta = (1.0 - cos_theta * cos_theta).sqrt();
         let cannot_refract = refraction_ratio * sin_theta > 1.0;
-        let direction = if cannot_refract || double_reflectance(cos_theta, refraction_ratio) > rand::random() {
-            reflect(ray.direction, normal)
+        let (direction, kind) = if cannot_refract || double_reflectance(cos_theta, refraction_ratio) > sampler.get_1d() {
+            (reflect(ray.direction, normal), ScatterKind::Specular)
         } else {
-            refract(ray.direction, normal, refraction_ratio)?
+            (refract(ray.direction, normal, refraction_ratio)?, ScatterKind::Transmission)
         };
 
-        let refracted_fuzz = direction + self.fuzz * geometry::random_in_unit_sphere();
-        let scattered = Ray::new_with_eps(intersection.point, refracted_fuzz, 0.1);
+        let refracted_fuzz = direction + self.fuzz * geometry::random_in_unit_sphere(sampler);
+        let scattered = Ray::new_with_eps(intersection.point, refracted_fuzz, 0.1).with_time(ray.time);
         Some(
             Scattering {
                 ray: scattered,
                 attenuation,
+                kind,
             }
         )
     }
-}
\ No newline at end of file
+
+    fn is_specular(&self) -> bool {
+        true
+    }
+
+    fn fingerprint(&self, fingerprint: &mut Fingerprint) {
+        fingerprint.add_str("dielectric");
+        fingerprint.add_debug(&(self.color, self.fuzz, self.refraction_index));
+    }
+}
@@ -0,0 +1,170 @@
+use bvh::aabb::AABB;
+use nalgebra::{Matrix4, Point3, UnitQuaternion, Vector3};
+
+/// Where an object or the camera is at a moment: scaled, then rotated, then moved
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Keyframe {
+    pub time: f32,
+    pub translation: Vector3<f32>,
+    pub rotation: UnitQuaternion<f32>,
+    pub scale: Vector3<f32>,
+}
+
+impl Keyframe {
+    pub fn new(time: f32, translation: Vector3<f32>, rotation: UnitQuaternion<f32>) -> Self {
+        Self {
+            time,
+            translation,
+            rotation,
+            scale: Vector3::new(1.0, 1.0, 1.0),
+        }
+    }
+
+    pub fn with_scale(mut self, scale: Vector3<f32>) -> Self {
+        self.scale = scale;
+        self
+    }
+
+    pub fn matrix(&self) -> Matrix4<f32> {
+        Matrix4::new_translation(&self.translation) * self.rotation.to_homogeneous() * Matrix4::new_nonuniform_scaling(&self.scale)
+    }
+
+    /// The pose a fraction `t` of the way to `next`. Translation and scale move in straight
+    /// lines, the rotation turns at a constant speed around a fixed axis.
+    fn lerp(&self, next: &Self, t: f32) -> Self {
+        Self {
+            time: self.time + (next.time - self.time) * t,
+            translation: self.translation.lerp(&next.translation, t),
+            // Opposite quaternions are the same rotation, which has nothing to interpolate
+            rotation: self.rotation.try_slerp(&next.rotation, t, 1.0e-6).unwrap_or(self.rotation),
+            scale: self.scale.lerp(&next.scale, t),
+        }
+    }
+}
+
+/// A transform interpolated between keyframes. Before the first keyframe and after the last
+/// one it holds still.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AnimatedTransform {
+    keyframes: Vec<Keyframe>,
+}
+
+impl AnimatedTransform {
+    /// Steps each pair of keyframes is split in to bound the arcs of the rotations
+    const BOUND_STEPS: usize = 16;
+
+    /// Interpolates the keyframes in the order of their times. Panics without keyframes, or if
+    /// one of them scales an axis to zero, as the transform couldn't be inverted.
+    pub fn new(mut keyframes: Vec<Keyframe>) -> Self {
+        assert!(!keyframes.is_empty(), "an animated transform needs at least one keyframe");
+        assert!(
+            keyframes.iter().all(|keyframe| keyframe.scale.iter().all(|&s| s != 0.0)),
+            "the keyframes of an animated transform can't scale an axis to zero"
+        );
+        keyframes.sort_by(|a, b| a.time.total_cmp(&b.time));
+        Self { keyframes }
+    }
+
+    /// Moves by `translation` between the times 0 and 1, like an object traveling while the
+    /// default shutter is open
+    pub fn linear(translation: Vector3<f32>) -> Self {
+        Self::new(vec![
+            Keyframe::new(0.0, Vector3::zeros(), UnitQuaternion::identity()),
+            Keyframe::new(1.0, translation, UnitQuaternion::identity()),
+        ])
+    }
+
+    pub fn keyframes(&self) -> &[Keyframe] {
+        &self.keyframes
+    }
+
+    pub fn pose_at(&self, time: f32) -> Keyframe {
+        let next = self.keyframes.partition_point(|keyframe| keyframe.time <= time);
+        if next == 0 {
+            return self.keyframes[0];
+        }
+        if next == self.keyframes.len() {
+            return self.keyframes[next - 1];
+        }
+        let (a, b) = (&self.keyframes[next - 1], &self.keyframes[next]);
+        a.lerp(b, (time - a.time) / (b.time - a.time))
+    }
+
+    pub fn matrix_at(&self, time: f32) -> Matrix4<f32> {
+        self.pose_at(time).matrix()
+    }
+
+    /// A box holding `bounds` wherever the transform takes it. Each step between keyframes
+    /// turns the corners along an arc, which bulges out of the box of its ends by at most the
+    /// sagitta of the arc.
+    pub fn bounds(&self, bounds: &AABB) -> AABB {
+        let corners = corners(bounds);
+        let mut swept = transform_bounds(&self.keyframes[0].matrix(), bounds);
+        for pair in self.keyframes.windows(2) {
+            let (a, b) = (&pair[0], &pair[1]);
+            let step_angle = a.rotation.angle_to(&b.rotation) / Self::BOUND_STEPS as f32;
+            let scale = a.scale.abs().sup(&b.scale.abs());
+            let radius = corners.iter().map(|corner| corner.coords.component_mul(&scale).norm()).fold(0.0, f32::max);
+            let bulge = radius * (1.0 - (step_angle / 2.0).cos());
+            for step in 1..=Self::BOUND_STEPS {
+                let step_bounds = transform_bounds(&a.lerp(b, step as f32 / Self::BOUND_STEPS as f32).matrix(), bounds);
+                let bulge = bvh::Vector3::splat(bulge);
+                swept.join_mut(&AABB::with_bounds(step_bounds.min - bulge, step_bounds.max + bulge));
+            }
+        }
+        swept
+    }
+}
+
+fn corners(bounds: &AABB) -> [Point3<f32>; 8] {
+    std::array::from_fn(|i| {
+        let pick = |bit: usize, axis: usize| if i & bit == 0 { bounds.min[axis] } else { bounds.max[axis] };
+        Point3::new(pick(1, 0), pick(2, 1), pick(4, 2))
+    })
+}
+
+/// The box around `bounds` once transformed by `matrix`
+pub fn transform_bounds(matrix: &Matrix4<f32>, bounds: &AABB) -> AABB {
+    corners(bounds).iter().fold(AABB::empty(), |transformed, corner| {
+        let point = matrix.transform_point(corner);
+        transformed.grow(&bvh::Point3::new(point.x, point.y, point.z))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::f32::consts::PI;
+
+    use bvh::aabb::AABB;
+    use nalgebra::{Point3, UnitQuaternion, Vector3};
+
+    use super::{AnimatedTransform, Keyframe};
+
+    #[test]
+    fn interpolates_between_keyframes_and_bounds_the_whole_motion() {
+        let turn = UnitQuaternion::from_axis_angle(&Vector3::y_axis(), PI / 2.0);
+        let motion = AnimatedTransform::new(vec![
+            Keyframe::new(1.0, Vector3::new(2.0, 0.0, 0.0), turn).with_scale(Vector3::new(2.0, 2.0, 2.0)),
+            Keyframe::new(0.0, Vector3::zeros(), UnitQuaternion::identity()),
+        ]);
+        // Holds still outside of the keyframes
+        assert_eq!(motion.matrix_at(-1.0), motion.matrix_at(0.0));
+        assert_eq!(motion.matrix_at(2.0), motion.matrix_at(1.0));
+
+        // Halfway, it's moved by half, scaled by 1.5 and turned by 45 degrees
+        let point = motion.matrix_at(0.5).transform_point(&Point3::new(1.0, 0.0, 0.0));
+        let expected = Point3::new(1.0, 0.0, 0.0) + Vector3::new((PI / 4.0).cos(), 0.0, -(PI / 4.0).sin()) * 1.5;
+        assert!((point - expected).norm() < 1e-5, "{} against {}", point, expected);
+
+        // The box holds the unit cube at every moment, though its corners move along arcs
+        let cube = AABB::with_bounds(bvh::Point3::new(-1.0, -1.0, -1.0), bvh::Point3::new(1.0, 1.0, 1.0));
+        let swept = motion.bounds(&cube);
+        for step in 0..=1000 {
+            let matrix = motion.matrix_at(step as f32 / 1000.0);
+            for corner in [Point3::new(1.0, 1.0, 1.0), Point3::new(-1.0, 1.0, 1.0), Point3::new(1.0, -1.0, -1.0)] {
+                let point = matrix.transform_point(&corner);
+                assert!(swept.approx_contains_eps(&bvh::Point3::new(point.x, point.y, point.z), 1e-5), "{} at {}", point, step);
+            }
+        }
+    }
+}
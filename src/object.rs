@@ -1,19 +1,62 @@
-use nalgebra::Vector3;
+use bvh::aabb::AABB;
+use nalgebra::{Vector2, Vector3};
 
-use crate::{geometry::{Intersectable, Point}, material::Material, world::Intersection};
+use crate::{checkpoint::Fingerprint, geometry::{Intersectable, Point}, material::Material, sampler::Sampler, world::Intersection};
 
+/// A point sampled on the surface of an object, used to sample emitters.
+pub struct SurfaceSample {
+    pub point: Point,
+    pub normal: Vector3<f32>,
+    /// Probability density of the sample with respect to surface area
+    pub pdf: f32,
+}
 
-pub trait Object: Intersectable {
+/// Something the rays can hit. Implement it, with `Intersectable`, to add custom shapes to a
+/// `World`. Objects that move are hit where they are at the time of the ray; `surface_normal`
+/// and `surface_uv`, which have no time, describe them at time 0.
+pub trait Object: Intersectable + Send + Sync {
+    /// Unit normal of the surface at a point on it
     fn surface_normal(&self, point: &Point) -> Vector3<f32>;
-    fn material(&self) -> &Box<dyn Material + Send + Sync>;
-    fn intersection<'a>(&'a self, r: &crate::geometry::Ray, b: &'a Box<dyn Object + Send + Sync>) -> Option<Intersection> {
+    /// Texture coordinates of a point on the surface
+    fn surface_uv(&self, _point: &Point) -> Vector2<f32> {
+        Vector2::zeros()
+    }
+    fn material(&self) -> &dyn Material;
+    /// Adds the kind of object, its shape, placement and motion, and its materials, so a
+    /// checkpoint isn't resumed after any of them changed, seen by the camera or not
+    fn fingerprint(&self, fingerprint: &mut Fingerprint);
+    /// Samples a point uniformly over the surface as it is at `time`. Unbounded objects can't be
+    /// sampled.
+    fn sample_surface(&self, _time: f32, _sampler: &mut dyn Sampler) -> Option<SurfaceSample> {
+        None
+    }
+    /// Area density with which `sample_surface` returns `point` at `time`, where the surface has
+    /// `normal`
+    fn surface_pdf(&self, _point: &Point, _normal: &Vector3<f32>, _time: f32) -> f32 {
+        0.0
+    }
+    /// Box around the object at every time it moves through, `None` for unbounded objects
+    fn bounds(&self) -> Option<AABB> {
+        None
+    }
+    /// Triangles the object is made of, for the scene statistics
+    fn triangle_count(&self) -> usize {
+        0
+    }
+    /// The closest hit along the ray with its normal and texture coordinates, `b` being the
+    /// object itself. Shapes that find the normal while intersecting override it.
+    fn intersection<'a>(&'a self, r: &crate::geometry::Ray, b: &'a dyn Object) -> Option<Intersection<'a>> {
         if let Some(distance) = self.intersect(r) {
+            let point = r.point_at(distance);
             Some(Intersection {
                 distance,
-                point: r.point_at(distance),
+                point,
                 object: b,
-                normal: self.surface_normal(&r.point_at(distance)),
+                object_id: 0,
+                normal: self.surface_normal(&point),
+                uv: self.surface_uv(&point),
+                time: r.time,
             })
         } else {None}
     }
-}
\ No newline at end of file
+}
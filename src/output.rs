@@ -0,0 +1,291 @@
+use std::{
+    fmt,
+    fs::File,
+    io::{BufWriter, Write},
+    path::Path,
+    str::FromStr,
+};
+
+use anyhow::Context;
+use exr::prelude::{
+    f16, AnyChannel, AnyChannels, Blocks, Compression, Encoding, FlatSamples, Image, ImageAttributes, IntegerBounds, Layer,
+    LayerAttributes, LineOrder, SmallVec, Vec2, WritableImage,
+};
+use image::{codecs::hdr::HdrEncoder, Rgb, Rgba32FImage};
+
+use crate::{
+    tiles::{Crop, Tile},
+    tonemap::ToneMapping,
+};
+
+/// How the channels of an EXR are compressed, all of them lossless
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExrCompression {
+    None,
+    Rle,
+    Zip,
+    Piz,
+}
+
+/// How a render restricted to a crop window is saved
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CropOutput {
+    /// An image of the whole film, black outside the crop. EXRs only store the crop, as the data
+    /// window of the full display window.
+    #[default]
+    Full,
+    /// An image of the crop alone
+    Cropped,
+}
+
+impl FromStr for CropOutput {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "full" => Ok(Self::Full),
+            "cropped" => Ok(Self::Cropped),
+            _ => anyhow::bail!("unknown crop output '{}', expected full or cropped", s),
+        }
+    }
+}
+
+/// File formats the render can be saved in. The PNGs are tone mapped, the others keep the
+/// linear radiance.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputFormat {
+    /// sRGB encoded PNG with 8 or 16 bits per channel
+    Png { bits: u8 },
+    /// Radiance RGBE
+    Hdr,
+    /// Portable float map
+    Pfm,
+    /// 32 bit float TIFF
+    Tiff,
+    /// OpenEXR, the only format with room for the AOV layers
+    Exr { half: bool, compression: ExrCompression },
+}
+
+impl OutputFormat {
+    pub const DEFAULT_EXR: Self = Self::Exr { half: false, compression: ExrCompression::Rle };
+
+    /// The format a file extension stands for, with the default options
+    pub fn from_path(path: &Path) -> anyhow::Result<Self> {
+        let extension = path.extension().and_then(|extension| extension.to_str()).unwrap_or("");
+        match extension.to_ascii_lowercase().as_str() {
+            "png" => Ok(Self::Png { bits: 8 }),
+            "hdr" => Ok(Self::Hdr),
+            "pfm" => Ok(Self::Pfm),
+            "tif" | "tiff" => Ok(Self::Tiff),
+            "exr" => Ok(Self::DEFAULT_EXR),
+            _ => anyhow::bail!(
+                "can't tell the format of '{}', expected a .png, .hdr, .pfm, .tiff or .exr file",
+                path.display()
+            ),
+        }
+    }
+
+    /// Whether `path` has an extension this format is written with
+    pub fn matches(&self, path: &Path) -> bool {
+        Self::from_path(path).is_ok_and(|format| std::mem::discriminant(&format) == std::mem::discriminant(self))
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Self::Png { .. } => "png",
+            Self::Hdr => "hdr",
+            Self::Pfm => "pfm",
+            Self::Tiff => "tiff",
+            Self::Exr { .. } => "exr",
+        }
+    }
+
+    /// Whether an EXR is worth writing next to the file: when it's tone mapped and loses the
+    /// linear radiance, or when it can't store the AOV layers
+    pub fn needs_exr(&self, has_layers: bool) -> bool {
+        match self {
+            Self::Png { .. } => true,
+            Self::Exr { .. } => false,
+            _ => has_layers,
+        }
+    }
+
+    /// Writes `image`. `layers` are the AOVs, as named channels, which only EXR stores. EXRs
+    /// only store the pixels of the `data_window` when there's one, the other formats the whole
+    /// image.
+    pub fn write(
+        &self,
+        path: &Path,
+        image: &Rgba32FImage,
+        tone_mapping: &ToneMapping,
+        layers: &[(String, Vec<f32>)],
+        data_window: Option<Crop>,
+    ) -> anyhow::Result<()> {
+        let result = match *self {
+            Self::Png { bits: 16 } => tone_mapping.map_image_16(image).save(path).map_err(anyhow::Error::from),
+            Self::Png { .. } => tone_mapping.map_image(image).save(path).map_err(anyhow::Error::from),
+            Self::Hdr => write_hdr(path, image),
+            Self::Pfm => write_pfm(path, image),
+            Self::Tiff => write_tiff(path, image),
+            Self::Exr { half, compression } => write_exr(path, image, layers, data_window, half, compression),
+        };
+        result.with_context(|| format!("failed to write '{}'", path.display()))
+    }
+}
+
+impl fmt::Display for OutputFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Png { bits: 16 } => write!(f, "png16"),
+            Self::Png { .. } => write!(f, "png"),
+            Self::Hdr => write!(f, "hdr"),
+            Self::Pfm => write!(f, "pfm"),
+            Self::Tiff => write!(f, "tiff"),
+            Self::Exr { half, compression } => {
+                let compression = match compression {
+                    ExrCompression::None => "none",
+                    ExrCompression::Rle => "rle",
+                    ExrCompression::Zip => "zip",
+                    ExrCompression::Piz => "piz",
+                };
+                write!(f, "exr:{},{}", if *half { "half" } else { "float" }, compression)
+            }
+        }
+    }
+}
+
+impl FromStr for OutputFormat {
+    type Err = anyhow::Error;
+
+    /// Parses `png`, `png16`, `hdr`, `pfm`, `tiff` or `exr[:<options>]`, with the EXR options a
+    /// comma separated list of `half` or `float` and `none`, `rle`, `zip` or `piz`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "png" => Ok(Self::Png { bits: 8 }),
+            "png16" => Ok(Self::Png { bits: 16 }),
+            "hdr" => Ok(Self::Hdr),
+            "pfm" => Ok(Self::Pfm),
+            "tiff" => Ok(Self::Tiff),
+            "exr" => Ok(Self::DEFAULT_EXR),
+            _ => {
+                let options = s.strip_prefix("exr:").ok_or_else(|| {
+                    anyhow::anyhow!("unknown output format '{}', expected png, png16, hdr, pfm, tiff, exr or exr:<options>", s)
+                })?;
+                let (mut half, mut compression) = (false, ExrCompression::Rle);
+                for option in options.split(',').filter(|option| !option.is_empty()) {
+                    match option {
+                        "half" => half = true,
+                        "float" => half = false,
+                        "none" => compression = ExrCompression::None,
+                        "rle" => compression = ExrCompression::Rle,
+                        "zip" => compression = ExrCompression::Zip,
+                        "piz" => compression = ExrCompression::Piz,
+                        _ => anyhow::bail!("unknown exr option '{}', expected half, float, none, rle, zip or piz", option),
+                    }
+                }
+                Ok(Self::Exr { half, compression })
+            }
+        }
+    }
+}
+
+fn create(path: &Path) -> anyhow::Result<BufWriter<File>> {
+    Ok(BufWriter::new(File::create(path)?))
+}
+
+fn write_hdr(path: &Path, image: &Rgba32FImage) -> anyhow::Result<()> {
+    let pixels: Vec<Rgb<f32>> = image.pixels().map(|pixel| Rgb([pixel[0], pixel[1], pixel[2]])).collect();
+    HdrEncoder::new(create(path)?).encode(&pixels, image.width() as usize, image.height() as usize)?;
+    Ok(())
+}
+
+/// Portable float map: a text header, then little endian RGB floats from the bottom row up
+fn write_pfm(path: &Path, image: &Rgba32FImage) -> anyhow::Result<()> {
+    let mut file = create(path)?;
+    // A negative scale marks the data as little endian
+    write!(file, "PF\n{} {}\n-1.0\n", image.width(), image.height())?;
+    for y in (0..image.height()).rev() {
+        for x in 0..image.width() {
+            for channel in &image.get_pixel(x, y).0[..3] {
+                file.write_all(&channel.to_le_bytes())?;
+            }
+        }
+    }
+    file.flush()?;
+    Ok(())
+}
+
+fn write_tiff(path: &Path, image: &Rgba32FImage) -> anyhow::Result<()> {
+    let data: Vec<f32> = image.pixels().flat_map(|pixel| [pixel[0], pixel[1], pixel[2]]).collect();
+    let mut encoder = tiff::encoder::TiffEncoder::new(create(path)?)?;
+    encoder.write_image::<tiff::encoder::colortype::RGB32Float>(image.width(), image.height(), &data)?;
+    Ok(())
+}
+
+/// Writes the image as the RGBA channels of an EXR and the `layers` as extra channels, only
+/// keeping the pixels of `data_window` if there's one
+fn write_exr(
+    path: &Path,
+    image: &Rgba32FImage,
+    layers: &[(String, Vec<f32>)],
+    data_window: Option<Crop>,
+    half: bool,
+    compression: ExrCompression,
+) -> anyhow::Result<()> {
+    let window = data_window.unwrap_or(Crop::full(image.width(), image.height()));
+    let samples = |values: &dyn Fn(usize) -> f32| {
+        let values = Tile::from(window).pixels().map(|(x, y)| values((y * image.width() + x) as usize));
+        if half {
+            FlatSamples::F16(values.map(f16::from_f32).collect())
+        } else {
+            FlatSamples::F32(values.collect())
+        }
+    };
+    let pixels = image.as_raw();
+    let mut channels: SmallVec<[AnyChannel<FlatSamples>; 4]> = ["R", "G", "B", "A"]
+        .iter()
+        .enumerate()
+        .map(|(index, name)| AnyChannel::new(*name, samples(&|pixel| pixels[4 * pixel + index])))
+        .collect();
+    for (name, values) in layers {
+        channels.push(AnyChannel::new(name.as_str(), samples(&|pixel| values[pixel])));
+    }
+    let encoding = match compression {
+        ExrCompression::None => Encoding::UNCOMPRESSED,
+        ExrCompression::Rle => Encoding::FAST_LOSSLESS,
+        ExrCompression::Zip => Encoding::SMALL_LOSSLESS,
+        ExrCompression::Piz => Encoding {
+            compression: Compression::PIZ,
+            blocks: Blocks::ScanLines,
+            line_order: LineOrder::Increasing,
+        },
+    };
+    let attributes = LayerAttributes {
+        layer_position: Vec2(window.x as i32, window.y as i32),
+        ..LayerAttributes::default()
+    };
+    let layer = Layer::new((window.width as usize, window.height as usize), attributes, encoding, AnyChannels::sort(channels));
+    let display_window = IntegerBounds::from_dimensions((image.width() as usize, image.height() as usize));
+    Image::new(ImageAttributes::new(display_window), layer).write().to_file(path)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::OutputFormat;
+
+    #[test]
+    fn picks_the_format_from_the_extension_and_parses_what_it_prints() {
+        assert_eq!(OutputFormat::from_path(Path::new("out/render.PNG")).unwrap(), OutputFormat::Png { bits: 8 });
+        assert_eq!(OutputFormat::from_path(Path::new("render.tif")).unwrap(), OutputFormat::Tiff);
+        assert!(OutputFormat::from_path(Path::new("render.jpg")).is_err());
+        assert!("png16".parse::<OutputFormat>().unwrap().matches(Path::new("a.png")));
+        assert!(!"exr".parse::<OutputFormat>().unwrap().matches(Path::new("a.png")));
+        for s in ["png", "png16", "hdr", "pfm", "tiff", "exr:half,piz", "exr:float,none"] {
+            assert_eq!(s.parse::<OutputFormat>().unwrap().to_string(), s);
+        }
+        assert!("exr:lossy".parse::<OutputFormat>().is_err());
+    }
+}
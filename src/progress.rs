@@ -0,0 +1,69 @@
+use std::{
+    io::{IsTerminal, Write},
+    time::{Duration, Instant},
+};
+
+/// Progress of a command line render on stderr. It's redrawn in place on a terminal, and
+/// printed as a new line every few seconds otherwise, e.g. in CI logs.
+pub struct ProgressBar {
+    started: Instant,
+    last_drawn: Option<Instant>,
+    terminal: bool,
+}
+
+impl ProgressBar {
+    const WIDTH: usize = 30;
+
+    pub fn new() -> Self {
+        Self {
+            started: Instant::now(),
+            last_drawn: None,
+            terminal: std::io::stderr().is_terminal(),
+        }
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        self.started.elapsed()
+    }
+
+    /// Shows `fraction` of the render done, with an estimate of the time left
+    pub fn update(&mut self, fraction: f64, samples_per_pixel: f64) {
+        let interval = if self.terminal { Duration::from_millis(100) } else { Duration::from_secs(10) };
+        if self.last_drawn.is_some_and(|drawn| drawn.elapsed() < interval) {
+            return;
+        }
+        self.last_drawn = Some(Instant::now());
+        let fraction = fraction.clamp(0.0, 1.0);
+        let elapsed = self.elapsed().as_secs_f64();
+        let eta = if fraction > 0.0 {
+            format!("{:.1}s", elapsed * (1.0 - fraction) / fraction)
+        } else {
+            "?".to_string()
+        };
+        let filled = (fraction * Self::WIDTH as f64) as usize;
+        let line = format!(
+            "[{}{}] {:5.1}% {:.0} spp, {:.1}s elapsed, ETA {}",
+            "=".repeat(filled),
+            " ".repeat(Self::WIDTH - filled),
+            100.0 * fraction,
+            samples_per_pixel,
+            elapsed,
+            eta
+        );
+        let mut stderr = std::io::stderr();
+        if self.terminal {
+            // Clears what's left of a longer previous line
+            let _ = write!(stderr, "\r{}\x1b[K", line);
+            let _ = stderr.flush();
+        } else {
+            let _ = writeln!(stderr, "{}", line);
+        }
+    }
+
+    /// Ends the line of the bar, so the next messages start on their own
+    pub fn finish(&self) {
+        if self.terminal && self.last_drawn.is_some() {
+            eprintln!();
+        }
+    }
+}
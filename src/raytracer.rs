@@ -1,36 +1,115 @@
-use std::{f32::EPSILON, path};
+use std::{path, sync::{atomic::{AtomicUsize, Ordering}, Mutex}};
 
 use image::{DynamicImage, Rgba32FImage};
 use nalgebra::Vector3;
-use rayon::prelude::{ParallelBridge, ParallelIterator};
+use rayon::prelude::{IntoParallelIterator, ParallelIterator};
 
-use crate::{geometry::{Point, Ray}, camera::Camera, world::World , color::{ColorF32, self} };
+use crate::{geometry::Point, camera::Camera, world::World , color::ColorF32, integrator::{Integrator, IntegratorKind, LightContribution, Splat, TraceContext}, sampler::SamplerKind, film::{AdaptiveSettings, Features, PixelStats}, tonemap::ToneMapping, denoise::Denoiser, aov::{self, Aov, AovFilm}, output::{CropOutput, OutputFormat}, tiles::{Crop, Tile, TileSettings}, checkpoint::{CheckpointReader, CheckpointWriter, Fingerprint}, stats::{Phase, Timings} };
+/// The new samples of the pixels of a tile, until the tile is merged into the film. Each tile
+/// keeps its buffer between passes, so tracing doesn't allocate once the buffers have grown.
+#[derive(Default)]
+struct TileBuffer {
+    /// Mean of all the samples of each pixel, `None` for the pixels that converged
+    colors: Vec<Option<ColorF32>>,
+    stats: Vec<PixelStats>,
+    splats: Vec<Splat>,
+    /// Light recorded by the integrator for the sample being traced
+    contributions: Vec<LightContribution>,
+    /// Sums of the lighting AOVs over the new samples, pixel after pixel
+    lighting: Vec<f32>,
+}
+
+/// Renders a `World` seen from a `Camera`, accumulating passes of an integrator over the film.
+/// Build the scene with `world`, render with `render` or `render_until`, then `present` or
+/// save the image.
 pub struct Pathtracer {
     width: u32,
     height: u32,
     image: Rgba32FImage,
+    /// Sum of the contributions splatted by integrators that trace from the lights
+    splats: Vec<ColorF32>,
+    /// Samples taken by each pixel and their variance
+    stats: Vec<PixelStats>,
+    /// Stops sampling the pixels that converged, when set
+    adaptive: Option<AdaptiveSettings>,
+    /// First hit albedo and normal of each pixel, to guide the denoiser
+    features: Vec<Features>,
+    feature_samples: u32,
+    /// Filters the noise of the presented and saved images, when set
+    denoiser: Option<Denoiser>,
+    /// AOVs written with the saved images
+    aovs: Vec<Aov>,
+    /// Channels of the AOVs, laid out for the scene at the first pass
+    aov_film: Option<AovFilm>,
     world: World,
     camera: Camera,
+    integrator_kind: IntegratorKind,
+    integrator: Box<dyn Integrator>,
     samples: u64,
+    /// Global seed every random number derives from, together with the pixel and sample index
+    seed: u64,
+    sampler_kind: SamplerKind,
+    /// How the film is turned into the 8 bit images, the EXRs keep the linear radiance
+    tone_mapping: ToneMapping,
+    /// Part of the film that's rendered, the whole film when `None`
+    crop: Option<Crop>,
+    crop_output: CropOutput,
+    /// How the film is split into tiles and in which order they're rendered
+    tile_settings: TileSettings,
+    tiles: Vec<Tile>,
+    tile_buffers: Vec<Mutex<TileBuffer>>,
+    /// First tile of the pass in progress that isn't rendered yet
+    next_tile: usize,
+    pass_started: std::time::Instant,
+    /// Time spent in each part of the passes
+    timings: Timings,
     started: std::time::Instant,
+    /// Samples per pixel already accumulated when `started`, read from a checkpoint
+    started_samples: u64,
 }
 
 impl Pathtracer {
+    /// A `width` x `height` film of an empty world, seen by the default camera with the path
+    /// integrator
     pub fn new(width : u32, height : u32) -> Self {
         let camera = Camera::new(
             Point::new(3.0, 0.0, 5.2), 
             Vector3::new(0.0, 0.0, -1.0), 90.0,
             width, 
             height);
-        Self {
+        let mut pathtracer = Self {
             width,
             height,
             image: Rgba32FImage::new(width, height),
+            splats: vec![ColorF32::new(0.0, 0.0, 0.0); (width * height) as usize],
+            stats: vec![PixelStats::default(); (width * height) as usize],
+            adaptive: None,
+            features: vec![Features::default(); (width * height) as usize],
+            feature_samples: 0,
+            denoiser: None,
+            aovs: Vec::new(),
+            aov_film: None,
             world: World::new(),
             camera,
+            integrator_kind: IntegratorKind::default(),
+            integrator: IntegratorKind::default().build(),
             samples: 0,
+            seed: 0,
+            sampler_kind: SamplerKind::default(),
+            tone_mapping: ToneMapping::default(),
+            crop: None,
+            crop_output: CropOutput::default(),
+            tile_settings: TileSettings::default(),
+            tiles: Vec::new(),
+            tile_buffers: Vec::new(),
+            next_tile: 0,
+            pass_started: std::time::Instant::now(),
+            timings: Timings::default(),
             started: std::time::Instant::now(),
-        }
+            started_samples: 0,
+        };
+        pathtracer.split_tiles();
+        pathtracer
     }
 
     pub fn resize(&mut self, width : u32, height : u32) {
@@ -38,145 +117,791 @@ impl Pathtracer {
         self.height = height;
         self.camera.resize(width, height);
         self.image = Rgba32FImage::new(self.width, self.height);
+        self.crop = self.crop.and_then(|crop| crop.clamp(width, height));
+        self.split_tiles();
+        self.reset();
+    }
+
+    fn split_tiles(&mut self) {
+        self.tiles = self.tile_settings.tiles(&self.region());
+        self.tile_buffers = self.tiles.iter().map(|_| Mutex::default()).collect();
+    }
+
+    /// Changes the tiles the film is split into, restarting the accumulation
+    pub fn set_tiles(&mut self, settings: TileSettings) {
+        self.tile_settings = settings;
+        self.split_tiles();
         self.reset();
     }
 
+    pub fn crop(&self) -> Option<Crop> {
+        self.crop
+    }
+
+    /// Only renders the pixels inside `crop`, the part of it inside the film, or the whole film
+    /// with `None`, restarting the accumulation. The camera keeps the projection of the whole
+    /// film, so the pixels look the same as in a full render.
+    pub fn set_crop(&mut self, crop: Option<Crop>) {
+        self.crop = crop.and_then(|crop| crop.clamp(self.width, self.height));
+        self.split_tiles();
+        self.reset();
+    }
+
+    /// Whether a render restricted to a crop window is saved at the size of the film or of the crop
+    pub fn set_crop_output(&mut self, output: CropOutput) {
+        self.crop_output = output;
+    }
+
+    /// The pixels that are rendered
+    fn region(&self) -> Crop {
+        self.crop.unwrap_or(Crop::full(self.width, self.height))
+    }
+
     pub fn reset(&mut self) {
         self.samples = 0;
+        self.integrator.reset();
+        self.splats = vec![ColorF32::new(0.0, 0.0, 0.0); (self.width * self.height) as usize];
+        self.stats = vec![PixelStats::default(); (self.width * self.height) as usize];
+        self.features = vec![Features::default(); (self.width * self.height) as usize];
+        self.feature_samples = 0;
+        self.aov_film = None;
+        self.next_tile = 0;
+    }
+
+    pub fn integrator_kind(&self) -> IntegratorKind {
+        self.integrator_kind
+    }
+
+    /// Changes the seed of the random numbers, restarting the accumulation
+    pub fn set_seed(&mut self, seed: u64) {
+        self.seed = seed;
+        self.reset();
+    }
+
+    /// Changes how the random numbers of the samples are distributed, restarting the accumulation
+    pub fn set_sampler(&mut self, kind: SamplerKind) {
+        self.sampler_kind = kind;
+        self.reset();
+    }
+
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    pub fn sampler_kind(&self) -> SamplerKind {
+        self.sampler_kind
+    }
+
+    pub fn tone_mapping(&self) -> ToneMapping {
+        self.tone_mapping
+    }
+
+    /// Changes how the 8 bit images are displayed, keeping the accumulation
+    pub fn set_tone_mapping(&mut self, tone_mapping: ToneMapping) {
+        self.tone_mapping = tone_mapping;
+    }
+
+    pub fn denoiser(&self) -> Option<Denoiser> {
+        self.denoiser
+    }
+
+    /// Filters the presented and saved images with `denoiser`, or shows the raw samples with
+    /// `None`. The saved images keep a copy of the noisy image.
+    pub fn set_denoiser(&mut self, denoiser: Option<Denoiser>) {
+        self.denoiser = denoiser;
+    }
+
+    /// Accumulates `aovs` next to the beauty pass, restarting the accumulation. They're written
+    /// as extra layers of the saved EXR, and as PNGs for those that fit in 8 bits.
+    pub fn set_aovs(&mut self, aovs: Vec<Aov>) {
+        self.aovs = aovs;
+        self.reset();
+    }
+
+    /// Makes the pixels stop sampling once their noise is below the threshold, or sample on
+    /// every pass with `None`. Only the integrators that trace each pixel with `li` can stop
+    /// early, the others keep a single estimate for the whole image.
+    pub fn set_adaptive(&mut self, adaptive: Option<AdaptiveSettings>) {
+        self.adaptive = adaptive;
+        self.reset();
+    }
+
+    /// Whether adaptive sampling is on and every pixel stopped sampling
+    pub fn converged(&self) -> bool {
+        self.adaptive.is_some_and(|adaptive| {
+            Tile::from(self.region()).pixels().all(|(x, y)| self.stats[(y * self.width + x) as usize].converged(&adaptive))
+        })
+    }
+
+    /// Switches the light transport algorithm, restarting the accumulation
+    pub fn set_integrator(&mut self, kind: IntegratorKind) {
+        self.integrator_kind = kind;
+        self.integrator = kind.build();
+        self.reset();
+    }
+
+    fn new_aov_film(&self) -> Option<AovFilm> {
+        if self.aovs.is_empty() {
+            return None;
+        }
+        Some(AovFilm::new(&self.aovs, &self.world, (self.width * self.height) as usize))
     }
 
+    /// Renders a whole pass, or the rest of the pass the viewer started
     pub fn render(&mut self) {
+        self.render_until(None);
+    }
 
-        let now = std::time::Instant::now();
-        if self.samples == 0 {
-            println!("Rendering {}x{} image", self.width, self.height);
-            self.started = std::time::Instant::now();
-        }
-        // for y in 0..self.height {
-        //     for x in 0..self.width {
-        //         let r = Ray::new_prime(x, y, &self.camera);
-        //         self.trace(r, x, y, 0)
-        //     }
-        // }
-        let color = (0..self.height).par_bridge().map(|y| {
-            (0..self.width).par_bridge().map(|x| {
-                let r = Ray::new_prime(x, y, &self.camera);
-                (x, y, self.trace(r, x, y, 0))
-            }).collect::<Vec<_>>()
-        }).collect::<Vec<_>>();
-        for y in 0..self.height {
-            for x in 0..self.width {
-                let (x, y, color) = &color[y as usize][x as usize];
-                self.image.put_pixel(*x, *y, (*color).into());
+    /// Renders the tiles of the current pass until `deadline`, or all of them with `None`, and
+    /// finishes the pass once every tile is done. At least one tile is rendered per call.
+    pub fn render_until(&mut self, deadline: Option<std::time::Instant>) {
+        if self.next_tile == 0 {
+            self.pass_started = std::time::Instant::now();
+            if self.samples == 0 {
+                log::info!("Rendering {}x{} image (seed {})", self.width, self.height, self.seed);
+                self.started = std::time::Instant::now();
+                self.started_samples = 0;
+                self.aov_film = self.new_aov_film();
+            }
+            let started = std::time::Instant::now();
+            if let Some(estimate) = self.integrator.render_pass(&self.world, &self.camera, Self::SINGLE_SHOT_SAMPLES as u32, self.seed) {
+                self.timings.add(Phase::Trace, started.elapsed());
+                // The integrator keeps its own running estimate, so it replaces the image
+                for (pixel, color) in self.image.pixels_mut().zip(estimate) {
+                    *pixel = color.into();
+                }
+                self.finish_pass();
+                return;
             }
         }
+        let started = std::time::Instant::now();
+        let end = self.trace_tiles(deadline);
+        self.timings.add(Phase::Trace, started.elapsed());
+        let started = std::time::Instant::now();
+        self.merge_tiles(self.next_tile..end);
+        self.timings.add(Phase::Merge, started.elapsed());
+        self.next_tile = end;
+        if self.next_tile == self.tiles.len() {
+            self.next_tile = 0;
+            self.finish_pass();
+        }
+    }
+
+    /// Hands out the tiles from `next_tile` on to the threads, in order, until `deadline`.
+    /// Returns the end of the tiles traced, which always follow each other.
+    fn trace_tiles(&self, deadline: Option<std::time::Instant>) -> usize {
+        let start = self.next_tile;
+        let next = AtomicUsize::new(start);
+        rayon::scope(|scope| {
+            for _ in 0..rayon::current_num_threads() {
+                scope.spawn(|_| {
+                    let claim = |index: usize| {
+                        let late = deadline.is_some_and(|deadline| std::time::Instant::now() >= deadline);
+                        (index < self.tiles.len() && (index == start || !late)).then_some(index + 1)
+                    };
+                    while let Ok(index) = next.fetch_update(Ordering::Relaxed, Ordering::Relaxed, claim) {
+                        let mut buffer = self.tile_buffers[index].lock().unwrap();
+                        self.trace_tile(&self.tiles[index], &mut buffer);
+                    }
+                });
+            }
+        });
+        next.into_inner()
+    }
+
+    /// Adds the buffers of the traced tiles to the film, in the order of the tiles so the
+    /// splats are always summed in the same order
+    fn merge_tiles(&mut self, tiles: std::ops::Range<usize>) {
+        for index in tiles {
+            let tile = &self.tiles[index];
+            let buffer = self.tile_buffers[index].get_mut().unwrap();
+            let channels = buffer.lighting.len() / tile.pixel_count();
+            for (i, (x, y)) in tile.pixels().enumerate() {
+                let Some(color) = buffer.colors[i] else {
+                    continue;
+                };
+                let pixel = (y * self.width + x) as usize;
+                self.image.put_pixel(x, y, color.into());
+                self.stats[pixel] = buffer.stats[i];
+                if let Some(film) = &mut self.aov_film {
+                    film.merge_lighting(pixel, &buffer.lighting[i * channels..(i + 1) * channels]);
+                }
+            }
+            for splat in &buffer.splats {
+                self.splats[(splat.y * self.width + splat.x) as usize] += splat.color;
+            }
+        }
+    }
+
+    fn finish_pass(&mut self) {
+        if self.feature_samples < Self::FEATURE_SAMPLES {
+            let started = std::time::Instant::now();
+            self.trace_features();
+            self.timings.add(Phase::Features, started.elapsed());
+        }
         self.samples += Self::SINGLE_SHOT_SAMPLES as u64;
-        let elapsed = now.elapsed();
+        let elapsed = self.pass_started.elapsed();
         let total_elapsed = self.started.elapsed();
-        println!("Elapsed: {:?} (fps: {}, {:?}) {} samples ({} ms/sa)", total_elapsed, 1.0 / (elapsed.as_secs_f32() + EPSILON), elapsed, self.samples, total_elapsed.as_millis() as f32 / self.samples as f32);
+        log::info!("Elapsed: {:?} (fps: {}, {:?}) {} samples ({} ms/sa)", total_elapsed, 1.0 / (elapsed.as_secs_f32() + f32::EPSILON), elapsed, self.samples, total_elapsed.as_millis() as f32 / (self.samples - self.started_samples) as f32);
+        if let Some(adaptive) = self.adaptive {
+            let region = self.region();
+            let active = Tile::from(region).pixels().filter(|&(x, y)| !self.stats[(y * self.width + x) as usize].converged(&adaptive)).count();
+            log::info!("{} of {} pixels still above the noise level", active, region.width * region.height);
+        }
+    }
+
+    /// Samples per pixel of the passes done, plus the part of the current pass that's done
+    pub fn samples_done(&self) -> f64 {
+        let pass = self.next_tile as f64 / self.tiles.len().max(1) as f64;
+        self.samples as f64 + pass * Self::SINGLE_SHOT_SAMPLES as f64
+    }
+
+    /// Average of the samples taken by the pixels that are rendered
+    pub fn samples_per_pixel(&self) -> f64 {
+        if self.integrator_kind.keeps_own_estimate() {
+            // The pixels aren't sampled one by one, every pass adds the same samples to all of them
+            return self.samples as f64;
+        }
+        let region = self.region();
+        let samples: u64 = Tile::from(region).pixels().map(|(x, y)| self.stats[(y * self.width + x) as usize].samples()).sum();
+        samples as f64 / (region.width * region.height) as f64
+    }
+
+    /// Part of the rendered pixels that stopped sampling, with adaptive sampling
+    pub fn converged_fraction(&self) -> Option<f32> {
+        let adaptive = self.adaptive?;
+        let region = self.region();
+        let converged = Tile::from(region).pixels().filter(|&(x, y)| self.stats[(y * self.width + x) as usize].converged(&adaptive)).count();
+        Some(converged as f32 / (region.width * region.height) as f32)
+    }
 
+    pub fn timings(&self) -> Timings {
+        self.timings
+    }
 
+    /// The next `count` tiles of the pass in progress, none between passes
+    pub fn pending_tiles(&self, count: usize) -> &[Tile] {
+        if self.next_tile == 0 {
+            return &[];
+        }
+        &self.tiles[self.next_tile..(self.next_tile + count).min(self.tiles.len())]
     }
     const SINGLE_SHOT_SAMPLES: i32 = 32;
+    /// The features are anti-aliased with a few samples, spread over the first passes
+    const FEATURE_SAMPLES: u32 = 16;
+    const FEATURE_SAMPLES_PER_PASS: u32 = 4;
 
-    fn trace(&self, r: Ray, x: u32, y: u32, depth: u16) -> ColorF32 {
-        let color = if let Some(intersection) = self.world.intersect(&r) {
-            let intersection =Box::new( intersection);
-            let color = (0..Self::SINGLE_SHOT_SAMPLES).map(|_| {
-                self.ray_color(&intersection, &r, depth)
-                // }).reduce(||->ColorF32 {ColorF32::new(0.0,0.0,0.0)}, |x, y| x + y);
-                }).reduce(|x, y| x + y).unwrap();
-            color/(Self::SINGLE_SHOT_SAMPLES as f32)
-        } else {
-           self.world.background_color(&r)
-        };
+    /// Adds the next feature samples of every pixel that's rendered, and of the geometric AOVs
+    fn trace_features(&mut self) {
+        let this = &*self;
+        let region = self.region();
+        let features = (region.y..region.y + region.height).into_par_iter().flat_map_iter(|y| {
+            let mut sampler = this.sampler_kind.build(this.seed, Self::FEATURE_SAMPLES);
+            (region.x..region.x + region.width).map(move |x| {
+                let mut mean = Features::default();
+                let mut geometry = this.aov_film.as_ref().map(AovFilm::empty_geometry);
+                for i in 0..Self::FEATURE_SAMPLES_PER_PASS {
+                    sampler.start_pixel_sample(x, y, (this.feature_samples + i) as u64);
+                    let (dx, dy) = sampler.get_pixel_2d();
+                    // Moving objects blur the features like the beauty pass
+                    let time = this.camera.sample_time(sampler.get_1d());
+                    let ray = this.camera.generate_ray(x as f32 + dx, y as f32 + dy, time);
+                    let hit = this.world.intersect(&ray);
+                    let features = Features::first_hit(&this.world, &ray, hit.as_ref());
+                    if let (Some(film), Some(geometry)) = (&this.aov_film, &mut geometry) {
+                        film.add_first_hit(&ray, hit.as_ref(), &features, geometry);
+                    }
+                    mean = mean.blend(i, &features, 1);
+                }
+                (mean, geometry)
+            }).collect::<Vec<_>>()
+        }).collect::<Vec<_>>();
+        for ((x, y), (added, geometry)) in Tile::from(region).pixels().zip(features) {
+            let pixel = (y * self.width + x) as usize;
+            self.features[pixel] = self.features[pixel].blend(self.feature_samples, &added, Self::FEATURE_SAMPLES_PER_PASS);
+            if let (Some(film), Some(geometry)) = (&mut self.aov_film, geometry) {
+                film.merge_geometry(pixel, &geometry);
+            }
+        }
+        self.feature_samples += Self::FEATURE_SAMPLES_PER_PASS;
+    }
+
+    /// Takes the next samples of the pixels of a tile that haven't converged, into its buffer
+    fn trace_tile(&self, tile: &Tile, buffer: &mut TileBuffer) {
+        let mut sampler = self.sampler_kind.build(self.seed, Self::SINGLE_SHOT_SAMPLES as u32);
+        let mut ctx = TraceContext::new(&self.world, &self.camera, sampler.as_mut());
+        let film = self.aov_film.as_ref().filter(|film| film.needs_lighting());
+        let channels = film.map_or(0, AovFilm::lighting_channel_count);
+        buffer.colors.resize(tile.pixel_count(), None);
+        buffer.stats.resize(tile.pixel_count(), PixelStats::default());
+        buffer.lighting.resize(tile.pixel_count() * channels, 0.0);
+        buffer.splats.clear();
+        ctx.splats = std::mem::take(&mut buffer.splats);
+        if film.is_some() {
+            ctx.lighting = Some(std::mem::take(&mut buffer.contributions));
+        }
+        for (i, (x, y)) in tile.pixels().enumerate() {
+            let mut stats = self.stats[(y * self.width + x) as usize];
+            if self.adaptive.is_some_and(|adaptive| stats.converged(&adaptive)) {
+                buffer.colors[i] = None;
+                continue;
+            }
+            let lighting = &mut buffer.lighting[i * channels..(i + 1) * channels];
+            lighting.fill(0.0);
+            buffer.colors[i] = Some(self.trace(x, y, &mut ctx, &mut stats, film, lighting));
+            buffer.stats[i] = stats;
+        }
+        buffer.splats = ctx.splats;
+        buffer.contributions = ctx.lighting.unwrap_or_default();
+    }
+
+    /// Takes the next samples of a pixel, returning the mean of all its samples. `lighting`
+    /// gets the sums of the lighting AOVs over the new samples.
+    fn trace(&self, x: u32, y: u32, ctx: &mut TraceContext, stats: &mut PixelStats, film: Option<&AovFilm>, lighting: &mut [f32]) -> ColorF32 {
+        // Pixels that stopped early are behind the others, their samples are numbered per pixel
+        let samples = stats.samples();
+        let color = (0..Self::SINGLE_SHOT_SAMPLES).map(|i| {
+            ctx.sampler.start_pixel_sample(x, y, samples + i as u64);
+            // Jitter inside the pixel, so it matches the splats that can land anywhere in it
+            let (dx, dy) = ctx.sampler.get_pixel_2d();
+            let time = self.camera.sample_time(ctx.sampler.get_1d());
+            let r = self.camera.generate_ray(x as f32 + dx, y as f32 + dy, time);
+            let color = self.integrator.li(&r, ctx);
+            stats.add(color);
+            if let (Some(film), Some(contributions)) = (film, &mut ctx.lighting) {
+                film.add_lighting(contributions, lighting);
+                contributions.clear();
+            }
+            color
+            }).reduce(|x, y| x + y).unwrap();
+        let color = color/(Self::SINGLE_SHOT_SAMPLES as f32);
         // Load the color from the current pixel
-        if self.samples > 0 {
+        if samples > 0 {
             let orig = self.image.get_pixel(x, y);
             let orig : ColorF32 = orig.into();
-            orig * (self.samples as f32 / (self.samples as f32 + Self::SINGLE_SHOT_SAMPLES as f32)) + color * (Self::SINGLE_SHOT_SAMPLES as f32 / (self.samples as f32 + Self::SINGLE_SHOT_SAMPLES as f32))
+            orig * (samples as f32 / (samples as f32 + Self::SINGLE_SHOT_SAMPLES as f32)) + color * (Self::SINGLE_SHOT_SAMPLES as f32 / (samples as f32 + Self::SINGLE_SHOT_SAMPLES as f32))
         } else {
             color
         }
     }
 
+    /// The accumulated image with the splats added in, black outside the crop window
+    fn resolve(&self) -> Rgba32FImage {
+        let mut image = self.image.clone();
+        if let Some(crop) = self.crop {
+            for (x, y, pixel) in image.enumerate_pixels_mut() {
+                if !crop.contains(x, y) {
+                    *pixel = image::Rgba([0.0, 0.0, 0.0, 1.0]);
+                }
+            }
+        }
+        // Every camera sample traced a light path that splats anywhere, so the splats are
+        // divided by the average samples per pixel even when some pixels stopped early
+        let traced: u64 = self.stats.iter().map(PixelStats::samples).sum();
+        if traced > 0 {
+            let samples_per_pixel = traced as f32 / self.stats.len() as f32;
+            for (x, y, pixel) in image.enumerate_pixels_mut() {
+                let color: ColorF32 = (&*pixel).into();
+                if self.crop.is_some_and(|crop| !crop.contains(x, y)) {
+                    continue;
+                }
+                let splat = self.splats[(y * self.width + x) as usize] / samples_per_pixel;
+                *pixel = (color + splat).into();
+            }
+        }
+        image
+    }
+
+    /// The tone mapped, sRGB encoded image for the viewer, denoised if a denoiser is set
     pub fn present(&self) ->image::DynamicImage { 
-        // ...
-        DynamicImage::ImageRgba32F(self.image.clone())
+        let resolved = self.resolve();
+        let image = match self.denoiser {
+            Some(denoiser) => self.denoise(&denoiser, &resolved),
+            None => resolved,
+        };
+        DynamicImage::ImageRgba8(self.tone_mapping.map_image(&image))
     }
 
-    pub(crate) fn world(&mut self) -> &mut World{
-        &mut self.world
+    /// Denoises the pixels that are rendered on their own, so the black outside a crop window
+    /// doesn't bleed into it
+    fn denoise(&self, denoiser: &Denoiser, image: &Rgba32FImage) -> Rgba32FImage {
+        let Some(crop) = self.crop else {
+            return denoiser.denoise(image, &self.features);
+        };
+        let region = image::imageops::crop_imm(image, crop.x, crop.y, crop.width, crop.height).to_image();
+        let features: Vec<Features> = Tile::from(crop).pixels().map(|(x, y)| self.features[(y * self.width + x) as usize]).collect();
+        let mut denoised = image.clone();
+        image::imageops::replace(&mut denoised, &denoiser.denoise(&region, &features), crop.x as i64, crop.y as i64);
+        denoised
     }
-    const MAX_DEPTH: u16 = 32;
-
-    fn ray_color(&self, intersection: &crate::world::Intersection<'_>, ray: &Ray, depth: u16) -> ColorF32 {
-        let material = intersection.object.material();
-        let emisivty  = material.emissivity();
-        let reflection = if depth < Self::MAX_DEPTH {
-            let scatter = material.scatter(ray, intersection);
-            if let Some(scatter) = scatter {
-                let random_ray = scatter.ray;
-                
-                let random_intersection = self.world.intersect(&random_ray);
-                if let Some(random_intersection) = random_intersection {
-                    self.ray_color(&random_intersection, &random_ray, depth + 1) * scatter.attenuation
-                } else {
-                    self.world.background_color(&random_ray) * scatter.attenuation
-                }
-            } else {
-                // No scatter, so no color
-                color::BLACK
+
+    /// Writes the image in `format`. With a denoiser it's denoised, and the noisy image is kept
+    /// next to it with a `_noisy` suffix. PNGs, and formats that can't store the AOVs when there
+    /// are some, get a linear EXR with the same name next to them. With a crop window, the images
+    /// are either cut to it or keep the size of the film, with the crop as the EXR data window.
+    fn write_images(&self, output: &path::Path, format: OutputFormat) -> anyhow::Result<()> {
+        let (cut, data_window) = match self.crop {
+            Some(crop) if self.crop_output == CropOutput::Cropped => (Some(crop), None),
+            crop => (None, crop),
+        };
+        let frame = |image: Rgba32FImage| match cut {
+            Some(crop) => image::imageops::crop_imm(&image, crop.x, crop.y, crop.width, crop.height).to_image(),
+            None => image,
+        };
+        let layers = match &self.aov_film {
+            Some(film) => film.resolve(self.feature_samples, &self.stats),
+            None => Vec::new(),
+        };
+        let layers: Vec<(String, Vec<f32>)> = match cut {
+            Some(crop) => layers
+                .into_iter()
+                .map(|(name, values)| (name, Tile::from(crop).pixels().map(|(x, y)| values[(y * self.width + x) as usize]).collect()))
+                .collect(),
+            None => layers,
+        };
+        let resolved = self.resolve();
+        let image = match self.denoiser {
+            Some(denoiser) => {
+                let stem = output.file_stem().and_then(|stem| stem.to_str()).unwrap_or("render");
+                let noisy = output.with_file_name(format!("{}_noisy.{}", stem, format.extension()));
+                format.write(&noisy, &frame(resolved.clone()), &self.tone_mapping, &[], data_window)?;
+                self.denoise(&denoiser, &resolved)
             }
-        } else {
-            // Max depth reached, don't recurse
-            material.color() * 0.1 // Ambiente?
-            
+            None => resolved,
         };
-        
-        emisivty + reflection
+        let image = frame(image);
+        format.write(output, &image, &self.tone_mapping, &layers, data_window)?;
+        if format.needs_exr(!layers.is_empty()) {
+            OutputFormat::DEFAULT_EXR.write(&output.with_extension("exr"), &image, &self.tone_mapping, &layers, data_window)?;
+        }
+        if !layers.is_empty() {
+            aov::write_pngs(output, image.width(), image.height(), &layers)?;
+        }
+        Ok(())
+    }
+
+    /// The scene, to add objects to before rendering. Changing it doesn't restart the
+    /// accumulation, call `reset` afterwards.
+    pub fn world(&mut self) -> &mut World{
+        &mut self.world
+    }
+
+    pub fn camera(&self) -> &Camera {
+        &self.camera
     }
 
-    pub(crate) fn camera_mut(&mut self) -> &mut Camera {
+    /// The camera, to move it. Moving it doesn't restart the accumulation, call `reset`
+    /// afterwards.
+    pub fn camera_mut(&mut self) -> &mut Camera {
         &mut self.camera
     }
 
-    pub(crate) fn save(&self) {
+    /// Saves a PNG, and the EXR next to it, under the first free name in the results folder,
+    /// returning the path of the PNG
+    pub fn save(&self) -> anyhow::Result<path::PathBuf> {
+        self.save_in_results(OutputFormat::Png { bits: 8 })
+    }
+
+    fn save_in_results(&self, format: OutputFormat) -> anyhow::Result<path::PathBuf> {
+        std::fs::create_dir_all("results")?;
         // Find a unique filename
         let mut i = 0;
         loop {
-            let filename = format!("results/render_{}.png", i);
-            if !std::path::Path::new(&filename).exists() {
-                // Save the image
-                let dynamic_image = DynamicImage::ImageRgba32F(self.image.clone());
-                dynamic_image.into_rgba8().save(&filename).unwrap();
-                self.image.save_with_format(path::Path::new(&format!("results/render_hdr_{}.exr",i)), image::ImageFormat::OpenExr).unwrap();
-                break;
+            let filename = path::PathBuf::from(format!("results/render_{}.{}", i, format.extension()));
+            if !filename.exists() {
+                self.write_images(&filename, format)?;
+                return Ok(filename);
             }
             i += 1;
         }
     }
 
-    pub(crate) fn samples(&self) -> u64 {
+    /// Samples per pixel of the passes done so far, the pixels that converged took fewer
+    pub fn samples(&self) -> u64 {
         self.samples
     }
 
-    pub(crate) fn save_as(&self, output: String)  {
-            if !std::path::Path::new(&output).exists() {
-                // Save the image
-                let dynamic_image = DynamicImage::ImageRgba32F(self.image.clone());
-                dynamic_image.into_rgba8().save(&output).unwrap();
-                let path_withou_extension = path::Path::new(&output).file_stem().unwrap().to_str().unwrap();
-                self.image.save_with_format(path::Path::new(&format!("{}.exr",path_withou_extension)), image::ImageFormat::OpenExr).unwrap();
+    /// Saves the image at `output` in `format`, or in the results folder if the file exists,
+    /// returning the path it was saved at
+    pub fn save_as(&self, output: &path::Path, format: OutputFormat) -> anyhow::Result<path::PathBuf> {
+        if output.exists() {
+            return self.save_in_results(format);
+        }
+        self.write_images(output, format)?;
+        Ok(output.to_path_buf())
+    }
+
+    /// Saves the image at `output` in `format`, replacing the file if it exists
+    pub fn overwrite(&self, output: &path::Path, format: OutputFormat) -> anyhow::Result<()> {
+        self.write_images(output, format)
+    }
+
+    /// Hash of the scene and of the settings the accumulation depends on: the camera with its
+    /// path and motion, and every object with its placement, motion and materials, whether the
+    /// camera sees it or not
+    fn fingerprint(&self) -> u64 {
+        let mut fingerprint = Fingerprint::default();
+        fingerprint.add(self.width as u64);
+        fingerprint.add(self.height as u64);
+        let (origin, direction) = (self.camera.origin(), self.camera.direction());
+        let (open, close) = self.camera.shutter();
+        for value in [origin.x, origin.y, origin.z, direction.x, direction.y, direction.z, self.camera.fov(), open, close] {
+            fingerprint.add_f32(value);
+        }
+        fingerprint.add_debug(&(self.camera.path(), self.camera.motion()));
+        fingerprint.add_str(&self.integrator_kind.to_string());
+        fingerprint.add_str(&self.sampler_kind.to_string());
+        fingerprint.add(self.seed);
+        fingerprint.add(Self::SINGLE_SHOT_SAMPLES as u64);
+        // The tiles decide the order in which the splats are summed
+        fingerprint.add_str(&self.tile_settings.to_string());
+        fingerprint.add_str(&self.crop.map_or_else(|| "full".to_string(), |crop| crop.to_string()));
+        for aov in &self.aovs {
+            fingerprint.add_str(&aov.to_string());
+        }
+        fingerprint.add(self.world.objects.len() as u64);
+        for object in &self.world.objects {
+            object.fingerprint(&mut fingerprint);
+        }
+        fingerprint.value()
+    }
+
+    /// Writes everything accumulated so far to `path`, to continue the render with `resume` if
+    /// the process stops. The integrators that keep their own estimate can't be checkpointed.
+    pub fn save_checkpoint(&self, path: &path::Path) -> anyhow::Result<()> {
+        if self.integrator_kind.keeps_own_estimate() {
+            anyhow::bail!("the {} integrator keeps its own estimate between passes and can't be checkpointed", self.integrator_kind);
+        }
+        let mut checkpoint = CheckpointWriter::default();
+        checkpoint.u64(self.fingerprint());
+        checkpoint.u64(self.samples);
+        checkpoint.u32(self.feature_samples);
+        checkpoint.u64(self.next_tile as u64);
+        checkpoint.f32s(self.image.as_raw());
+        let splats: Vec<f32> = self.splats.iter().flat_map(|splat| [splat.r, splat.g, splat.b]).collect();
+        checkpoint.f32s(&splats);
+        // The sample counts are also the state of the samplers, which number the samples of
+        // each pixel from them
+        for stats in &self.stats {
+            stats.write(&mut checkpoint);
+        }
+        let features: Vec<f32> = self.features.iter().flat_map(|features| {
+            let (albedo, normal) = (features.albedo, features.normal);
+            [albedo.r, albedo.g, albedo.b, normal.x, normal.y, normal.z]
+        }).collect();
+        checkpoint.f32s(&features);
+        if let Some(film) = &self.aov_film {
+            film.write(&mut checkpoint);
+        }
+        checkpoint.finish(path)
+    }
+
+    /// Continues the accumulation saved by `save_checkpoint`, the next passes add their samples
+    /// to it. Fails if the scene or the settings changed since the checkpoint was written.
+    pub fn resume(&mut self, path: &path::Path) -> anyhow::Result<()> {
+        let mut checkpoint = CheckpointReader::open(path)?;
+        if checkpoint.u64()? != self.fingerprint() {
+            anyhow::bail!(
+                "'{}' was rendered from another scene or with other settings (size, camera, integrator, seed, sampler or AOVs)",
+                path.display()
+            );
+        }
+        let pixels = (self.width * self.height) as usize;
+        let samples = checkpoint.u64()?;
+        let feature_samples = checkpoint.u32()?;
+        let next_tile = checkpoint.u64()? as usize;
+        let image = Rgba32FImage::from_raw(self.width, self.height, checkpoint.f32s(4 * pixels)?)
+            .ok_or_else(|| anyhow::anyhow!("the checkpoint image doesn't match the size of the render"))?;
+        let splats = checkpoint.f32s(3 * pixels)?.chunks_exact(3).map(|splat| ColorF32::new(splat[0], splat[1], splat[2])).collect();
+        let stats = (0..pixels).map(|_| PixelStats::read(&mut checkpoint)).collect::<anyhow::Result<Vec<_>>>()?;
+        let features = checkpoint.f32s(6 * pixels)?.chunks_exact(6).map(|features| Features {
+            albedo: ColorF32::new(features[0], features[1], features[2]),
+            normal: Vector3::new(features[3], features[4], features[5]),
+        }).collect();
+        let mut aov_film = if samples > 0 || next_tile > 0 { self.new_aov_film() } else { None };
+        if let Some(film) = &mut aov_film {
+            film.read(&mut checkpoint)?;
+        }
+
+        self.reset();
+        self.samples = samples;
+        self.feature_samples = feature_samples;
+        self.next_tile = next_tile;
+        self.image = image;
+        self.splats = splats;
+        self.stats = stats;
+        self.features = features;
+        self.aov_film = aov_film;
+        self.started = std::time::Instant::now();
+        self.started_samples = samples;
+        log::info!("Resuming from {} samples per pixel", samples);
+        Ok(())
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use nalgebra::Vector3;
+
+    use crate::{
+        animation::{CameraKey, CameraPath},
+        color,
+        geometry::{Plane, Point, Sphere},
+        material::{Dielectric, Diffuse, Emmisive},
+    };
+
+    use super::{Aov, Crop, Pathtracer};
+
+    /// A small film over a gray floor lit by the sky, and by a small bright sphere with `emitter`
+    fn test_pathtracer(emitter: bool) -> Pathtracer {
+        let mut pathtracer = Pathtracer::new(16, 12);
+        let world = pathtracer.world();
+        world.add_object(Box::new(Plane::new(
+            Point::new(0.0, -1.0, 0.0),
+            Vector3::new(0.0, 1.0, 0.0),
+            Box::new(Diffuse::new(color::GRAY)),
+        )));
+        if emitter {
+            world.add_object(Box::new(Sphere::new_with_material(4.0, 1.0, 1.0, 0.5, Box::new(Emmisive::new(color::WHITE, 10.0)))));
+        }
+        pathtracer
+    }
+
+    /// Raw bits of the image after two passes, rendered on a pool of `threads` threads
+    fn render(integrator: &str, seed: u64, threads: usize) -> Vec<u32> {
+        let pool = rayon::ThreadPoolBuilder::new().num_threads(threads).build().unwrap();
+        pool.install(|| {
+            let mut pathtracer = test_pathtracer(true);
+            pathtracer.world().add_object(Box::new(Sphere::new_with_material(2.0, 0.0, 2.0, 1.0, Box::new(Dielectric::new(color::WHITE, 0.0, 1.5)))));
+            pathtracer.set_integrator(integrator.parse().unwrap());
+            pathtracer.set_seed(seed);
+            pathtracer.render();
+            pathtracer.render();
+            pathtracer.resolve().into_raw().into_iter().map(f32::to_bits).collect()
+        })
+    }
+
+    #[test]
+    fn same_seed_renders_the_same_image_on_any_number_of_threads() {
+        for integrator in ["path", "bdpt", "sppm:photons=2000", "mlt:bootstrap=2000,chains=16"] {
+            assert!(render(integrator, 3, 1) == render(integrator, 3, 4), "{} depends on the threads", integrator);
+        }
+        assert!(render("path", 3, 4) != render("path", 4, 4));
+    }
+
+    #[test]
+    fn rendering_tile_by_tile_matches_whole_passes() {
+        let render = |one_tile_at_a_time: bool| {
+            let mut pathtracer = test_pathtracer(true);
+            pathtracer.set_integrator("bdpt".parse().unwrap());
+            pathtracer.set_tiles("spiral:5".parse().unwrap());
+            while pathtracer.samples() < 64 {
+                if one_tile_at_a_time {
+                    // A deadline in the past still renders one tile per call
+                    pathtracer.render_until(Some(std::time::Instant::now()));
+                } else {
+                    pathtracer.render();
+                }
             }
-            else {
-                println!("File already exists. Saving in results folder");
-                self.save();
+            pathtracer.resolve().into_raw().into_iter().map(f32::to_bits).collect::<Vec<_>>()
+        };
+        assert!(render(true) == render(false));
+    }
+
+    #[test]
+    fn crop_window_renders_the_same_pixels_as_the_full_frame() {
+        let render = |crop: Option<Crop>| {
+            let mut pathtracer = test_pathtracer(false);
+            pathtracer.set_crop(crop);
+            pathtracer.render();
+            pathtracer.resolve()
+        };
+        let crop: Crop = "3,2,7,5".parse().unwrap();
+        let (full, cropped) = (render(None), render(Some(crop)));
+        for (x, y, pixel) in cropped.enumerate_pixels() {
+            if crop.contains(x, y) {
+                assert_eq!(pixel, full.get_pixel(x, y));
+            } else {
+                assert_eq!(pixel[0], 0.0);
             }
+        }
     }
 
-    pub(crate) fn printCamera(&self)  {
-        println!("Camera is at {:?}", self.camera.origin());
+    #[test]
+    fn lighting_aovs_add_up_to_the_beauty_pass() {
+        let mut pathtracer = test_pathtracer(true);
+        pathtracer.set_aovs(Aov::ALL.to_vec());
+        pathtracer.render();
+        pathtracer.render();
+
+        let beauty = pathtracer.resolve();
+        let layers = pathtracer.aov_film.as_ref().unwrap().resolve(pathtracer.feature_samples, &pathtracer.stats);
+        let layer = |name: &str| &layers.iter().find(|(other, _)| other == name).unwrap().1;
+        for (i, pixel) in beauty.pixels().enumerate() {
+            let by_bounce = layer("emission.R")[i] + layer("direct.R")[i] + layer("indirect.R")[i];
+            let by_light = layer("light_background.R")[i] + layer("light_object_1.R")[i];
+            assert!((by_bounce - pixel[0]).abs() <= 1e-3 * pixel[0].max(1.0), "{} against {}", by_bounce, pixel[0]);
+            assert!((by_light - pixel[0]).abs() <= 1e-3 * pixel[0].max(1.0), "{} against {}", by_light, pixel[0]);
+            let coverage = layer("mask.object_0")[i] + layer("mask.object_1")[i];
+            assert!(coverage <= 1.0 + 1e-6);
+            assert_eq!(coverage == 0.0, layer("depth.Z")[i].is_infinite());
+        }
     }
 
+    #[test]
+    fn resuming_a_checkpoint_continues_the_same_render() {
+        let scene = |seed: u64| {
+            let mut pathtracer = test_pathtracer(false);
+            pathtracer.set_seed(seed);
+            pathtracer.set_aovs(vec![Aov::Depth, Aov::Direct]);
+            pathtracer
+        };
+        let checkpoint = std::env::temp_dir().join(format!("pathtracer_checkpoint_{}", std::process::id()));
+        let mut continued = scene(5);
+        continued.render();
+        continued.save_checkpoint(&checkpoint).unwrap();
+        continued.render();
+
+        let mut resumed = scene(5);
+        resumed.resume(&checkpoint).unwrap();
+        resumed.render();
+        assert_eq!(resumed.samples(), continued.samples());
+        assert!(resumed.resolve() == continued.resolve());
+        let layers = |pathtracer: &Pathtracer| pathtracer.aov_film.as_ref().unwrap().resolve(pathtracer.feature_samples, &pathtracer.stats);
+        assert!(layers(&resumed) == layers(&continued));
+
+        assert!(scene(6).resume(&checkpoint).is_err());
+        std::fs::remove_file(&checkpoint).unwrap();
+
+        // Edits the camera doesn't see still make another scene
+        let with_glass = |center: Point, refraction_index: f32| {
+            let mut pathtracer = scene(5);
+            let glass = Box::new(Dielectric::new(color::WHITE, 0.0, refraction_index));
+            pathtracer.world().add_object(Box::new(Sphere::new_with_material(center.x, center.y, center.z, 0.5, glass)));
+            pathtracer.fingerprint()
+        };
+        let behind = Point::new(0.0, 0.0, 50.0);
+        assert_eq!(with_glass(behind, 1.5), with_glass(behind, 1.5));
+        assert_ne!(with_glass(behind, 1.5), with_glass(Point::new(1.0, 0.0, 50.0), 1.5));
+        assert_ne!(with_glass(behind, 1.5), with_glass(behind, 1.6));
+        let mut flying = scene(5);
+        flying.camera_mut().set_path(Some(CameraPath::new(vec![CameraKey::new(0.0, Point::origin(), Point::new(0.0, 0.0, -1.0), 60.0)])));
+        assert_ne!(flying.fingerprint(), scene(5).fingerprint());
+    }
+
+    #[test]
+    fn adaptive_sampling_stops_flat_pixels_first() {
+        let mut pathtracer = test_pathtracer(false);
+        pathtracer.set_adaptive(Some("noise=0.01,min=64,max=1024".parse().unwrap()));
+        while !pathtracer.converged() {
+            pathtracer.render();
+        }
+        // The sky is the same in every direction, the plane is lit by a random part of it
+        let sky = pathtracer.stats[0];
+        let ground = pathtracer.stats[pathtracer.stats.len() - 1];
+        assert_eq!(sky.samples(), 64);
+        assert!(ground.samples() > sky.samples());
+        assert!(ground.samples() == 1024 || ground.relative_error() <= 0.01);
+    }
 }
@@ -1,19 +1,12 @@
-use std::path::Path;
-
-use crate::{
-    color,
-    geometry::{Plane, Point, Sphere, Mesh}, 
-    light::{DirectionalLight, PointLight},
-    raytracer::Pathtracer,
-    renderer::texture, world::World, material, scene,
-};
-use nalgebra::Vector3;
+use pathtracer::{denoise::Denoiser, raytracer::Pathtracer, scene::SceneKind, tiles};
 use wgpu::util::DeviceExt;
 use winit::{
     event::{VirtualKeyCode, WindowEvent},
     window::Window,
 };
 
+use crate::renderer::texture;
+
 #[repr(C)]
 #[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
 struct Vertex {
@@ -71,10 +64,12 @@ pub struct State {
     index_buffer: wgpu::Buffer,
     diffuse_bind_group: wgpu::BindGroup,
     diffuse_texture: texture::Texture,
-    pathtracer: crate::raytracer::Pathtracer,
+    pathtracer: Pathtracer,
     last_update: std::time::Instant,
     mouse_pressed: bool,
     mouse_position: winit::dpi::PhysicalPosition<f64>,
+    /// Pixel where the right button was pressed, while a crop window is dragged
+    crop_start: Option<(u32, u32)>,
 }
 
 impl State {
@@ -261,6 +256,7 @@ impl State {
             last_update: std::time::Instant::now(),
             mouse_pressed: false,
             mouse_position: Default::default(),
+            crop_start: None,
         }
     }
 
@@ -325,17 +321,46 @@ impl State {
                     };
                     true
                 }
-                Some(VirtualKeyCode::P) => {
-                    if winit::event::ElementState::Pressed == input.state {
-                        self.pathtracer.save();
-                        true
-                    } else {
-                        false
+                Some(VirtualKeyCode::P) if input.state == winit::event::ElementState::Pressed => {
+                    match self.pathtracer.save() {
+                        Ok(saved) => println!("Saved to '{}'", saved.display()),
+                        Err(e) => eprintln!("{:#}", e),
                     }
+                    true
+                }
+                Some(VirtualKeyCode::I) if input.state == winit::event::ElementState::Pressed => {
+                    let next = self.pathtracer.integrator_kind().next();
+                    println!("Integrator: {}", next);
+                    self.pathtracer.set_integrator(next);
+                    true
+                }
+                Some(VirtualKeyCode::T) if input.state == winit::event::ElementState::Pressed => {
+                    let mut tone_mapping = self.pathtracer.tone_mapping();
+                    tone_mapping.operator = tone_mapping.operator.next();
+                    println!("Tone mapping: {}", tone_mapping);
+                    self.pathtracer.set_tone_mapping(tone_mapping);
+                    true
+                }
+                Some(VirtualKeyCode::N) if input.state == winit::event::ElementState::Pressed => {
+                    let denoiser = match self.pathtracer.denoiser() {
+                        Some(_) => None,
+                        None => Some(Denoiser::default()),
+                    };
+                    println!("Denoiser: {}", if denoiser.is_some() { "on" } else { "off" });
+                    self.pathtracer.set_denoiser(denoiser);
+                    true
+                }
+                Some(VirtualKeyCode::Equals) | Some(VirtualKeyCode::Minus) if input.state == winit::event::ElementState::Pressed => {
+                    let mut tone_mapping = self.pathtracer.tone_mapping();
+                    let step = if input.virtual_keycode == Some(VirtualKeyCode::Equals) { 0.5 } else { -0.5 };
+                    tone_mapping.exposure += step;
+                    println!("Tone mapping: {}", tone_mapping);
+                    self.pathtracer.set_tone_mapping(tone_mapping);
+                    true
                 }
                 Some(VirtualKeyCode::C) => {
                     // Print camera pos
-                    self.pathtracer.printCamera();
+                    println!("Camera is at {:?}", self.pathtracer.camera().origin());
                     true
                 }
                 _ => false,
@@ -352,6 +377,26 @@ impl State {
                 };
                 true
             }
+            // Dragging with the right button only renders the pixels inside the box, a right
+            // click without moving renders the whole frame again
+            WindowEvent::MouseInput {
+                state,
+                button: winit::event::MouseButton::Right,
+                ..
+            } => {
+                if *state == winit::event::ElementState::Pressed {
+                    self.crop_start = Some(self.mouse_pixel());
+                } else if let Some(start) = self.crop_start.take() {
+                    let end = self.mouse_pixel();
+                    let crop = (start != end).then(|| tiles::Crop::from_corners(start, end));
+                    match crop {
+                        Some(crop) => println!("Crop window: {}", crop),
+                        None => println!("Crop window: full frame"),
+                    }
+                    self.pathtracer.set_crop(crop);
+                }
+                true
+            }
             WindowEvent::CursorMoved { device_id: _, position, ..} => {
                 if self.mouse_pressed {
                     let delta_x = (self.mouse_position.x - position.x) as f32;
@@ -367,12 +412,23 @@ impl State {
         }
     }
 
+    /// Pixel of the film under the mouse
+    fn mouse_pixel(&self) -> (u32, u32) {
+        let x = self.mouse_position.x.clamp(0.0, self.size.width.saturating_sub(1) as f64);
+        let y = self.mouse_position.y.clamp(0.0, self.size.height.saturating_sub(1) as f64);
+        (x as u32, y as u32)
+    }
+
+    /// Time spent rendering tiles each frame, so the viewer stays responsive
+    const FRAME_BUDGET: std::time::Duration = std::time::Duration::from_millis(50);
+
     pub fn update(&mut self) {
         let now = std::time::Instant::now();
         let delta_time = (now - self.last_update).as_secs_f32();
         self.last_update = now;
         self.pathtracer.camera_mut().update(delta_time);
-        self.pathtracer.render();
+        // Passes are spread over several frames when they take longer than the budget
+        self.pathtracer.render_until(Some(now + Self::FRAME_BUDGET));
     }
 
     pub fn render(&mut self) -> Result<(), wgpu::SurfaceError> {
@@ -388,7 +444,17 @@ impl State {
             });
         // Update the image data
 
-        let image = self.pathtracer.present();
+        let mut image = self.pathtracer.present();
+        // Mark the tiles the threads take next while a pass is in progress
+        if let Some(rgba) = image.as_mut_rgba8() {
+            for tile in self.pathtracer.pending_tiles(rayon::current_num_threads()) {
+                tiles::outline(rgba, tile);
+            }
+            let dragged = self.crop_start.map(|start| tiles::Crop::from_corners(start, self.mouse_pixel()));
+            if let Some(crop) = dragged.or(self.pathtracer.crop()) {
+                tiles::frame(rgba, &crop);
+            }
+        }
         let new_texture = texture::Texture::from_image(
             &self.device,
             &self.queue,
@@ -470,13 +536,11 @@ impl State {
         Ok(())
     }
 
-    pub(crate) fn init(&mut self) {
-        let w = self.pathtracer.world();
-        scene::build_scene(w);
+    pub(crate) fn init(&mut self, scene: SceneKind) -> anyhow::Result<()> {
+        scene.build(&mut self.pathtracer)
     }
 
-    pub fn create_cornell_box(world: &mut World) {
-        // Above 
-
+    pub(crate) fn pathtracer_mut(&mut self) -> &mut Pathtracer {
+        &mut self.pathtracer
     }
 }
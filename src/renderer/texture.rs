@@ -2,6 +2,8 @@ use image::GenericImageView;
 use anyhow::*;
 
 pub struct Texture {
+    /// Only held so the texture lives as long as its view
+    #[allow(dead_code)]
     pub texture: wgpu::Texture,
     pub view: wgpu::TextureView,
     pub sampler: wgpu::Sampler,
@@ -0,0 +1,66 @@
+use super::{hash, hash_to_f32, pixel_stream, Sampler, ONE_MINUS_EPSILON};
+
+/// Bases of the Halton dimensions; dimensions past the last one fall back to random values
+const PRIMES: [u64; 64] = [
+    2, 3, 5, 7, 11, 13, 17, 19, 23, 29, 31, 37, 41, 43, 47, 53, 59, 61, 67, 71, 73, 79, 83, 89, 97, 101, 103, 107, 109,
+    113, 127, 131, 137, 139, 149, 151, 157, 163, 167, 173, 179, 181, 191, 193, 197, 199, 211, 223, 227, 229, 233, 239,
+    241, 251, 257, 263, 269, 271, 277, 281, 283, 293, 307, 311,
+];
+
+/// Halton sequence, with dimension `d` the radical inverse of the sample index in the `d`-th
+/// prime base. Every pixel gets its own Owen scrambling of the digits, which keeps the
+/// stratification while decorrelating the pixels and the higher dimensions.
+pub struct HaltonSampler {
+    seed: u64,
+    stream: u64,
+    index: u64,
+    dimension: usize,
+}
+
+impl HaltonSampler {
+    pub fn new(seed: u64) -> Self {
+        Self {
+            seed,
+            stream: 0,
+            index: 0,
+            dimension: 0,
+        }
+    }
+}
+
+impl Sampler for HaltonSampler {
+    fn start_pixel_sample(&mut self, x: u32, y: u32, index: u64) {
+        self.stream = hash(self.seed, pixel_stream(x, y));
+        self.index = index;
+        self.dimension = 0;
+    }
+
+    fn get_1d(&mut self) -> f32 {
+        let dimension = self.dimension;
+        self.dimension += 1;
+        let scramble = hash(self.stream, dimension as u64);
+        match PRIMES.get(dimension) {
+            Some(&base) => owen_scrambled_radical_inverse(base, self.index, scramble),
+            None => hash_to_f32(hash(scramble, self.index)),
+        }
+    }
+}
+
+/// Mirrors the base `base` digits of `index` around the radix point. Each digit is shifted by
+/// an amount hashed from `seed` and the digits before it, which is a nested (Owen) scrambling.
+fn owen_scrambled_radical_inverse(base: u64, mut index: u64, seed: u64) -> f32 {
+    let inverse_base = 1.0 / base as f64;
+    let mut scale = inverse_base;
+    let mut value = 0.0;
+    let mut node = seed;
+    // Zero digits past the end of the index are scrambled too, so keep going to f32 precision
+    while scale > 1e-8 {
+        let digit = index % base;
+        index /= base;
+        let shifted = (digit + hash(node, 0) % base) % base;
+        value += shifted as f64 * scale;
+        node = hash(node, digit + 1);
+        scale *= inverse_base;
+    }
+    (value as f32).min(ONE_MINUS_EPSILON)
+}
@@ -0,0 +1,44 @@
+use rand::Rng;
+use rand_pcg::Pcg64;
+
+use super::{hash, pixel_stream, Sampler};
+
+/// Independent uniform numbers. Every (stream, sample index, dimension) maps to a fixed position
+/// of a PCG sequence picked by the global seed, so a sample gets the same numbers no matter
+/// which thread traces it or in which order.
+pub struct IndependentSampler {
+    seed: u64,
+    rng: Pcg64,
+}
+
+impl IndependentSampler {
+    /// Numbers reserved for each sample, far more than the longest path draws
+    const DIMENSIONS_PER_SAMPLE: u128 = 1 << 16;
+
+    pub fn new(seed: u64) -> Self {
+        let mut sampler = Self {
+            seed,
+            rng: Pcg64::new(0, 0),
+        };
+        sampler.start_stream(0, 0);
+        sampler
+    }
+
+    /// Restarts at sample `index` of an arbitrary stream, for samples that don't belong to a
+    /// pixel, like photons
+    pub fn start_stream(&mut self, stream: u64, index: u64) {
+        let sequence = hash(self.seed, stream);
+        self.rng = Pcg64::new(hash(sequence, self.seed) as u128, sequence as u128);
+        self.rng.advance(index as u128 * Self::DIMENSIONS_PER_SAMPLE);
+    }
+}
+
+impl Sampler for IndependentSampler {
+    fn start_pixel_sample(&mut self, x: u32, y: u32, index: u64) {
+        self.start_stream(pixel_stream(x, y), index);
+    }
+
+    fn get_1d(&mut self) -> f32 {
+        self.rng.gen()
+    }
+}
@@ -0,0 +1,163 @@
+use std::{fmt, str::FromStr};
+
+pub mod halton;
+pub mod independent;
+pub mod sobol;
+pub mod stratified;
+
+pub use halton::HaltonSampler;
+pub use independent::IndependentSampler;
+pub use sobol::SobolSampler;
+pub use stratified::StratifiedSampler;
+
+/// Source of the uniform numbers behind every random decision taken while tracing a sample.
+/// Integrators and materials draw from it in a fixed order, so a sampler that replays or
+/// perturbs the same numbers reproduces or perturbs the same path.
+///
+/// Each call to `get_1d` or `get_2d` is one dimension of the sample. Low discrepancy samplers
+/// spread the values of every dimension evenly over the samples of a pixel, so a pair of values
+/// that's used together, like a direction on the hemisphere, has to come from one `get_2d`.
+pub trait Sampler {
+    /// Restarts the dimensions for sample `index` of the pixel at (x, y)
+    fn start_pixel_sample(&mut self, x: u32, y: u32, index: u64);
+    /// Next uniform number in [0, 1), e.g. to pick a light or a lobe
+    fn get_1d(&mut self) -> f32;
+    /// Next pair of uniform numbers, e.g. for a direction or a point on a surface
+    fn get_2d(&mut self) -> (f32, f32) {
+        (self.get_1d(), self.get_1d())
+    }
+    /// Offset of the camera ray inside its pixel, the first dimension of every sample
+    fn get_pixel_2d(&mut self) -> (f32, f32) {
+        self.get_2d()
+    }
+}
+
+/// Largest f32 below one
+pub(crate) const ONE_MINUS_EPSILON: f32 = 1.0 - f32::EPSILON / 2.0;
+
+/// Mixes a seed and a stream id into a well distributed 64 bit value (splitmix64 finalizer)
+pub fn hash(seed: u64, stream: u64) -> u64 {
+    let mut x = seed ^ stream.wrapping_mul(0x9e37_79b9_7f4a_7c15);
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    x ^ (x >> 31)
+}
+
+/// Uniform number in [0, 1) from the high bits of a hash
+pub(crate) fn hash_to_f32(hash: u64) -> f32 {
+    (hash >> 40) as f32 / (1u64 << 24) as f32
+}
+
+/// Stream of the samples of a pixel
+pub(crate) fn pixel_stream(x: u32, y: u32) -> u64 {
+    ((y as u64) << 32) | x as u64
+}
+
+/// The samplers that can be picked from the command line
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SamplerKind {
+    Independent,
+    Stratified,
+    Halton,
+    #[default]
+    Sobol,
+}
+
+impl SamplerKind {
+    /// `samples_per_pixel` is the number of consecutive samples the stratified sampler splits
+    /// into strata, the others don't need it
+    pub fn build(&self, seed: u64, samples_per_pixel: u32) -> Box<dyn Sampler> {
+        match self {
+            Self::Independent => Box::new(IndependentSampler::new(seed)),
+            Self::Stratified => Box::new(StratifiedSampler::new(seed, samples_per_pixel)),
+            Self::Halton => Box::new(HaltonSampler::new(seed)),
+            Self::Sobol => Box::new(SobolSampler::new(seed)),
+        }
+    }
+}
+
+impl fmt::Display for SamplerKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Self::Independent => "independent",
+            Self::Stratified => "stratified",
+            Self::Halton => "halton",
+            Self::Sobol => "sobol",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl FromStr for SamplerKind {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "independent" => Ok(Self::Independent),
+            "stratified" => Ok(Self::Stratified),
+            "halton" => Ok(Self::Halton),
+            "sobol" => Ok(Self::Sobol),
+            _ => anyhow::bail!("unknown sampler '{}', expected independent, stratified, halton or sobol", s),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use nalgebra::Vector3;
+
+    use crate::{
+        camera::Camera,
+        color,
+        geometry::{Plane, Point, Sphere},
+        integrator::{AmbientOcclusionIntegrator, Integrator, TraceContext},
+        material::Diffuse,
+        world::World,
+    };
+
+    use super::SamplerKind;
+
+    /// Ambient occlusion image of a sphere on a plane, with `samples` per pixel
+    fn render(world: &World, camera: &Camera, kind: SamplerKind, samples: u32) -> Vec<f32> {
+        let integrator = AmbientOcclusionIntegrator::new(2.0);
+        let mut sampler = kind.build(1, samples);
+        let mut image = Vec::new();
+        for y in 0..camera.height() {
+            for x in 0..camera.width() {
+                let mut sum = 0.0;
+                for index in 0..samples as u64 {
+                    sampler.start_pixel_sample(x, y, index);
+                    let (dx, dy) = sampler.get_pixel_2d();
+                    let ray = camera.generate_ray(x as f32 + dx, y as f32 + dy, 0.0);
+                    let mut ctx = TraceContext::new(world, camera, sampler.as_mut());
+                    sum += integrator.li(&ray, &mut ctx).r;
+                }
+                image.push(sum / samples as f32);
+            }
+        }
+        image
+    }
+
+    fn mse(image: &[f32], reference: &[f32]) -> f32 {
+        image.iter().zip(reference).map(|(a, b)| (a - b) * (a - b)).sum::<f32>() / image.len() as f32
+    }
+
+    #[test]
+    fn low_discrepancy_samplers_converge_faster() {
+        let mut world = World::new();
+        world.add_object(Box::new(Plane::new(
+            Point::new(0.0, -1.0, 0.0),
+            Vector3::new(0.0, 1.0, 0.0),
+            Box::new(Diffuse::new(color::GRAY)),
+        )));
+        world.add_object(Box::new(Sphere::new_with_material(0.0, 0.0, -3.0, 1.0, Box::new(Diffuse::new(color::WHITE)))));
+        let camera = Camera::new(Point::new(0.0, 0.5, 1.0), Vector3::new(0.0, -0.3, -1.0), 60.0, 12, 9);
+
+        let reference = render(&world, &camera, SamplerKind::Independent, 4096);
+        let independent = mse(&render(&world, &camera, SamplerKind::Independent, 64), &reference);
+        for kind in [SamplerKind::Stratified, SamplerKind::Halton, SamplerKind::Sobol] {
+            let error = mse(&render(&world, &camera, kind, 64), &reference);
+            assert!(error < 0.6 * independent, "{} has an error of {} against {} for independent", kind, error, independent);
+        }
+    }
+}
@@ -0,0 +1,89 @@
+use super::{hash, pixel_stream, Sampler};
+
+/// The first two dimensions of the Sobol sequence, Owen scrambled with a fresh seed for every
+/// dimension of the sample and with the sample order shuffled per dimension, following Burley,
+/// "Practical Hash-based Owen Scrambling". Pairs of values keep the 2D stratification of the
+/// Sobol points, and any number of dimensions can be drawn.
+pub struct SobolSampler {
+    seed: u64,
+    stream: u64,
+    index: u32,
+    dimension: u64,
+}
+
+impl SobolSampler {
+    pub fn new(seed: u64) -> Self {
+        Self {
+            seed,
+            stream: 0,
+            index: 0,
+            dimension: 0,
+        }
+    }
+
+    /// Seeds of the index shuffle and of the scrambling of the two Sobol dimensions
+    fn next_dimension(&mut self) -> [u32; 3] {
+        let dimension = hash(self.stream, self.dimension);
+        self.dimension += 1;
+        [dimension as u32, (dimension >> 32) as u32, hash(dimension, 1) as u32]
+    }
+}
+
+impl Sampler for SobolSampler {
+    fn start_pixel_sample(&mut self, x: u32, y: u32, index: u64) {
+        self.stream = hash(self.seed, pixel_stream(x, y));
+        // Sobol points repeat after 2^32 samples, far beyond any render
+        self.index = index as u32;
+        self.dimension = 0;
+    }
+
+    fn get_1d(&mut self) -> f32 {
+        let [shuffle, scramble, _] = self.next_dimension();
+        let index = nested_uniform_scramble(self.index, shuffle);
+        to_f32(nested_uniform_scramble(index.reverse_bits(), scramble))
+    }
+
+    fn get_2d(&mut self) -> (f32, f32) {
+        let [shuffle, scramble_x, scramble_y] = self.next_dimension();
+        let index = nested_uniform_scramble(self.index, shuffle);
+        (
+            to_f32(nested_uniform_scramble(index.reverse_bits(), scramble_x)),
+            to_f32(nested_uniform_scramble(sobol_second_dimension(index), scramble_y)),
+        )
+    }
+}
+
+/// Second dimension of the Sobol sequence, from the primitive polynomial x + 1. The first
+/// dimension is the van der Corput sequence, the index with its bits reversed.
+fn sobol_second_dimension(mut index: u32) -> u32 {
+    let mut direction = 1u32 << 31;
+    let mut value = 0;
+    while index != 0 {
+        if index & 1 != 0 {
+            value ^= direction;
+        }
+        index >>= 1;
+        direction ^= direction >> 1;
+    }
+    value
+}
+
+/// Owen scrambling of the bits of `x`, most significant first
+fn nested_uniform_scramble(x: u32, seed: u32) -> u32 {
+    laine_karras_permutation(x.reverse_bits(), seed).reverse_bits()
+}
+
+/// Hash where each bit only depends on the bits below it, which reversed is an Owen scrambling
+fn laine_karras_permutation(mut x: u32, seed: u32) -> u32 {
+    x ^= x.wrapping_mul(0x3d20_adea);
+    x = x.wrapping_add(seed);
+    x = x.wrapping_mul((seed >> 16) | 1);
+    x ^= x.wrapping_mul(0x0552_6c56);
+    x ^= x.wrapping_mul(0x53a2_2864);
+    x
+}
+
+/// Keeps the 24 bits an f32 can hold, so the value stays below one
+fn to_f32(bits: u32) -> f32 {
+    (bits >> 8) as f32 / (1u32 << 24) as f32
+}
@@ -0,0 +1,108 @@
+use super::{hash, hash_to_f32, pixel_stream, Sampler};
+
+/// Jittered stratification: every run of `samples_per_pixel` consecutive samples puts exactly
+/// one value in each stratum of every dimension. The strata are visited in a random order
+/// that differs per pixel, dimension and run, so dimensions aren't correlated.
+pub struct StratifiedSampler {
+    seed: u64,
+    samples_per_pixel: u32,
+    /// Strata along x and y of the 2D dimensions, as close to a square as the count allows
+    strata_2d: (u32, u32),
+    stream: u64,
+    index: u64,
+    dimension: u64,
+}
+
+impl StratifiedSampler {
+    pub fn new(seed: u64, samples_per_pixel: u32) -> Self {
+        let samples_per_pixel = samples_per_pixel.max(1);
+        let columns = (1..=(samples_per_pixel as f32).sqrt() as u32)
+            .rev()
+            .find(|&columns| samples_per_pixel.is_multiple_of(columns))
+            .unwrap_or(1);
+        Self {
+            seed,
+            samples_per_pixel,
+            strata_2d: (columns, samples_per_pixel / columns),
+            stream: 0,
+            index: 0,
+            dimension: 0,
+        }
+    }
+
+    /// Hash of the current dimension, unique for each run of samples of the pixel
+    fn next_dimension(&mut self) -> u64 {
+        let run = self.index / self.samples_per_pixel as u64;
+        let dimension = hash(hash(self.seed, self.stream), (run << 16) | self.dimension);
+        self.dimension += 1;
+        dimension
+    }
+
+    /// Stratum of the current sample, and a jitter inside it
+    fn stratum(&self, dimension: u64) -> (u32, f32) {
+        let within_run = (self.index % self.samples_per_pixel as u64) as u32;
+        let stratum = permutation_element(within_run, self.samples_per_pixel, dimension as u32);
+        (stratum, hash_to_f32(hash(dimension, self.index)))
+    }
+}
+
+impl Sampler for StratifiedSampler {
+    fn start_pixel_sample(&mut self, x: u32, y: u32, index: u64) {
+        self.stream = pixel_stream(x, y);
+        self.index = index;
+        self.dimension = 0;
+    }
+
+    fn get_1d(&mut self) -> f32 {
+        let dimension = self.next_dimension();
+        let (stratum, jitter) = self.stratum(dimension);
+        (stratum as f32 + jitter) / self.samples_per_pixel as f32
+    }
+
+    fn get_2d(&mut self) -> (f32, f32) {
+        let dimension = self.next_dimension();
+        let (stratum, jitter_x) = self.stratum(dimension);
+        let jitter_y = hash_to_f32(hash(dimension ^ 1, self.index));
+        let (columns, rows) = self.strata_2d;
+        (
+            ((stratum % columns) as f32 + jitter_x) / columns as f32,
+            ((stratum / columns) as f32 + jitter_y) / rows as f32,
+        )
+    }
+}
+
+/// Element `i` of a random permutation of 0..`length` chosen by `seed`, computed without
+/// storing the permutation (Kensler, "Correlated Multi-Jittered Sampling")
+pub(crate) fn permutation_element(mut i: u32, length: u32, seed: u32) -> u32 {
+    let mut mask = length.wrapping_sub(1);
+    mask |= mask >> 1;
+    mask |= mask >> 2;
+    mask |= mask >> 4;
+    mask |= mask >> 8;
+    mask |= mask >> 16;
+    // Cycle walking: permute within the next power of two until the value lands in range
+    loop {
+        i ^= seed;
+        i = i.wrapping_mul(0xe170_893d);
+        i ^= seed >> 16;
+        i ^= (i & mask) >> 4;
+        i ^= seed >> 8;
+        i = i.wrapping_mul(0x0929_eb3f);
+        i ^= seed >> 23;
+        i ^= (i & mask) >> 1;
+        i = i.wrapping_mul(1 | seed >> 27);
+        i = i.wrapping_mul(0x6935_fa69);
+        i ^= (i & mask) >> 11;
+        i = i.wrapping_mul(0x74dc_b303);
+        i ^= (i & mask) >> 2;
+        i = i.wrapping_mul(0x9e50_1cc3);
+        i ^= (i & mask) >> 2;
+        i = i.wrapping_mul(0xc860_a3df);
+        i &= mask;
+        i ^= i >> 5;
+        if i < length {
+            break;
+        }
+    }
+    i.wrapping_add(seed) % length
+}
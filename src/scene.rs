@@ -1,10 +1,140 @@
-use std::path::Path;
+use std::{f32::consts::PI, fmt, path::Path, str::FromStr, sync::Arc};
 
-use nalgebra::Vector3;
+use nalgebra::{Matrix4, UnitQuaternion, Vector3};
+use rand::{Rng, SeedableRng};
+use rand_pcg::Pcg64;
 
-use crate::{world::World, geometry::{Plane, Point, Sphere, Mesh}, color, material};
+use crate::{
+    animation::{CameraKey, CameraPath},
+    camera::Camera,
+    color::{self, ColorF32},
+    csg::Csg,
+    geometry::{Mesh, MovingSphere, Plane, Point, Sphere},
+    instance::Instance,
+    material,
+    motion::{AnimatedTransform, Keyframe},
+    object::Object,
+    raytracer::Pathtracer,
+    sdf::{DistanceField, Sdf},
+    shapes::{Cone, Cuboid, Cylinder, Disk, Quad, Torus},
+    subdivision::{Displacement, DisplacementMap, Subdivision},
+    world::World,
+};
 
-pub fn build_scene(w: &mut World) {
+/// The scenes that come with the renderer
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SceneKind {
+    /// The cow and the text of `build_scene`, over a glossy floor
+    #[default]
+    Default,
+    /// Small spheres bouncing around three big ones, blurred by their motion
+    MotionBlur,
+    /// The cow turning once in four seconds, with the camera flying around it. The camera
+    /// follows its path, whatever its position, direction and field of view are set to. The
+    /// shutter is closed to an instant, so a still and each of the frames are sharp.
+    Turntable,
+    /// Each of the analytic shapes on a floor, under a square light
+    Shapes,
+    /// The rounded cube drilled through on all three axes, of constructive solid geometry
+    Csg,
+    /// Metaballs, a twisted bar, a Mandelbulb and a row of rings, as signed distance fields
+    Sdf,
+    /// A box of quads smoothed by Catmull-Clark, a cube of triangles keeping its creases under a
+    /// displacement map, and the cow roughened by noise
+    Subdivision,
+}
+
+impl SceneKind {
+    /// Adds the objects of the scene to the world of `pathtracer` and points its camera at them.
+    /// Fails if a mesh of the scene can't be loaded.
+    pub fn build(&self, pathtracer: &mut Pathtracer) -> anyhow::Result<()> {
+        match self {
+            Self::Default => build_scene(pathtracer.world())?,
+            Self::MotionBlur => {
+                build_motion_blur_scene(pathtracer.world());
+                let camera = pathtracer.camera_mut();
+                camera.set_origin(Point::new(13.0, 2.0, 3.0));
+                camera.set_direction(Vector3::new(-13.0, -2.0, -3.0));
+                camera.set_fov(20.0);
+            }
+            Self::Turntable => {
+                build_turntable_scene(pathtracer.world())?;
+                let camera = pathtracer.camera_mut();
+                // Zero length reads the same as seconds for a still or as frames for --frames
+                camera.set_shutter(0.0, 0.0);
+                camera.set_path(Some(CameraPath::new(vec![
+                    CameraKey::new(0.0, Point::new(0.0, 1.0, 5.0), Point::new(0.0, 0.0, 0.0), 40.0),
+                    CameraKey::new(1.5, Point::new(3.5, 0.6, 3.0), Point::new(0.0, 0.0, 0.0), 30.0),
+                    CameraKey::new(3.0, Point::new(2.5, 2.5, -2.0), Point::new(0.0, -0.2, 0.0), 45.0),
+                    CameraKey::new(4.0, Point::new(0.0, 1.0, 5.0), Point::new(0.0, 0.0, 0.0), 40.0),
+                ])));
+            }
+            Self::Shapes => {
+                build_shapes_scene(pathtracer.world());
+                let camera = pathtracer.camera_mut();
+                camera.set_origin(Point::new(0.0, 3.0, 8.0));
+                camera.set_direction(Vector3::new(0.0, -2.5, -8.0));
+                camera.set_fov(40.0);
+            }
+            Self::Csg => {
+                build_csg_scene(pathtracer.world());
+                let camera = pathtracer.camera_mut();
+                camera.set_origin(Point::new(4.5, 4.0, 6.0));
+                camera.set_direction(Vector3::new(-4.5, -4.0, -6.0));
+                camera.set_fov(35.0);
+            }
+            Self::Sdf => {
+                build_sdf_scene(pathtracer.world());
+                let camera = pathtracer.camera_mut();
+                camera.set_origin(Point::new(0.0, 2.5, 6.5));
+                camera.set_direction(Vector3::new(0.0, -1.7, -6.5));
+                camera.set_fov(40.0);
+            }
+            Self::Subdivision => {
+                build_subdivision_scene(pathtracer.world())?;
+                let camera = pathtracer.camera_mut();
+                camera.set_origin(Point::new(0.0, 3.0, 7.5));
+                camera.set_direction(Vector3::new(0.0, -2.2, -7.5));
+                camera.set_fov(40.0);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for SceneKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Self::Default => "default",
+            Self::MotionBlur => "motion-blur",
+            Self::Turntable => "turntable",
+            Self::Shapes => "shapes",
+            Self::Csg => "csg",
+            Self::Sdf => "sdf",
+            Self::Subdivision => "subdivision",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl FromStr for SceneKind {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "default" => Ok(Self::Default),
+            "motion-blur" => Ok(Self::MotionBlur),
+            "turntable" => Ok(Self::Turntable),
+            "shapes" => Ok(Self::Shapes),
+            "csg" => Ok(Self::Csg),
+            "sdf" => Ok(Self::Sdf),
+            "subdivision" => Ok(Self::Subdivision),
+            _ => anyhow::bail!("unknown scene '{}', expected default, motion-blur, turntable, shapes, csg, sdf or subdivision", s),
+        }
+    }
+}
+
+pub fn build_scene(w: &mut World) -> anyhow::Result<()> {
         // w.add_object(Box::new(
         //     Sphere::new_with_material(0.0, 2.0, -4.0, 1.5, 
         //         Box::new(crate::material::Diffuse::new(color::BLUE)))));
@@ -26,11 +156,11 @@ pub fn build_scene(w: &mut World) {
         //     Sphere::new_with_material(-3.0, 0.7, -2.0, 1.0, 
         //         Box::new(crate::material::Dielectric::new(color::WHITE, 0.05, 1.4)))));
 
-        let mut mesh = Mesh::from_obj(Path::new("assets/cow.obj"), Box::new(material::Dielectric::new(color::WHITE, 0.05, 2.1)));
+        let mut mesh = Mesh::from_obj(Path::new("assets/cow.obj"), Box::new(material::Dielectric::new(color::WHITE, 0.05, 2.1)))?;
         mesh.build_bvh();
         w.add_object( Box::new(mesh));
 
-       let mut mesh = Mesh::from_obj(Path::new("assets/text.obj"), Box::new(material::Emmisive::new(color::WHITE, 2.3)));
+       let mut mesh = Mesh::from_obj(Path::new("assets/text.obj"), Box::new(material::Emmisive::new(color::WHITE, 2.3)))?;
         mesh.build_bvh();
         w.add_object( Box::new(mesh));
 
@@ -44,4 +174,249 @@ pub fn build_scene(w: &mut World) {
         //     )
         // );
 
+        Ok(())
+}
+
+/// The cover of Ray Tracing: The Next Week. Small spheres, the diffuse ones bouncing up while
+/// the shutter is open, around a glass, a diffuse and a metal sphere, lit by the sky.
+pub fn build_motion_blur_scene(w: &mut World) {
+    // The same spheres every time
+    let mut rng = Pcg64::seed_from_u64(0);
+    let random_color = |rng: &mut Pcg64, min: f32| ColorF32::new(rng.gen_range(min..1.0), rng.gen_range(min..1.0), rng.gen_range(min..1.0));
+    w.add_object(Box::new(Sphere::new_with_material(0.0, -1000.0, 0.0, 1000.0, Box::new(material::Diffuse::new(color::GRAY)))));
+    for a in -11..11 {
+        for b in -11..11 {
+            let choice = rng.gen::<f32>();
+            let center = Point::new(a as f32 + 0.9 * rng.gen::<f32>(), 0.2, b as f32 + 0.9 * rng.gen::<f32>());
+            if (center - Point::new(4.0, 0.2, 0.0)).norm() <= 0.9 {
+                continue;
+            }
+            if choice < 0.8 {
+                let albedo = random_color(&mut rng, 0.0) * random_color(&mut rng, 0.0);
+                let to = center + Vector3::new(0.0, rng.gen_range(0.0..0.5), 0.0);
+                w.add_object(Box::new(MovingSphere::new(center, to, 0.2, Box::new(material::Diffuse::new(albedo)))));
+            } else if choice < 0.95 {
+                let albedo = random_color(&mut rng, 0.5);
+                let fuzz = rng.gen_range(0.0..0.5);
+                w.add_object(Box::new(Sphere::new_with_material(center.x, center.y, center.z, 0.2, Box::new(material::Metal::new(albedo, fuzz)))));
+            } else {
+                w.add_object(Box::new(Sphere::new_with_material(center.x, center.y, center.z, 0.2, Box::new(material::Dielectric::new(color::WHITE, 0.0, 1.5)))));
+            }
+        }
+    }
+    w.add_object(Box::new(Sphere::new_with_material(0.0, 1.0, 0.0, 1.0, Box::new(material::Dielectric::new(color::WHITE, 0.0, 1.5)))));
+    w.add_object(Box::new(Sphere::new_with_material(-4.0, 1.0, 0.0, 1.0, Box::new(material::Diffuse::new(ColorF32::new(0.4, 0.2, 0.1))))));
+    w.add_object(Box::new(Sphere::new_with_material(4.0, 1.0, 0.0, 1.0, Box::new(material::Metal::new(ColorF32::new(0.7, 0.6, 0.5), 0.0)))));
+}
+
+/// The cow on a turntable, a quarter turn every second, on a diffuse floor under a warm light
+pub fn build_turntable_scene(w: &mut World) -> anyhow::Result<()> {
+    w.add_object(Box::new(Plane::new(
+        Point::new(0.0, -0.62, 0.0),
+        Vector3::new(0.0, 1.0, 0.0),
+        Box::new(material::Diffuse::new(color::GRAY)),
+    )));
+    w.add_object(Box::new(Sphere::new_with_material(-2.0, 4.0, 2.0, 0.5, Box::new(material::Emmisive::new(color::ORANGE, 8.0)))));
+
+    let mut mesh = Mesh::from_obj(Path::new("assets/cow.obj"), Box::new(material::Diffuse::new(color::WHITE)))?;
+    mesh.build_bvh();
+    // Quarter turns, as a keyframe half a turn or more away would be reached the short way
+    let turn = AnimatedTransform::new(
+        (0..=4)
+            .map(|quarter| {
+                let rotation = UnitQuaternion::from_axis_angle(&Vector3::y_axis(), quarter as f32 * PI / 2.0);
+                Keyframe::new(quarter as f32, Vector3::zeros(), rotation)
+            })
+            .collect(),
+    );
+    w.add_object(Box::new(Instance::animated(Arc::new(mesh), turn)));
+    Ok(())
+}
+
+/// Two rows of shapes on a gray floor: boxes, a torus and a glass cylinder at the back, a cone, a
+/// metal disk and a small glowing disk in front. A quad above lights them.
+pub fn build_shapes_scene(w: &mut World) {
+    w.add_object(Box::new(Plane::new(Point::origin(), Vector3::y(), Box::new(material::Diffuse::new(color::GRAY)))));
+    // Facing down, the edges turn from x to z
+    w.add_object(Box::new(Quad::new(
+        Point::new(-1.0, 5.0, -1.0),
+        Vector3::new(2.0, 0.0, 0.0),
+        Vector3::new(0.0, 0.0, 2.0),
+        Box::new(material::Emmisive::new(color::WHITE, 6.0)),
+    )));
+
+    w.add_object(Box::new(Cuboid::new(Point::new(-3.5, 0.0, -2.5), Point::new(-2.5, 1.0, -1.5), Box::new(material::Diffuse::new(ColorF32::new(0.8, 0.3, 0.2))))));
+    w.add_object(Box::new(Cuboid::oriented(
+        Point::new(-1.2, 0.6, -2.0),
+        Vector3::new(0.4, 0.6, 0.4),
+        UnitQuaternion::from_axis_angle(&Vector3::y_axis(), PI / 5.0),
+        Box::new(material::Diffuse::new(ColorF32::new(0.2, 0.6, 0.3))),
+    )));
+    w.add_object(Box::new(Torus::new(
+        Point::new(1.0, 0.9, -2.0),
+        Vector3::new(0.0, 1.0, 1.0),
+        0.6,
+        0.2,
+        Box::new(material::Metal::new(ColorF32::new(0.9, 0.7, 0.4), 0.1)),
+    )));
+    w.add_object(Box::new(Cylinder::new(Point::new(3.0, 0.0, -2.0), Vector3::y(), 0.5, 1.4, Box::new(material::Dielectric::new(color::WHITE, 0.0, 1.5)))));
+
+    w.add_object(Box::new(Cone::new(Point::new(-2.0, 0.0, 0.5), Vector3::y(), 0.6, 1.2, Box::new(material::Diffuse::new(ColorF32::new(0.3, 0.4, 0.8))))));
+    w.add_object(Box::new(Disk::new(Point::new(0.0, 0.6, 0.5), Vector3::new(0.0, 1.0, 1.0), 0.6, Box::new(material::Metal::new(color::WHITE, 0.0)))));
+    w.add_object(Box::new(Disk::new(Point::new(2.0, 0.01, 0.5), Vector3::y(), 0.4, Box::new(material::Emmisive::new(color::ORANGE, 3.0)))));
+}
+
+/// A cube rounded by a sphere, with three cylinders carved out of it along the axes, on a floor
+/// under a square light. The holes show the color of the cylinders that drilled them.
+pub fn build_csg_scene(w: &mut World) {
+    w.add_object(Box::new(Plane::new(Point::new(0.0, -1.0, 0.0), Vector3::y(), Box::new(material::Diffuse::new(color::GRAY)))));
+    w.add_object(Box::new(Quad::new(
+        Point::new(-1.0, 4.0, -1.0),
+        Vector3::new(2.0, 0.0, 0.0),
+        Vector3::new(0.0, 0.0, 2.0),
+        Box::new(material::Emmisive::new(color::WHITE, 6.0)),
+    )));
+
+    let rounded = Csg::intersection(
+        Box::new(Cuboid::new(Point::new(-1.0, -1.0, -1.0), Point::new(1.0, 1.0, 1.0), Box::new(material::Metal::new(ColorF32::new(0.8, 0.8, 0.85), 0.2)))),
+        Box::new(Sphere::new_with_material(0.0, 0.0, 0.0, 1.35, Box::new(material::Diffuse::new(ColorF32::new(0.8, 0.3, 0.2))))),
+    );
+    let drill = |axis: Vector3<f32>| -> Box<dyn Object> {
+        Box::new(Cylinder::new(Point::origin() - axis * 1.5, axis, 0.5, 3.0, Box::new(material::Diffuse::new(ColorF32::new(0.2, 0.4, 0.8)))))
+    };
+    let drills = Csg::union(Box::new(Csg::union(drill(Vector3::x()), drill(Vector3::y()))), drill(Vector3::z()));
+    w.add_object(Box::new(Csg::difference(Box::new(rounded), Box::new(drills))));
+}
+
+/// Shapes sphere traced from their distance fields, on a floor under a square light
+pub fn build_sdf_scene(w: &mut World) {
+    w.add_object(Box::new(Plane::new(Point::origin(), Vector3::y(), Box::new(material::Diffuse::new(color::GRAY)))));
+    w.add_object(Box::new(Quad::new(
+        Point::new(-1.0, 5.0, -1.0),
+        Vector3::new(2.0, 0.0, 0.0),
+        Vector3::new(0.0, 0.0, 2.0),
+        Box::new(material::Emmisive::new(color::WHITE, 6.0)),
+    )));
+
+    let metaballs = Sdf::sphere(0.5)
+        .smooth_union(Sdf::sphere(0.35).translate(Vector3::new(0.55, 0.3, 0.0)), 0.4)
+        .smooth_union(Sdf::sphere(0.3).translate(Vector3::new(-0.3, 0.55, 0.2)), 0.4)
+        .translate(Vector3::new(-2.2, 0.55, 0.0));
+    w.add_object(Box::new(DistanceField::new(metaballs, Box::new(material::Diffuse::new(ColorF32::new(0.3, 0.7, 0.4))))));
+
+    let bar = Sdf::cuboid(Vector3::new(0.3, 1.0, 0.3)).round(0.05).twist(1.2).translate(Vector3::new(0.0, 1.05, -0.5));
+    w.add_object(Box::new(DistanceField::new(bar, Box::new(material::Metal::new(ColorF32::new(0.9, 0.8, 0.6), 0.15)))));
+
+    let bulb = Sdf::mandelbulb(8.0, 8).scale(0.8).translate(Vector3::new(2.2, 0.9, 0.0));
+    w.add_object(Box::new(DistanceField::new(bulb, Box::new(material::Diffuse::new(ColorF32::new(0.8, 0.4, 0.2))))));
+
+    let rings = Sdf::torus(0.25, 0.08).repeat(Vector3::new(0.8, 1.0, 1.0), Vector3::new(3.0, 0.0, 0.0)).translate(Vector3::new(0.0, 0.08, 1.5));
+    w.add_object(Box::new(DistanceField::new(rings, Box::new(material::Dielectric::new(color::WHITE, 0.0, 1.5)))));
+}
+
+/// Meshes subdivided when they're loaded, on a floor under a square light: the box of quads
+/// smoothed by Catmull-Clark into a rounded blob, the cube of triangles with creases along its
+/// edges and the texture pushing its faces out, and the cow with its surface roughened by noise
+pub fn build_subdivision_scene(w: &mut World) -> anyhow::Result<()> {
+    w.add_object(Box::new(Plane::new(Point::origin(), Vector3::y(), Box::new(material::Diffuse::new(color::GRAY)))));
+    w.add_object(Box::new(Quad::new(
+        Point::new(-1.0, 5.0, -1.0),
+        Vector3::new(2.0, 0.0, 0.0),
+        Vector3::new(0.0, 0.0, 2.0),
+        Box::new(material::Emmisive::new(color::WHITE, 6.0)),
+    )));
+
+    let mut place = |path: &str, material: Box<dyn material::Material>, subdivision: Subdivision, translation: Vector3<f32>, scale: f32| -> anyhow::Result<()> {
+        let mut mesh = Mesh::from_obj_subdivided(Path::new(path), &subdivision, material)?;
+        mesh.build_bvh();
+        let transform = Matrix4::new_translation(&translation) * Matrix4::new_scaling(scale);
+        w.add_object(Box::new(Instance::new(Arc::new(mesh), transform)));
+        Ok(())
+    };
+    place("assets/box.obj", Box::new(material::Diffuse::new(ColorF32::new(0.3, 0.5, 0.8))), Subdivision::new(4), Vector3::new(-2.4, 0.76, 0.0), 0.9)?;
+    let texture = DisplacementMap::image(Path::new("assets/texture.png"))?;
+    place(
+        "assets/cube.obj",
+        Box::new(material::Metal::new(ColorF32::new(0.9, 0.7, 0.4), 0.2)),
+        Subdivision::new(5).with_crease_angle(60.0).with_displacement(Displacement::new(texture, 0.1)),
+        Vector3::new(0.0, 0.7, 0.0),
+        0.7,
+    )?;
+    place(
+        "assets/cow.obj",
+        Box::new(material::Diffuse::new(ColorF32::new(0.8, 0.4, 0.2))),
+        Subdivision::new(1).with_displacement(Displacement::new(DisplacementMap::noise(12.0, 4, 1), 0.01)),
+        Vector3::new(2.4, 0.55, 0.0),
+        0.9,
+    )
+}
+
+/// A setting of the scene replaced from the command line, like `camera.fov=60`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SceneOverride {
+    /// Position of the camera
+    Position(Point),
+    /// Direction the camera looks at
+    Direction(Vector3<f32>),
+    /// Vertical field of view of the camera in degrees
+    Fov(f32),
+    /// Times the shutter of the camera opens and closes at
+    Shutter(f32, f32),
+}
+
+impl SceneOverride {
+    pub fn apply(&self, camera: &mut Camera) {
+        match self {
+            Self::Position(position) => camera.set_origin(*position),
+            Self::Direction(direction) => camera.set_direction(*direction),
+            Self::Fov(fov) => camera.set_fov(*fov),
+            Self::Shutter(open, close) => camera.set_shutter(*open, *close),
+        }
+    }
+}
+
+impl FromStr for SceneOverride {
+    type Err = anyhow::Error;
+
+    /// Parses `camera.position=x,y,z`, `camera.direction=x,y,z`, `camera.fov=degrees` or
+    /// `camera.shutter=open,close`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (key, value) = s.split_once('=').ok_or_else(|| anyhow::anyhow!("expected key=value, got '{}'", s))?;
+        let numbers = || -> anyhow::Result<Vec<f32>> {
+            value
+                .split(',')
+                .map(|number| number.trim().parse::<f32>())
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(|e| anyhow::anyhow!("invalid value '{}' for {}: {}", value, key, e))
+        };
+        let vector = || -> anyhow::Result<Vector3<f32>> {
+            match numbers()?[..] {
+                [x, y, z] => Ok(Vector3::new(x, y, z)),
+                _ => anyhow::bail!("expected x,y,z for {}, got '{}'", key, value),
+            }
+        };
+        match key {
+            "camera.position" => Ok(Self::Position(Point::from(vector()?))),
+            "camera.direction" => {
+                let direction = vector()?;
+                if direction.norm() == 0.0 {
+                    anyhow::bail!("the camera direction can't be zero");
+                }
+                Ok(Self::Direction(direction))
+            }
+            "camera.fov" => {
+                let fov = value.parse::<f32>().map_err(|e| anyhow::anyhow!("invalid value '{}' for camera.fov: {}", value, e))?;
+                if !(fov > 0.0 && fov < 180.0) {
+                    anyhow::bail!("the field of view must be between 0 and 180 degrees, got {}", fov);
+                }
+                Ok(Self::Fov(fov))
+            }
+            "camera.shutter" => match numbers()?[..] {
+                [open, close] if open <= close => Ok(Self::Shutter(open, close)),
+                [_, _] => anyhow::bail!("the shutter can't close before it opens, got '{}'", value),
+                _ => anyhow::bail!("expected open,close for camera.shutter, got '{}'", value),
+            },
+            _ => anyhow::bail!("unknown scene setting '{}', expected camera.position, camera.direction, camera.fov or camera.shutter", key),
+        }
+    }
 }
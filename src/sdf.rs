@@ -0,0 +1,408 @@
+use bvh::aabb::AABB;
+use nalgebra::{Matrix4, UnitQuaternion, Vector2, Vector3};
+
+use crate::{
+    checkpoint::Fingerprint,
+    geometry::{Intersectable, Interval, Point, Ray},
+    material::Material,
+    motion,
+    object::Object,
+};
+
+/// A shape given by the signed distance to its surface, negative inside, built from primitives
+/// centered on the origin and operators that move, deform and combine them.
+///
+/// The operators that deform space, like `twist`, stretch the distances, which `lipschitz`
+/// accounts for. `smooth_union` and `repeat` only give a bound of the distance, which is all
+/// sphere tracing needs.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Sdf {
+    Sphere { radius: f32 },
+    Cuboid { half_size: Vector3<f32> },
+    /// A ring around the y axis
+    Torus { major_radius: f32, minor_radius: f32 },
+    /// The points within `radius` of the segment from `a` to `b`
+    Capsule { a: Point, b: Point, radius: f32 },
+    /// The fractal of the powers of points in spherical coordinates, within a radius of about 1.2
+    Mandelbulb { power: f32, iterations: u32 },
+    Translate(Box<Sdf>, Vector3<f32>),
+    Rotate(Box<Sdf>, UnitQuaternion<f32>),
+    Scale(Box<Sdf>, f32),
+    /// The shape grown by a radius, rounding its edges
+    Round(Box<Sdf>, f32),
+    Union(Box<Sdf>, Box<Sdf>),
+    /// A union blending the shapes together where they're closer than the radius
+    SmoothUnion(Box<Sdf>, Box<Sdf>, f32),
+    Intersection(Box<Sdf>, Box<Sdf>),
+    Difference(Box<Sdf>, Box<Sdf>),
+    /// The shape turned around the y axis by an angle growing with the height, in radians per unit
+    Twist(Box<Sdf>, f32),
+    /// Copies of the shape every `spacing`, from `-limit` to `limit` copies away along each axis.
+    /// The shape has to fit in its cell for the distances to hold.
+    Repeat(Box<Sdf>, Vector3<f32>, Vector3<f32>),
+}
+
+impl Sdf {
+    pub fn sphere(radius: f32) -> Self {
+        Self::Sphere { radius }
+    }
+
+    pub fn cuboid(half_size: Vector3<f32>) -> Self {
+        Self::Cuboid { half_size }
+    }
+
+    pub fn torus(major_radius: f32, minor_radius: f32) -> Self {
+        Self::Torus { major_radius, minor_radius }
+    }
+
+    pub fn capsule(a: Point, b: Point, radius: f32) -> Self {
+        Self::Capsule { a, b, radius }
+    }
+
+    /// The classic bulb has a power of 8, and enough detail after 8 iterations
+    pub fn mandelbulb(power: f32, iterations: u32) -> Self {
+        Self::Mandelbulb { power, iterations }
+    }
+
+    pub fn translate(self, offset: Vector3<f32>) -> Self {
+        Self::Translate(Box::new(self), offset)
+    }
+
+    pub fn rotate(self, rotation: UnitQuaternion<f32>) -> Self {
+        Self::Rotate(Box::new(self), rotation)
+    }
+
+    pub fn scale(self, scale: f32) -> Self {
+        Self::Scale(Box::new(self), scale)
+    }
+
+    pub fn round(self, radius: f32) -> Self {
+        Self::Round(Box::new(self), radius)
+    }
+
+    pub fn union(self, other: Sdf) -> Self {
+        Self::Union(Box::new(self), Box::new(other))
+    }
+
+    pub fn smooth_union(self, other: Sdf, radius: f32) -> Self {
+        Self::SmoothUnion(Box::new(self), Box::new(other), radius)
+    }
+
+    pub fn intersection(self, other: Sdf) -> Self {
+        Self::Intersection(Box::new(self), Box::new(other))
+    }
+
+    /// The shape with `other` carved out of it
+    pub fn difference(self, other: Sdf) -> Self {
+        Self::Difference(Box::new(self), Box::new(other))
+    }
+
+    pub fn twist(self, rate: f32) -> Self {
+        Self::Twist(Box::new(self), rate)
+    }
+
+    pub fn repeat(self, spacing: Vector3<f32>, limit: Vector3<f32>) -> Self {
+        Self::Repeat(Box::new(self), spacing, limit)
+    }
+
+    /// Signed distance from `p` to the surface, or a bound of it that's never too large once
+    /// divided by `lipschitz`
+    pub fn distance(&self, p: &Point) -> f32 {
+        // Most of them from https://iquilezles.org/articles/distfunctions/
+        match self {
+            Self::Sphere { radius } => p.coords.norm() - radius,
+            Self::Cuboid { half_size } => {
+                let q = p.coords.abs() - half_size;
+                q.sup(&Vector3::zeros()).norm() + q.max().min(0.0)
+            }
+            Self::Torus { major_radius, minor_radius } => Vector2::new(p.x.hypot(p.z) - major_radius, p.y).norm() - minor_radius,
+            Self::Capsule { a, b, radius } => {
+                let (pa, ba) = (p - a, b - a);
+                let h = (pa.dot(&ba) / ba.norm_squared()).clamp(0.0, 1.0);
+                (pa - ba * h).norm() - radius
+            }
+            Self::Mandelbulb { power, iterations } => mandelbulb(p, *power, *iterations),
+            Self::Translate(sdf, offset) => sdf.distance(&(p - offset)),
+            Self::Rotate(sdf, rotation) => sdf.distance(&rotation.inverse_transform_point(p)),
+            Self::Scale(sdf, scale) => sdf.distance(&(p / *scale)) * scale,
+            Self::Round(sdf, radius) => sdf.distance(p) - radius,
+            Self::Union(a, b) => a.distance(p).min(b.distance(p)),
+            Self::SmoothUnion(a, b, radius) => {
+                let (da, db) = (a.distance(p), b.distance(p));
+                let h = (radius - (da - db).abs()).max(0.0) / radius;
+                da.min(db) - h * h * radius / 4.0
+            }
+            Self::Intersection(a, b) => a.distance(p).max(b.distance(p)),
+            Self::Difference(a, b) => a.distance(p).max(-b.distance(p)),
+            Self::Twist(sdf, rate) => {
+                let angle = rate * p.y;
+                let (sin, cos) = angle.sin_cos();
+                sdf.distance(&Point::new(cos * p.x - sin * p.z, p.y, sin * p.x + cos * p.z))
+            }
+            Self::Repeat(sdf, spacing, limit) => {
+                let cell = p.coords.component_div(spacing).map(f32::round).sup(&-limit).inf(limit);
+                sdf.distance(&(p - spacing.component_mul(&cell)))
+            }
+        }
+    }
+
+    /// Box the surface stays in
+    pub fn bounds(&self) -> AABB {
+        let cube = |half: Vector3<f32>| AABB::with_bounds(bvh::Point3::new(-half.x, -half.y, -half.z), bvh::Point3::new(half.x, half.y, half.z));
+        let grow = |bounds: AABB, by: Vector3<f32>| {
+            let by = bvh::Vector3::new(by.x, by.y, by.z);
+            AABB::with_bounds(bounds.min - by, bounds.max + by)
+        };
+        match self {
+            Self::Sphere { radius } => cube(Vector3::repeat(*radius)),
+            Self::Cuboid { half_size } => cube(*half_size),
+            Self::Torus { major_radius, minor_radius } => {
+                let outer = major_radius + minor_radius;
+                cube(Vector3::new(outer, *minor_radius, outer))
+            }
+            Self::Capsule { a, b, radius } => {
+                let ends = AABB::empty().grow(&bvh::Point3::new(a.x, a.y, a.z)).grow(&bvh::Point3::new(b.x, b.y, b.z));
+                grow(ends, Vector3::repeat(*radius))
+            }
+            Self::Mandelbulb { .. } => cube(Vector3::repeat(1.25)),
+            Self::Translate(sdf, offset) => motion::transform_bounds(&Matrix4::new_translation(offset), &sdf.bounds()),
+            Self::Rotate(sdf, rotation) => motion::transform_bounds(&rotation.to_homogeneous(), &sdf.bounds()),
+            Self::Scale(sdf, scale) => motion::transform_bounds(&Matrix4::new_scaling(*scale), &sdf.bounds()),
+            Self::Round(sdf, radius) => grow(sdf.bounds(), Vector3::repeat(*radius)),
+            Self::Union(a, b) => a.bounds().join(&b.bounds()),
+            // The blend fills in at most a quarter of the radius further than the shapes
+            Self::SmoothUnion(a, b, radius) => grow(a.bounds().join(&b.bounds()), Vector3::repeat(radius / 4.0)),
+            Self::Intersection(a, b) => {
+                let (a, b) = (a.bounds(), b.bounds());
+                let (min, max) = (a.min.max(b.min), a.max.min(b.max));
+                AABB::with_bounds(min, max.max(min))
+            }
+            Self::Difference(a, _) => a.bounds(),
+            Self::Twist(sdf, _) => {
+                // Turned any way around y, the box stays within the circle through its furthest corner
+                let bounds = sdf.bounds();
+                let radius = [bounds.min, bounds.max]
+                    .iter()
+                    .flat_map(|x| [bounds.min, bounds.max].map(|z| x.x.hypot(z.z)))
+                    .fold(0.0, f32::max);
+                AABB::with_bounds(bvh::Point3::new(-radius, bounds.min.y, -radius), bvh::Point3::new(radius, bounds.max.y, radius))
+            }
+            Self::Repeat(sdf, spacing, limit) => grow(sdf.bounds(), spacing.component_mul(limit).abs()),
+        }
+    }
+
+    /// How much the distances can be stretched, the sphere tracing steps are shortened by it
+    pub fn lipschitz(&self) -> f32 {
+        match self {
+            Self::Sphere { .. } | Self::Cuboid { .. } | Self::Torus { .. } | Self::Capsule { .. } | Self::Mandelbulb { .. } => 1.0,
+            Self::Translate(sdf, _) | Self::Rotate(sdf, _) | Self::Scale(sdf, _) | Self::Round(sdf, _) | Self::Repeat(sdf, _, _) => sdf.lipschitz(),
+            Self::Union(a, b) | Self::SmoothUnion(a, b, _) | Self::Intersection(a, b) | Self::Difference(a, b) => a.lipschitz().max(b.lipschitz()),
+            Self::Twist(sdf, rate) => {
+                // A point at a radius r from the axis moves by r times the rate per unit of height
+                let bounds = sdf.bounds();
+                let radius = bounds.min.x.abs().max(bounds.max.x.abs()).hypot(bounds.min.z.abs().max(bounds.max.z.abs()));
+                sdf.lipschitz() * (1.0 + (rate * radius) * (rate * radius)).sqrt()
+            }
+        }
+    }
+}
+
+/// Distance estimate of the Mandelbulb, from the growth of the derivative of its iterations
+fn mandelbulb(p: &Point, power: f32, iterations: u32) -> f32 {
+    let mut z = p.coords;
+    let mut dr = 1.0;
+    let mut r = z.norm();
+    for _ in 0..iterations {
+        if r > 2.0 || r == 0.0 {
+            break;
+        }
+        let theta = (z.z / r).acos() * power;
+        let phi = z.y.atan2(z.x) * power;
+        dr = r.powf(power - 1.0) * power * dr + 1.0;
+        let (sin_theta, cos_theta) = theta.sin_cos();
+        z = Vector3::new(sin_theta * phi.cos(), sin_theta * phi.sin(), cos_theta) * r.powf(power) + p.coords;
+        r = z.norm();
+    }
+    if r == 0.0 {
+        return 0.0;
+    }
+    0.5 * r.ln() * r / dr
+}
+
+/// An object whose surface is the zero of a signed distance field, found by sphere tracing:
+/// stepping along the ray by the distance to the closest surface, which can't be crossed.
+pub struct DistanceField {
+    sdf: Sdf,
+    bounds: AABB,
+    lipschitz: f32,
+    pub material: Box<dyn Material>,
+}
+
+impl DistanceField {
+    /// Closer than that to the surface is a hit
+    const HIT_DISTANCE: f32 = 1e-4;
+    const MAX_STEPS: usize = 512;
+    /// Room around the surface in the bounds, so that the rays don't start marching on it
+    const BOUNDS_PADDING: f32 = 1e-3;
+
+    pub fn new(sdf: Sdf, material: Box<dyn Material>) -> Self {
+        let bounds = sdf.bounds();
+        let padding = bvh::Vector3::splat(Self::BOUNDS_PADDING);
+        Self {
+            bounds: AABB::with_bounds(bounds.min - padding, bounds.max + padding),
+            lipschitz: sdf.lipschitz(),
+            sdf,
+            material,
+        }
+    }
+
+    pub fn sdf(&self) -> &Sdf {
+        &self.sdf
+    }
+
+    /// Where the line of the ray is inside the bounds
+    fn span(&self, ray: &Ray) -> Option<Interval> {
+        (0..3).try_fold(Interval::everywhere(), |span, axis| {
+            let (origin, direction) = (ray.origin[axis], ray.direction[axis]);
+            let (min, max) = (self.bounds.min[axis], self.bounds.max[axis]);
+            if direction == 0.0 {
+                return (min..=max).contains(&origin).then_some(span);
+            }
+            let (t0, t1) = ((min - origin) / direction, (max - origin) / direction);
+            span.overlap(&Interval::new(t0.min(t1), t0.max(t1)))
+        })
+    }
+
+    /// First crossing of the surface between the distances `from` and `to`. A ray starting on
+    /// the surface, like the ones it scatters, has to leave it before it can hit it again.
+    fn march(&self, ray: &Ray, from: f32, to: f32) -> Option<f32> {
+        let length = ray.direction.norm();
+        let mut t = from;
+        let mut left_surface = false;
+        for _ in 0..Self::MAX_STEPS {
+            if t > to {
+                return None;
+            }
+            let distance = (self.sdf.distance(&ray.point_at(t)) / self.lipschitz).abs();
+            if distance < Self::HIT_DISTANCE {
+                if left_surface {
+                    return Some(t);
+                }
+            } else {
+                left_surface = true;
+            }
+            t += distance.max(Self::HIT_DISTANCE) / length;
+        }
+        None
+    }
+}
+
+impl Intersectable for DistanceField {
+    fn intersect(&self, ray: &Ray) -> Option<f32> {
+        let span = self.span(ray)?;
+        if span.exit < 0.0 {
+            return None;
+        }
+        self.march(ray, span.enter.max(0.0), span.exit)
+    }
+
+    /// From crossing to crossing through the bounds, inside where the distance is negative
+    fn intervals(&self, ray: &Ray) -> Vec<Interval> {
+        let Some(span) = self.span(ray) else {
+            return Vec::new();
+        };
+        let mut intervals = Vec::new();
+        let mut enter = (self.sdf.distance(&ray.point_at(span.enter)) < 0.0).then_some(span.enter);
+        let mut t = span.enter;
+        while let Some(crossing) = self.march(ray, t, span.exit) {
+            match enter.take() {
+                Some(enter) => intervals.push(Interval::new(enter, crossing)),
+                None => enter = Some(crossing),
+            }
+            t = crossing;
+        }
+        if let Some(enter) = enter {
+            intervals.push(Interval::new(enter, span.exit));
+        }
+        intervals
+    }
+}
+
+impl Object for DistanceField {
+    /// The gradient of the distance, from four samples around the point
+    fn surface_normal(&self, point: &Point) -> Vector3<f32> {
+        // https://iquilezles.org/articles/normalsSDF/
+        const H: f32 = 1e-4;
+        let gradient = [Vector3::new(1.0, -1.0, -1.0), Vector3::new(-1.0, -1.0, 1.0), Vector3::new(-1.0, 1.0, -1.0), Vector3::new(1.0, 1.0, 1.0)]
+            .iter()
+            .map(|k| k * self.sdf.distance(&(point + k * H)))
+            .sum::<Vector3<f32>>();
+        gradient.try_normalize(0.0).unwrap_or(Vector3::y())
+    }
+
+    fn material(&self) -> &dyn Material {
+        self.material.as_ref()
+    }
+
+    fn fingerprint(&self, fingerprint: &mut Fingerprint) {
+        fingerprint.add_str("distance field");
+        fingerprint.add_debug(&(self.sdf));
+        self.material.fingerprint(fingerprint);
+    }
+
+    fn bounds(&self) -> Option<AABB> {
+        Some(self.bounds)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use nalgebra::Vector3;
+
+    use super::{DistanceField, Sdf};
+    use crate::{
+        color,
+        geometry::{Intersectable, Point, Ray, Sphere},
+        material::Diffuse,
+        object::Object,
+    };
+
+    #[test]
+    fn sphere_traces_the_surface_of_the_distance_field() {
+        let material = || Box::new(Diffuse::new(color::WHITE));
+        // The same sphere, analytic and as a distance field
+        let field = DistanceField::new(Sdf::sphere(1.0).translate(Vector3::new(0.0, 0.0, -4.0)), material());
+        let sphere = Sphere::new_with_material(0.0, 0.0, -4.0, 1.0, material());
+        for direction in [Vector3::new(0.0, 0.0, -1.0), Vector3::new(0.2, 0.1, -1.0), Vector3::new(0.0, 0.24, -2.0)] {
+            let ray = Ray::new(Point::origin(), direction);
+            let expected = sphere.intersection(&ray, &sphere).unwrap();
+            let hit = field.intersection(&ray, &field).unwrap();
+            assert!((hit.distance - expected.distance).abs() < 1e-3, "{} against {}", hit.distance, expected.distance);
+            assert!((hit.normal - expected.normal).norm() < 1e-2);
+        }
+        assert!(field.intersect(&Ray::new(Point::origin(), Vector3::new(0.0, 1.0, -1.0))).is_none());
+        // Leaving the surface, it doesn't hit itself but the other side
+        let inside = field.intersect(&Ray::new(Point::new(0.0, 0.0, -3.0), -Vector3::z())).unwrap();
+        assert!((inside - 2.0).abs() < 1e-3, "{}", inside);
+        let intervals = field.intervals(&Ray::new(Point::origin(), -Vector3::z()));
+        assert!(intervals.len() == 1 && (intervals[0].enter - 3.0).abs() < 1e-3 && (intervals[0].exit - 5.0).abs() < 1e-3, "{:?}", intervals);
+
+        // Blended, twisted and repeated shapes stay inside their bounds, and are still hit
+        let shapes = [
+            Sdf::sphere(0.5).smooth_union(Sdf::sphere(0.5).translate(Vector3::new(0.8, 0.0, 0.0)), 0.5),
+            Sdf::cuboid(Vector3::new(0.8, 1.0, 0.2)).twist(1.5),
+            Sdf::torus(0.3, 0.1).round(0.05).repeat(Vector3::new(1.0, 1.0, 1.0), Vector3::new(2.0, 0.0, 1.0)),
+            Sdf::mandelbulb(8.0, 8),
+        ];
+        for sdf in shapes {
+            let bounds = sdf.bounds();
+            for i in 0..1000 {
+                let p = Point::new((i % 10) as f32, ((i / 10) % 10) as f32, (i / 100) as f32) * 0.4 - Vector3::repeat(1.8);
+                let outside = p.iter().enumerate().any(|(axis, &x)| x < bounds.min[axis] - 1e-3 || x > bounds.max[axis] + 1e-3);
+                assert!(!outside || sdf.distance(&p) > 0.0, "{:?} is inside at {} out of its bounds", sdf, p);
+            }
+            let field = DistanceField::new(sdf, material());
+            assert!(field.intersect(&Ray::new(Point::new(0.01, 0.02, 5.0), Vector3::new(0.0, 0.0, -1.0))).is_some());
+        }
+    }
+}
@@ -0,0 +1,951 @@
+use std::f32::consts::PI;
+
+use bvh::aabb::AABB;
+use nalgebra::{Matrix4, UnitQuaternion, Vector2, Vector3};
+
+use crate::{
+    checkpoint::Fingerprint,
+    geometry::{Intersectable, Interval, Point, Ray},
+    material::Material,
+    motion,
+    object::{Object, SurfaceSample},
+    sampler::Sampler,
+};
+
+/// Thickness given to the boxes of flat shapes, which the rays could slip past otherwise
+const FLAT_BOUNDS_PADDING: f32 = 1e-4;
+
+/// Where a shape is placed: its local frame is rotated by `rotation`, then moved to `origin`
+#[derive(Debug, Clone, Copy)]
+struct Frame {
+    origin: Point,
+    rotation: UnitQuaternion<f32>,
+}
+
+impl Frame {
+    /// A frame at `origin` whose local z axis points along `axis`
+    fn along(origin: Point, axis: &Vector3<f32>) -> Self {
+        let rotation = UnitQuaternion::rotation_between(&Vector3::z(), axis)
+            // Only fails for an axis opposite to z, which half a turn around x reaches
+            .unwrap_or_else(|| UnitQuaternion::from_axis_angle(&Vector3::x_axis(), PI));
+        Self { origin, rotation }
+    }
+
+    /// The ray in the local frame. Rotations keep lengths, so distances along it are the same.
+    fn ray_to_local(&self, ray: &Ray) -> Ray {
+        Ray {
+            origin: Point::from(self.rotation.inverse_transform_vector(&(ray.origin - self.origin))),
+            direction: self.rotation.inverse_transform_vector(&ray.direction),
+            time: ray.time,
+        }
+    }
+
+    fn point_to_local(&self, point: &Point) -> Point {
+        Point::from(self.rotation.inverse_transform_vector(&(point - self.origin)))
+    }
+
+    fn point_to_world(&self, point: &Point) -> Point {
+        self.origin + self.rotation * point.coords
+    }
+
+    fn vector_to_world(&self, vector: &Vector3<f32>) -> Vector3<f32> {
+        self.rotation * vector
+    }
+
+    /// Box around the local box from `min` to `max` once placed in the world
+    fn bounds(&self, min: Vector3<f32>, max: Vector3<f32>) -> AABB {
+        let matrix = Matrix4::new_translation(&self.origin.coords) * self.rotation.to_homogeneous();
+        let local = AABB::with_bounds(bvh::Point3::new(min.x, min.y, min.z), bvh::Point3::new(max.x, max.y, max.z));
+        motion::transform_bounds(&matrix, &local)
+    }
+}
+
+/// Roots of `a t² + b t + c`, the smallest first. Falls back to the linear equation when `a` is
+/// zero, as for rays parallel to the side of a cone.
+fn solve_quadratic(a: f32, b: f32, c: f32) -> Option<(f32, f32)> {
+    if a.abs() < 1e-12 {
+        if b == 0.0 {
+            return None;
+        }
+        let t = -c / b;
+        return Some((t, t));
+    }
+    let discriminant = b * b - 4.0 * a * c;
+    if discriminant < 0.0 {
+        return None;
+    }
+    // Avoids the cancellation of -b + sqrt(discriminant) when b is large
+    let q = -0.5 * (b + b.signum() * discriminant.sqrt());
+    let (t0, t1) = if q == 0.0 { (0.0, 0.0) } else { (q / a, c / q) };
+    Some((t0.min(t1), t0.max(t1)))
+}
+
+/// Where `a t² + b t + c` is negative or zero, the inside of a quadric along a ray
+fn quadric_intervals(a: f32, b: f32, c: f32) -> Vec<Interval> {
+    if a.abs() < 1e-12 {
+        return match b {
+            _ if b > 0.0 => vec![Interval::new(f32::NEG_INFINITY, -c / b)],
+            _ if b < 0.0 => vec![Interval::new(-c / b, f32::INFINITY)],
+            _ if c <= 0.0 => vec![Interval::everywhere()],
+            _ => Vec::new(),
+        };
+    }
+    match solve_quadratic(a, b, c) {
+        Some((t0, t1)) if a > 0.0 => vec![Interval::new(t0, t1)],
+        Some((t0, t1)) => vec![Interval::new(f32::NEG_INFINITY, t0), Interval::new(t1, f32::INFINITY)],
+        // Opening downwards, it's negative everywhere
+        None if a < 0.0 => vec![Interval::everywhere()],
+        None => Vec::new(),
+    }
+}
+
+/// Where the ray is between `min` and `max` along the local axis `axis`
+fn slab_interval(local: &Ray, axis: usize, min: f32, max: f32) -> Option<Interval> {
+    let (origin, direction) = (local.origin[axis], local.direction[axis]);
+    if direction == 0.0 {
+        return (min..=max).contains(&origin).then_some(Interval::everywhere());
+    }
+    let (t0, t1) = ((min - origin) / direction, (max - origin) / direction);
+    Some(Interval::new(t0.min(t1), t0.max(t1)))
+}
+
+/// Real roots of `c[0] t⁴ + c[1] t³ + c[2] t² + c[3] t + c[4]`, in any order, by Ferrari's
+/// method and a few Newton steps to polish them
+pub fn solve_quartic(c: [f64; 5]) -> Vec<f64> {
+    if c[0] == 0.0 {
+        return Vec::new();
+    }
+    let (a, b, cc, d) = (c[1] / c[0], c[2] / c[0], c[3] / c[0], c[4] / c[0]);
+    // Depressed quartic y⁴ + p y² + q y + r with t = y - a/4
+    let p = b - 3.0 * a * a / 8.0;
+    let q = cc - a * b / 2.0 + a * a * a / 8.0;
+    let r = d - a * cc / 4.0 + a * a * b / 16.0 - 3.0 * a * a * a * a / 256.0;
+    let mut roots = Vec::with_capacity(4);
+    if q.abs() < 1e-12 {
+        // Biquadratic, a quadratic in y²
+        let discriminant = p * p - 4.0 * r;
+        if discriminant >= 0.0 {
+            for y2 in [(-p + discriminant.sqrt()) / 2.0, (-p - discriminant.sqrt()) / 2.0] {
+                if y2 >= 0.0 {
+                    roots.extend([y2.sqrt(), -y2.sqrt()]);
+                }
+            }
+        }
+    } else {
+        // The resolvent cubic always has a positive root, as it's negative at zero
+        let m = largest_cubic_root(p, p * p / 4.0 - r, -q * q / 8.0);
+        if m > 0.0 {
+            let s = (2.0 * m).sqrt();
+            for sign in [1.0, -1.0] {
+                let discriminant = -(2.0 * p + 2.0 * m + sign * 2.0 * q / s);
+                if discriminant >= 0.0 {
+                    roots.extend([(sign * s + discriminant.sqrt()) / 2.0, (sign * s - discriminant.sqrt()) / 2.0]);
+                }
+            }
+        }
+    }
+    let value = |t: f64| (((c[0] * t + c[1]) * t + c[2]) * t + c[3]) * t + c[4];
+    let slope = |t: f64| ((4.0 * c[0] * t + 3.0 * c[1]) * t + 2.0 * c[2]) * t + c[3];
+    roots
+        .into_iter()
+        .map(|y| {
+            let mut t = y - a / 4.0;
+            for _ in 0..4 {
+                let derivative = slope(t);
+                if derivative == 0.0 {
+                    break;
+                }
+                t -= value(t) / derivative;
+            }
+            t
+        })
+        .collect()
+}
+
+/// Largest real root of `m³ + a m² + b m + c`
+fn largest_cubic_root(a: f64, b: f64, c: f64) -> f64 {
+    // Depressed cubic x³ + p x + q with m = x - a/3
+    let p = b - a * a / 3.0;
+    let q = 2.0 * a * a * a / 27.0 - a * b / 3.0 + c;
+    let discriminant = q * q / 4.0 + p * p * p / 27.0;
+    let x = if discriminant > 0.0 {
+        let root = discriminant.sqrt();
+        (-q / 2.0 + root).cbrt() + (-q / 2.0 - root).cbrt()
+    } else if p == 0.0 {
+        0.0
+    } else {
+        // Three real roots, the first of the trigonometric ones is the largest
+        let cos = (3.0 * q / (2.0 * p) * (-3.0 / p).sqrt()).clamp(-1.0, 1.0);
+        2.0 * (-p / 3.0).sqrt() * (cos.acos() / 3.0).cos()
+    };
+    x - a / 3.0
+}
+
+/// A box, axis-aligned or turned by a rotation around its center
+pub struct Cuboid {
+    frame: Frame,
+    /// Half the size of the box along each of its local axes
+    pub half_size: Vector3<f32>,
+    pub material: Box<dyn Material>,
+}
+
+impl Cuboid {
+    /// The axis-aligned box from the corner `min` to the corner `max`
+    pub fn new(min: Point, max: Point, material: Box<dyn Material>) -> Self {
+        Self::oriented(nalgebra::center(&min, &max), (max - min).abs() / 2.0, UnitQuaternion::identity(), material)
+    }
+
+    /// The box of `half_size` around `center`, turned by `rotation`
+    pub fn oriented(center: Point, half_size: Vector3<f32>, rotation: UnitQuaternion<f32>, material: Box<dyn Material>) -> Self {
+        Self {
+            frame: Frame { origin: center, rotation },
+            half_size,
+            material,
+        }
+    }
+
+    pub fn area(&self) -> f32 {
+        let h = self.half_size;
+        8.0 * (h.x * h.y + h.y * h.z + h.z * h.x)
+    }
+
+    /// Axis of the face the local point is on, the one it's the furthest along relative to the
+    /// size of the box
+    fn face_axis(&self, local: &Point) -> usize {
+        let relative = local.coords.component_div(&self.half_size).abs();
+        relative.imax()
+    }
+}
+
+impl Intersectable for Cuboid {
+    fn intersect(&self, ray: &Ray) -> Option<f32> {
+        let interval = self.intervals(ray).pop()?;
+        if interval.exit < 0.0 {
+            return None;
+        }
+        Some(if interval.enter >= 0.0 { interval.enter } else { interval.exit })
+    }
+
+    /// Where the ray is between the three pairs of faces at once, https://tavianator.com/2011/ray_box.html
+    fn intervals(&self, ray: &Ray) -> Vec<Interval> {
+        let local = self.frame.ray_to_local(ray);
+        (0..3)
+            .try_fold(Interval::everywhere(), |inside, axis| {
+                inside.overlap(&slab_interval(&local, axis, -self.half_size[axis], self.half_size[axis])?)
+            })
+            .into_iter()
+            .collect()
+    }
+}
+
+impl Object for Cuboid {
+    fn surface_normal(&self, point: &Point) -> Vector3<f32> {
+        let local = self.frame.point_to_local(point);
+        let axis = self.face_axis(&local);
+        let mut normal = Vector3::zeros();
+        normal[axis] = local[axis].signum();
+        self.frame.vector_to_world(&normal)
+    }
+
+    /// Each face has the whole texture, along the two other axes in their order
+    fn surface_uv(&self, point: &Point) -> Vector2<f32> {
+        let local = self.frame.point_to_local(point);
+        let axis = self.face_axis(&local);
+        let (u, v) = ((axis + 1) % 3, (axis + 2) % 3);
+        let coordinate = |other: usize| (local[other] / self.half_size[other] * 0.5 + 0.5).clamp(0.0, 1.0);
+        Vector2::new(coordinate(u), coordinate(v))
+    }
+
+    fn sample_surface(&self, _time: f32, sampler: &mut dyn Sampler) -> Option<SurfaceSample> {
+        let area = self.area();
+        if area <= 0.0 {
+            return None;
+        }
+        // Pick a pair of opposite faces by their area, then one of them with what's left of the number
+        let h = self.half_size;
+        let faces = [h.y * h.z, h.z * h.x, h.x * h.y];
+        let mut pick = sampler.get_1d() * (faces[0] + faces[1] + faces[2]);
+        let mut axis = 2;
+        for (i, face) in faces.iter().enumerate() {
+            if pick < *face {
+                axis = i;
+                break;
+            }
+            pick -= face;
+        }
+        let side = if pick < faces[axis] / 2.0 { -1.0 } else { 1.0 };
+        let (u, v) = sampler.get_2d();
+        let mut local = Vector3::zeros();
+        local[axis] = side * h[axis];
+        local[(axis + 1) % 3] = (2.0 * u - 1.0) * h[(axis + 1) % 3];
+        local[(axis + 2) % 3] = (2.0 * v - 1.0) * h[(axis + 2) % 3];
+        let mut normal = Vector3::zeros();
+        normal[axis] = side;
+        Some(SurfaceSample {
+            point: self.frame.point_to_world(&Point::from(local)),
+            normal: self.frame.vector_to_world(&normal),
+            pdf: 1.0 / area,
+        })
+    }
+
+    fn surface_pdf(&self, _point: &Point, _normal: &Vector3<f32>, _time: f32) -> f32 {
+        let area = self.area();
+        if area > 0.0 { 1.0 / area } else { 0.0 }
+    }
+
+    fn bounds(&self) -> Option<AABB> {
+        Some(self.frame.bounds(-self.half_size, self.half_size))
+    }
+
+    fn material(&self) -> &dyn Material {
+        self.material.as_ref()
+    }
+
+    fn fingerprint(&self, fingerprint: &mut Fingerprint) {
+        fingerprint.add_str("cuboid");
+        fingerprint.add_debug(&(self.frame, self.half_size));
+        self.material.fingerprint(fingerprint);
+    }
+}
+
+/// A parallelogram with a corner at `origin` and sides `edge_u` and `edge_v`. It faces the side
+/// of `edge_u × edge_v`, e.g. down for a ceiling light with the edges along x then z.
+pub struct Quad {
+    pub origin: Point,
+    pub edge_u: Vector3<f32>,
+    pub edge_v: Vector3<f32>,
+    pub material: Box<dyn Material>,
+}
+
+impl Quad {
+    pub fn new(origin: Point, edge_u: Vector3<f32>, edge_v: Vector3<f32>, material: Box<dyn Material>) -> Self {
+        Self {
+            origin,
+            edge_u,
+            edge_v,
+            material,
+        }
+    }
+
+    pub fn area(&self) -> f32 {
+        self.edge_u.cross(&self.edge_v).norm()
+    }
+
+    /// Coordinates of a point of the plane along the edges, in [0, 1] inside the quad
+    fn coordinates(&self, point: &Point) -> Vector2<f32> {
+        let n = self.edge_u.cross(&self.edge_v);
+        let w = n / n.norm_squared();
+        let offset = point - self.origin;
+        Vector2::new(w.dot(&offset.cross(&self.edge_v)), w.dot(&self.edge_u.cross(&offset)))
+    }
+}
+
+impl Intersectable for Quad {
+    fn intersect(&self, ray: &Ray) -> Option<f32> {
+        let n = self.edge_u.cross(&self.edge_v);
+        let denom = n.dot(&ray.direction);
+        if denom == 0.0 {
+            return None;
+        }
+        let t = n.dot(&(self.origin - ray.origin)) / denom;
+        if t < 0.0 {
+            return None;
+        }
+        let uv = self.coordinates(&ray.point_at(t));
+        ((0.0..=1.0).contains(&uv.x) && (0.0..=1.0).contains(&uv.y)).then_some(t)
+    }
+}
+
+impl Object for Quad {
+    fn surface_normal(&self, _point: &Point) -> Vector3<f32> {
+        self.edge_u.cross(&self.edge_v).normalize()
+    }
+
+    fn surface_uv(&self, point: &Point) -> Vector2<f32> {
+        self.coordinates(point)
+    }
+
+    fn sample_surface(&self, _time: f32, sampler: &mut dyn Sampler) -> Option<SurfaceSample> {
+        let area = self.area();
+        if area <= 0.0 {
+            return None;
+        }
+        let (u, v) = sampler.get_2d();
+        Some(SurfaceSample {
+            point: self.origin + self.edge_u * u + self.edge_v * v,
+            normal: self.edge_u.cross(&self.edge_v) / area,
+            pdf: 1.0 / area,
+        })
+    }
+
+    fn surface_pdf(&self, _point: &Point, _normal: &Vector3<f32>, _time: f32) -> f32 {
+        let area = self.area();
+        if area > 0.0 { 1.0 / area } else { 0.0 }
+    }
+
+    fn bounds(&self) -> Option<AABB> {
+        let corners = [self.origin, self.origin + self.edge_u, self.origin + self.edge_v, self.origin + self.edge_u + self.edge_v];
+        let bounds = corners.iter().fold(AABB::empty(), |bounds, corner| bounds.grow(&bvh::Point3::new(corner.x, corner.y, corner.z)));
+        let padding = bvh::Vector3::splat(FLAT_BOUNDS_PADDING);
+        Some(AABB::with_bounds(bounds.min - padding, bounds.max + padding))
+    }
+
+    fn material(&self) -> &dyn Material {
+        self.material.as_ref()
+    }
+
+    fn fingerprint(&self, fingerprint: &mut Fingerprint) {
+        fingerprint.add_str("quad");
+        fingerprint.add_debug(&(self.origin, self.edge_u, self.edge_v));
+        self.material.fingerprint(fingerprint);
+    }
+}
+
+/// A flat disk facing along `normal`
+pub struct Disk {
+    frame: Frame,
+    pub radius: f32,
+    pub material: Box<dyn Material>,
+}
+
+impl Disk {
+    pub fn new(center: Point, normal: Vector3<f32>, radius: f32, material: Box<dyn Material>) -> Self {
+        Self {
+            frame: Frame::along(center, &normal),
+            radius,
+            material,
+        }
+    }
+
+    pub fn area(&self) -> f32 {
+        PI * self.radius * self.radius
+    }
+}
+
+/// Distance to the disk of `radius` around the local z axis at the height `z`, if the ray
+/// crosses it
+fn intersect_local_disk(local: &Ray, z: f32, radius: f32) -> Option<f32> {
+    if local.direction.z == 0.0 {
+        return None;
+    }
+    let t = (z - local.origin.z) / local.direction.z;
+    let point = local.point_at(t);
+    (t >= 0.0 && point.x * point.x + point.y * point.y <= radius * radius).then_some(t)
+}
+
+/// Uniform point on the disk of `radius` around the local z axis, at the height `z`
+fn sample_local_disk(sampler: &mut dyn Sampler, z: f32, radius: f32) -> Point {
+    let (u, v) = sampler.get_2d();
+    let r = radius * u.sqrt();
+    let phi = 2.0 * PI * v;
+    Point::new(r * phi.cos(), r * phi.sin(), z)
+}
+
+/// Texture coordinates around the local z axis: the angle, then the distance to the axis
+/// relative to `radius`
+fn polar_uv(local: &Point, radius: f32) -> Vector2<f32> {
+    let u = 0.5 + local.y.atan2(local.x) / (2.0 * PI);
+    Vector2::new(u, (local.x.hypot(local.y) / radius).min(1.0))
+}
+
+impl Intersectable for Disk {
+    fn intersect(&self, ray: &Ray) -> Option<f32> {
+        intersect_local_disk(&self.frame.ray_to_local(ray), 0.0, self.radius)
+    }
+}
+
+impl Object for Disk {
+    fn surface_normal(&self, _point: &Point) -> Vector3<f32> {
+        self.frame.vector_to_world(&Vector3::z())
+    }
+
+    fn surface_uv(&self, point: &Point) -> Vector2<f32> {
+        polar_uv(&self.frame.point_to_local(point), self.radius)
+    }
+
+    fn sample_surface(&self, _time: f32, sampler: &mut dyn Sampler) -> Option<SurfaceSample> {
+        let area = self.area();
+        if area <= 0.0 {
+            return None;
+        }
+        Some(SurfaceSample {
+            point: self.frame.point_to_world(&sample_local_disk(sampler, 0.0, self.radius)),
+            normal: self.frame.vector_to_world(&Vector3::z()),
+            pdf: 1.0 / area,
+        })
+    }
+
+    fn surface_pdf(&self, _point: &Point, _normal: &Vector3<f32>, _time: f32) -> f32 {
+        let area = self.area();
+        if area > 0.0 { 1.0 / area } else { 0.0 }
+    }
+
+    fn bounds(&self) -> Option<AABB> {
+        let extent = Vector3::new(self.radius, self.radius, FLAT_BOUNDS_PADDING);
+        Some(self.frame.bounds(-extent, extent))
+    }
+
+    fn material(&self) -> &dyn Material {
+        self.material.as_ref()
+    }
+
+    fn fingerprint(&self, fingerprint: &mut Fingerprint) {
+        fingerprint.add_str("disk");
+        fingerprint.add_debug(&(self.frame, self.radius));
+        self.material.fingerprint(fingerprint);
+    }
+}
+
+/// A cylinder closed by disks at both ends, from `base` up `height` along `axis`
+pub struct Cylinder {
+    frame: Frame,
+    pub radius: f32,
+    pub height: f32,
+    pub material: Box<dyn Material>,
+}
+
+impl Cylinder {
+    pub fn new(base: Point, axis: Vector3<f32>, radius: f32, height: f32, material: Box<dyn Material>) -> Self {
+        Self {
+            frame: Frame::along(base, &axis),
+            radius,
+            height,
+            material,
+        }
+    }
+
+    pub fn area(&self) -> f32 {
+        2.0 * PI * self.radius * (self.height + self.radius)
+    }
+
+    /// Whether a local point is on one of the caps rather than on the side
+    fn on_cap(&self, local: &Point) -> bool {
+        let side = (local.x.hypot(local.y) - self.radius).abs();
+        local.z.abs().min((local.z - self.height).abs()) < side
+    }
+}
+
+impl Intersectable for Cylinder {
+    fn intersect(&self, ray: &Ray) -> Option<f32> {
+        let local = self.frame.ray_to_local(ray);
+        let (o, d) = (local.origin, local.direction);
+        let side = solve_quadratic(d.x * d.x + d.y * d.y, 2.0 * (o.x * d.x + o.y * d.y), o.x * o.x + o.y * o.y - self.radius * self.radius)
+            .map(|(t0, t1)| [t0, t1])
+            .unwrap_or([f32::NAN; 2])
+            .into_iter()
+            .filter(|&t| t >= 0.0 && (0.0..=self.height).contains(&local.point_at(t).z));
+        let caps = [0.0, self.height].into_iter().filter_map(|z| intersect_local_disk(&local, z, self.radius));
+        side.chain(caps).min_by(f32::total_cmp)
+    }
+
+    /// Where the ray is inside the infinite cylinder and between the caps
+    fn intervals(&self, ray: &Ray) -> Vec<Interval> {
+        let local = self.frame.ray_to_local(ray);
+        let (o, d) = (local.origin, local.direction);
+        let Some(caps) = slab_interval(&local, 2, 0.0, self.height) else {
+            return Vec::new();
+        };
+        quadric_intervals(d.x * d.x + d.y * d.y, 2.0 * (o.x * d.x + o.y * d.y), o.x * o.x + o.y * o.y - self.radius * self.radius)
+            .iter()
+            .filter_map(|side| side.overlap(&caps))
+            .collect()
+    }
+}
+
+impl Object for Cylinder {
+    fn surface_normal(&self, point: &Point) -> Vector3<f32> {
+        let local = self.frame.point_to_local(point);
+        let normal = if self.on_cap(&local) {
+            Vector3::new(0.0, 0.0, if local.z > self.height / 2.0 { 1.0 } else { -1.0 })
+        } else {
+            Vector3::new(local.x, local.y, 0.0).try_normalize(0.0).unwrap_or(Vector3::x())
+        };
+        self.frame.vector_to_world(&normal)
+    }
+
+    /// The side has the angle and the height, the caps their polar coordinates
+    fn surface_uv(&self, point: &Point) -> Vector2<f32> {
+        let local = self.frame.point_to_local(point);
+        let polar = polar_uv(&local, self.radius);
+        if self.on_cap(&local) {
+            polar
+        } else {
+            Vector2::new(polar.x, (local.z / self.height).clamp(0.0, 1.0))
+        }
+    }
+
+    fn sample_surface(&self, _time: f32, sampler: &mut dyn Sampler) -> Option<SurfaceSample> {
+        let area = self.area();
+        if area <= 0.0 {
+            return None;
+        }
+        let side_area = 2.0 * PI * self.radius * self.height;
+        let pick = sampler.get_1d() * area;
+        let (local, normal) = if pick < side_area {
+            let (u, v) = sampler.get_2d();
+            let phi = 2.0 * PI * u;
+            let normal = Vector3::new(phi.cos(), phi.sin(), 0.0);
+            (Point::new(self.radius * normal.x, self.radius * normal.y, v * self.height), normal)
+        } else {
+            // The caps share what's left, the top one past the middle of it
+            let top = pick - side_area > (area - side_area) / 2.0;
+            let z = if top { self.height } else { 0.0 };
+            (sample_local_disk(sampler, z, self.radius), Vector3::new(0.0, 0.0, if top { 1.0 } else { -1.0 }))
+        };
+        Some(SurfaceSample {
+            point: self.frame.point_to_world(&local),
+            normal: self.frame.vector_to_world(&normal),
+            pdf: 1.0 / area,
+        })
+    }
+
+    fn surface_pdf(&self, _point: &Point, _normal: &Vector3<f32>, _time: f32) -> f32 {
+        let area = self.area();
+        if area > 0.0 { 1.0 / area } else { 0.0 }
+    }
+
+    fn bounds(&self) -> Option<AABB> {
+        Some(self.frame.bounds(Vector3::new(-self.radius, -self.radius, 0.0), Vector3::new(self.radius, self.radius, self.height)))
+    }
+
+    fn material(&self) -> &dyn Material {
+        self.material.as_ref()
+    }
+
+    fn fingerprint(&self, fingerprint: &mut Fingerprint) {
+        fingerprint.add_str("cylinder");
+        fingerprint.add_debug(&(self.frame, self.radius, self.height));
+        self.material.fingerprint(fingerprint);
+    }
+}
+
+/// A cone standing on a disk of `radius` at `base`, with its apex `height` up along `axis`
+pub struct Cone {
+    frame: Frame,
+    pub radius: f32,
+    pub height: f32,
+    pub material: Box<dyn Material>,
+}
+
+impl Cone {
+    pub fn new(base: Point, axis: Vector3<f32>, radius: f32, height: f32, material: Box<dyn Material>) -> Self {
+        Self {
+            frame: Frame::along(base, &axis),
+            radius,
+            height,
+            material,
+        }
+    }
+
+    fn slant(&self) -> f32 {
+        self.radius.hypot(self.height)
+    }
+
+    pub fn area(&self) -> f32 {
+        PI * self.radius * (self.slant() + self.radius)
+    }
+
+    fn on_base(&self, local: &Point) -> bool {
+        let side_radius = self.radius * (1.0 - local.z / self.height);
+        let side = (local.x.hypot(local.y) - side_radius).abs() * self.height / self.slant();
+        local.z.abs() < side
+    }
+}
+
+impl Cone {
+    /// Coefficients of x² + y² - (radius - k z)² along the local ray, which is zero on the
+    /// double cone around the side, the radius shrinking to zero at the apex
+    fn side_quadric(&self, local: &Ray) -> (f32, f32, f32) {
+        let (o, d) = (local.origin, local.direction);
+        let k = self.radius / self.height;
+        let (s0, sd) = (self.radius - k * o.z, -k * d.z);
+        (d.x * d.x + d.y * d.y - sd * sd, 2.0 * (o.x * d.x + o.y * d.y - s0 * sd), o.x * o.x + o.y * o.y - s0 * s0)
+    }
+}
+
+impl Intersectable for Cone {
+    fn intersect(&self, ray: &Ray) -> Option<f32> {
+        let local = self.frame.ray_to_local(ray);
+        let (a, b, c) = self.side_quadric(&local);
+        let side = solve_quadratic(a, b, c)
+            .map(|(t0, t1)| [t0, t1])
+            .unwrap_or([f32::NAN; 2])
+            .into_iter()
+            .filter(|&t| t >= 0.0 && (0.0..=self.height).contains(&local.point_at(t).z));
+        side.chain(intersect_local_disk(&local, 0.0, self.radius)).min_by(f32::total_cmp)
+    }
+
+    /// Where the ray is inside the double cone and between the base and the apex, where only
+    /// the lower cone is
+    fn intervals(&self, ray: &Ray) -> Vec<Interval> {
+        let local = self.frame.ray_to_local(ray);
+        let Some(height) = slab_interval(&local, 2, 0.0, self.height) else {
+            return Vec::new();
+        };
+        let (a, b, c) = self.side_quadric(&local);
+        quadric_intervals(a, b, c).iter().filter_map(|side| side.overlap(&height)).collect()
+    }
+}
+
+impl Object for Cone {
+    fn surface_normal(&self, point: &Point) -> Vector3<f32> {
+        let local = self.frame.point_to_local(point);
+        let normal = if self.on_base(&local) {
+            -Vector3::z()
+        } else {
+            match Vector2::new(local.x, local.y).try_normalize(0.0) {
+                Some(radial) => Vector3::new(radial.x * self.height, radial.y * self.height, self.radius) / self.slant(),
+                None => Vector3::z(),
+            }
+        };
+        self.frame.vector_to_world(&normal)
+    }
+
+    /// The side has the angle and the height, the base its polar coordinates
+    fn surface_uv(&self, point: &Point) -> Vector2<f32> {
+        let local = self.frame.point_to_local(point);
+        let polar = polar_uv(&local, self.radius);
+        if self.on_base(&local) {
+            polar
+        } else {
+            Vector2::new(polar.x, (local.z / self.height).clamp(0.0, 1.0))
+        }
+    }
+
+    fn sample_surface(&self, _time: f32, sampler: &mut dyn Sampler) -> Option<SurfaceSample> {
+        let area = self.area();
+        if area <= 0.0 {
+            return None;
+        }
+        let side_area = PI * self.radius * self.slant();
+        let (local, normal) = if sampler.get_1d() * area < side_area {
+            // The side is as wide as the distance to the apex, which makes it grow as a square root
+            let (u, v) = sampler.get_2d();
+            let r = self.radius * u.sqrt();
+            let phi = 2.0 * PI * v;
+            let normal = Vector3::new(phi.cos() * self.height, phi.sin() * self.height, self.radius) / self.slant();
+            (Point::new(r * phi.cos(), r * phi.sin(), self.height * (1.0 - r / self.radius)), normal)
+        } else {
+            (sample_local_disk(sampler, 0.0, self.radius), -Vector3::z())
+        };
+        Some(SurfaceSample {
+            point: self.frame.point_to_world(&local),
+            normal: self.frame.vector_to_world(&normal),
+            pdf: 1.0 / area,
+        })
+    }
+
+    fn surface_pdf(&self, _point: &Point, _normal: &Vector3<f32>, _time: f32) -> f32 {
+        let area = self.area();
+        if area > 0.0 { 1.0 / area } else { 0.0 }
+    }
+
+    fn bounds(&self) -> Option<AABB> {
+        Some(self.frame.bounds(Vector3::new(-self.radius, -self.radius, 0.0), Vector3::new(self.radius, self.radius, self.height)))
+    }
+
+    fn material(&self) -> &dyn Material {
+        self.material.as_ref()
+    }
+
+    fn fingerprint(&self, fingerprint: &mut Fingerprint) {
+        fingerprint.add_str("cone");
+        fingerprint.add_debug(&(self.frame, self.radius, self.height));
+        self.material.fingerprint(fingerprint);
+    }
+}
+
+/// A ring around `axis`: the circle of `major_radius` swept by a tube of `minor_radius`
+pub struct Torus {
+    frame: Frame,
+    pub major_radius: f32,
+    pub minor_radius: f32,
+    pub material: Box<dyn Material>,
+}
+
+impl Torus {
+    pub fn new(center: Point, axis: Vector3<f32>, major_radius: f32, minor_radius: f32, material: Box<dyn Material>) -> Self {
+        Self {
+            frame: Frame::along(center, &axis),
+            major_radius,
+            minor_radius,
+            material,
+        }
+    }
+
+    pub fn area(&self) -> f32 {
+        4.0 * PI * PI * self.major_radius * self.minor_radius
+    }
+}
+
+impl Torus {
+    /// Distances along the line of the ray to where it crosses the surface, in order
+    fn crossings(&self, ray: &Ray) -> Vec<f32> {
+        let local = self.frame.ray_to_local(ray);
+        // Start from where the line enters the bounding sphere, the roots lose precision far away
+        let bound = (self.major_radius + self.minor_radius) as f64;
+        let o = local.origin.coords.cast::<f64>();
+        let d = local.direction.cast::<f64>();
+        let (a, b, c) = (d.norm_squared(), o.dot(&d), o.norm_squared() - bound * bound);
+        let discriminant = b * b - a * c;
+        if discriminant < 0.0 {
+            return Vec::new();
+        }
+        let (start, exit) = ((-b - discriminant.sqrt()) / a, (-b + discriminant.sqrt()) / a);
+        let o = o + d * start;
+
+        // (|p|² - R² - r²)² = 4 R² (r² - z²)
+        let (major, minor) = (self.major_radius as f64, self.minor_radius as f64);
+        let (dd, od) = (d.norm_squared(), o.dot(&d));
+        let e = o.norm_squared() - major * major - minor * minor;
+        let four_major2 = 4.0 * major * major;
+        let coefficients = [
+            dd * dd,
+            4.0 * dd * od,
+            2.0 * dd * e + 4.0 * od * od + four_major2 * d.z * d.z,
+            4.0 * od * e + 2.0 * four_major2 * o.z * d.z,
+            e * e - four_major2 * (minor * minor - o.z * o.z),
+        ];
+        let mut crossings: Vec<f32> = solve_quartic(coefficients)
+            .into_iter()
+            .map(|t| t + start)
+            .filter(|&t| t <= exit)
+            .map(|t| t as f32)
+            .collect();
+        crossings.sort_by(f32::total_cmp);
+        crossings
+    }
+}
+
+impl Intersectable for Torus {
+    fn intersect(&self, ray: &Ray) -> Option<f32> {
+        self.crossings(ray).into_iter().find(|&t| t >= 0.0)
+    }
+
+    /// Between pairs of crossings. A line grazing the tube crosses it at a double root, which
+    /// leaves an odd one out without an inside.
+    fn intervals(&self, ray: &Ray) -> Vec<Interval> {
+        self.crossings(ray).chunks_exact(2).map(|pair| Interval::new(pair[0], pair[1])).collect()
+    }
+}
+
+impl Object for Torus {
+    fn surface_normal(&self, point: &Point) -> Vector3<f32> {
+        let local = self.frame.point_to_local(point);
+        let ring = Vector3::new(local.x, local.y, 0.0).try_normalize(0.0).unwrap_or(Vector3::x()) * self.major_radius;
+        self.frame.vector_to_world(&(local.coords - ring).normalize())
+    }
+
+    /// The angle around the axis, then the angle around the tube
+    fn surface_uv(&self, point: &Point) -> Vector2<f32> {
+        let local = self.frame.point_to_local(point);
+        let u = 0.5 + local.y.atan2(local.x) / (2.0 * PI);
+        let v = 0.5 + local.z.atan2(local.x.hypot(local.y) - self.major_radius) / (2.0 * PI);
+        Vector2::new(u, v)
+    }
+
+    fn sample_surface(&self, _time: f32, sampler: &mut dyn Sampler) -> Option<SurfaceSample> {
+        let area = self.area();
+        if area <= 0.0 {
+            return None;
+        }
+        let (major, minor) = (self.major_radius, self.minor_radius);
+        let (u, v) = sampler.get_2d();
+        let theta = 2.0 * PI * u;
+        // The outside of the tube is larger than the inside: invert the share of the area
+        // (R φ + r sin φ) / 2πR up to the angle φ around the tube, which always grows
+        let target = 2.0 * PI * major * v;
+        let mut phi = 2.0 * PI * v;
+        for _ in 0..8 {
+            phi -= (major * phi + minor * phi.sin() - target) / (major + minor * phi.cos());
+            phi = phi.clamp(0.0, 2.0 * PI);
+        }
+        let normal = Vector3::new(phi.cos() * theta.cos(), phi.cos() * theta.sin(), phi.sin());
+        let ring = Vector3::new(theta.cos(), theta.sin(), 0.0) * major;
+        Some(SurfaceSample {
+            point: self.frame.point_to_world(&Point::from(ring + normal * minor)),
+            normal: self.frame.vector_to_world(&normal),
+            pdf: 1.0 / area,
+        })
+    }
+
+    fn surface_pdf(&self, _point: &Point, _normal: &Vector3<f32>, _time: f32) -> f32 {
+        let area = self.area();
+        if area > 0.0 { 1.0 / area } else { 0.0 }
+    }
+
+    fn bounds(&self) -> Option<AABB> {
+        let outer = self.major_radius + self.minor_radius;
+        Some(self.frame.bounds(Vector3::new(-outer, -outer, -self.minor_radius), Vector3::new(outer, outer, self.minor_radius)))
+    }
+
+    fn material(&self) -> &dyn Material {
+        self.material.as_ref()
+    }
+
+    fn fingerprint(&self, fingerprint: &mut Fingerprint) {
+        fingerprint.add_str("torus");
+        fingerprint.add_debug(&(self.frame, self.major_radius, self.minor_radius));
+        self.material.fingerprint(fingerprint);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::f32::consts::PI;
+
+    use nalgebra::{UnitQuaternion, Vector3};
+
+    use super::{solve_quartic, Cone, Cuboid, Cylinder, Disk, Quad, Torus};
+    use crate::{
+        color,
+        geometry::{Intersectable, Point, Ray},
+        material::Diffuse,
+        object::Object,
+        sampler::{IndependentSampler, Sampler},
+    };
+
+    #[test]
+    fn shapes_are_hit_where_they_sample_their_surface() {
+        let material = || Box::new(Diffuse::new(color::WHITE));
+        let turn = UnitQuaternion::from_euler_angles(0.3, 0.5, 0.7);
+        let tilted = Vector3::new(1.0, 2.0, -0.5).normalize();
+        let shapes: Vec<(Box<dyn Object>, f32)> = vec![
+            (Box::new(Cuboid::new(Point::new(-1.0, 0.0, 2.0), Point::new(1.0, 0.5, 3.0), material())), 2.0 * (1.0 + 2.0 + 0.5)),
+            (Box::new(Cuboid::oriented(Point::new(1.0, 2.0, 3.0), Vector3::new(0.5, 1.0, 1.5), turn, material())), 8.0 * (0.5 + 1.5 + 0.75)),
+            (Box::new(Quad::new(Point::new(0.0, 2.0, 0.0), Vector3::new(2.0, 0.0, 0.0), Vector3::new(0.0, 0.5, 1.0), material())), 2.0 * 1.25f32.sqrt()),
+            (Box::new(Disk::new(Point::new(1.0, 1.0, 1.0), tilted, 0.5, material())), PI * 0.25),
+            (Box::new(Cylinder::new(Point::new(0.0, -1.0, 0.0), -tilted, 0.5, 2.0, material())), 2.0 * PI * 0.5 * 2.5),
+            (Box::new(Cone::new(Point::new(2.0, 0.0, 0.0), tilted, 1.0, 2.0, material())), PI * (5.0f32.sqrt() + 1.0)),
+            (Box::new(Torus::new(Point::new(0.0, 0.0, -2.0), tilted, 1.0, 0.25, material())), 4.0 * PI * PI * 0.25),
+        ];
+        let mut sampler = IndependentSampler::new(3);
+        for (index, (shape, area)) in shapes.iter().enumerate() {
+            let bounds = shape.bounds().unwrap();
+            for sample in 0..200 {
+                sampler.start_pixel_sample(0, 0, sample);
+                let surface = shape.sample_surface(0.0, &mut sampler).unwrap();
+                assert!((surface.pdf * area - 1.0).abs() < 1e-4, "shape {} has a density of {} for an area of {}", index, surface.pdf, area);
+                let point = bvh::Point3::new(surface.point.x, surface.point.y, surface.point.z);
+                assert!(bounds.approx_contains_eps(&point, 1e-4), "shape {} sampled {} out of its bounds", index, surface.point);
+                // Coming back along the normal, the ray hits the sampled point, where the normal is
+                let ray = Ray::new(surface.point + surface.normal * 0.01, -surface.normal);
+                let distance = shape.intersect(&ray).unwrap_or_else(|| panic!("shape {} missed at {}", index, surface.point));
+                assert!((distance - 0.01).abs() < 1e-3, "shape {} hit {} away from {}", index, distance, surface.point);
+                let normal = shape.surface_normal(&surface.point);
+                assert!((normal - surface.normal).norm() < 1e-2, "shape {} has the normal {} at {}, not {}", index, normal, surface.point, surface.normal);
+            }
+        }
+
+        // A ray through the ring crosses the tube twice on each side
+        let mut roots = solve_quartic([1.0, 0.0, -8.5, 0.0, 14.0625]);
+        roots.sort_by(f64::total_cmp);
+        for (root, expected) in roots.iter().zip([-2.5, -1.5, 1.5, 2.5]) {
+            assert!((root - expected).abs() < 1e-9, "{:?}", roots);
+        }
+        let torus = Torus::new(Point::origin(), Vector3::z(), 2.0, 0.5, material());
+        let distance = torus.intersect(&Ray::new(Point::new(-5.0, 0.0, 0.0), Vector3::x())).unwrap();
+        assert!((distance - 2.5).abs() < 1e-5, "{}", distance);
+        assert_eq!(torus.intersect(&Ray::new(Point::new(0.0, 0.0, -5.0), Vector3::z())), None);
+    }
+}
@@ -0,0 +1,197 @@
+use std::{
+    cell::Cell,
+    fmt::Write,
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+/// Work done while rendering, counted for the stats report
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Counter {
+    /// Rays intersected with the scene, shadow rays included
+    Rays,
+    /// Nodes of the mesh BVHs visited by those rays
+    BvhNodes,
+}
+
+const COUNTERS: usize = 2;
+
+thread_local! {
+    /// Counts of the current thread, so counting doesn't contend on shared cache lines
+    static LOCAL: [Cell<u64>; COUNTERS] = const { [Cell::new(0), Cell::new(0)] };
+}
+
+/// Counts of the threads that have been flushed
+static TOTALS: [AtomicU64; COUNTERS] = [AtomicU64::new(0), AtomicU64::new(0)];
+
+pub fn count(counter: Counter, amount: u64) {
+    LOCAL.with(|local| {
+        let cell = &local[counter as usize];
+        cell.set(cell.get() + amount);
+    });
+}
+
+/// Moves the counts of the current thread to the totals
+fn flush() {
+    LOCAL.with(|local| {
+        for (cell, total) in local.iter().zip(&TOTALS) {
+            total.fetch_add(cell.take(), Ordering::Relaxed);
+        }
+    });
+}
+
+/// Everything counted so far by this thread and the rayon threads
+pub fn total(counter: Counter) -> u64 {
+    rayon::broadcast(|_| flush());
+    flush();
+    TOTALS[counter as usize].load(Ordering::Relaxed)
+}
+
+/// Parts of a render whose time is reported
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Phase {
+    /// Building the scene and its BVHs
+    Scene,
+    /// Tracing the camera samples, or the passes of the integrators that render whole passes
+    Trace,
+    /// Adding the traced tiles to the film
+    Merge,
+    /// Tracing the features that guide the denoiser and the geometric AOVs
+    Features,
+    /// Denoising and writing the images
+    Output,
+}
+
+impl Phase {
+    const ALL: [Phase; 5] = [Phase::Scene, Phase::Trace, Phase::Merge, Phase::Features, Phase::Output];
+
+    fn name(&self) -> &'static str {
+        match self {
+            Self::Scene => "scene",
+            Self::Trace => "trace",
+            Self::Merge => "merge",
+            Self::Features => "features",
+            Self::Output => "output",
+        }
+    }
+}
+
+/// Time spent in each phase
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Timings([Duration; Phase::ALL.len()]);
+
+impl Timings {
+    pub fn add(&mut self, phase: Phase, duration: Duration) {
+        self.0[phase as usize] += duration;
+    }
+}
+
+/// Summary of a command line render, written as JSON for tools that track the performance of the
+/// renderer over time
+pub struct Report {
+    pub width: u32,
+    pub height: u32,
+    pub integrator: String,
+    pub sampler: String,
+    pub seed: u64,
+    /// Average over the rendered pixels, which differ with adaptive sampling
+    pub samples_per_pixel: f64,
+    /// Which condition stopped the render: samples, noise or time
+    pub stopped_by: &'static str,
+    pub elapsed: Duration,
+    pub timings: Timings,
+}
+
+impl Report {
+    pub fn to_json(&self) -> String {
+        let rays = total(Counter::Rays);
+        let seconds = self.elapsed.as_secs_f64();
+        let mut json = String::from("{\n");
+        let mut field = |name: &str, value: String| {
+            writeln!(json, "  \"{}\": {},", name, value).unwrap();
+        };
+        field("width", self.width.to_string());
+        field("height", self.height.to_string());
+        field("integrator", quote(&self.integrator));
+        field("sampler", quote(&self.sampler));
+        field("seed", self.seed.to_string());
+        field("samples_per_pixel", number(self.samples_per_pixel));
+        field("stopped_by", quote(self.stopped_by));
+        field("rays", rays.to_string());
+        field("rays_per_second", number(if seconds > 0.0 { rays as f64 / seconds } else { 0.0 }));
+        field("bvh_node_visits", total(Counter::BvhNodes).to_string());
+        let timings = Phase::ALL
+            .iter()
+            .map(|phase| format!("\"{}\": {}", phase.name(), number(self.timings.0[*phase as usize].as_secs_f64())))
+            .collect::<Vec<_>>();
+        field("seconds", format!("{{ \"total\": {}, {} }}", number(seconds), timings.join(", ")));
+        // No comma after the last field
+        json.truncate(json.len() - 2);
+        json.push_str("\n}\n");
+        json
+    }
+}
+
+/// A JSON string
+fn quote(s: &str) -> String {
+    let mut quoted = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            c if c.is_control() => write!(quoted, "\\u{:04x}", c as u32).unwrap(),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// A JSON number, which can't be infinite or NaN
+fn number(value: f64) -> String {
+    if value.is_finite() {
+        format!("{}", value)
+    } else {
+        "null".to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{count, quote, total, Counter, Phase, Report, Timings};
+
+    #[test]
+    fn counts_on_every_thread_and_writes_json() {
+        let before = total(Counter::Rays);
+        rayon::scope(|scope| {
+            for _ in 0..8 {
+                scope.spawn(|_| count(Counter::Rays, 5));
+            }
+        });
+        count(Counter::Rays, 2);
+        // Other tests trace rays at the same time
+        assert!(total(Counter::Rays) >= before + 42);
+
+        let mut timings = Timings::default();
+        timings.add(Phase::Trace, Duration::from_millis(1500));
+        let report = Report {
+            width: 4,
+            height: 3,
+            integrator: "path".to_string(),
+            sampler: "sobol".to_string(),
+            seed: 0,
+            samples_per_pixel: 32.0,
+            stopped_by: "samples",
+            elapsed: Duration::from_secs(2),
+            timings,
+        };
+        let json = report.to_json();
+        assert!(json.starts_with("{\n  \"width\": 4,\n"));
+        assert!(json.contains("\"stopped_by\": \"samples\","));
+        assert!(json.contains("\"trace\": 1.5,"));
+        assert!(json.ends_with("}\n}\n"));
+        assert_eq!(quote("a\"b\\\n"), "\"a\\\"b\\\\\\u000a\"");
+    }
+}
@@ -0,0 +1,568 @@
+use std::{
+    collections::{HashMap, HashSet},
+    path::Path,
+    str::FromStr,
+    sync::Arc,
+};
+
+use anyhow::Context;
+use image::{ImageBuffer, Luma};
+use nalgebra::Vector3;
+
+use crate::{
+    geometry::{Point, Triangle},
+    sampler,
+};
+
+/// Vertices shared by the triangles, and the indices of the corners of each triangle
+pub struct IndexedMesh {
+    pub vertices: Vec<Point>,
+    pub faces: Vec<[u32; 3]>,
+}
+
+/// The distinct positions of the corners, and the index of each corner among them, merging
+/// the corners at exactly the same position
+fn weld(corners: impl Iterator<Item = Point>) -> (Vec<Point>, Vec<u32>) {
+    let mut indices = HashMap::new();
+    let mut vertices = Vec::new();
+    let indices = corners
+        .map(|point| {
+            *indices.entry([point.x.to_bits(), point.y.to_bits(), point.z.to_bits()]).or_insert_with(|| {
+                vertices.push(point);
+                vertices.len() as u32 - 1
+            })
+        })
+        .collect();
+    (vertices, indices)
+}
+
+impl IndexedMesh {
+    /// Merges the corners at exactly the same position
+    pub fn new(triangles: &[Triangle]) -> Self {
+        let (vertices, indices) = weld(triangles.iter().flat_map(|triangle| [triangle.a, triangle.b, triangle.c]));
+        let faces = indices.chunks_exact(3).map(|corners| [corners[0], corners[1], corners[2]]).collect();
+        Self { vertices, faces }
+    }
+
+    pub fn triangles(&self) -> Vec<Triangle> {
+        self.faces
+            .iter()
+            .map(|&[a, b, c]| Triangle::new(self.vertices[a as usize], self.vertices[b as usize], self.vertices[c as usize]))
+            .collect()
+    }
+
+    /// Area weighted normals of the vertices
+    fn vertex_normals(&self) -> Vec<Vector3<f32>> {
+        let mut normals = vec![Vector3::zeros(); self.vertices.len()];
+        for face in &self.faces {
+            let [a, b, c] = face.map(|index| self.vertices[index as usize]);
+            // Twice the area, along the normal
+            let normal = (b - a).cross(&(c - a));
+            for &index in face {
+                normals[index as usize] += normal;
+            }
+        }
+        normals.into_iter().map(|normal| normal.try_normalize(0.0).unwrap_or_else(Vector3::zeros)).collect()
+    }
+}
+
+impl FromStr for Subdivision {
+    type Err = anyhow::Error;
+
+    /// Parses the number of levels followed by options, e.g. `2,crease=40`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut options = s.split(',');
+        let levels = options.next().unwrap_or_default();
+        let levels: u32 = levels.parse().map_err(|e| anyhow::anyhow!("invalid subdivision levels '{}': {}", levels, e))?;
+        // Each level has four times the triangles of the previous one
+        if levels > 8 {
+            anyhow::bail!("at most 8 subdivision levels, got {}", levels);
+        }
+        let mut subdivision = Self::new(levels);
+        for option in options.filter(|option| !option.is_empty()) {
+            match option.split_once('=') {
+                Some(("crease", degrees)) => {
+                    let degrees: f32 = degrees.parse().map_err(|e| anyhow::anyhow!("invalid crease angle '{}': {}", degrees, e))?;
+                    if !(0.0..=180.0).contains(&degrees) {
+                        anyhow::bail!("the crease angle must be between 0 and 180 degrees, got {}", degrees);
+                    }
+                    subdivision.crease_angle = Some(degrees);
+                }
+                _ => anyhow::bail!("unknown subdivision option '{}', expected crease=<degrees>", option),
+            }
+        }
+        Ok(subdivision)
+    }
+}
+
+/// Edge between two vertices, the smallest index first
+fn edge(a: u32, b: u32) -> (u32, u32) {
+    (a.min(b), a.max(b))
+}
+
+/// Smooths a mesh by subdivision, then optionally displaces it along its normals. Triangle
+/// meshes are subdivided by Loop, each level splitting every triangle in four, and meshes with
+/// other polygons, like quads, by Catmull-Clark, each level splitting every face in quads.
+///
+/// Edges with one face, or more than two, stay sharp, and so do the edges whose faces meet at
+/// more than the crease angle, as well as the edges split from them at the next levels.
+#[derive(Debug, Clone)]
+pub struct Subdivision {
+    pub levels: u32,
+    /// Angle between the normals of two faces, in degrees, above which their edge is a crease
+    pub crease_angle: Option<f32>,
+    pub displacement: Option<Displacement>,
+}
+
+impl Subdivision {
+    pub fn new(levels: u32) -> Self {
+        Self {
+            levels,
+            crease_angle: None,
+            displacement: None,
+        }
+    }
+
+    pub fn with_crease_angle(mut self, degrees: f32) -> Self {
+        self.crease_angle = Some(degrees);
+        self
+    }
+
+    pub fn with_displacement(mut self, displacement: Displacement) -> Self {
+        self.displacement = Some(displacement);
+        self
+    }
+
+    /// Subdivides the triangles by Loop
+    pub fn apply(&self, triangles: &[Triangle]) -> Vec<Triangle> {
+        let mut mesh = IndexedMesh::new(triangles);
+        let mut creases = self.creases(&mesh.vertices, mesh.faces.iter().map(|face| &face[..]));
+        for _ in 0..self.levels {
+            (mesh, creases) = loop_subdivide(&mesh, &creases);
+        }
+        self.displace(mesh)
+    }
+
+    /// Subdivides the polygons, of any number of corners, by Loop if they're all triangles and
+    /// by Catmull-Clark otherwise, which turns every face into quads at the first level
+    pub fn apply_polygons(&self, polygons: &[Vec<Point>]) -> Vec<Triangle> {
+        if polygons.iter().all(|corners| corners.len() == 3) {
+            let triangles: Vec<Triangle> = polygons.iter().map(|corners| Triangle::new(corners[0], corners[1], corners[2])).collect();
+            return self.apply(&triangles);
+        }
+        let mut mesh = PolygonMesh::new(polygons);
+        let mut creases = self.creases(&mesh.vertices, mesh.faces.iter().map(Vec::as_slice));
+        for _ in 0..self.levels {
+            (mesh, creases) = catmull_clark_subdivide(&mesh, &creases);
+        }
+        self.displace(mesh.triangulate())
+    }
+
+    fn displace(&self, mut mesh: IndexedMesh) -> Vec<Triangle> {
+        if let Some(displacement) = &self.displacement {
+            displacement.apply(&mut mesh);
+        }
+        mesh.triangles()
+    }
+
+    /// The edges of the faces sharper than the crease angle
+    fn creases<'a>(&self, vertices: &[Point], faces: impl Iterator<Item = &'a [u32]>) -> HashSet<(u32, u32)> {
+        let Some(angle) = self.crease_angle else {
+            return HashSet::new();
+        };
+        let cos = angle.to_radians().cos();
+        let mut faces_of_edges: HashMap<(u32, u32), Vec<Vector3<f32>>> = HashMap::new();
+        for face in faces {
+            // Newell's normal, which also holds for polygons that aren't quite flat
+            let corners = || face.iter().zip(face.iter().cycle().skip(1)).map(|(&from, &to)| (from, to));
+            let normal = corners()
+                .map(|(from, to)| vertices[from as usize].coords.cross(&vertices[to as usize].coords))
+                .sum::<Vector3<f32>>()
+                .try_normalize(0.0)
+                .unwrap_or_else(Vector3::zeros);
+            for (from, to) in corners() {
+                faces_of_edges.entry(edge(from, to)).or_default().push(normal);
+            }
+        }
+        faces_of_edges
+            .into_iter()
+            .filter(|(_, normals)| matches!(normals[..], [n0, n1] if n0.dot(&n1) < cos))
+            .map(|(edge, _)| edge)
+            .collect()
+    }
+}
+
+/// One level of Loop subdivision, https://www.microsoft.com/en-us/research/wp-content/uploads/2016/02/thesis-10.pdf,
+/// with the crease rules of Hoppe et al., Piecewise Smooth Surface Reconstruction
+fn loop_subdivide(mesh: &IndexedMesh, creases: &HashSet<(u32, u32)>) -> (IndexedMesh, HashSet<(u32, u32)>) {
+    // The edges in the order of the faces, so the result doesn't depend on the order of a hash
+    // map, with the vertices opposite to each edge, one per face
+    let mut edges: Vec<((u32, u32), Vec<u32>)> = Vec::new();
+    let mut edge_indices = HashMap::new();
+    for &[a, b, c] in &mesh.faces {
+        for (from, to, across) in [(a, b, c), (b, c, a), (c, a, b)] {
+            let index = *edge_indices.entry(edge(from, to)).or_insert_with(|| {
+                edges.push((edge(from, to), Vec::new()));
+                edges.len() - 1
+            });
+            edges[index].1.push(across);
+        }
+    }
+    let sharp = |edge: &(u32, u32), across: &[u32]| across.len() != 2 || creases.contains(edge);
+
+    // Neighbors of each vertex, and those across sharp edges
+    let count = mesh.vertices.len();
+    let mut neighbors = vec![Vec::new(); count];
+    let mut sharp_neighbors = vec![Vec::new(); count];
+    for ((a, b), across) in &edges {
+        neighbors[*a as usize].push(*b);
+        neighbors[*b as usize].push(*a);
+        if sharp(&(*a, *b), across) {
+            sharp_neighbors[*a as usize].push(*b);
+            sharp_neighbors[*b as usize].push(*a);
+        }
+    }
+    let position = |index: u32| mesh.vertices[index as usize].coords;
+
+    let mut vertices: Vec<Point> = (0..count)
+        .map(|v| {
+            let point = position(v as u32);
+            let smooth = || {
+                let n = neighbors[v].len() as f32;
+                let beta = if neighbors[v].len() == 3 { 3.0 / 16.0 } else { 3.0 / (8.0 * n) };
+                point * (1.0 - n * beta) + neighbors[v].iter().map(|&u| position(u)).sum::<Vector3<f32>>() * beta
+            };
+            Point::from(match sharp_neighbors[v][..] {
+                // Along a crease, only the crease pulls
+                [a, b] => point * 0.75 + (position(a) + position(b)) * 0.125,
+                // Corners where three creases or more meet stay put, a single crease fades out
+                [_, _, _, ..] => point,
+                _ if neighbors[v].is_empty() => point,
+                _ => smooth(),
+            })
+        })
+        .collect();
+
+    let mut midpoints = Vec::with_capacity(edges.len());
+    let mut next_creases = HashSet::new();
+    for &((a, b), ref across) in &edges {
+        let point = if sharp(&(a, b), across) {
+            (position(a) + position(b)) * 0.5
+        } else {
+            (position(a) + position(b)) * 0.375 + (position(across[0]) + position(across[1])) * 0.125
+        };
+        let mid = vertices.len() as u32;
+        vertices.push(Point::from(point));
+        midpoints.push(mid);
+        if creases.contains(&(a, b)) {
+            next_creases.insert(edge(a, mid));
+            next_creases.insert(edge(mid, b));
+        }
+    }
+
+    let faces = mesh
+        .faces
+        .iter()
+        .flat_map(|&[a, b, c]| {
+            let [ab, bc, ca] = [(a, b), (b, c), (c, a)].map(|(from, to)| midpoints[edge_indices[&edge(from, to)]]);
+            [[a, ab, ca], [b, bc, ab], [c, ca, bc], [ab, bc, ca]]
+        })
+        .collect();
+    (IndexedMesh { vertices, faces }, next_creases)
+}
+
+/// Vertices shared by polygons of any number of corners, the faces of a mesh before they're
+/// split in triangles
+struct PolygonMesh {
+    vertices: Vec<Point>,
+    faces: Vec<Vec<u32>>,
+}
+
+impl PolygonMesh {
+    /// Merges the corners at exactly the same position
+    fn new(polygons: &[Vec<Point>]) -> Self {
+        let (vertices, indices) = weld(polygons.iter().flatten().copied());
+        let mut indices = indices.into_iter();
+        let faces = polygons.iter().map(|corners| indices.by_ref().take(corners.len()).collect()).collect();
+        Self { vertices, faces }
+    }
+
+    /// The faces split in fans of triangles
+    fn triangulate(self) -> IndexedMesh {
+        let faces = self
+            .faces
+            .iter()
+            .flat_map(|face| (1..face.len().saturating_sub(1)).map(move |i| [face[0], face[i], face[i + 1]]))
+            .collect();
+        IndexedMesh { vertices: self.vertices, faces }
+    }
+}
+
+/// One level of Catmull-Clark subdivision, https://doi.org/10.1016/0010-4485(78)90110-0, with
+/// the same crease rules as `loop_subdivide`. Every face is split in quads, one per corner.
+fn catmull_clark_subdivide(mesh: &PolygonMesh, creases: &HashSet<(u32, u32)>) -> (PolygonMesh, HashSet<(u32, u32)>) {
+    let position = |index: u32| mesh.vertices[index as usize].coords;
+    let face_points: Vec<Vector3<f32>> = mesh
+        .faces
+        .iter()
+        .map(|face| face.iter().map(|&v| position(v)).sum::<Vector3<f32>>() / face.len() as f32)
+        .collect();
+
+    // The edges in the order of the faces, with the faces on each side
+    let mut edges: Vec<((u32, u32), Vec<usize>)> = Vec::new();
+    let mut edge_indices = HashMap::new();
+    for (f, face) in mesh.faces.iter().enumerate() {
+        for (&from, &to) in face.iter().zip(face.iter().cycle().skip(1)) {
+            let index = *edge_indices.entry(edge(from, to)).or_insert_with(|| {
+                edges.push((edge(from, to), Vec::new()));
+                edges.len() - 1
+            });
+            edges[index].1.push(f);
+        }
+    }
+    let sharp = |edge: &(u32, u32), faces: &[usize]| faces.len() != 2 || creases.contains(edge);
+
+    let count = mesh.vertices.len();
+    let mut neighbors = vec![Vec::new(); count];
+    let mut sharp_neighbors = vec![Vec::new(); count];
+    for ((a, b), faces) in &edges {
+        neighbors[*a as usize].push(*b);
+        neighbors[*b as usize].push(*a);
+        if sharp(&(*a, *b), faces) {
+            sharp_neighbors[*a as usize].push(*b);
+            sharp_neighbors[*b as usize].push(*a);
+        }
+    }
+    let mut faces_of_vertices = vec![Vec::new(); count];
+    for (f, face) in mesh.faces.iter().enumerate() {
+        for &v in face {
+            faces_of_vertices[v as usize].push(f);
+        }
+    }
+
+    let mut vertices: Vec<Point> = (0..count)
+        .map(|v| {
+            let point = position(v as u32);
+            // The average of the face points around, twice the average of the edge midpoints,
+            // and the vertex for the rest of its valence
+            let smooth = || {
+                let n = neighbors[v].len() as f32;
+                let faces = &faces_of_vertices[v];
+                let q = faces.iter().map(|&f| face_points[f]).sum::<Vector3<f32>>() / faces.len() as f32;
+                let r = neighbors[v].iter().map(|&u| (point + position(u)) * 0.5).sum::<Vector3<f32>>() / n;
+                (q + r * 2.0 + point * (n - 3.0)) / n
+            };
+            Point::from(match sharp_neighbors[v][..] {
+                [a, b] => point * 0.75 + (position(a) + position(b)) * 0.125,
+                [_, _, _, ..] => point,
+                _ if faces_of_vertices[v].is_empty() => point,
+                _ => smooth(),
+            })
+        })
+        .collect();
+    let first_face_point = vertices.len() as u32;
+    vertices.extend(face_points.iter().map(|&point| Point::from(point)));
+
+    let mut edge_points = Vec::with_capacity(edges.len());
+    let mut next_creases = HashSet::new();
+    for &((a, b), ref faces) in &edges {
+        let point = if sharp(&(a, b), faces) {
+            (position(a) + position(b)) * 0.5
+        } else {
+            (position(a) + position(b) + face_points[faces[0]] + face_points[faces[1]]) * 0.25
+        };
+        let mid = vertices.len() as u32;
+        vertices.push(Point::from(point));
+        edge_points.push(mid);
+        if creases.contains(&(a, b)) {
+            next_creases.insert(edge(a, mid));
+            next_creases.insert(edge(mid, b));
+        }
+    }
+
+    let faces = mesh
+        .faces
+        .iter()
+        .enumerate()
+        .flat_map(|(f, face)| {
+            let edge_point = |from: u32, to: u32| edge_points[edge_indices[&edge(from, to)]];
+            let center = first_face_point + f as u32;
+            (0..face.len()).map(move |i| {
+                let (previous, corner, next) = (face[(i + face.len() - 1) % face.len()], face[i], face[(i + 1) % face.len()]);
+                vec![corner, edge_point(corner, next), center, edge_point(previous, corner)]
+            })
+        })
+        .collect();
+    (PolygonMesh { vertices, faces }, next_creases)
+}
+
+/// Pushes the vertices along their normals by `scale` times the value of the map there
+#[derive(Debug, Clone)]
+pub struct Displacement {
+    pub map: DisplacementMap,
+    pub scale: f32,
+}
+
+impl Displacement {
+    pub fn new(map: DisplacementMap, scale: f32) -> Self {
+        Self { map, scale }
+    }
+
+    fn apply(&self, mesh: &mut IndexedMesh) {
+        let normals = mesh.vertex_normals();
+        let center = self.map.needs_center().then(|| {
+            let sum = mesh.vertices.iter().map(|vertex| vertex.coords).sum::<Vector3<f32>>();
+            Point::from(sum / mesh.vertices.len().max(1) as f32)
+        });
+        for (vertex, normal) in mesh.vertices.iter_mut().zip(normals) {
+            *vertex += normal * self.scale * self.map.value(vertex, center.as_ref());
+        }
+    }
+}
+
+/// Where the displacement comes from
+#[derive(Debug, Clone)]
+pub enum DisplacementMap {
+    /// Fractal value noise of the position, between -1 and 1, with `octaves` layers of details
+    /// twice as fine and half as strong as the previous one
+    Noise { frequency: f32, octaves: u32, seed: u64 },
+    /// Brightness of an image between 0 and 1, wrapped around the mesh like a map of the world
+    /// around its center
+    Image(Arc<ImageBuffer<Luma<f32>, Vec<f32>>>),
+}
+
+impl DisplacementMap {
+    pub fn noise(frequency: f32, octaves: u32, seed: u64) -> Self {
+        Self::Noise { frequency, octaves, seed }
+    }
+
+    pub fn image(path: &Path) -> anyhow::Result<Self> {
+        let image = image::open(path).with_context(|| format!("failed to read the displacement map '{}'", path.display()))?;
+        Ok(Self::Image(Arc::new(image.to_luma32f())))
+    }
+
+    fn needs_center(&self) -> bool {
+        matches!(self, Self::Image(_))
+    }
+
+    fn value(&self, point: &Point, center: Option<&Point>) -> f32 {
+        match self {
+            Self::Noise { frequency, octaves, seed } => {
+                let (mut value, mut amplitude, mut total) = (0.0, 1.0, 0.0);
+                for octave in 0..*octaves {
+                    let scaled = point.coords * (*frequency * (1u32 << octave.min(31)) as f32);
+                    value += value_noise(&scaled, sampler::hash(*seed, octave as u64)) * amplitude;
+                    total += amplitude;
+                    amplitude *= 0.5;
+                }
+                if total > 0.0 { value / total * 2.0 - 1.0 } else { 0.0 }
+            }
+            Self::Image(image) => {
+                let direction = (point - center.copied().unwrap_or_else(Point::origin)).try_normalize(0.0).unwrap_or(Vector3::y());
+                let u = 0.5 + direction.z.atan2(direction.x) / (2.0 * std::f32::consts::PI);
+                let v = 0.5 - direction.y.clamp(-1.0, 1.0).asin() / std::f32::consts::PI;
+                bilinear(image, u, v)
+            }
+        }
+    }
+}
+
+/// Smoothly interpolated random values in [0, 1) at the corners of the unit lattice
+fn value_noise(point: &Vector3<f32>, seed: u64) -> f32 {
+    let cell = point.map(f32::floor);
+    let fraction = (point - cell).map(|t| t * t * (3.0 - 2.0 * t));
+    let corner = |dx: i64, dy: i64, dz: i64| {
+        let [x, y, z] = [cell.x as i64 + dx, cell.y as i64 + dy, cell.z as i64 + dz].map(|c| c as u64);
+        sampler::hash_to_f32(sampler::hash(sampler::hash(sampler::hash(seed, x), y), z))
+    };
+    let lerp = |a: f32, b: f32, t: f32| a + (b - a) * t;
+    let plane = |dz: i64| {
+        lerp(
+            lerp(corner(0, 0, dz), corner(1, 0, dz), fraction.x),
+            lerp(corner(0, 1, dz), corner(1, 1, dz), fraction.x),
+            fraction.y,
+        )
+    };
+    lerp(plane(0), plane(1), fraction.z)
+}
+
+/// The image at texture coordinates in [0, 1], wrapping around horizontally
+fn bilinear(image: &ImageBuffer<Luma<f32>, Vec<f32>>, u: f32, v: f32) -> f32 {
+    let (width, height) = image.dimensions();
+    if width == 0 || height == 0 {
+        return 0.0;
+    }
+    let x = u * width as f32 - 0.5;
+    let y = (v * height as f32 - 0.5).clamp(0.0, (height - 1) as f32);
+    let (x0, y0) = (x.floor(), y.floor());
+    let (fx, fy) = (x - x0, y - y0);
+    let pixel = |x: f32, y: f32| image.get_pixel((x as i64).rem_euclid(width as i64) as u32, (y as u32).min(height - 1))[0];
+    let top = pixel(x0, y0) * (1.0 - fx) + pixel(x0 + 1.0, y0) * fx;
+    let bottom = pixel(x0, y0 + 1.0) * (1.0 - fx) + pixel(x0 + 1.0, y0 + 1.0) * fx;
+    top * (1.0 - fy) + bottom * fy
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::{Displacement, DisplacementMap, IndexedMesh, Subdivision};
+    use crate::geometry::{read_obj, read_obj_polygons};
+
+    #[test]
+    fn subdivision_smooths_the_cube_unless_its_edges_are_creases() {
+        let cube = read_obj(Path::new("assets/cube.obj")).unwrap();
+        let extent = |triangles: &[crate::geometry::Triangle]| {
+            let mesh = IndexedMesh::new(triangles);
+            let norms: Vec<f32> = mesh.vertices.iter().map(|vertex| vertex.coords.norm()).collect();
+            (mesh.faces.len(), norms.iter().copied().fold(f32::INFINITY, f32::min), norms.iter().copied().fold(0.0, f32::max))
+        };
+
+        // Closed and smooth, each level has four times the faces and pulls the corners in
+        let smooth = Subdivision::new(3).apply(&cube);
+        let (faces, nearest, furthest) = extent(&smooth);
+        assert_eq!(faces, 12 * 64);
+        assert!(furthest < 3f32.sqrt() * 0.8 && nearest > 0.5, "{} to {}", nearest, furthest);
+
+        // With every edge a crease the cube keeps its shape, only split into smaller triangles
+        let creased = Subdivision::new(2).with_crease_angle(60.0).apply(&cube);
+        let (faces, _, furthest) = extent(&creased);
+        assert_eq!(faces, 12 * 16);
+        assert!((furthest - 3f32.sqrt()).abs() < 1e-5);
+        for triangle in &creased {
+            for vertex in [triangle.a, triangle.b, triangle.c] {
+                assert!(vertex.coords.amax() > 1.0 - 1e-5, "{} isn't on a face of the cube", vertex);
+            }
+        }
+
+        // Displaced by noise, the surface moves by at most the scale
+        let smooth = Subdivision::new(2).apply(&cube);
+        let displaced = Subdivision::new(2).with_displacement(Displacement::new(DisplacementMap::noise(3.0, 3, 7), 0.1)).apply(&cube);
+        let moved = smooth.iter().zip(&displaced).map(|(before, after)| (after.a - before.a).norm()).fold(0.0, f32::max);
+        assert!(moved > 0.0 && moved <= 0.1 + 1e-5, "{}", moved);
+    }
+
+    #[test]
+    fn catmull_clark_rounds_the_box_of_quads_symmetrically() {
+        let quads = read_obj_polygons(Path::new("assets/box.obj")).unwrap();
+        assert!(quads.iter().all(|corners| corners.len() == 4));
+
+        // Each quad is split in four at each level, then in two triangles
+        let smooth = Subdivision::new(3).apply_polygons(&quads);
+        assert_eq!(smooth.len(), 6 * 64 * 2);
+        let mesh = IndexedMesh::new(&smooth);
+        for vertex in &mesh.vertices {
+            // Opposite sides are smoothed the same way
+            let mirrored = -vertex.coords;
+            assert!(mesh.vertices.iter().any(|other| (other.coords - mirrored).norm() < 1e-5), "{} has no mirror image", vertex);
+            assert!(vertex.coords.amax() < 1.0 && vertex.coords.norm() < 3f32.sqrt() * 0.8);
+        }
+
+        // With every edge a crease the box keeps its shape
+        for triangle in Subdivision::new(2).with_crease_angle(60.0).apply_polygons(&quads) {
+            for vertex in [triangle.a, triangle.b, triangle.c] {
+                assert!((vertex.coords.amax() - 1.0).abs() < 1e-5, "{} isn't on a face of the box", vertex);
+            }
+        }
+    }
+}
@@ -0,0 +1,308 @@
+use std::{fmt, str::FromStr};
+
+use image::{Rgba, RgbaImage};
+
+/// Order in which the tiles of the film are handed to the threads
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TileOrder {
+    /// Row by row from the top left
+    Scanline,
+    /// Outwards from the center of the image, which usually holds the subject
+    Spiral,
+    /// Along a Hilbert curve, so consecutive tiles are neighbours and share cached geometry
+    Hilbert,
+}
+
+/// How the film is split into tiles
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TileSettings {
+    pub order: TileOrder,
+    /// Width and height of the tiles in pixels, the last row and column may be smaller
+    pub size: u32,
+}
+
+impl Default for TileSettings {
+    fn default() -> Self {
+        Self {
+            order: TileOrder::Hilbert,
+            size: 32,
+        }
+    }
+}
+
+/// A rectangle of the film, traced by a single thread
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Tile {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl Tile {
+    pub fn pixel_count(&self) -> usize {
+        (self.width * self.height) as usize
+    }
+
+    /// Film coordinates of the pixels, row by row
+    pub fn pixels(&self) -> impl Iterator<Item = (u32, u32)> {
+        let Self { x, y, width, height } = *self;
+        (y..y + height).flat_map(move |y| (x..x + width).map(move |x| (x, y)))
+    }
+}
+
+/// Rectangle of the film that's rendered, in pixels. The camera keeps the projection of the
+/// whole film, so the pixels inside look the same as in a full render.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Crop {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl Crop {
+    pub fn full(width: u32, height: u32) -> Self {
+        Self { x: 0, y: 0, width, height }
+    }
+
+    /// The rectangle between two opposite corners, both included, e.g. dragged in the viewer
+    pub fn from_corners((x0, y0): (u32, u32), (x1, y1): (u32, u32)) -> Self {
+        Self {
+            x: x0.min(x1),
+            y: y0.min(y1),
+            width: x0.abs_diff(x1) + 1,
+            height: y0.abs_diff(y1) + 1,
+        }
+    }
+
+    pub fn contains(&self, x: u32, y: u32) -> bool {
+        (self.x..self.x.saturating_add(self.width)).contains(&x) && (self.y..self.y.saturating_add(self.height)).contains(&y)
+    }
+
+    /// The part of the crop inside a `width` x `height` film, `None` if there's none
+    pub fn clamp(&self, width: u32, height: u32) -> Option<Self> {
+        let (right, bottom) = (self.x.saturating_add(self.width).min(width), self.y.saturating_add(self.height).min(height));
+        (self.x < right && self.y < bottom).then(|| Self {
+            x: self.x,
+            y: self.y,
+            width: right - self.x,
+            height: bottom - self.y,
+        })
+    }
+}
+
+impl From<Crop> for Tile {
+    fn from(crop: Crop) -> Self {
+        let Crop { x, y, width, height } = crop;
+        Self { x, y, width, height }
+    }
+}
+
+impl fmt::Display for Crop {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{},{},{},{}", self.x, self.y, self.width, self.height)
+    }
+}
+
+impl FromStr for Crop {
+    type Err = anyhow::Error;
+
+    /// Parses `x,y,width,height` in pixels, from the top left corner of the film
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let values = s
+            .split(',')
+            .map(|value| value.parse::<u32>().map_err(|e| anyhow::anyhow!("invalid crop window '{}': {}", s, e)))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        let [x, y, width, height] = values[..] else {
+            anyhow::bail!("expected the crop window as x,y,width,height, got '{}'", s);
+        };
+        if width == 0 || height == 0 {
+            anyhow::bail!("the crop window '{}' is empty", s);
+        }
+        if x.checked_add(width).is_none() || y.checked_add(height).is_none() {
+            anyhow::bail!("the crop window '{}' ends past the largest film", s);
+        }
+        Ok(Self { x, y, width, height })
+    }
+}
+
+impl TileSettings {
+    /// Splits the `region` of the film into tiles, in the order they're rendered
+    pub fn tiles(&self, region: &Crop) -> Vec<Tile> {
+        let (columns, rows) = (region.width.div_ceil(self.size), region.height.div_ceil(self.size));
+        let tile = |column: u32, row: u32| {
+            let (x, y) = (column * self.size, row * self.size);
+            Tile {
+                x: region.x + x,
+                y: region.y + y,
+                width: self.size.min(region.width - x),
+                height: self.size.min(region.height - y),
+            }
+        };
+        let mut cells: Vec<(u32, u32)> = (0..rows).flat_map(|row| (0..columns).map(move |column| (column, row))).collect();
+        match self.order {
+            TileOrder::Scanline => {}
+            TileOrder::Spiral => cells = spiral(columns, rows),
+            TileOrder::Hilbert => {
+                let side = columns.max(rows).next_power_of_two();
+                cells.sort_by_key(|&(column, row)| hilbert_index(side, column, row));
+            }
+        }
+        cells.into_iter().map(|(column, row)| tile(column, row)).collect()
+    }
+}
+
+/// Cells of a `columns` x `rows` grid, walking a square spiral out of the center one
+fn spiral(columns: u32, rows: u32) -> Vec<(u32, u32)> {
+    let count = (columns * rows) as usize;
+    let mut cells = Vec::with_capacity(count);
+    let (mut x, mut y) = (((columns - 1) / 2) as i64, ((rows - 1) / 2) as i64);
+    let (mut dx, mut dy) = (1i64, 0i64);
+    let mut run = 1;
+    while cells.len() < count {
+        // Runs grow by one every two turns: right 1, down 1, left 2, up 2, right 3...
+        for _ in 0..2 {
+            for _ in 0..run {
+                if (0..columns as i64).contains(&x) && (0..rows as i64).contains(&y) {
+                    cells.push((x as u32, y as u32));
+                }
+                x += dx;
+                y += dy;
+            }
+            (dx, dy) = (-dy, dx);
+        }
+        run += 1;
+    }
+    cells
+}
+
+/// Distance along the Hilbert curve filling a `side` x `side` grid, `side` a power of two
+fn hilbert_index(side: u32, mut x: u32, mut y: u32) -> u64 {
+    let mut index = 0u64;
+    let mut s = side / 2;
+    while s > 0 {
+        let rx = (x & s > 0) as u32;
+        let ry = (y & s > 0) as u32;
+        index += s as u64 * s as u64 * ((3 * rx) ^ ry) as u64;
+        // Rotate the quadrant so the curve inside it starts where the previous one ended
+        if ry == 0 {
+            if rx == 1 {
+                x = s - 1 - (x & (s - 1));
+                y = s - 1 - (y & (s - 1));
+            }
+            std::mem::swap(&mut x, &mut y);
+        }
+        x &= s - 1;
+        y &= s - 1;
+        s /= 2;
+    }
+    index
+}
+
+/// Draws the corners of `tile` over the image, to show the tiles being rendered
+pub fn outline(image: &mut RgbaImage, tile: &Tile) {
+    let length = (tile.width.min(tile.height) / 4).max(1);
+    draw_corners(image, &Crop { x: tile.x, y: tile.y, width: tile.width, height: tile.height }, length);
+}
+
+/// Draws the border of `crop` over the image, e.g. while it's dragged in the viewer
+pub fn frame(image: &mut RgbaImage, crop: &Crop) {
+    draw_corners(image, crop, crop.width.max(crop.height));
+}
+
+/// Draws lines of `length` pixels from each corner of `rect` along its sides, clipped to the image
+fn draw_corners(image: &mut RgbaImage, rect: &Crop, length: u32) {
+    let Some(rect) = rect.clamp(image.width(), image.height()) else {
+        return;
+    };
+    let (right, bottom) = (rect.x + rect.width - 1, rect.y + rect.height - 1);
+    for i in 0..length {
+        for (x, y) in [
+            (rect.x + i, rect.y),
+            (rect.x, rect.y + i),
+            (right.wrapping_sub(i), rect.y),
+            (right, rect.y + i),
+            (rect.x + i, bottom),
+            (rect.x, bottom.wrapping_sub(i)),
+            (right.wrapping_sub(i), bottom),
+            (right, bottom.wrapping_sub(i)),
+        ] {
+            if rect.contains(x, y) {
+                image.put_pixel(x, y, Rgba([255, 255, 255, 255]));
+            }
+        }
+    }
+}
+
+impl fmt::Display for TileSettings {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let order = match self.order {
+            TileOrder::Scanline => "scanline",
+            TileOrder::Spiral => "spiral",
+            TileOrder::Hilbert => "hilbert",
+        };
+        write!(f, "{}:{}", order, self.size)
+    }
+}
+
+impl FromStr for TileSettings {
+    type Err = anyhow::Error;
+
+    /// Parses `scanline`, `spiral` or `hilbert`, optionally followed by the tile size like
+    /// `spiral:64`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (order, size) = match s.split_once(':') {
+            Some((order, size)) => (order, Some(size)),
+            None => (s, None),
+        };
+        let order = match order {
+            "scanline" => TileOrder::Scanline,
+            "spiral" => TileOrder::Spiral,
+            "hilbert" => TileOrder::Hilbert,
+            _ => anyhow::bail!("unknown tile order '{}', expected scanline, spiral or hilbert", order),
+        };
+        let size = match size {
+            Some(size) => size.parse::<u32>().map_err(|e| anyhow::anyhow!("invalid tile size '{}': {}", size, e))?,
+            None => Self::default().size,
+        };
+        if size == 0 {
+            anyhow::bail!("the tile size must be positive");
+        }
+        Ok(Self { order, size })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Crop, Tile, TileSettings};
+
+    #[test]
+    fn every_order_covers_each_pixel_once() {
+        for order in ["scanline", "spiral", "hilbert"] {
+            let settings: TileSettings = format!("{}:7", order).parse().unwrap();
+            assert_eq!(settings.to_string().parse::<TileSettings>().unwrap(), settings);
+            let (width, height) = (45, 23);
+            let mut covered = vec![0; (width * height) as usize];
+            let tiles = settings.tiles(&Crop::full(width, height));
+            for tile in &tiles {
+                for (x, y) in tile.pixels() {
+                    covered[(y * width + x) as usize] += 1;
+                }
+            }
+            assert!(covered.iter().all(|&count| count == 1), "{} misses pixels", order);
+            let crop: Crop = "10,3,20,11".parse().unwrap();
+            let inside: usize = settings.tiles(&crop).iter().map(|tile| tile.pixels().filter(|&(x, y)| crop.contains(x, y)).count()).sum();
+            assert_eq!(inside, Tile::from(crop).pixels().count());
+            assert!(settings.tiles(&crop).iter().all(|tile| tile.pixels().all(|(x, y)| crop.contains(x, y))));
+            if order == "hilbert" {
+                // Consecutive tiles on the curve are neighbours, when both are inside the film
+                let full = 8;
+                let tiles = TileSettings { size: 1, ..settings }.tiles(&Crop::full(full, full));
+                for pair in tiles.windows(2) {
+                    assert_eq!(pair[0].x.abs_diff(pair[1].x) + pair[0].y.abs_diff(pair[1].y), 1);
+                }
+            }
+        }
+    }
+}
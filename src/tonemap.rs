@@ -0,0 +1,260 @@
+use std::{fmt, str::FromStr};
+
+use image::{ImageBuffer, Rgba, Rgba32FImage, RgbaImage};
+
+use crate::color::ColorF32;
+
+/// Curve compressing the scene radiance into the [0, 1] range of a display
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ToneMapOperator {
+    /// Radiance above one is clipped
+    Clamp,
+    /// L / (1 + L) on the luminance, keeping the hue
+    Reinhard,
+    /// Reinhard that reaches white at the luminance `white` instead of infinity
+    ExtendedReinhard { white: f32 },
+    /// Stephen Hill's fit of the ACES reference and sRGB output transforms
+    Aces,
+    /// Troy Sobotka's AgX, which desaturates highlights instead of skewing their hue
+    Agx,
+}
+
+impl ToneMapOperator {
+    const DEFAULT_WHITE: f32 = 4.0;
+
+    /// Maps linear radiance to linear display values in [0, 1]
+    pub fn apply(&self, color: ColorF32) -> ColorF32 {
+        let color = match *self {
+            Self::Clamp => color,
+            Self::Reinhard => scale_luminance(color, |l| l / (1.0 + l)),
+            Self::ExtendedReinhard { white } => scale_luminance(color, |l| l * (1.0 + l / (white * white)) / (1.0 + l)),
+            Self::Aces => aces(color),
+            Self::Agx => agx(color),
+        };
+        ColorF32::new(color.r.clamp(0.0, 1.0), color.g.clamp(0.0, 1.0), color.b.clamp(0.0, 1.0))
+    }
+
+    /// The operator after this one, used to cycle through them in the viewer
+    pub fn next(&self) -> Self {
+        match self {
+            Self::Clamp => Self::Reinhard,
+            Self::Reinhard => Self::ExtendedReinhard { white: Self::DEFAULT_WHITE },
+            Self::ExtendedReinhard { .. } => Self::Aces,
+            Self::Aces => Self::Agx,
+            Self::Agx => Self::Clamp,
+        }
+    }
+}
+
+/// Tone mapping of the film for the 8 bit outputs: the PNGs and the viewer
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ToneMapping {
+    pub operator: ToneMapOperator,
+    /// Exposure compensation in stops, the radiance is scaled by 2^exposure
+    pub exposure: f32,
+}
+
+impl Default for ToneMapping {
+    fn default() -> Self {
+        Self {
+            operator: ToneMapOperator::Aces,
+            exposure: 0.0,
+        }
+    }
+}
+
+impl ToneMapping {
+    /// sRGB encoded display color of a linear radiance, in [0, 1]
+    fn display(&self, color: ColorF32) -> [f32; 3] {
+        let color = self.operator.apply(color * self.exposure.exp2());
+        [srgb_oetf(color.r), srgb_oetf(color.g), srgb_oetf(color.b)]
+    }
+
+    pub fn map(&self, color: ColorF32) -> Rgba<u8> {
+        let [r, g, b] = self.display(color).map(|value| (value * 255.0).round() as u8);
+        Rgba([r, g, b, 255])
+    }
+
+    pub fn map_image(&self, image: &Rgba32FImage) -> RgbaImage {
+        RgbaImage::from_fn(image.width(), image.height(), |x, y| self.map(image.get_pixel(x, y).into()))
+    }
+
+    /// `map_image` with 16 bits per channel, for smooth gradients
+    pub fn map_image_16(&self, image: &Rgba32FImage) -> ImageBuffer<Rgba<u16>, Vec<u16>> {
+        ImageBuffer::from_fn(image.width(), image.height(), |x, y| {
+            let [r, g, b] = self.display(image.get_pixel(x, y).into()).map(|value| (value * 65535.0).round() as u16);
+            Rgba([r, g, b, u16::MAX])
+        })
+    }
+}
+
+impl fmt::Display for ToneMapping {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self.operator {
+            ToneMapOperator::Clamp => "clamp",
+            ToneMapOperator::Reinhard => "reinhard",
+            ToneMapOperator::ExtendedReinhard { .. } => "reinhard-extended",
+            ToneMapOperator::Aces => "aces",
+            ToneMapOperator::Agx => "agx",
+        };
+        write!(f, "{}:", name)?;
+        if let ToneMapOperator::ExtendedReinhard { white } = self.operator {
+            write!(f, "white={},", white)?;
+        }
+        write!(f, "ev={}", self.exposure)
+    }
+}
+
+impl FromStr for ToneMapping {
+    type Err = anyhow::Error;
+
+    /// Parses `<operator>[:ev=<stops>][,white=<luminance>]`, e.g. `aces:ev=-1` or
+    /// `reinhard-extended:white=8`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, options) = s.split_once(':').unwrap_or((s, ""));
+        let mut white = ToneMapOperator::DEFAULT_WHITE;
+        let mut exposure = 0.0;
+        for option in options.split(',').filter(|option| !option.is_empty()) {
+            let (key, value) = option
+                .split_once('=')
+                .ok_or_else(|| anyhow::anyhow!("expected key=value in tone mapping options, got '{}'", option))?;
+            let invalid = |e: &dyn fmt::Display| anyhow::anyhow!("invalid value for tone mapping option '{}': {}", key, e);
+            match key {
+                "ev" => exposure = value.parse().map_err(|e| invalid(&e))?,
+                "white" if name == "reinhard-extended" => {
+                    white = value.parse().map_err(|e| invalid(&e))?;
+                    if white <= 0.0 {
+                        return Err(invalid(&"the white point must be positive"));
+                    }
+                }
+                _ => anyhow::bail!("unknown option '{}' for tone mapping operator '{}'", key, name),
+            }
+        }
+        let operator = match name {
+            "clamp" => ToneMapOperator::Clamp,
+            "reinhard" => ToneMapOperator::Reinhard,
+            "reinhard-extended" => ToneMapOperator::ExtendedReinhard { white },
+            "aces" => ToneMapOperator::Aces,
+            "agx" => ToneMapOperator::Agx,
+            _ => anyhow::bail!(
+                "unknown tone mapping operator '{}', expected clamp, reinhard, reinhard-extended, aces or agx",
+                name
+            ),
+        };
+        Ok(Self { operator, exposure })
+    }
+}
+
+/// sRGB transfer function, from linear light to the encoded value
+pub fn srgb_oetf(value: f32) -> f32 {
+    if value <= 0.003_130_8 {
+        value * 12.92
+    } else {
+        1.055 * value.powf(1.0 / 2.4) - 0.055
+    }
+}
+
+/// Scales the color so its luminance goes through `curve`, which keeps the ratios of the channels
+fn scale_luminance(color: ColorF32, curve: impl Fn(f32) -> f32) -> ColorF32 {
+    let luminance = color.luminance();
+    if luminance <= 0.0 {
+        return ColorF32::new(0.0, 0.0, 0.0);
+    }
+    color * (curve(luminance) / luminance)
+}
+
+/// Rows of a 3x3 matrix applied to the channels of a color
+fn transform(matrix: [[f32; 3]; 3], color: ColorF32) -> ColorF32 {
+    let [r, g, b] = matrix.map(|row| row[0] * color.r + row[1] * color.g + row[2] * color.b);
+    ColorF32::new(r, g, b)
+}
+
+fn aces(color: ColorF32) -> ColorF32 {
+    // sRGB to the ACES rendering space, with the exposure boost of the RRT baked in
+    const INPUT: [[f32; 3]; 3] = [
+        [0.597_19, 0.354_58, 0.048_23],
+        [0.076_00, 0.908_34, 0.015_66],
+        [0.028_40, 0.133_83, 0.837_77],
+    ];
+    // ODT space back to linear sRGB
+    const OUTPUT: [[f32; 3]; 3] = [
+        [1.604_75, -0.531_08, -0.073_67],
+        [-0.102_08, 1.108_13, -0.006_05],
+        [-0.003_27, -0.072_76, 1.076_02],
+    ];
+    let fit = |v: f32| (v * (v + 0.024_578_6) - 0.000_090_537) / (v * (0.983_729 * v + 0.432_951) + 0.238_081);
+    let color = transform(INPUT, color);
+    transform(OUTPUT, ColorF32::new(fit(color.r), fit(color.g), fit(color.b)))
+}
+
+fn agx(color: ColorF32) -> ColorF32 {
+    // Insets the primaries so bright saturated colors slide towards white
+    const INSET: [[f32; 3]; 3] = [
+        [0.842_479_06, 0.078_433_6, 0.079_223_745],
+        [0.042_328_242, 0.878_468_64, 0.079_166_13],
+        [0.042_375_656, 0.078_433_6, 0.879_143],
+    ];
+    const OUTSET: [[f32; 3]; 3] = [
+        [1.196_879, -0.098_020_88, -0.099_029_74],
+        [-0.052_896_85, 1.151_903_1, -0.098_961_176],
+        [-0.052_971_635, -0.098_043_45, 1.151_073_7],
+    ];
+    // Range of the log encoding in stops around middle gray
+    const MIN_EV: f32 = -12.473_93;
+    const MAX_EV: f32 = 4.026_069;
+    // Polynomial fit of the AgX base contrast sigmoid
+    let sigmoid = |value: f32| {
+        let x = (value.max(1e-10).log2().clamp(MIN_EV, MAX_EV) - MIN_EV) / (MAX_EV - MIN_EV);
+        let x2 = x * x;
+        let x4 = x2 * x2;
+        15.5 * x4 * x2 - 40.14 * x4 * x + 31.96 * x4 - 6.868 * x2 * x + 0.4298 * x2 + 0.1191 * x - 0.002_32
+    };
+    let color = transform(INSET, color);
+    let color = transform(OUTSET, ColorF32::new(sigmoid(color.r), sigmoid(color.g), sigmoid(color.b)));
+    // The sigmoid outputs display encoded values, back to linear for the sRGB encoding
+    let linear = |value: f32| value.max(0.0).powf(2.2);
+    ColorF32::new(linear(color.r), linear(color.g), linear(color.b))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::color::ColorF32;
+
+    use super::{srgb_oetf, ToneMapOperator, ToneMapping};
+
+    #[test]
+    fn encodes_srgb() {
+        assert_eq!(srgb_oetf(0.0), 0.0);
+        assert!((srgb_oetf(1.0) - 1.0).abs() < 1e-6);
+        let clamp = ToneMapping { operator: ToneMapOperator::Clamp, exposure: 0.0 };
+        assert_eq!(clamp.map(ColorF32::new(0.5, 0.2, 0.0)).0, [188, 124, 0, 255]);
+        assert_eq!(ToneMapping { exposure: 1.0, ..clamp }.map(ColorF32::new(0.25, 0.25, 0.25)).0[0], 188);
+    }
+
+    #[test]
+    fn operators_are_monotonic_and_stay_in_range() {
+        let mut operator = ToneMapOperator::Clamp;
+        loop {
+            let mut previous = 0.0;
+            for i in 0..200 {
+                let value = operator.apply(ColorF32::new(1.0, 0.6, 0.3) * (i as f32 * 0.25)).luminance();
+                assert!((0.0..=1.0).contains(&value), "{:?} gives {}", operator, value);
+                assert!(value >= previous - 1e-4, "{:?} isn't monotonic at {}", operator, i);
+                previous = value;
+            }
+            operator = operator.next();
+            if operator == ToneMapOperator::Clamp {
+                break;
+            }
+        }
+    }
+
+    #[test]
+    fn parses_what_it_prints() {
+        for s in ["clamp:ev=0", "reinhard:ev=-1.5", "reinhard-extended:white=8,ev=0", "aces:ev=2", "agx:ev=0"] {
+            assert_eq!(s.parse::<ToneMapping>().unwrap().to_string(), s);
+        }
+        assert!("filmic".parse::<ToneMapping>().is_err());
+        assert!("aces:white=2".parse::<ToneMapping>().is_err());
+    }
+}
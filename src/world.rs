@@ -1,46 +1,101 @@
 use std::f32::consts::PI;
 
-use crate::{object::Object, light::Light, color::{ColorF32, self}};
+use bvh::aabb::AABB;
 
+use crate::{object::Object, color::{ColorF32, self}, geometry::{Point, Ray}, stats::{self, Counter}};
+
+/// Where a ray hits an object
+#[derive(Clone)]
 pub struct Intersection<'a> {
     pub distance: f32,
     pub point: nalgebra::Point3<f32>,
-    pub object: &'a Box<dyn Object + Send+Sync>,
+    pub object: &'a dyn Object,
+    /// Index of the object in `World::objects`
+    pub object_id: usize,
     pub normal: nalgebra::Vector3<f32>,
+    pub uv: nalgebra::Vector2<f32>,
+    /// Time of the ray, which the rays leaving the hit keep
+    pub time: f32,
 }
 
+/// The objects of a scene, lit by their emissive materials and by the sky
 pub struct World {
-    pub objects: Vec<Box<dyn Object + Send+Sync>>,
+    pub objects: Vec<Box<dyn Object>>,
+    emitters: Vec<usize>,
+    /// Bounds of the objects added with `add_object`, the rays that miss them skip the object
+    bounds: Vec<Option<AABB>>,
+}
+
+impl Default for World {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl World {
     pub fn new() -> Self {
         Self {
             objects: Vec::new(),
+            emitters: Vec::new(),
+            bounds: Vec::new(),
         }
     }
 
-    pub fn add_object(&mut self, object: Box<dyn Object +Send+Sync>) {
+    /// Adds an object to the scene, as a light if its material emits
+    pub fn add_object(&mut self, object: Box<dyn Object>) {
+        let emissivity = object.material().emissivity();
+        if emissivity.r > 0.0 || emissivity.g > 0.0 || emissivity.b > 0.0 {
+            self.emitters.push(self.objects.len());
+        }
+        self.bounds.resize(self.objects.len(), None);
+        self.bounds.push(object.bounds());
         self.objects.push(object);
     }
 
+    /// Indices of the objects with an emissive material
+    pub fn emitters(&self) -> &[usize] {
+        &self.emitters
+    }
 
-    pub fn intersect(&self, r: &crate::geometry::Ray) -> Option<Intersection> {
+    pub fn is_emitter(&self, object_id: usize) -> bool {
+        self.emitters.binary_search(&object_id).is_ok()
+    }
+
+
+    pub fn intersect(&self, r: &crate::geometry::Ray) -> Option<Intersection<'_>> {
+        stats::count(Counter::Rays, 1);
+        let bvh_ray: bvh::ray::Ray = r.into();
         let mut closest: Option<Intersection> = None;
-        for object in &self.objects {
-            if let Some(intersection) = object.intersection(r, object) {
+        for (id, object) in self.objects.iter().enumerate() {
+            if self.bounds.get(id).copied().flatten().is_some_and(|bounds| !bvh_ray.intersects_aabb(&bounds)) {
+                continue;
+            }
+            if let Some(mut intersection) = object.intersection(r, object.as_ref()) {
                 if closest.is_none() || intersection.distance < closest.as_ref().unwrap().distance {
+                    intersection.object_id = id;
                     closest = Some(intersection);
                 }
             }
         }
         closest
     }
+
+    /// Checks if something blocks the segment between `from` and `to` at `time`
+    pub fn occluded(&self, from: &Point, to: &Point, time: f32) -> bool {
+        let offset = to - from;
+        let distance = offset.magnitude();
+        let ray = Ray::new_with_eps(*from, offset / distance, Self::SHADOW_EPSILON).with_time(time);
+        match self.intersect(&ray) {
+            Some(intersection) => intersection.distance < distance - 2.0 * Self::SHADOW_EPSILON,
+            None => false,
+        }
+    }
+    const SHADOW_EPSILON: f32 = 0.001;
     const SUN_INTENSITY: f32 = 0.4;
 
-    const Br : f32 = 0.0025;
-    const Bm : f32 = 0.0003;
-    const g: f32 =  0.9800;
+    const RAYLEIGH: f32 = 0.0025;
+    const MIE: f32 = 0.0003;
+    const MIE_ASYMMETRY: f32 = 0.9800;
 
     pub fn background_color(&self, r: &crate::geometry::Ray) -> ColorF32 {
         let direction = r.direction.normalize();
@@ -51,16 +106,16 @@ impl World {
         let sun_direction = sun_direction.normalize();
         // https://github.com/shff/opengl_sky
         let nitrogen : ColorF32 = ColorF32::new(0.650, 0.570, 0.475);
-        let kr = Self::Br / ColorF32::pow(nitrogen, 4.0);
-        let km = Self::Bm / ColorF32::pow(nitrogen, 0.84);
+        let kr = Self::RAYLEIGH / ColorF32::pow(nitrogen, 4.0);
+        let km = Self::MIE / ColorF32::pow(nitrogen, 0.84);
         let mu = direction.dot(&sun_direction);
         let rayleigh = 3.0 / (8.0 * PI) * (1.0 + mu * mu);
-        let g = Self::g;
-        let mie = (kr + km * (1.0 - g * g) / (2.0 + g * g) / (1.0 + g * g - 2.0 * g * mu).powf(1.5)) / (Self::Br + Self::Bm);
+        let g = Self::MIE_ASYMMETRY;
+        let mie = (kr + km * (1.0 - g * g) / (2.0 + g * g) / (1.0 + g * g - 2.0 * g * mu).powf(1.5)) / (Self::RAYLEIGH + Self::MIE);
         let day_extinction = 
             (-(-((direction.y + sun_direction.y * 4.0) *
-            ((-direction.y * 16.0).exp() + 0.1) / 80.0) / Self::Br).exp() *
-            ((-direction.y * 16.0).exp() + 0.1) * kr / Self::Br).exp() *
+            ((-direction.y * 16.0).exp() + 0.1) / 80.0) / Self::RAYLEIGH).exp() *
+            ((-direction.y * 16.0).exp() + 0.1) * kr / Self::RAYLEIGH).exp() *
          (-direction.y * (-direction.y * 8.0 ).exp() * 4.0).exp() * 
          (-direction.y * 2.0).exp() * 4.0;
 